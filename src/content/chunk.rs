@@ -1,4 +1,5 @@
 use std::fmt::{self, Debug};
+use std::mem;
 
 use linked_hash_map::LinkedHashMap;
 
@@ -34,6 +35,21 @@ impl Chunk {
         self.refcnt.dec_ref()
     }
 
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn refcnt(&self) -> u32 {
+        self.refcnt.val()
+    }
+
+    #[inline]
+    pub fn reset_ref(&mut self, refcnt: u32) {
+        self.refcnt.reset(refcnt)
+    }
+
     #[inline]
     pub fn end_pos(&self) -> usize {
         self.pos + self.len
@@ -94,13 +110,19 @@ impl ChunkMap {
         }
     }
 
+    // approximate heap memory size, in bytes
+    pub fn heap_size(&self) -> usize {
+        self.seg_ids.len() * mem::size_of::<Eid>()
+            + self.map.len() * mem::size_of::<(Hash, ChunkIdx)>()
+    }
+
     pub fn get_refresh(&mut self, hash: &Hash) -> Option<ChunkLoc> {
         if !self.is_enabled {
             return None;
         }
         let seg_ids = &self.seg_ids;
         self.map.get_refresh(hash).map(|ci| ChunkLoc {
-            seg_id: seg_ids[ci.seg_idx as usize].clone(),
+            seg_id: seg_ids[ci.seg_idx].clone(),
             idx: ci.chk_idx,
         })
     }
@@ -153,6 +175,28 @@ impl ChunkMap {
             });
     }
 
+    // get segments which have chunks in the map
+    pub fn segments(&self) -> Vec<Eid> {
+        let mut used = vec![false; self.seg_ids.len()];
+        for val in self.map.values() {
+            used[val.seg_idx] = true;
+        }
+        self.seg_ids
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| *used)
+            .map(|(seg_id, _)| seg_id.clone())
+            .collect()
+    }
+
+    pub fn has_segment(&self, seg_id: &Eid) -> bool {
+        self.seg_ids
+            .iter()
+            .position(|s| s == seg_id)
+            .map(|seg_idx| self.map.values().any(|val| val.seg_idx == seg_idx))
+            .unwrap_or(false)
+    }
+
     pub fn remove_segment(&mut self, seg_id: &Eid) {
         if !self.is_enabled {
             return;
@@ -246,7 +246,7 @@ mod tests {
 
         fn flush(&mut self) -> IoResult<()> {
             // verify
-            let sum = self.chks.iter().fold(0, |sum, ref t| sum + t.len);
+            let sum = self.chks.iter().fold(0, |sum, t| sum + t.len);
             assert_eq!(sum, self.len);
             for i in 0..(self.chks.len() - 2) {
                 assert_eq!(self.chks[i].pos + self.chks[i].len, self.chks[i + 1].pos);
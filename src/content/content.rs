@@ -1,7 +1,7 @@
 use std::cmp::min;
-use std::error::Error as StdError;
 use std::fmt::{self, Debug};
-use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::io::{self, Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::ops::Range;
 
 use super::chunk::ChunkMap;
 use super::entry::{CutableList, EntryList};
@@ -40,6 +40,11 @@ impl Content {
         self.ents.len()
     }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ents.is_empty()
+    }
+
     #[inline]
     pub fn end_offset(&self) -> usize {
         self.ents.end_offset()
@@ -89,11 +94,32 @@ impl Content {
         Ok(())
     }
 
+    /// Get segment chunk ranges referred by this content
+    pub fn seg_chunks(&self) -> Vec<(Eid, Range<usize>)> {
+        let mut ret = Vec::new();
+        for ent in self.ents.iter() {
+            for span in ent.iter() {
+                ret.push((ent.seg_id().clone(), span.begin..span.end));
+            }
+        }
+        ret
+    }
+
+    /// Re-calculate merkle root hash from content data and check if it
+    /// matches the saved one
+    pub fn verify_hash(&self, store: &StoreRef) -> Result<bool> {
+        let mut rdr = Reader::new(self.clone(), store);
+        let mut wtr = MerkleTreeWriter::new();
+        io::copy(&mut rdr, &mut wtr)?;
+        let mtree = MerkleTree::build(&wtr.finish_with_leaves());
+        Ok(mtree.root_hash() == self.hash())
+    }
+
     // build reference between content and segment
     #[inline]
     pub fn link(&self, store: &StoreRef) -> Result<()> {
         let mut store = store.write().unwrap();
-        self.ents.link(&mut store)
+        self.ents.link(store.make_mut()?)
     }
 
     // remove reference between content and segment
@@ -127,7 +153,12 @@ impl Debug for Content {
     }
 }
 
-impl Cowable for Content {}
+impl Cowable for Content {
+    #[inline]
+    fn heap_size(&self) -> usize {
+        self.ents.heap_size() + self.mtree.heap_size()
+    }
+}
 
 impl<'de> IntoCow<'de> for Content {}
 
@@ -154,7 +185,7 @@ impl Reader {
 
 impl Read for Reader {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        if buf.len() == 0 {
+        if buf.is_empty() {
             return Ok(0);
         }
 
@@ -168,9 +199,36 @@ impl Read for Reader {
             .iter()
             .skip_while(|e| e.end_offset() <= start)
         {
-            let seg_ref = map_io_err!(store.get_seg(ent.seg_id()))?;
+            let loaded = store.get_seg(ent.seg_id()).and_then(|seg_ref| {
+                let segdata_ref = {
+                    let seg = seg_ref.read().unwrap();
+                    store.get_segdata(seg.data_id())?
+                };
+                Ok((seg_ref, segdata_ref))
+            });
+            let (seg_ref, segdata_ref) = match loaded {
+                Ok(refs) => refs,
+                Err(err) => {
+                    if !store.is_salvage() {
+                        return map_io_err!(Err(err));
+                    }
+
+                    // in salvage mode, damaged entry is read as zeros
+                    warn!("damaged segment {:?}: {}", ent.seg_id(), err);
+                    let dst = &mut buf[buf_read..];
+                    let read_len = min(ent.end_offset() - self.pos as usize, dst.len());
+                    for b in dst[..read_len].iter_mut() {
+                        *b = 0;
+                    }
+                    buf_read += read_len;
+                    self.pos += read_len as u64;
+                    if buf_read >= buf.len() {
+                        return Ok(buf_read);
+                    }
+                    continue;
+                }
+            };
             let seg = seg_ref.read().unwrap();
-            let segdata_ref = map_io_err!(store.get_segdata(seg.data_id()))?;
             let segdata = segdata_ref.read().unwrap();
 
             for span in ent.iter().skip_while(|s| s.end_offset() <= start) {
@@ -224,7 +282,6 @@ pub struct Writer {
     chk_map: ChunkMap,
     seg_wtr: SegWriter,
     mtree_wtr: MerkleTreeWriter,
-    txid: Txid,
     store: StoreRef,
 }
 
@@ -241,7 +298,6 @@ impl Writer {
             chk_map,
             seg_wtr: SegWriter::new(txid, store, txmgr, vol),
             mtree_wtr: MerkleTreeWriter::new(),
-            txid,
             store: store.clone(),
         }
     }
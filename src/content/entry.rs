@@ -1,4 +1,5 @@
 use std::io::{Result as IoResult, Seek, SeekFrom};
+use std::mem;
 use std::ops::Index;
 use std::slice::Iter;
 
@@ -44,7 +45,7 @@ pub(super) trait CutableList: Clone + Extent {
         if !split.is_empty() {
             tail.insert(0, split);
         }
-        ret.set_len(tail.iter().fold(0, |sum, ref i| sum + i.len()));
+        ret.set_len(tail.iter().fold(0, |sum, i| sum + i.len()));
         ret.set_items(tail);
 
         let new_len = at - self.offset();
@@ -74,7 +75,7 @@ pub(super) trait CutableList: Clone + Extent {
 
         let mut ret = self.clone();
         ret.set_items(self.items().to_vec());
-        let head_len = ret.items().iter().fold(0, |sum, ref i| sum + i.len());
+        let head_len = ret.items().iter().fold(0, |sum, i| sum + i.len());
         ret.set_len(head_len);
 
         self.set_items(tail);
@@ -114,7 +115,7 @@ impl Entry {
     }
 
     #[inline]
-    pub fn iter(&self) -> Iter<Span> {
+    pub fn iter(&self) -> Iter<'_, Span> {
         self.spans.iter()
     }
 
@@ -227,8 +228,16 @@ impl EntryList {
         EntryList::default()
     }
 
+    // approximate heap memory size, in bytes
+    pub fn heap_size(&self) -> usize {
+        self.ents
+            .iter()
+            .map(|ent| mem::size_of::<Entry>() + ent.spans.len() * mem::size_of::<Span>())
+            .sum()
+    }
+
     #[inline]
-    pub fn iter(&self) -> Iter<Entry> {
+    pub fn iter(&self) -> Iter<'_, Entry> {
         self.ents.iter()
     }
 
@@ -295,8 +304,9 @@ impl EntryList {
     }
 
     // create reference relationship between content and segment
-    pub fn link(&self, store: &Store) -> Result<()> {
+    pub fn link(&self, store: &mut Store) -> Result<()> {
         for ent in self.ents.iter() {
+            store.register_segment(&ent.seg_id);
             let seg_ref = store.get_seg(&ent.seg_id)?;
             let mut seg_cow = seg_ref.write().unwrap();
             let seg = seg_cow.make_mut()?;
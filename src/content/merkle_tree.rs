@@ -1,6 +1,7 @@
 use std::cmp::{max, min};
 use std::fmt::{self, Debug};
 use std::io::{Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::mem;
 
 use crate::error::Result;
 use crate::util;
@@ -100,9 +101,15 @@ impl MerkleTree {
         MerkleTree::default()
     }
 
+    // approximate heap memory size, in bytes
+    #[inline]
+    pub fn heap_size(&self) -> usize {
+        self.nodes.len() * mem::size_of::<Hash>()
+    }
+
     #[inline]
     pub fn root_hash(&self) -> &Hash {
-        &self.nodes.first().unwrap()
+        self.nodes.first().unwrap()
     }
 
     #[inline]
@@ -132,7 +139,7 @@ impl MerkleTree {
     }
 
     // build merkle tree from bottom up
-    fn build(leaves: &Leaves) -> MerkleTree {
+    pub fn build(leaves: &Leaves) -> MerkleTree {
         assert_eq!(leaves.offset, 0);
         let leaf_cnt = leaves.nodes.len();
         let total_node_cnt = tree_node_cnt(leaf_cnt);
@@ -184,7 +191,7 @@ impl MerkleTree {
         self.nodes[leaves_begin..leaves_begin + old_leaves.len()].clone_from_slice(&old_leaves[..]);
 
         // copy in leave nodes
-        &self.nodes[overlap_begin..overlap_begin + leaves.nodes.len()]
+        self.nodes[overlap_begin..overlap_begin + leaves.nodes.len()]
             .clone_from_slice(&leaves.nodes[..]);
 
         // re-hash head and tail overlapping pieces
@@ -275,7 +282,7 @@ impl MerkleTree {
             let src_begin = parent(old_begin, old_begin, old_lvl_node_cnt);
             let src_end = parent(old_end - 1, old_begin, old_lvl_node_cnt) + 1;
             assert_eq!(dst_end - dst_begin, src_end - src_begin);
-            &new.nodes[dst_begin..dst_end].clone_from_slice(&self.nodes[src_begin..src_end]);
+            new.nodes[dst_begin..dst_end].clone_from_slice(&self.nodes[src_begin..src_end]);
 
             // re-hash the last node
             if (end - begin) & 1 == 0 {
@@ -425,7 +432,7 @@ mod tests {
         let mut wtr = Writer::new();
         wtr.seek(SeekFrom::Start(offset as u64)).unwrap();
         for chunk in buf.chunks(PIECE_SIZE) {
-            wtr.write(&chunk[..]).unwrap();
+            wtr.write_all(chunk).unwrap();
         }
         wtr.finish_with_leaves()
     }
@@ -465,7 +472,7 @@ mod tests {
         let mut mtree = build_mtree(&dst[..]);
 
         dst.resize(max(dst_len, offset + src_len), 0);
-        &dst[offset..offset + src_len].copy_from_slice(&src[..]);
+        dst[offset..offset + src_len].copy_from_slice(&src[..]);
 
         let mut rdr = Cursor::new(&dst);
         let leaves = make_leaves(offset, &src[..]);
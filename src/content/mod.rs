@@ -3,6 +3,7 @@
 
 mod chunk;
 mod chunker;
+#[allow(clippy::module_inception)]
 mod content;
 mod entry;
 mod merkle_tree;
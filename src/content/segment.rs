@@ -1,6 +1,6 @@
-use std::error::Error as StdError;
 use std::fmt::{self, Debug};
 use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::mem;
 use std::ops::{Index, IndexMut, Range};
 use std::sync::{Arc, RwLock};
 
@@ -10,7 +10,7 @@ use crate::error::Result;
 use crate::trans::cow::{CowCache, CowRef, Cowable, IntoCow};
 use crate::trans::trans::{Action, Transable};
 use crate::trans::{Eid, EntityType, Finish, Id, TxMgrRef, Txid};
-use crate::util::lru::{Lru, Meter, PinChecker};
+use crate::util::lru::{Meter, PinChecker, ShardedLru};
 use crate::util::IntoRef;
 use crate::volume::volume::{Reader as VolReader, VolumeRef, Writer as VolWriter};
 use crate::volume::Arm;
@@ -32,6 +32,11 @@ impl SegData {
         }
     }
 
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
     // Note: offset is in the segment data
     #[inline]
     pub fn read(&self, dst: &mut [u8], offset: usize) -> usize {
@@ -56,7 +61,7 @@ impl SegData {
     }
 
     fn load(id: &Eid, vol: &VolumeRef) -> Result<Self> {
-        let mut rdr = VolReader::new(id, vol)?;
+        let mut rdr = VolReader::with_type(id, EntityType::Direct, vol)?;
         let mut buf = Vec::new();
         rdr.read_to_end(&mut buf)?;
 
@@ -68,7 +73,7 @@ impl SegData {
     }
 
     fn save(&self, vol: &VolumeRef) -> Result<()> {
-        let mut wtr = VolWriter::new(&self.id, vol)?;
+        let mut wtr = VolWriter::with_type(&self.id, EntityType::Direct, vol)?;
         wtr.write_all(&self.data[..])?;
         wtr.finish()?;
         Ok(())
@@ -92,7 +97,7 @@ impl IntoRef for SegData {}
 impl Transable for SegData {
     #[inline]
     fn action(&self) -> Action {
-        self.action.clone().unwrap()
+        self.action.unwrap()
     }
 
     fn commit(&mut self, _vol: &VolumeRef) -> Result<()> {
@@ -138,35 +143,41 @@ impl Debug for SegData {
 /// Segment data reference type
 pub type SegDataRef = Arc<RwLock<SegData>>;
 
-// Segment data meter, measured by segment data bytes size
+// Segment data meter, measured by segment data memory size
 #[derive(Debug, Default)]
 struct SegDataMeter;
 
 impl Meter<SegDataRef> for SegDataMeter {
     fn measure(&self, item: &SegDataRef) -> isize {
         let seg_data = item.read().unwrap();
-        seg_data.data.len() as isize
+        (mem::size_of::<SegData>() + seg_data.data.capacity()) as isize
     }
 }
 
 // Segment data LRU
-type SegDataLru = Lru<Eid, SegDataRef, SegDataMeter, PinChecker<SegDataRef>>;
+type SegDataLru = ShardedLru<Eid, SegDataRef, SegDataMeter, PinChecker<SegDataRef>>;
 
 /// Segment data cache
 #[derive(Debug, Clone, Default)]
 pub struct DataCache {
-    lru: Arc<RwLock<SegDataLru>>,
+    lru: Arc<SegDataLru>,
 }
 
 impl DataCache {
     pub fn new(capacity: usize) -> Self {
         DataCache {
-            lru: Arc::new(RwLock::new(SegDataLru::new(capacity))),
+            lru: Arc::new(SegDataLru::new(capacity)),
         }
     }
 
+    // set cache size, in bytes
+    #[inline]
+    pub fn set_capacity(&self, capacity: usize) {
+        self.lru.set_capacity(capacity);
+    }
+
     pub fn get(&self, id: &Eid, vol: &VolumeRef) -> Result<SegDataRef> {
-        let mut lru = self.lru.write().unwrap();
+        let mut lru = self.lru.shard(id);
 
         // get from cache first
         if let Some(val) = lru.get_refresh(id) {
@@ -181,14 +192,15 @@ impl DataCache {
         Ok(ent)
     }
 
+    #[inline]
     pub fn remove(&self, id: &Eid) -> Option<SegDataRef> {
-        let mut lru = self.lru.write().unwrap();
-        lru.remove(id)
+        self.lru.remove(id)
     }
 }
 
 /// Segment
 #[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(from = "SegmentRepr")]
 pub struct Segment {
     len: usize,  // segment data length, in bytes,
     used: usize, // currently used segment data length, in bytes
@@ -196,6 +208,40 @@ pub struct Segment {
     chunks: Vec<Chunk>,
 }
 
+// serialized segment
+//
+// Older versions counted chunk length twice, once when chunk is appended
+// and again when it is referred, so saved used bytes cannot be trusted. It
+// is re-calculated from chunk reference counts when segment is loaded, the
+// fixed value is saved when the segment is changed next time.
+#[derive(Deserialize)]
+struct SegmentRepr {
+    len: usize,
+    used: usize,
+    data_id: Eid,
+    chunks: Vec<Chunk>,
+}
+
+impl From<SegmentRepr> for Segment {
+    fn from(repr: SegmentRepr) -> Self {
+        let used = repr
+            .chunks
+            .iter()
+            .filter(|chunk| !chunk.is_orphan())
+            .map(|chunk| chunk.len)
+            .sum();
+        if used != repr.used {
+            debug!("fix segment used bytes from {} to {}", repr.used, used);
+        }
+        Segment {
+            len: repr.len,
+            used,
+            data_id: repr.data_id,
+            chunks: repr.chunks,
+        }
+    }
+}
+
 impl Segment {
     // maximum number of chunks in a segment
     const MAX_CHUNKS: usize = 256;
@@ -209,6 +255,16 @@ impl Segment {
         }
     }
 
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
     #[inline]
     pub fn data_id(&self) -> &Eid {
         &self.data_id
@@ -234,12 +290,12 @@ impl Segment {
         self.used < self.len >> 2
     }
 
-    // create a new chunk and append to segment
+    // create a new chunk and append to segment, the chunk is not used until
+    // it is referred by ref_chunk()
     fn append_chunk(&mut self, data_len: usize) {
         let chunk = Chunk::new(self.len, data_len);
         self.chunks.push(chunk);
         self.len += data_len;
-        self.used += data_len;
     }
 
     pub fn ref_chunk(&mut self, idx: usize) -> Result<u32> {
@@ -272,6 +328,18 @@ impl Segment {
         Ok(())
     }
 
+    // reset chunks reference count and re-calculate used bytes
+    pub fn reset_refs(&mut self, refs: &[u32]) {
+        self.used = 0;
+        for (idx, chunk) in self.chunks.iter_mut().enumerate() {
+            let refcnt = refs.get(idx).cloned().unwrap_or(0);
+            chunk.reset_ref(refcnt);
+            if refcnt > 0 {
+                self.used += chunk.len;
+            }
+        }
+    }
+
     // shrink segment by creating a new segment data, return retired chunks
     // indices
     pub fn shrink(
@@ -301,7 +369,8 @@ impl Segment {
         // write new segment data to volume
         // and add a dummy segment data to transaction
         let new_data_id = Eid::new();
-        let new_seg_data = SegData::new(&new_data_id);
+        let mut new_seg_data = SegData::new(&new_data_id);
+        new_seg_data.data = buf;
         new_seg_data.save(vol)?;
         SegData::add_to_trans(&new_data_id, Action::New, txid, txmgr)?;
 
@@ -315,9 +384,9 @@ impl Segment {
 
 impl Debug for Segment {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
+        writeln!(
             f,
-            "Segment(len: {}, used: {}, data_id: {:?}, [\n",
+            "Segment(len: {}, used: {}, data_id: {:?}, [",
             self.len, self.used, self.data_id
         )
         .unwrap();
@@ -366,7 +435,12 @@ impl Index<Range<usize>> for Segment {
     }
 }
 
-impl Cowable for Segment {}
+impl Cowable for Segment {
+    #[inline]
+    fn heap_size(&self) -> usize {
+        self.chunks.len() * mem::size_of::<Chunk>()
+    }
+}
 
 impl<'de> IntoCow<'de> for Segment {}
 
@@ -417,7 +491,11 @@ impl Writer {
         }
 
         // and then create a new segment data writer and add segment to tx
-        self.data_wtr = Some(VolWriter::new(&seg.data_id, &self.vol)?);
+        self.data_wtr = Some(VolWriter::with_type(
+            &seg.data_id,
+            EntityType::Direct,
+            &self.vol,
+        )?);
         self.seg = seg.into_cow(&self.txmgr)?;
 
         // inject segment to segment cache in store
@@ -476,6 +554,69 @@ mod tests {
     use crate::content::entry::{CutableList, EntryList};
     use crate::content::span::{Extent, Span};
     use crate::util::init_env;
+    use rmp_serde::{Deserializer, Serializer};
+    use serde::{Deserialize, Serialize};
+
+    #[test]
+    fn chunk_usage() {
+        let mut seg = Segment::new();
+        seg.append_chunk(10);
+        seg.append_chunk(20);
+
+        // appended chunks are not used until they are referred
+        assert_eq!(seg.len, 30);
+        assert_eq!(seg.used, 0);
+        assert!(seg.is_orphan());
+
+        // chunk length is counted once, no matter how many refs it has
+        seg.ref_chunks(0..2).unwrap();
+        seg.ref_chunk(1).unwrap();
+        assert_eq!(seg.used, 30);
+        seg.deref_chunk(1).unwrap();
+        assert_eq!(seg.used, 30);
+
+        // segment becomes shrinkable and then orphan when chunks are unused
+        seg.deref_chunk(1).unwrap();
+        assert_eq!(seg.used, 10);
+        assert!(!seg.is_shrinkable());
+        seg.append_chunk(50);
+        assert!(seg.is_shrinkable());
+        seg.deref_chunk(0).unwrap();
+        assert!(seg.is_orphan());
+    }
+
+    #[test]
+    fn legacy_usage() {
+        let mut seg = Segment::new();
+        seg.append_chunk(10);
+        seg.append_chunk(20);
+        seg.append_chunk(30);
+        seg.ref_chunk(0).unwrap();
+        seg.ref_chunk(2).unwrap();
+        seg.ref_chunk(2).unwrap();
+        assert_eq!(seg.used, 40);
+
+        // segment saved by older versions counted appended chunks as well
+        let mut legacy = seg.clone();
+        legacy.used += legacy.len;
+        let mut buf = Vec::new();
+        legacy.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        let mut de = Deserializer::new(&buf[..]);
+        let loaded: Segment = Deserialize::deserialize(&mut de).unwrap();
+        assert_eq!(loaded.len, 60);
+        assert_eq!(loaded.used, 40);
+        assert!(!loaded.is_shrinkable());
+
+        // unused segment becomes orphan after load
+        let mut legacy = Segment::new();
+        legacy.append_chunk(10);
+        legacy.used = 10;
+        let mut buf = Vec::new();
+        legacy.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        let mut de = Deserializer::new(&buf[..]);
+        let loaded: Segment = Deserialize::deserialize(&mut de).unwrap();
+        assert!(loaded.is_orphan());
+    }
 
     fn test_split_off(elst: &EntryList, seg_begin: &Segment, seg_end: &Segment) {
         // split at the beginning
@@ -599,7 +740,7 @@ mod tests {
         split.check();
         assert_eq!(dst.len(), at);
         assert_eq!(dst.offset(), 0);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 1);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 1);
         assert_eq!(split.len(), 5);
         assert_eq!(split.offset(), 5);
 
@@ -611,7 +752,7 @@ mod tests {
         split.check();
         assert_eq!(dst.len(), at);
         assert_eq!(dst.offset(), 0);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 2);
         assert_eq!(split.len(), 0);
         assert_eq!(split.offset(), elst.len());
         assert_eq!(split.iter().count(), 0);
@@ -635,7 +776,7 @@ mod tests {
         split.check();
         assert_eq!(dst.len(), elst.len() - at);
         assert_eq!(dst.offset(), at);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 2);
         assert_eq!(split.len(), 0);
         assert_eq!(split.offset(), 0);
         assert_eq!(split.iter().count(), 0);
@@ -648,10 +789,10 @@ mod tests {
         split.check();
         assert_eq!(dst.len(), elst.len() - at);
         assert_eq!(dst.offset(), at);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 1);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 1);
         assert_eq!(split.len(), 5);
         assert_eq!(split.offset(), 0);
-        assert_eq!(split.iter().nth(0).unwrap().iter().count(), 1);
+        assert_eq!(split.iter().next().unwrap().iter().count(), 1);
     }
 
     fn multiple_segs_spans() {
@@ -683,11 +824,11 @@ mod tests {
         assert_eq!(dst.len(), half);
         assert_eq!(dst.offset(), 0);
         assert_eq!(dst.iter().count(), 1);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 2);
         assert_eq!(split.len(), half);
         assert_eq!(split.offset(), half);
         assert_eq!(split.iter().count(), 1);
-        assert_eq!(split.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(split.iter().next().unwrap().iter().count(), 2);
 
         // split off at 1/3
         let at = elst.len() / 3;
@@ -698,11 +839,11 @@ mod tests {
         assert_eq!(dst.len(), at);
         assert_eq!(dst.offset(), 0);
         assert_eq!(dst.iter().count(), 1);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 2);
         assert_eq!(split.len(), 10);
         assert_eq!(split.offset(), 10);
         assert_eq!(split.iter().count(), 1);
-        assert_eq!(split.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(split.iter().next().unwrap().iter().count(), 2);
 
         // split off at 2/3
         let at = elst.len() * 2 / 3;
@@ -713,12 +854,12 @@ mod tests {
         assert_eq!(dst.len(), at);
         assert_eq!(dst.offset(), 0);
         assert_eq!(dst.iter().count(), 2);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 2);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 2);
         assert_eq!(dst.iter().nth(1).unwrap().iter().count(), 1);
         assert_eq!(split.len(), 5);
         assert_eq!(split.offset(), 15);
         assert_eq!(split.iter().count(), 1);
-        assert_eq!(split.iter().nth(0).unwrap().iter().count(), 1);
+        assert_eq!(split.iter().next().unwrap().iter().count(), 1);
 
         // split to in the middle
         let half = elst.len() / 2;
@@ -740,7 +881,7 @@ mod tests {
         assert_eq!(dst.len(), elst.len() - at);
         assert_eq!(dst.offset(), at);
         assert_eq!(dst.iter().count(), 2);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 1);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 1);
         assert_eq!(dst.iter().nth(1).unwrap().iter().count(), 2);
         assert_eq!(split.len(), 5);
         assert_eq!(split.offset(), 0);
@@ -754,12 +895,12 @@ mod tests {
         split.check();
         assert_eq!(dst.len(), elst.len() - at);
         assert_eq!(dst.offset(), at);
-        assert_eq!(dst.iter().nth(0).unwrap().iter().count(), 2);
-        assert_eq!(dst.iter().nth(0).unwrap().seg_id(), &seg2_id);
+        assert_eq!(dst.iter().next().unwrap().iter().count(), 2);
+        assert_eq!(dst.iter().next().unwrap().seg_id(), &seg2_id);
         assert_eq!(split.len(), 10);
         assert_eq!(split.offset(), 0);
-        assert_eq!(split.iter().nth(0).unwrap().iter().count(), 2);
-        assert_eq!(split.iter().nth(0).unwrap().seg_id(), &seg_id);
+        assert_eq!(split.iter().next().unwrap().iter().count(), 2);
+        assert_eq!(split.iter().next().unwrap().seg_id(), &seg_id);
     }
 
     #[test]
@@ -136,11 +136,7 @@ impl Cutable for Span {
         self.len -= delta;
         self.offset = at;
         ret.end = self.begin;
-        ret.len = if align_at > ret.offset {
-            align_at - ret.offset
-        } else {
-            0
-        };
+        ret.len = align_at.saturating_sub(ret.offset);
         ret
     }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::io::{Result as IoResult, Seek, SeekFrom, Write};
 
@@ -10,6 +10,7 @@ use super::segment::{
 };
 use super::Content;
 use crate::error::{Error, Result};
+use crate::fs::CacheSizes;
 use crate::trans::cow::{Cow, CowRef, Cowable, IntoCow};
 use crate::trans::trans::Action;
 use crate::trans::{Eid, Id, TxMgrRef, Txid};
@@ -49,6 +50,11 @@ pub struct Store {
     chunker_params: ChunkerParams,
     content_map: HashMap<Hash, ContentMapEntry>,
 
+    // ids of segments referred by contents, segments are added when they
+    // are linked and removed along with their data
+    #[serde(default)]
+    segments: HashSet<Eid>,
+
     #[serde(skip_serializing, skip_deserializing, default)]
     content_cache: ContentCache,
 
@@ -63,44 +69,64 @@ pub struct Store {
 
     #[serde(skip_serializing, skip_deserializing, default)]
     vol: VolumeRef,
+
+    // if segments failed to load are read as zeros
+    #[serde(skip_serializing, skip_deserializing, default)]
+    salvage: bool,
 }
 
 impl Store {
-    // segment cache size
-    const SEG_CACHE_SIZE: usize = 16;
-
-    // segment data cache size, in bytes
-    const SEG_DATA_CACHE_SIZE: usize = 16 * 1024 * 1024;
+    // segment cache size, in bytes
+    const SEG_CACHE_SIZE: usize = 256 * 1024;
 
-    // default content cache size
-    const CONTENT_CACHE_SIZE: usize = 16;
+    // content cache size, in bytes
+    const CONTENT_CACHE_SIZE: usize = 256 * 1024;
 
     pub fn new(txmgr: &TxMgrRef, vol: &VolumeRef) -> Self {
         Store {
             chunker_params: ChunkerParams::new(),
             content_map: HashMap::new(),
+            segments: HashSet::new(),
             content_cache: ContentCache::new(Self::CONTENT_CACHE_SIZE, txmgr),
             seg_cache: SegCache::new(Self::SEG_CACHE_SIZE, txmgr),
-            segdata_cache: SegDataCache::new(Self::SEG_DATA_CACHE_SIZE),
+            segdata_cache: SegDataCache::new(CacheSizes::default().seg_data),
             txmgr: txmgr.clone(),
             vol: vol.clone(),
+            salvage: false,
         }
     }
 
-    pub fn open(store_id: &Eid, txmgr: &TxMgrRef, vol: &VolumeRef) -> Result<StoreRef> {
+    pub fn open(
+        store_id: &Eid,
+        txmgr: &TxMgrRef,
+        vol: &VolumeRef,
+        salvage: bool,
+    ) -> Result<StoreRef> {
         let store = Cow::<Store>::load(store_id, txmgr, vol)?;
         {
             let mut store_cow = store.write().unwrap();
             let store = store_cow.make_mut_naive();
             store.content_cache = ContentCache::new(Self::CONTENT_CACHE_SIZE, txmgr);
             store.seg_cache = SegCache::new(Self::SEG_CACHE_SIZE, txmgr);
-            store.segdata_cache = SegDataCache::new(Self::SEG_DATA_CACHE_SIZE);
+            store.segdata_cache = SegDataCache::new(CacheSizes::default().seg_data);
             store.txmgr = txmgr.clone();
             store.vol = vol.clone();
+            store.salvage = salvage;
         }
         Ok(store)
     }
 
+    #[inline]
+    pub fn is_salvage(&self) -> bool {
+        self.salvage
+    }
+
+    // set segment data cache size, in bytes
+    #[inline]
+    pub fn set_seg_data_cache_size(&self, size: usize) {
+        self.segdata_cache.set_capacity(size);
+    }
+
     #[inline]
     pub fn get_seg(&self, seg_id: &Eid) -> Result<SegRef> {
         self.seg_cache.get(seg_id, &self.vol)
@@ -122,13 +148,57 @@ impl Store {
         self.content_cache.get(content_id, &self.vol)
     }
 
+    /// Find content id by its hash
+    #[inline]
+    pub fn find_content(&self, hash: &Hash) -> Option<&Eid> {
+        self.content_map.get(hash).map(|ent| &ent.content_id)
+    }
+
+    /// Get all contents in content map
+    ///
+    /// Each item is content hash, content id and its reference count.
+    pub fn contents(&self) -> Vec<(Hash, Eid, u32)> {
+        self.content_map
+            .iter()
+            .map(|(hash, ent)| (hash.clone(), ent.content_id.clone(), ent.refcnt.val()))
+            .collect()
+    }
+
+    /// Get ids of all segments linked to contents
+    ///
+    /// Segments linked by older versions are not included until they are
+    /// linked again or registered by repair.
+    pub fn segments(&self) -> Vec<Eid> {
+        self.segments.iter().cloned().collect()
+    }
+
+    /// Register segment as linked to contents
+    #[inline]
+    pub fn register_segment(&mut self, seg_id: &Eid) {
+        self.segments.insert(seg_id.clone());
+    }
+
+    /// Reset content reference count
+    pub fn reset_content_ref(&mut self, hash: &Hash, refcnt: u32) -> Result<()> {
+        let ent = self.content_map.get_mut(hash).ok_or(Error::NoContent)?;
+        ent.refcnt.reset(refcnt);
+        Ok(())
+    }
+
+    /// Remove content from content map regardless of its reference count
+    pub fn remove_content(&mut self, hash: &Hash) -> Result<Option<ContentRef>> {
+        let ent = self.content_map.remove(hash).ok_or(Error::NoContent)?;
+        self.get_content(&ent.content_id)?;
+        Ok(self.content_cache.remove(&ent.content_id))
+    }
+
     /// Dedup content based on its hash
     pub fn dedup_content(&mut self, content: &Content) -> Result<(bool, Eid)> {
         let mut deduped = true;
         let ent = self
             .content_map
             .entry(content.hash().clone())
-            .or_insert_with(|| ContentMapEntry::new());
+            .or_insert_with(ContentMapEntry::new);
         ent.inc_ref()?;
         if ent.content_id.is_empty() {
             // no duplication found
@@ -151,7 +221,7 @@ impl Store {
                 .content_map
                 .get_mut(ctn.hash())
                 .ok_or(Error::NoContent)
-                .and_then(|ent| ent.dec_ref().map_err(|e| Error::from(e)))?;
+                .and_then(|ent| ent.dec_ref())?;
             if refcnt > 0 {
                 return Ok(None);
             }
@@ -163,20 +233,20 @@ impl Store {
 
     /// Remove segment and its associated segment data
     pub fn remove_segment(&mut self, seg_cow: &mut Cow<Segment>) -> Result<()> {
+        // get segment data id before segment is deleted, as deleted cow
+        // cannot be dereferenced
+        let data_id = seg_cow.data_id().clone();
+
         // add segment to tx for deletion
         seg_cow.make_del()?;
 
         // add segment data to transaction for deletion
-        SegData::add_to_trans(
-            seg_cow.data_id(),
-            Action::Delete,
-            Txid::current()?,
-            &self.txmgr,
-        )?;
+        SegData::add_to_trans(&data_id, Action::Delete, Txid::current()?, &self.txmgr)?;
 
         // remove seg and seg data from cache
-        self.segdata_cache.remove(seg_cow.data_id());
+        self.segdata_cache.remove(&data_id);
         self.seg_cache.remove(seg_cow.id());
+        self.segments.remove(seg_cow.id());
 
         Ok(())
     }
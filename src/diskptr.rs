@@ -1,8 +1,20 @@
 use super::*;
 
+/// A pointer to a location on disk or an off-log blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DiskPtr {
     /// Points to a value stored in the single-file log.
     Inline(LogId),
     /// Points to a value stored off-log in the blob directory.
     Blob(LogId, BlobPointer),
 }
+
+impl DiskPtr {
+    /// Returns the log offset this pointer refers to.
+    #[inline]
+    pub fn lid(&self) -> LogId {
+        match *self {
+            DiskPtr::Inline(lid) | DiskPtr::Blob(lid, _) => lid,
+        }
+    }
+}
@@ -2,13 +2,117 @@ use std::env::VarError;
 use std::error::Error as StdError;
 use std::fmt::{self, Display, Formatter};
 use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
 use std::result;
 
 use rmp_serde::decode::Error as DecodeError;
 use rmp_serde::encode::Error as EncodeError;
+#[cfg(feature = "storage-sqlite")]
+use rusqlite::Error as SqliteError;
+
+use crate::trans::Eid;
+
+/// Context information attached to an [`Error`].
+///
+/// All fields are optional, only the information known where the error
+/// happened is recorded.
+///
+/// [`Error`]: enum.Error.html
+#[derive(Debug, Clone, Default)]
+pub struct ErrorContext {
+    path: Option<PathBuf>,
+    to_path: Option<PathBuf>,
+    eid: Option<Eid>,
+    op: Option<&'static str>,
+    uri: Option<String>,
+}
+
+impl ErrorContext {
+    /// Returns the repository path where the error happened.
+    #[inline]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the destination repository path of a copy or rename where
+    /// the error happened.
+    #[inline]
+    pub fn to_path(&self) -> Option<&Path> {
+        self.to_path.as_deref()
+    }
+
+    /// Returns the entity id where the error happened.
+    #[inline]
+    pub fn eid(&self) -> Option<&Eid> {
+        self.eid.as_ref()
+    }
+
+    /// Returns the operation name where the error happened.
+    #[inline]
+    pub fn op(&self) -> Option<&'static str> {
+        self.op
+    }
+
+    /// Returns the underlying storage URI where the error happened.
+    #[inline]
+    pub fn uri(&self) -> Option<&str> {
+        self.uri.as_deref()
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut sep = "";
+        if let Some(op) = self.op {
+            write!(f, "op: {}", op)?;
+            sep = ", ";
+        }
+        if let Some(ref path) = self.path {
+            write!(f, "{}path: {}", sep, path.display())?;
+            sep = ", ";
+        }
+        if let Some(ref to_path) = self.to_path {
+            write!(f, "{}to: {}", sep, to_path.display())?;
+            sep = ", ";
+        }
+        if let Some(ref eid) = self.eid {
+            write!(f, "{}entity: {}", sep, eid)?;
+            sep = ", ";
+        }
+        if let Some(ref uri) = self.uri {
+            write!(f, "{}uri: {}", sep, uri)?;
+        }
+        Ok(())
+    }
+}
 
 /// The error type for operations with [`Repo`] and [`File`].
 ///
+/// Errors returned from [`Repo`] and [`File`] can carry context information
+/// such as path and operation name, they are wrapped in [`Error::Context`].
+/// Use `==` or match on [`Error::inner`] to check the error, matching
+/// variants directly does not look through the context.
+///
+/// # Examples
+///
+/// ```
+/// use f2ufs::repo::RepoOpener;
+/// use f2ufs::util::init_env;
+/// use f2ufs::Error;
+///
+/// init_env();
+/// let mut repo = RepoOpener::new()
+///     .create(true)
+///     .open("mem://", "pwd")
+///     .unwrap();
+/// let err = repo.remove_file("/foo").unwrap_err();
+/// assert_eq!(err, Error::NotFound);
+/// match *err.inner() {
+///     Error::NotFound => {}
+///     _ => unreachable!(),
+/// }
+/// ```
+///
 /// [`Repo`]: struct.Repo.html
 /// [`File`]: struct.File.html
 #[derive(Debug)]
@@ -31,6 +135,8 @@ pub enum Error {
     WrongVersion,
     NoEntity,
     NotInSync,
+    NotSupported,
+    NoMember,
 
     InTrans,
     NotInTrans,
@@ -59,10 +165,106 @@ pub enum Error {
     NotFinish,
     Closed,
 
+    FailPoint,
+
     Encode(EncodeError),
     Decode(DecodeError),
     Var(VarError),
     Io(IoError),
+
+    #[cfg(feature = "storage-sqlite")]
+    Sqlite(SqliteError),
+
+    /// An error with context information attached.
+    ///
+    /// Comparison and error code of this error are the same as the inner
+    /// error.
+    Context(Box<Error>, Box<ErrorContext>),
+}
+
+impl Error {
+    /// Returns the error without context information.
+    #[inline]
+    pub fn inner(&self) -> &Error {
+        match *self {
+            Error::Context(ref err, _) => err.inner(),
+            _ => self,
+        }
+    }
+
+    /// Returns the context information attached to this error, if any.
+    #[inline]
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match *self {
+            Error::Context(_, ref ctx) => Some(&**ctx),
+            _ => None,
+        }
+    }
+
+    // update context with the function, context is only set once so the
+    // innermost and most specific context is kept
+    fn update_context<F>(self, f: F) -> Error
+    where
+        F: FnOnce(&mut ErrorContext),
+    {
+        match self {
+            Error::Context(err, mut ctx) => {
+                f(&mut ctx);
+                Error::Context(err, ctx)
+            }
+            err => {
+                let mut ctx = Box::new(ErrorContext::default());
+                f(&mut ctx);
+                Error::Context(Box::new(err), ctx)
+            }
+        }
+    }
+
+    /// Attaches repository path to the error, if it is not set yet.
+    pub fn with_path(self, path: &Path) -> Error {
+        self.update_context(|ctx| {
+            if ctx.path.is_none() {
+                ctx.path = Some(path.to_path_buf());
+            }
+        })
+    }
+
+    /// Attaches destination repository path to the error, if it is not set
+    /// yet.
+    pub fn with_to_path(self, to_path: &Path) -> Error {
+        self.update_context(|ctx| {
+            if ctx.to_path.is_none() {
+                ctx.to_path = Some(to_path.to_path_buf());
+            }
+        })
+    }
+
+    /// Attaches entity id to the error, if it is not set yet.
+    pub fn with_eid(self, eid: &Eid) -> Error {
+        self.update_context(|ctx| {
+            if ctx.eid.is_none() {
+                ctx.eid = Some(eid.clone());
+            }
+        })
+    }
+
+    /// Attaches operation name to the error, if it is not set yet.
+    pub fn with_op(self, op: &'static str) -> Error {
+        self.update_context(|ctx| {
+            if ctx.op.is_none() {
+                ctx.op = Some(op);
+            }
+        })
+    }
+
+    /// Attaches underlying storage URI to the error, if it is not set yet.
+    pub fn with_uri(self, uri: &str) -> Error {
+        self.update_context(|ctx| {
+            if ctx.uri.is_none() {
+                ctx.uri = Some(uri.to_string());
+            }
+        })
+    }
 }
 
 impl Display for Error {
@@ -86,6 +288,8 @@ impl Display for Error {
             Error::WrongVersion => write!(f, "Version not match"),
             Error::NoEntity => write!(f, "Entity not found"),
             Error::NotInSync => write!(f, "Repo is not in sync"),
+            Error::NotSupported => write!(f, "Operation is not supported"),
+            Error::NoMember => write!(f, "No storage member available"),
 
             Error::InTrans => write!(f, "Already in transaction"),
             Error::NotInTrans => write!(f, "Not in transaction"),
@@ -114,15 +318,23 @@ impl Display for Error {
             Error::NotFinish => write!(f, "File does not finish yet"),
             Error::Closed => write!(f, "Repo is closed"),
 
+            Error::FailPoint => write!(f, "Fail point triggered"),
+
             Error::Encode(ref err) => err.fmt(f),
             Error::Decode(ref err) => err.fmt(f),
             Error::Var(ref err) => err.fmt(f),
             Error::Io(ref err) => err.fmt(f),
+
+            #[cfg(feature = "storage-sqlite")]
+            Error::Sqlite(ref err) => err.fmt(f),
+
+            Error::Context(ref err, ref ctx) => write!(f, "{} ({})", err, ctx),
         }
     }
 }
 
 impl StdError for Error {
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         match *self {
             Error::RefOverflow => "Refcnt overflow",
@@ -143,6 +355,8 @@ impl StdError for Error {
             Error::WrongVersion => "Version not match",
             Error::NoEntity => "Entity not found",
             Error::NotInSync => "Repo is not in sync",
+            Error::NotSupported => "Operation is not supported",
+            Error::NoMember => "No storage member available",
 
             Error::InTrans => "Already in transaction",
             Error::NotInTrans => "Not in transaction",
@@ -171,20 +385,33 @@ impl StdError for Error {
             Error::NotFinish => "File does not finish yet",
             Error::Closed => "Repo is closed",
 
+            Error::FailPoint => "Fail point triggered",
+
             Error::Encode(ref err) => err.description(),
             Error::Decode(ref err) => err.description(),
             Error::Var(ref err) => err.description(),
             Error::Io(ref err) => err.description(),
+
+            #[cfg(feature = "storage-sqlite")]
+            Error::Sqlite(ref err) => err.description(),
+
+            Error::Context(ref err, _) => err.description(),
         }
     }
 
-    fn cause(&self) -> Option<&StdError> {
+    #[allow(deprecated)]
+    fn cause(&self) -> Option<&dyn StdError> {
         match *self {
             Error::Encode(ref err) => Some(err),
             Error::Decode(ref err) => Some(err),
             Error::Var(ref err) => Some(err),
             Error::Io(ref err) => Some(err),
 
+            #[cfg(feature = "storage-sqlite")]
+            Error::Sqlite(ref err) => Some(err),
+
+            Error::Context(ref err, _) => err.cause(),
+
             _ => None,
         }
     }
@@ -214,9 +441,16 @@ impl From<IoError> for Error {
     }
 }
 
-impl Into<i32> for Error {
-    fn into(self) -> i32 {
-        match self {
+#[cfg(feature = "storage-sqlite")]
+impl From<SqliteError> for Error {
+    fn from(err: SqliteError) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<Error> for i32 {
+    fn from(val: Error) -> Self {
+        match val {
             Error::RefOverflow => -1000,
             Error::RefUnderflow => -1001,
 
@@ -235,6 +469,8 @@ impl Into<i32> for Error {
             Error::WrongVersion => -1024,
             Error::NoEntity => -1025,
             Error::NotInSync => -1026,
+            Error::NotSupported => -1027,
+            Error::NoMember => -1028,
 
             Error::InTrans => -1030,
             Error::NotInTrans => -1031,
@@ -263,17 +499,24 @@ impl Into<i32> for Error {
             Error::NotFinish => -1074,
             Error::Closed => -1075,
 
+            Error::FailPoint => -1080,
+
             Error::Encode(_) => -2000,
             Error::Decode(_) => -2010,
             Error::Var(_) => -2020,
             Error::Io(_) => -2030,
+
+            #[cfg(feature = "storage-sqlite")]
+            Error::Sqlite(_) => -2040,
+
+            Error::Context(err, _) => (*err).into(),
         }
     }
 }
 
 impl PartialEq for Error {
     fn eq(&self, other: &Error) -> bool {
-        match (self, other) {
+        match (self.inner(), other.inner()) {
             (&Error::RefOverflow, &Error::RefOverflow) => true,
             (&Error::RefUnderflow, &Error::RefUnderflow) => true,
 
@@ -292,6 +535,8 @@ impl PartialEq for Error {
             (&Error::WrongVersion, &Error::WrongVersion) => true,
             (&Error::NoEntity, &Error::NoEntity) => true,
             (&Error::NotInSync, &Error::NotInSync) => true,
+            (&Error::NotSupported, &Error::NotSupported) => true,
+            (&Error::NoMember, &Error::NoMember) => true,
 
             (&Error::InTrans, &Error::InTrans) => true,
             (&Error::NotInTrans, &Error::NotInTrans) => true,
@@ -320,10 +565,15 @@ impl PartialEq for Error {
             (&Error::NotFinish, &Error::NotFinish) => true,
             (&Error::Closed, &Error::Closed) => true,
 
+            (&Error::FailPoint, &Error::FailPoint) => true,
+
             (&Error::Encode(_), &Error::Encode(_)) => true,
             (&Error::Decode(_), &Error::Decode(_)) => true,
             (&Error::Var(_), &Error::Var(_)) => true,
-            (&Error::Io(ref a), &Error::Io(ref b)) => a.kind() == b.kind(),
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+
+            #[cfg(feature = "storage-sqlite")]
+            (&Error::Sqlite(_), &Error::Sqlite(_)) => true,
 
             (_, _) => false,
         }
@@ -337,3 +587,72 @@ impl PartialEq for Error {
 /// [`Result`]: https://doc.rust-lang.org/std/result/enum.Result.html
 /// [`f2ufs::Error`]: enum.Error.html
 pub type Result<T> = result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::RepoOpener;
+    use crate::util::init_env;
+    use crate::volume::storage::MemStorage;
+
+    #[test]
+    fn error_context() {
+        let eid = Eid::new();
+        let err = Error::Decrypt
+            .with_eid(&eid)
+            .with_op("read")
+            .with_eid(&Eid::new())
+            .with_uri("mem://foo");
+        assert_eq!(err, Error::Decrypt);
+        assert_eq!(Error::Decrypt, err);
+        assert!(err != Error::NotFound);
+        assert_eq!(*err.inner(), Error::Decrypt);
+        let code: i32 = err.into();
+        assert_eq!(code, Into::<i32>::into(Error::Decrypt));
+
+        let err = Error::Decrypt.with_eid(&eid).with_op("read");
+        let ctx = err.context().unwrap();
+        assert_eq!(ctx.eid(), Some(&eid));
+        assert_eq!(ctx.op(), Some("read"));
+        assert!(ctx.path().is_none());
+        assert_eq!(
+            err.to_string(),
+            format!("Decrypt error (op: read, entity: {})", eid)
+        );
+    }
+
+    #[test]
+    fn repo_error_context() {
+        init_env();
+        let mut repo = RepoOpener::new()
+            .create(true)
+            .open("mem://repo_error_context", "pwd")
+            .unwrap();
+
+        let err = repo.read_dir("/not/exists").unwrap_err();
+        assert_eq!(err, Error::NotFound);
+        let ctx = err.context().unwrap();
+        assert_eq!(ctx.path(), Some(Path::new("/not/exists")));
+        assert_eq!(ctx.op(), Some("read_dir"));
+
+        let err = repo.open_file("/foo").unwrap_err();
+        assert_eq!(err, Error::NotFound);
+        assert_eq!(err.context().unwrap().path(), Some(Path::new("/foo")));
+
+        let err = repo.rename("/foo", "/bar").unwrap_err();
+        assert_eq!(err, Error::NotFound);
+        let ctx = err.context().unwrap();
+        assert_eq!(ctx.path(), Some(Path::new("/foo")));
+        assert_eq!(ctx.to_path(), Some(Path::new("/bar")));
+        assert_eq!(
+            err.to_string(),
+            "File not found (op: rename, path: /foo, to: /bar)"
+        );
+
+        let err = RepoOpener::new().open("foo://bar", "pwd").unwrap_err();
+        assert_eq!(err, Error::InvalidUri);
+        assert_eq!(err.context().unwrap().uri(), Some("foo://bar"));
+
+        MemStorage::destroy("repo_error_context");
+    }
+}
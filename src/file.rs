@@ -1,4 +1,3 @@
-use std::error::Error as StdError;
 use std::fmt::{self, Debug};
 use std::io::{self, Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
 
@@ -16,6 +15,8 @@ use crate::trans::{TxHandle, TxMgr};
 /// [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
 #[derive(Debug)]
 pub struct VersionReader {
+    // keeps the file handle alive while reading
+    #[allow(dead_code)]
     handle: Handle,
     rdr: FnodeReader,
 }
@@ -447,7 +448,7 @@ impl File {
         }
 
         // re-create reader if there is an existing reader
-        if !self.rdr.is_none() {
+        if self.rdr.is_some() {
             self.renew_reader()?;
         }
 
@@ -510,7 +511,7 @@ impl File {
         tx_handle.run_all(|| Fnode::set_len(self.handle.clone(), len, tx_handle.txid))?;
 
         // re-create reader if there is an existing reader
-        if !self.rdr.is_none() {
+        if self.rdr.is_some() {
             self.renew_reader()?;
         }
 
@@ -524,7 +525,7 @@ impl Read for File {
         if !self.can_read {
             return Err(IoError::new(
                 ErrorKind::Other,
-                Error::CannotRead.description(),
+                Error::CannotRead.to_string(),
             ));
         }
 
@@ -583,7 +584,7 @@ impl Write for File {
             },
             None => Err(IoError::new(
                 ErrorKind::PermissionDenied,
-                Error::CannotWrite.description(),
+                Error::CannotWrite.to_string(),
             )),
         }
     }
@@ -593,10 +594,7 @@ impl Seek for File {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         map_io_err!(self.check_closed())?;
         if self.wtr.is_some() {
-            return Err(IoError::new(
-                ErrorKind::Other,
-                Error::NotFinish.description(),
-            ));
+            return Err(IoError::new(ErrorKind::Other, Error::NotFinish.to_string()));
         }
 
         self.pos = match self.rdr {
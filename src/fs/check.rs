@@ -0,0 +1,866 @@
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::fnode::{Cache as FnodeCache, Fnode, FnodeRef};
+use crate::content::{ContentRef, StoreRef};
+use crate::error::{Error, Result};
+use crate::trans::{Eid, EntityType, Id, TxMgrRef};
+use crate::volume::address::{BlockSet, Span};
+use crate::volume::storage::StorageRef;
+use crate::volume::{AllocatorRef, Arm, VolumeRef};
+
+/// Options for repository consistency check.
+///
+/// See [`Repo::check`] for more details.
+///
+/// [`Repo::check`]: ../../repo/struct.Repo.html#method.check
+#[derive(Debug, Clone, Copy)]
+pub struct CheckOptions {
+    /// Read all file contents and verify their merkle root hashes.
+    pub verify_data: bool,
+
+    /// Verify block addresses of all referenced entities.
+    pub verify_address: bool,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            verify_data: true,
+            verify_address: true,
+        }
+    }
+}
+
+/// A problem found by repository consistency check.
+#[derive(Debug)]
+pub enum Problem {
+    /// Fnode cannot be loaded
+    BadFnode { path: PathBuf, err: Error },
+
+    /// File version refers to a content which is not in content map
+    MissingContent {
+        path: PathBuf,
+        ver: usize,
+        content_id: Eid,
+    },
+
+    /// Content cannot be loaded
+    BadContent { content_id: Eid, err: Error },
+
+    /// Content is in content map but not used by any file version
+    OrphanContent { content_id: Eid },
+
+    /// Content reference count doesn't match number of file versions
+    ContentRefMismatch {
+        content_id: Eid,
+        refcnt: u32,
+        actual: u32,
+    },
+
+    /// Content merkle root hash doesn't match its data
+    HashMismatch { content_id: Eid },
+
+    /// Segment cannot be loaded
+    BadSegment { seg_id: Eid, err: Error },
+
+    /// Segment is not referred by any content
+    OrphanSegment { seg_id: Eid },
+
+    /// Segment data belongs to a segment not referred by any content
+    OrphanSegData { seg_id: Eid, data_id: Eid },
+
+    /// Content refers to a chunk which is not in segment
+    ChunkOutOfRange { seg_id: Eid, idx: usize },
+
+    /// Chunk reference count doesn't match number of content references
+    ChunkRefMismatch {
+        seg_id: Eid,
+        idx: usize,
+        refcnt: u32,
+        actual: u32,
+    },
+
+    /// Segment used bytes doesn't match its referenced chunks
+    SegmentUsageMismatch {
+        seg_id: Eid,
+        used: usize,
+        actual: usize,
+    },
+
+    /// Segment data cannot be loaded
+    BadSegData {
+        seg_id: Eid,
+        data_id: Eid,
+        err: Error,
+    },
+
+    /// Segment data length doesn't match segment length
+    SegDataMismatch {
+        seg_id: Eid,
+        data_id: Eid,
+        len: usize,
+        actual: usize,
+    },
+
+    /// Entity has no address in storage
+    MissingAddress { id: Eid },
+
+    /// Entity address cannot be loaded
+    BadAddress { id: Eid, err: Error },
+
+    /// Entity address has blocks beyond block watermark
+    BlockOutOfRange { id: Eid, span: Span },
+
+    /// Entity address shares blocks with another entity
+    BlockOverlap { id: Eid, other: Eid, span: Span },
+
+    /// Entity address has blocks which are free in block allocator
+    FreeBlockInUse { id: Eid, span: Span },
+
+    /// Entity address has blocks marked as deleted in storage sector
+    DeletedBlockInUse { id: Eid, span: Span },
+
+    /// Blocks are allocated but neither used by any entity nor marked as
+    /// deleted in storage sector
+    LeakedBlock { span: Span },
+}
+
+/// Repository consistency check report.
+///
+/// This structure is returned from [`Repo::check`].
+///
+/// [`Repo::check`]: ../../repo/struct.Repo.html#method.check
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    fnode_cnt: usize,
+    content_cnt: usize,
+    segment_cnt: usize,
+    address_cnt: usize,
+    problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// Returns whether no problem is found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Returns number of checked fnodes.
+    #[inline]
+    pub fn fnode_cnt(&self) -> usize {
+        self.fnode_cnt
+    }
+
+    /// Returns number of checked contents.
+    #[inline]
+    pub fn content_cnt(&self) -> usize {
+        self.content_cnt
+    }
+
+    /// Returns number of checked segments.
+    #[inline]
+    pub fn segment_cnt(&self) -> usize {
+        self.segment_cnt
+    }
+
+    /// Returns number of checked entity addresses.
+    #[inline]
+    pub fn address_cnt(&self) -> usize {
+        self.address_cnt
+    }
+
+    /// Returns problems found.
+    #[inline]
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+}
+
+/// Consistency checker
+pub(super) struct Checker<'a> {
+    opts: CheckOptions,
+    fcache: &'a FnodeCache,
+    store: &'a StoreRef,
+    txmgr: &'a TxMgrRef,
+    vol: &'a VolumeRef,
+
+    // number of file versions referring to each content
+    ver_refs: HashMap<Eid, u32>,
+
+    // the first file version referring to each content
+    ver_paths: HashMap<Eid, (PathBuf, usize)>,
+
+    // cow entities, they are saved in arms
+    cow_ids: Vec<Eid>,
+
+    // direct entities, they are saved without arms
+    direct_ids: Vec<Eid>,
+
+    report: CheckReport,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(
+        opts: CheckOptions,
+        fcache: &'a FnodeCache,
+        store: &'a StoreRef,
+        txmgr: &'a TxMgrRef,
+        vol: &'a VolumeRef,
+    ) -> Self {
+        Checker {
+            opts,
+            fcache,
+            store,
+            txmgr,
+            vol,
+            ver_refs: HashMap::new(),
+            ver_paths: HashMap::new(),
+            cow_ids: Vec::new(),
+            direct_ids: Vec::new(),
+            report: CheckReport::default(),
+        }
+    }
+
+    pub fn run(mut self, root: &FnodeRef) -> Result<CheckReport> {
+        {
+            let store = self.store.read().unwrap();
+            self.cow_ids.push(store.id().clone());
+        }
+        self.check_fnode(root, Path::new("/"));
+        let contents = self.check_contents();
+        if self.opts.verify_data {
+            self.check_data(contents);
+        }
+        if self.opts.verify_address {
+            self.check_addresses();
+        }
+        Ok(self.report)
+    }
+
+    #[inline]
+    fn add_problem(&mut self, problem: Problem) {
+        debug!("check problem: {:?}", problem);
+        self.report.problems.push(problem);
+    }
+
+    // walk through fnode tree and collect content references
+    fn check_fnode(&mut self, fnode_ref: &FnodeRef, path: &Path) {
+        self.report.fnode_cnt += 1;
+
+        let child_names = {
+            let fnode = fnode_ref.read().unwrap();
+            self.cow_ids.push(fnode.id().clone());
+            for ver in fnode.history().iter() {
+                *self.ver_refs.entry(ver.content_id().clone()).or_insert(0) += 1;
+                self.ver_paths
+                    .entry(ver.content_id().clone())
+                    .or_insert_with(|| (path.to_path_buf(), ver.num()));
+            }
+            fnode.children_names()
+        };
+
+        for name in child_names.iter() {
+            let child_path = path.join(name);
+            match Fnode::child(fnode_ref, name, self.fcache, self.vol) {
+                Ok(child) => self.check_fnode(&child, &child_path),
+                Err(err) => self.add_problem(Problem::BadFnode {
+                    path: child_path,
+                    err,
+                }),
+            }
+        }
+    }
+
+    // check contents in content map and their segments, return contents
+    // whose segments are all good
+    fn check_contents(&mut self) -> Vec<ContentRef> {
+        let store_ref = self.store;
+        let store = store_ref.read().unwrap();
+
+        // expected chunk reference count for each segment
+        let mut chk_refs: HashMap<Eid, Vec<u32>> = HashMap::new();
+        let mut contents = Vec::new();
+
+        for (hash, content_id, refcnt) in store.contents() {
+            self.report.content_cnt += 1;
+            self.cow_ids.push(content_id.clone());
+
+            let actual = self.ver_refs.remove(&content_id).unwrap_or(0);
+            if actual == 0 {
+                self.add_problem(Problem::OrphanContent {
+                    content_id: content_id.clone(),
+                });
+            } else if refcnt != actual {
+                self.add_problem(Problem::ContentRefMismatch {
+                    content_id: content_id.clone(),
+                    refcnt,
+                    actual,
+                });
+            }
+
+            let ctn_ref = match store.get_content(&content_id) {
+                Ok(ctn_ref) => ctn_ref,
+                Err(err) => {
+                    self.add_problem(Problem::BadContent { content_id, err });
+                    continue;
+                }
+            };
+
+            {
+                let ctn = ctn_ref.read().unwrap();
+                if *ctn.hash() != hash {
+                    self.add_problem(Problem::HashMismatch {
+                        content_id: content_id.clone(),
+                    });
+                }
+                for (seg_id, range) in ctn.seg_chunks() {
+                    let refs = chk_refs.entry(seg_id).or_default();
+                    if refs.len() < range.end {
+                        refs.resize(range.end, 0);
+                    }
+                    for idx in range {
+                        refs[idx] += 1;
+                    }
+                }
+            }
+
+            contents.push(ctn_ref);
+        }
+
+        // the remaining content references are not in content map
+        let ver_refs: Vec<Eid> = self.ver_refs.drain().map(|(id, _)| id).collect();
+        for content_id in ver_refs {
+            let (path, ver) = self.ver_paths.remove(&content_id).unwrap();
+            self.add_problem(Problem::MissingContent {
+                path,
+                ver,
+                content_id,
+            });
+        }
+
+        // check segments and their data
+        let mut bad_segs = HashSet::new();
+        for (seg_id, refs) in chk_refs.iter() {
+            self.report.segment_cnt += 1;
+            self.cow_ids.push(seg_id.clone());
+
+            let seg_ref = match store.get_seg(seg_id) {
+                Ok(seg_ref) => seg_ref,
+                Err(err) => {
+                    self.add_problem(Problem::BadSegment {
+                        seg_id: seg_id.clone(),
+                        err,
+                    });
+                    bad_segs.insert(seg_id.clone());
+                    continue;
+                }
+            };
+            let seg = seg_ref.read().unwrap();
+
+            let mut used = 0;
+            for idx in 0..max(seg.chunk_cnt(), refs.len()) {
+                let actual = refs.get(idx).cloned().unwrap_or(0);
+                if idx >= seg.chunk_cnt() {
+                    if actual > 0 {
+                        self.add_problem(Problem::ChunkOutOfRange {
+                            seg_id: seg_id.clone(),
+                            idx,
+                        });
+                        bad_segs.insert(seg_id.clone());
+                    }
+                    continue;
+                }
+
+                let chunk = &seg[idx];
+                if chunk.refcnt() != actual {
+                    self.add_problem(Problem::ChunkRefMismatch {
+                        seg_id: seg_id.clone(),
+                        idx,
+                        refcnt: chunk.refcnt(),
+                        actual,
+                    });
+                }
+                if actual > 0 {
+                    used += chunk.len();
+                }
+            }
+            if seg.used() != used {
+                self.add_problem(Problem::SegmentUsageMismatch {
+                    seg_id: seg_id.clone(),
+                    used: seg.used(),
+                    actual: used,
+                });
+            }
+
+            let data_id = seg.data_id().clone();
+            self.direct_ids.push(data_id.clone());
+            match store.get_segdata(&data_id) {
+                Ok(segdata_ref) => {
+                    let segdata = segdata_ref.read().unwrap();
+                    if segdata.len() != seg.len() {
+                        self.add_problem(Problem::SegDataMismatch {
+                            seg_id: seg_id.clone(),
+                            data_id,
+                            len: seg.len(),
+                            actual: segdata.len(),
+                        });
+                        bad_segs.insert(seg_id.clone());
+                    }
+                }
+                Err(err) => {
+                    self.add_problem(Problem::BadSegData {
+                        seg_id: seg_id.clone(),
+                        data_id,
+                        err,
+                    });
+                    bad_segs.insert(seg_id.clone());
+                }
+            }
+        }
+
+        // segments are not referred by any content
+        for seg_id in store.segments() {
+            if chk_refs.contains_key(&seg_id) {
+                continue;
+            }
+            self.report.segment_cnt += 1;
+            self.cow_ids.push(seg_id.clone());
+            self.add_problem(Problem::OrphanSegment {
+                seg_id: seg_id.clone(),
+            });
+            match store.get_seg(&seg_id) {
+                Ok(seg_ref) => {
+                    let data_id = {
+                        let seg = seg_ref.read().unwrap();
+                        seg.data_id().clone()
+                    };
+                    self.direct_ids.push(data_id.clone());
+                    self.add_problem(Problem::OrphanSegData { seg_id, data_id });
+                }
+                Err(err) => self.add_problem(Problem::BadSegment { seg_id, err }),
+            }
+        }
+
+        // skip contents referring to bad segments
+        contents.retain(|ctn_ref| {
+            let ctn = ctn_ref.read().unwrap();
+            ctn.seg_chunks()
+                .iter()
+                .all(|(seg_id, _)| !bad_segs.contains(seg_id))
+        });
+
+        contents
+    }
+
+    // read content data and verify merkle root hash
+    fn check_data(&mut self, contents: Vec<ContentRef>) {
+        for ctn_ref in contents {
+            let ctn = ctn_ref.read().unwrap();
+            match ctn.verify_hash(self.store) {
+                Ok(true) => {}
+                Ok(false) => self.add_problem(Problem::HashMismatch {
+                    content_id: ctn.id().clone(),
+                }),
+                Err(err) => self.add_problem(Problem::BadContent {
+                    content_id: ctn.id().clone(),
+                    err,
+                }),
+            }
+        }
+    }
+
+    // check entity addresses and their blocks
+    fn check_addresses(&mut self) {
+        // entities deleted by retired txs keep their blocks until their
+        // wals are recycled
+        let retired = {
+            let txmgr = self.txmgr.read().unwrap();
+            match txmgr.retired_entities() {
+                Ok(retired) => retired,
+                Err(err) => {
+                    warn!("cannot get retired entities: {}", err);
+                    Vec::new()
+                }
+            }
+        };
+
+        let vol_ref = self.vol;
+        let vol = vol_ref.read().unwrap();
+        let allocator_ref = vol.get_allocator();
+        let allocator = allocator_ref.read().unwrap();
+        let blk_wmark = allocator.block_wmark();
+
+        // collect addresses, cow entity must have at least one arm, retired
+        // entity may have no address if it was deleted in its creating tx
+        let mut addrs = Vec::new();
+        let mut ids = Vec::new();
+        for id in self.cow_ids.iter() {
+            ids.push((id.clone(), EntityType::Cow, true));
+        }
+        for id in self.direct_ids.iter() {
+            ids.push((id.clone(), EntityType::Direct, true));
+        }
+        for (id, ent_type) in retired {
+            ids.push((id, ent_type, false));
+        }
+        for (id, ent_type, required) in ids {
+            let addr_ids = match ent_type {
+                EntityType::Cow => vec![Arm::Left.to_eid(&id), Arm::Right.to_eid(&id)],
+                EntityType::Direct => vec![id.clone()],
+            };
+            let mut found = false;
+            for addr_id in addr_ids {
+                match vol.get_address(&addr_id) {
+                    Ok(addr) => {
+                        found = true;
+                        addrs.push((addr_id, ent_type, addr));
+                    }
+                    Err(ref err) if *err == Error::NotFound => {}
+                    Err(err) => {
+                        found = true;
+                        self.add_problem(Problem::BadAddress { id: addr_id, err });
+                    }
+                }
+            }
+            if !found && required {
+                self.add_problem(Problem::MissingAddress { id });
+            }
+        }
+
+        // check blocks are allocated and not shared by entities
+        let mut spans = Vec::new();
+        for (id, ent_type, addr) in addrs {
+            self.report.address_cnt += 1;
+            for loc_span in addr.iter() {
+                if loc_span.span.end() > blk_wmark {
+                    self.add_problem(Problem::BlockOutOfRange {
+                        id: id.clone(),
+                        span: loc_span.span,
+                    });
+                }
+                if allocator.is_free(loc_span.span) {
+                    self.add_problem(Problem::FreeBlockInUse {
+                        id: id.clone(),
+                        span: loc_span.span,
+                    });
+                }
+                spans.push((loc_span.span, ent_type, id.clone()));
+            }
+        }
+        spans.sort_by_key(|&(span, _, _)| span.begin);
+        for pair in spans.windows(2) {
+            if pair[0].0.end() > pair[1].0.begin {
+                self.add_problem(Problem::BlockOverlap {
+                    id: pair[1].2.clone(),
+                    other: pair[0].2.clone(),
+                    span: pair[1].0,
+                });
+            }
+        }
+
+        let storage = vol.storage();
+        drop(allocator);
+        drop(vol);
+        self.check_sectors(&spans, &storage, &allocator_ref);
+    }
+
+    // check blocks in use against deletion marks in storage sectors, every
+    // allocated block should be either used, free or deleted
+    fn check_sectors(
+        &mut self,
+        spans: &[(Span, EntityType, Eid)],
+        storage: &StorageRef,
+        allocator: &AllocatorRef,
+    ) {
+        // deletion marks of depots where cow and direct entity blocks are
+        // kept, they are the same depot if storage has no data depot
+        let mut marks = Vec::new();
+        for &ent_type in [EntityType::Cow, EntityType::Direct].iter() {
+            let mut storage = storage.write().unwrap();
+            match storage.deleted_blocks(ent_type) {
+                Ok(Some(deleted)) => {
+                    let mut set = BlockSet::default();
+                    for span in deleted {
+                        set.insert(span);
+                    }
+                    marks.push((ent_type, set));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("cannot get deleted blocks: {}", err);
+                    return;
+                }
+            }
+        }
+        if marks.is_empty() {
+            return;
+        }
+
+        let mut used = BlockSet::default();
+        for (span, ent_type, id) in spans.iter() {
+            used.insert(*span);
+            let deleted = marks.iter().find(|(t, _)| t == ent_type);
+            if let Some((_, deleted)) = deleted {
+                if deleted.intersects(*span) {
+                    self.add_problem(Problem::DeletedBlockInUse {
+                        id: id.clone(),
+                        span: *span,
+                    });
+                }
+            }
+        }
+
+        // a block is kept in one depot only, so it is not leaked if it is
+        // deleted in any of them
+        let allocator = allocator.read().unwrap();
+        let mut leaked: Vec<Span> = Vec::new();
+        for idx in 0..allocator.block_wmark() {
+            let blk = Span::new(idx, 1);
+            if used.contains(idx)
+                || allocator.is_free(blk)
+                || marks.iter().any(|(_, deleted)| deleted.contains(idx))
+            {
+                continue;
+            }
+            match leaked.last_mut() {
+                Some(ref mut span) if span.end() == idx => span.cnt += 1,
+                _ => leaked.push(blk),
+            }
+        }
+        for span in leaked {
+            self.add_problem(Problem::LeakedBlock { span });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::file::File;
+    use crate::fs::fnode::FileType;
+    use crate::fs::fs::Fs;
+    use crate::fs::Config;
+    use crate::repo::{OpenOptions, Repo, RepoOpener};
+    use crate::trans::{Finish, TxMgr};
+    use crate::util::crypto::{Crypto, RandomSeed};
+    use crate::util::init_env;
+    use crate::volume::storage::{MemStorage, Writer};
+
+    fn random_buf(len: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn verify_file(repo: &mut Repo, path: &str, expected: &[u8]) {
+        let mut f = repo.open_file(path).unwrap();
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], expected);
+    }
+
+    fn check_ok(repo: &Repo) {
+        let report = repo.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    #[test]
+    fn check_repo() {
+        init_env();
+        let mut repo = RepoOpener::new()
+            .create(true)
+            .version_limit(1)
+            .open("mem://check_repo", "pwd")
+            .unwrap();
+        check_ok(&repo);
+
+        // write duplicated files
+        let buf = random_buf(3 * 1024 * 1024, 42);
+        repo.create_dir_all("/a/b").unwrap();
+        for path in ["/a/foo", "/a/b/bar", "/baz"].iter() {
+            let mut f = repo.create_file(path).unwrap();
+            f.write_all(&buf).unwrap();
+            f.finish().unwrap();
+        }
+        check_ok(&repo);
+
+        // overwrite most of file, old segments will be shrunk
+        let mut expected = buf.clone();
+        let new_buf = random_buf(2 * 1024 * 1024, 43);
+        for path in ["/a/foo", "/a/b/bar", "/baz"].iter() {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .open(&mut repo, path)
+                .unwrap();
+            f.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+            f.write_all(&new_buf).unwrap();
+            f.finish().unwrap();
+        }
+        expected[1024 * 1024..].copy_from_slice(&new_buf);
+        check_ok(&repo);
+        verify_file(&mut repo, "/a/b/bar", &expected);
+
+        // remove and copy files
+        repo.remove_file("/baz").unwrap();
+        repo.copy("/a/foo", "/a/b/foo").unwrap();
+        check_ok(&repo);
+        verify_file(&mut repo, "/a/b/foo", &expected);
+
+        MemStorage::destroy("check_repo");
+    }
+
+    #[test]
+    fn orphan_segment() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let path = Path::new("/foo");
+        fs.create_fnode(path, FileType::File, fs.get_opts())
+            .unwrap();
+        let handle = fs.open_fnode(path).unwrap();
+        {
+            let mut f = File::new(handle.clone(), SeekFrom::Start(0), true, true);
+            f.write_all(&random_buf(1024 * 1024, 42)).unwrap();
+            f.finish().unwrap();
+        }
+
+        // link content once more, so its segments are still used after the
+        // file is removed
+        let content_id = {
+            let fnode = handle.fnode.read().unwrap();
+            fnode.history().last().unwrap().content_id().clone()
+        };
+        TxMgr::begin_trans(&handle.txmgr)
+            .unwrap()
+            .run_all(|| {
+                let ctn_ref = {
+                    let store = handle.store.read().unwrap();
+                    store.get_content(&content_id)?
+                };
+                let ctn = ctn_ref.read().unwrap();
+                ctn.link(&handle.store)
+            })
+            .unwrap();
+        drop(handle);
+        fs.remove_file(path).unwrap();
+
+        let report = fs.check(CheckOptions::default()).unwrap();
+        let mut orphan_segs = 0;
+        let mut orphan_data = 0;
+        for problem in report.problems() {
+            match problem {
+                Problem::OrphanSegment { .. } => orphan_segs += 1,
+                Problem::OrphanSegData { .. } => orphan_data += 1,
+                _ => panic!("unexpected problem {:?}", problem),
+            }
+        }
+        assert!(orphan_segs > 0);
+        assert_eq!(orphan_segs, orphan_data);
+        assert_eq!(report.segment_cnt(), orphan_segs);
+    }
+
+    #[test]
+    fn sector_marks() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("file://{}", tmpdir.path().display());
+        let mut fs = Fs::create(&uri, None, "pwd", &Config::default()).unwrap();
+        let opts = CheckOptions {
+            verify_data: false,
+            ..Default::default()
+        };
+        let problems = |fs: &Fs| {
+            let report = fs.check(opts).unwrap();
+            report.problems
+        };
+
+        // blocks of overwritten and removed files are marked as deleted
+        for (path, seed) in [("/foo", 42), ("/bar", 43), ("/foo", 44)].iter() {
+            let path = Path::new(path);
+            if fs.resolve(path).is_err() {
+                fs.create_fnode(path, FileType::File, fs.get_opts())
+                    .unwrap();
+            }
+            let handle = fs.open_fnode(path).unwrap();
+            let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+            f.write_all(&random_buf(256 * 1024, *seed)).unwrap();
+            f.finish().unwrap();
+        }
+        fs.remove_file(Path::new("/bar")).unwrap();
+        let found = problems(&fs);
+        assert!(found.is_empty(), "{:?}", found);
+
+        let handle = fs.open_fnode(Path::new("/foo")).unwrap();
+        let vol = handle.vol.clone();
+        let storage = vol.read().unwrap().storage();
+        let allocator = vol.read().unwrap().get_allocator();
+        let (fnode_addr_id, fnode_span) = {
+            let id = handle.fnode.read().unwrap().id().clone();
+            let vol = vol.read().unwrap();
+            let addr_id = [Arm::Left.to_eid(&id), Arm::Right.to_eid(&id)]
+                .iter()
+                .find(|addr_id| vol.get_address(addr_id).is_ok())
+                .cloned()
+                .unwrap();
+            let span = vol.get_address(&addr_id).unwrap()[0].span;
+            (addr_id, span)
+        };
+
+        // blocks written by an entity not known to the file system are
+        // leaked, and they are not after the entity is deleted
+        let id = Eid::new();
+        let mut wtr = Writer::new(&id, &storage);
+        wtr.write_all(&random_buf(8 * 1024, 45)).unwrap();
+        wtr.finish().unwrap();
+        let found = problems(&fs);
+        assert!(!found.is_empty());
+        for problem in found {
+            match problem {
+                Problem::LeakedBlock { .. } => {}
+                _ => panic!("unexpected problem {:?}", problem),
+            }
+        }
+        vol.write().unwrap().del(&id).unwrap();
+        assert!(problems(&fs).is_empty());
+
+        // free blocks in use
+        allocator.write().unwrap().set_free_extents(&[fnode_span]);
+        let found = problems(&fs);
+        assert_eq!(found.len(), 1);
+        match found[0] {
+            Problem::FreeBlockInUse { ref id, span } => {
+                assert_eq!(*id, fnode_addr_id);
+                assert_eq!(span, fnode_span);
+            }
+            _ => panic!("unexpected problem {:?}", found[0]),
+        }
+        allocator.write().unwrap().set_free_extents(&[]);
+
+        // allocate blocks of the fnode again to another entity and delete
+        // it, the fnode blocks are marked as deleted
+        let blk_wmark = allocator.read().unwrap().block_wmark();
+        allocator.write().unwrap().set_block_wmark(fnode_span.begin);
+        let id = Eid::new();
+        let mut wtr = Writer::new(&id, &storage);
+        wtr.write_all(&[42u8; 16]).unwrap();
+        wtr.finish().unwrap();
+        vol.write().unwrap().del(&id).unwrap();
+        allocator.write().unwrap().set_block_wmark(blk_wmark);
+        let found = problems(&fs);
+        assert_eq!(found.len(), 1);
+        match found[0] {
+            Problem::DeletedBlockInUse { ref id, span } => {
+                assert_eq!(*id, fnode_addr_id);
+                assert_eq!(span, fnode_span);
+            }
+            _ => panic!("unexpected problem {:?}", found[0]),
+        }
+    }
+}
@@ -0,0 +1,698 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::slice;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::fnode::{Cache as FnodeCache, Fnode, FnodeRef};
+use crate::content::StoreRef;
+use crate::error::{Error, Result};
+use crate::trans::{Eid, TxMgr, TxMgrRef};
+use crate::volume::VolumeRef;
+
+/// Options for repository compaction.
+///
+/// See [`Repo::compact`] for more details.
+///
+/// [`Repo::compact`]: ../../repo/struct.Repo.html#method.compact
+#[derive(Debug, Clone, Copy)]
+pub struct CompactOptions {
+    /// Minimum proportion of unused bytes in a segment for it to be
+    /// rewritten, from 0.0 to 1.0.
+    pub segment_dead_ratio: f32,
+
+    /// Minimum proportion of deleted blocks in a storage sector for it to
+    /// be rewritten, from 0.0 to 1.0.
+    pub sector_dead_ratio: f32,
+
+    /// Maximum number of segments rewritten in one run, 0 means no limit.
+    pub max_segments: usize,
+
+    /// Maximum number of storage sectors rewritten in one run, 0 means no
+    /// limit.
+    pub max_sectors: usize,
+
+    /// Maximum number of bytes rewritten per second, 0 means no limit.
+    pub io_rate: usize,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        CompactOptions {
+            segment_dead_ratio: 0.5,
+            sector_dead_ratio: 0.5,
+            max_segments: 0,
+            max_sectors: 0,
+            io_rate: 0,
+        }
+    }
+}
+
+/// Repository compaction report.
+///
+/// This structure is returned from [`Repo::compact`].
+///
+/// [`Repo::compact`]: ../../repo/struct.Repo.html#method.compact
+#[derive(Debug, Default)]
+pub struct CompactReport {
+    segments_compacted: usize,
+    segment_bytes_reclaimed: usize,
+    sectors_compacted: usize,
+    sector_bytes_reclaimed: usize,
+    bytes_written: usize,
+}
+
+impl CompactReport {
+    /// Returns number of segments rewritten.
+    #[inline]
+    pub fn segments_compacted(&self) -> usize {
+        self.segments_compacted
+    }
+
+    /// Returns number of unused segment bytes reclaimed.
+    #[inline]
+    pub fn segment_bytes_reclaimed(&self) -> usize {
+        self.segment_bytes_reclaimed
+    }
+
+    /// Returns number of storage sectors rewritten.
+    #[inline]
+    pub fn sectors_compacted(&self) -> usize {
+        self.sectors_compacted
+    }
+
+    /// Returns number of deleted block bytes reclaimed from storage sectors.
+    #[inline]
+    pub fn sector_bytes_reclaimed(&self) -> usize {
+        self.sector_bytes_reclaimed
+    }
+
+    /// Returns total number of bytes reclaimed.
+    #[inline]
+    pub fn bytes_reclaimed(&self) -> usize {
+        self.segment_bytes_reclaimed + self.sector_bytes_reclaimed
+    }
+
+    /// Returns total number of bytes rewritten.
+    #[inline]
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+// stop signal for compaction
+#[derive(Debug, Default)]
+struct Signal {
+    stopped: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Signal {
+    fn stop(&self) {
+        let mut stopped = self.stopped.lock().unwrap();
+        *stopped = true;
+        self.cond.notify_all();
+    }
+
+    // wait for the duration, return false if stopped
+    fn wait(&self, dur: Duration) -> bool {
+        let deadline = Instant::now() + dur;
+        let mut stopped = self.stopped.lock().unwrap();
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            stopped = self.cond.wait_timeout(stopped, deadline - now).unwrap().0;
+        }
+        !*stopped
+    }
+}
+
+// limit rewrite to the io rate, in bytes per second
+struct Throttle<'a> {
+    rate: usize,
+    start: Instant,
+    bytes: usize,
+    signal: &'a Signal,
+}
+
+impl<'a> Throttle<'a> {
+    fn new(rate: usize, signal: &'a Signal) -> Self {
+        Throttle {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+            signal,
+        }
+    }
+
+    // account written bytes and wait until rate is below the limit, return
+    // false if compaction is stopped
+    fn consume(&mut self, bytes: usize) -> bool {
+        self.bytes += bytes;
+        let due = if self.rate > 0 {
+            Duration::from_millis(self.bytes as u64 * 1000 / self.rate as u64)
+        } else {
+            Duration::default()
+        };
+        let elapsed = self.start.elapsed();
+        self.signal
+            .wait(due.checked_sub(elapsed).unwrap_or_default())
+    }
+}
+
+// compactor, rewrites segments and storage sectors with most dead space
+#[derive(Debug, Clone)]
+pub(super) struct Compactor {
+    root: FnodeRef,
+    fcache: FnodeCache,
+    store: StoreRef,
+    txmgr: TxMgrRef,
+    vol: VolumeRef,
+
+    // id of the last segment scanned by previous run, shared by all
+    // compactors of a file system
+    cursor: Arc<Mutex<Option<Eid>>>,
+
+    // maximum number of segments scanned in one run
+    scan_limit: usize,
+}
+
+impl Compactor {
+    // default maximum number of segments scanned in one run
+    const SCAN_LIMIT: usize = 4096;
+
+    pub fn new(
+        root: &FnodeRef,
+        fcache: &FnodeCache,
+        store: &StoreRef,
+        txmgr: &TxMgrRef,
+        vol: &VolumeRef,
+        cursor: &Arc<Mutex<Option<Eid>>>,
+    ) -> Self {
+        Compactor {
+            root: root.clone(),
+            fcache: fcache.clone(),
+            store: store.clone(),
+            txmgr: txmgr.clone(),
+            vol: vol.clone(),
+            cursor: cursor.clone(),
+            scan_limit: Self::SCAN_LIMIT,
+        }
+    }
+
+    #[inline]
+    pub fn run(&self, opts: CompactOptions) -> Result<CompactReport> {
+        self.run_until(opts, &Signal::default())
+    }
+
+    fn run_until(&self, opts: CompactOptions, signal: &Signal) -> Result<CompactReport> {
+        let mut report = CompactReport::default();
+        let mut throttle = Throttle::new(opts.io_rate, signal);
+
+        if self.compact_segments(&opts, &mut throttle, &mut report)? {
+            self.compact_sectors(&opts, &mut throttle, &mut report)?;
+        }
+
+        debug!(
+            "compacted {} segments and {} sectors, reclaimed {} bytes",
+            report.segments_compacted,
+            report.sectors_compacted,
+            report.bytes_reclaimed()
+        );
+
+        Ok(report)
+    }
+
+    // walk through all reachable fnodes and map the segments to fnodes
+    // whose chunk maps refer to them
+    fn segment_fnodes(&self, seg_ids: &[Eid]) -> Result<HashMap<Eid, Vec<FnodeRef>>> {
+        let seg_ids: HashSet<&Eid> = seg_ids.iter().collect();
+        let mut seg_fnodes: HashMap<Eid, Vec<FnodeRef>> = HashMap::new();
+        let mut stack = vec![self.root.clone()];
+        while let Some(fnode) = stack.pop() {
+            let (child_names, chunk_segs) = {
+                let fnode = fnode.read().unwrap();
+                (fnode.children_names(), fnode.chunk_segments())
+            };
+            for name in child_names.iter() {
+                stack.push(Fnode::child(&fnode, name, &self.fcache, &self.vol)?);
+            }
+            for seg_id in chunk_segs {
+                if seg_ids.contains(&seg_id) {
+                    seg_fnodes.entry(seg_id).or_default().push(fnode.clone());
+                }
+            }
+        }
+        Ok(seg_fnodes)
+    }
+
+    // get segments whose proportion of unused bytes is not less than the
+    // dead ratio, most unused segments first
+    //
+    // segments are taken from the set tracked by store, and at most scan
+    // limit of them are loaded in one run, next run continues from the
+    // segment after the last scanned one
+    fn segment_candidates(&self, opts: &CompactOptions) -> Result<Vec<Eid>> {
+        let store = self.store.read().unwrap();
+
+        let mut seg_ids = store.segments();
+        seg_ids.sort();
+        let scan_ids: Vec<Eid> = {
+            let mut cursor = self.cursor.lock().unwrap();
+            let start = match *cursor {
+                Some(ref last) => match seg_ids.binary_search(last) {
+                    Ok(idx) => idx + 1,
+                    Err(idx) => idx,
+                },
+                None => 0,
+            };
+            let cnt = seg_ids.len().min(self.scan_limit);
+            let scan_ids: Vec<Eid> = seg_ids
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(cnt)
+                .cloned()
+                .collect();
+            *cursor = if cnt < seg_ids.len() {
+                scan_ids.last().cloned()
+            } else {
+                None
+            };
+            scan_ids
+        };
+
+        let mut segs = Vec::new();
+        for seg_id in scan_ids {
+            let seg_ref = store.get_seg(&seg_id)?;
+            let seg = seg_ref.read().unwrap();
+            if is_compactable(seg.len(), seg.used(), opts.segment_dead_ratio) {
+                segs.push((seg_id, seg.len() - seg.used()));
+            }
+        }
+
+        segs.sort_by_key(|seg| Reverse(seg.1));
+        if opts.max_segments > 0 {
+            segs.truncate(opts.max_segments);
+        }
+
+        Ok(segs.into_iter().map(|(seg_id, _)| seg_id).collect())
+    }
+
+    // rewrite segments, return false if compaction is stopped
+    fn compact_segments(
+        &self,
+        opts: &CompactOptions,
+        throttle: &mut Throttle,
+        report: &mut CompactReport,
+    ) -> Result<bool> {
+        let seg_ids = self.segment_candidates(opts)?;
+        if seg_ids.is_empty() {
+            return Ok(true);
+        }
+
+        for seg_id in seg_ids.iter() {
+            match self.compact_segment(seg_id, opts) {
+                Ok(Some((written, reclaimed))) => {
+                    report.segments_compacted += 1;
+                    report.segment_bytes_reclaimed += reclaimed;
+                    report.bytes_written += written;
+                    if !throttle.consume(written) {
+                        return Ok(false);
+                    }
+                }
+                Ok(None) => {}
+                Err(ref err) if *err == Error::InUse => {
+                    // other transactions are in progress, leave the
+                    // segment for next run
+                    debug!("segment {:?} is in use, skip compaction", seg_id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(true)
+    }
+
+    // rewrite a segment in its own exclusive transaction, so it won't make
+    // store and fnodes in use by foreground transactions, return number of
+    // bytes written and reclaimed
+    fn compact_segment(
+        &self,
+        seg_id: &Eid,
+        opts: &CompactOptions,
+    ) -> Result<Option<(usize, usize)>> {
+        let mut result = None;
+
+        TxMgr::begin_exclusive_trans(&self.txmgr)?.run_all(|| {
+            {
+                let mut store_cow = self.store.write().unwrap();
+                let seg_ref = store_cow.get_seg(seg_id)?;
+                let mut seg_cow = seg_ref.write().unwrap();
+
+                // segment could have been changed since it was picked
+                let (len, used) = (seg_cow.len(), seg_cow.used());
+                if !is_compactable(len, used, opts.segment_dead_ratio) {
+                    return Ok(());
+                }
+
+                store_cow.make_mut()?.shrink_segment(&mut seg_cow)?;
+                result = Some((used, len - used));
+            }
+
+            // retired chunks cannot be used for chunk dedup anymore, fnodes
+            // are collected in this transaction so no writes can add new
+            // references to the segment after that
+            let seg_fnodes = self.segment_fnodes(slice::from_ref(seg_id))?;
+            for fnode in seg_fnodes.get(seg_id).into_iter().flatten() {
+                Fnode::forget_segments(fnode, slice::from_ref(seg_id))?;
+            }
+
+            Ok(())
+        })?;
+
+        if result.is_some() {
+            debug!("segment {:?} compacted", seg_id);
+        }
+
+        Ok(result)
+    }
+
+    // rewrite storage sectors, return false if compaction is stopped
+    fn compact_sectors(
+        &self,
+        opts: &CompactOptions,
+        throttle: &mut Throttle,
+        report: &mut CompactReport,
+    ) -> Result<bool> {
+        // sector IO only needs the storage lock, so take the storage out
+        // of the volume and release the volume lock before rewriting
+        let storage = {
+            let vol = self.vol.read().unwrap();
+            vol.storage()
+        };
+        let mut units = {
+            let mut storage = storage.write().unwrap();
+            storage.compact_candidates(opts.sector_dead_ratio)?
+        };
+        units.sort_by_key(|unit| Reverse(unit.1.dead));
+        if opts.max_sectors > 0 {
+            units.truncate(opts.max_sectors);
+        }
+
+        for (ent_type, unit) in units {
+            let (written, reclaimed) = {
+                let mut storage = storage.write().unwrap();
+                storage.compact_unit(ent_type, unit.idx)?
+            };
+            if reclaimed > 0 {
+                report.sectors_compacted += 1;
+                report.sector_bytes_reclaimed += reclaimed;
+                report.bytes_written += written;
+                if !throttle.consume(written) {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+// check if dead bytes in a unit reach the dead ratio, unused unit is not
+// compactable as it should be removed rather than rewritten
+#[inline]
+fn is_compactable(len: usize, used: usize, dead_ratio: f32) -> bool {
+    used > 0 && used < len && (len - used) as f32 >= len as f32 * dead_ratio
+}
+
+// background compactor, which runs compaction once it is started and then
+// periodically in a separate thread until it is dropped
+#[derive(Debug)]
+pub(super) struct Background {
+    signal: Arc<Signal>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Background {
+    pub fn start(compactor: Compactor, opts: CompactOptions, interval: Duration) -> Self {
+        let signal = Arc::new(Signal::default());
+        let sig = signal.clone();
+        let handle = thread::spawn(move || loop {
+            match compactor.run_until(opts, &sig) {
+                Ok(report) => debug!("background compaction done: {:?}", report),
+                Err(err) => warn!("background compaction failed: {}", err),
+            }
+            if !sig.wait(interval) {
+                break;
+            }
+        });
+
+        Background {
+            signal,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.signal.stop();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("background compactor panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, SeekFrom, Write};
+    use std::path::Path;
+
+    use super::*;
+    use crate::file::File;
+    use crate::fs::check::CheckOptions;
+    use crate::fs::fnode::FileType;
+    use crate::fs::fs::Fs;
+    use crate::fs::Config;
+    use crate::util::crypto::{Crypto, RandomSeed};
+    use crate::util::init_env;
+
+    fn random_buf(len: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn write_file(fs: &mut Fs, path: &str, offset: u64, buf: &[u8]) {
+        let path = Path::new(path);
+        if fs.resolve(path).is_err() {
+            let mut opts = fs.get_opts();
+            opts.version_limit = 1;
+            fs.create_fnode(path, FileType::File, opts).unwrap();
+        }
+        let handle = fs.open_fnode(path).unwrap();
+        let mut f = File::new(handle, SeekFrom::Start(offset), true, true);
+        f.write_all(buf).unwrap();
+        f.finish().unwrap();
+    }
+
+    fn verify_file(fs: &mut Fs, path: &str, expected: &[u8]) {
+        let handle = fs.open_fnode(Path::new(path)).unwrap();
+        let mut f = File::new(handle, SeekFrom::Start(0), true, false);
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], expected);
+    }
+
+    #[test]
+    fn compact_fs() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let mut foo = random_buf(256 * 1024, 42);
+        write_file(&mut fs, "/foo", 0, &foo);
+
+        // nothing to compact on a fresh repo
+        let report = fs.compact(CompactOptions::default()).unwrap();
+        assert_eq!(report.segments_compacted(), 0);
+        assert_eq!(report.bytes_reclaimed(), 0);
+
+        // overwrite first half of the file, the old version is retired so
+        // its segment has half of the bytes unused
+        let half = random_buf(128 * 1024, 43);
+        foo[..half.len()].copy_from_slice(&half);
+        write_file(&mut fs, "/foo", 0, &half);
+
+        // threshold above the unused proportion should skip the segment
+        let mut opts = CompactOptions {
+            segment_dead_ratio: 0.9,
+            ..Default::default()
+        };
+        let report = fs.compact(opts).unwrap();
+        assert_eq!(report.segments_compacted(), 0);
+
+        opts.segment_dead_ratio = 0.3;
+        opts.io_rate = 16 * 1024 * 1024;
+        let report = fs.compact(opts).unwrap();
+        assert_eq!(report.segments_compacted(), 1);
+        assert!(report.segment_bytes_reclaimed() > 0);
+        assert!(report.bytes_written() > 0);
+        verify_file(&mut fs, "/foo", &foo);
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+
+        // compacted segment should not be picked again
+        let report = fs.compact(opts).unwrap();
+        assert_eq!(report.segments_compacted(), 0);
+
+        // repo should still work as usual after compaction
+        let bar = random_buf(64 * 1024, 44);
+        foo[128 * 1024..192 * 1024].copy_from_slice(&bar);
+        write_file(&mut fs, "/foo", 128 * 1024, &bar);
+        verify_file(&mut fs, "/foo", &foo);
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    #[test]
+    fn write_between_scan_and_compact() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let mut foo = random_buf(256 * 1024, 42);
+        write_file(&mut fs, "/foo", 0, &foo);
+        let half = random_buf(128 * 1024, 43);
+        foo[..half.len()].copy_from_slice(&half);
+        write_file(&mut fs, "/foo", 0, &half);
+
+        let opts = CompactOptions {
+            segment_dead_ratio: 0.1,
+            ..Default::default()
+        };
+        let compactor = fs.compactor();
+        let seg_ids = compactor.segment_candidates(&opts).unwrap();
+        assert_eq!(seg_ids.len(), 1);
+        let seg_id = seg_ids[0].clone();
+
+        // write landed after the scan refers to chunks in the candidate
+        // segment again through chunk dedup
+        let tail = foo[128 * 1024..].to_vec();
+        foo[..tail.len()].copy_from_slice(&tail);
+        write_file(&mut fs, "/foo", 0, &tail);
+        let fnode = fs.resolve(Path::new("/foo")).unwrap();
+        assert!(fnode.read().unwrap().chunk_segments().contains(&seg_id));
+
+        // compaction should still make it forget the rewritten segment
+        let result = compactor.compact_segment(&seg_id, &opts).unwrap();
+        assert!(result.is_some());
+        assert!(!fnode.read().unwrap().chunk_segments().contains(&seg_id));
+        verify_file(&mut fs, "/foo", &foo);
+
+        // writing the same data again must not dedup to retired chunks
+        write_file(&mut fs, "/foo", 128 * 1024, &tail);
+        foo[128 * 1024..].copy_from_slice(&tail);
+        verify_file(&mut fs, "/foo", &foo);
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    #[test]
+    fn segment_scan_limit() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        for i in 0..4u8 {
+            let path = format!("/{}", i);
+            write_file(&mut fs, &path, 0, &random_buf(256 * 1024, i));
+            write_file(&mut fs, &path, 0, &random_buf(128 * 1024, i + 10));
+        }
+
+        let opts = CompactOptions {
+            segment_dead_ratio: 0.1,
+            ..Default::default()
+        };
+        let all: HashSet<Eid> = fs
+            .compactor()
+            .segment_candidates(&opts)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert!(all.len() > 2);
+
+        // each run scans no more than the limit, and consecutive runs
+        // continue the scan until all segments are covered
+        let mut compactor = fs.compactor();
+        compactor.scan_limit = 2;
+        let seg_cnt = compactor.store.read().unwrap().segments().len();
+        let mut found = HashSet::new();
+        for _ in 0..(seg_cnt + 1) / 2 {
+            let seg_ids = compactor.segment_candidates(&opts).unwrap();
+            assert!(seg_ids.len() <= 2);
+            found.extend(seg_ids);
+        }
+        assert_eq!(found, all);
+    }
+
+    #[test]
+    fn background_compact() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let mut foo = random_buf(256 * 1024, 42);
+        write_file(&mut fs, "/foo", 0, &foo);
+        let half = random_buf(128 * 1024, 43);
+        foo[..half.len()].copy_from_slice(&half);
+        write_file(&mut fs, "/foo", 0, &half);
+
+        // compactor runs once it is started, and stopping it waits for the
+        // run to complete
+        let opts = CompactOptions {
+            segment_dead_ratio: 0.3,
+            ..Default::default()
+        };
+        fs.start_compactor(opts, Duration::from_secs(3600)).unwrap();
+        fs.stop_compactor();
+
+        // background compactor should have rewritten the segment
+        let report = fs.compact(opts).unwrap();
+        assert_eq!(report.segments_compacted(), 0);
+        verify_file(&mut fs, "/foo", &foo);
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    #[test]
+    fn write_while_compacting() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let mut files: Vec<Vec<u8>> = (0..4).map(|i| random_buf(256 * 1024, i)).collect();
+        for (i, buf) in files.iter().enumerate() {
+            write_file(&mut fs, &format!("/{}", i), 0, buf);
+        }
+
+        let opts = CompactOptions {
+            segment_dead_ratio: 0.1,
+            ..Default::default()
+        };
+        fs.start_compactor(opts, Duration::from_millis(0)).unwrap();
+
+        // foreground writes should not fail while compactor keeps
+        // rewriting partially retired segments
+        for i in 0..40usize {
+            let buf = random_buf(64 * 1024, i as u8 + 10);
+            let offset = (i / 4 % 4) * buf.len();
+            let file = &mut files[i % 4];
+            file[offset..offset + buf.len()].copy_from_slice(&buf);
+            write_file(&mut fs, &format!("/{}", i % 4), offset as u64, &buf);
+        }
+        fs.stop_compactor();
+
+        for (i, buf) in files.iter().enumerate() {
+            verify_file(&mut fs, &format!("/{}", i), buf);
+        }
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+}
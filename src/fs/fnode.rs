@@ -2,22 +2,20 @@ use std::cmp::min;
 use std::collections::VecDeque;
 use std::fmt::{self, Debug};
 use std::io::{Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::SystemTime;
 
-use super::{Handle, Options};
+use super::{CacheSizes, Handle, Options};
 use crate::content::{ChunkMap, Content, ContentReader, StoreRef, Writer as StoreWriter};
 use crate::error::{Error, Result};
 use crate::trans::cow::{Cow, CowCache, CowRef, CowWeakRef, Cowable, IntoCow};
 use crate::trans::{Eid, Id, TxMgrRef, Txid};
-use crate::util::lru::{CountMeter, Lru, PinChecker};
+use crate::util::lru::{Lru, MemSize, PinChecker, SizeMeter};
 use crate::util::Time;
 use crate::volume::VolumeRef;
 
-// maximum sub nodes for a fnode
-const SUB_NODES_CNT: usize = 8;
-
 /// A structure representing a type of file with accessors for each file type.
 #[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
 pub enum FileType {
@@ -43,9 +41,9 @@ impl Default for FileType {
     }
 }
 
-impl Into<i32> for FileType {
-    fn into(self) -> i32 {
-        match self {
+impl From<FileType> for i32 {
+    fn from(val: FileType) -> Self {
+        match val {
             FileType::File => 0,
             FileType::Dir => 1,
         }
@@ -101,10 +99,20 @@ impl Version {
         self.len
     }
 
+    /// Returns `true` if this version of content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     /// Returns the creation time of this version of content.
     pub fn created_at(&self) -> SystemTime {
         self.ctime.to_system_time()
     }
+
+    #[inline]
+    pub(super) fn content_id(&self) -> &Eid {
+        &self.content_id
+    }
 }
 
 /// Metadata information about a file or a directory.
@@ -146,6 +154,11 @@ impl Metadata {
         self.len
     }
 
+    /// Returns `true` if the current version of file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     /// Returns current version number of file listed in this metadata.
     pub fn curr_version(&self) -> usize {
         self.curr_version
@@ -174,6 +187,7 @@ pub struct DirEntry {
     path: PathBuf,
     name: String,
     metadata: Metadata,
+    damaged: bool,
 }
 
 impl DirEntry {
@@ -192,9 +206,26 @@ impl DirEntry {
     pub fn metadata(&self) -> Metadata {
         self.metadata
     }
+
+    /// Returns whether the file this entry points at failed to load.
+    ///
+    /// Damaged entries are only returned when the repository is opened in
+    /// [`RepoOpener::salvage`] mode, their metadata only has the file type.
+    ///
+    /// [`RepoOpener::salvage`]: ../../repo/struct.RepoOpener.html#method.salvage
+    pub fn is_damaged(&self) -> bool {
+        self.damaged
+    }
+}
+
+impl MemSize for FnodeWeakRef {
+    #[inline]
+    fn mem_size(&self) -> usize {
+        mem::size_of::<(String, FnodeWeakRef)>()
+    }
 }
 
-type SubNodes = Lru<String, FnodeWeakRef, CountMeter<FnodeWeakRef>, PinChecker<FnodeWeakRef>>;
+type SubNodes = Lru<String, FnodeWeakRef, SizeMeter<FnodeWeakRef>, PinChecker<FnodeWeakRef>>;
 
 /// File node
 #[derive(Default, Clone, Deserialize, Serialize)]
@@ -255,6 +286,7 @@ impl Fnode {
 
             // create child fnode and add the initial version
             let mut kid = Fnode::new(ftype, opts, &pfnode.store);
+            kid.sub_nodes.set_capacity(pfnode.sub_nodes.capacity());
             if kid.is_file() {
                 kid.add_version(Content::new())?;
             }
@@ -270,7 +302,13 @@ impl Fnode {
 
     #[inline]
     fn default_sub_nodes() -> SubNodes {
-        Lru::new(SUB_NODES_CNT)
+        Lru::new(CacheSizes::default().sub_nodes)
+    }
+
+    // set sub node cache size, in bytes
+    #[inline]
+    pub fn set_sub_nodes_cache_size(&mut self, size: usize) {
+        self.sub_nodes.set_capacity(size);
     }
 
     /// Check if fnode is regular file
@@ -359,28 +397,29 @@ impl Fnode {
         // if child is not in sub node list, load it from fnode cache
         self.kids
             .iter()
-            .find(|ref c| c.name == name)
+            .find(|c| c.name == name)
             .ok_or(Error::NotFound)
-            .and_then(|child| cache.get(&child.id, vol).map_err(|e| Error::from(e)))
-            .and_then(|child| {
-                // set parent, store and volume for the child
+            .and_then(|child| cache.get(&child.id, vol))
+            .map(|child| {
+                // set parent, store and sub node cache size for the child
                 {
                     let mut child_cow = child.write().unwrap();
                     let c = child_cow.make_mut_naive();
                     c.parent = Some(self_ref);
                     c.store = self.store.clone();
+                    c.sub_nodes.set_capacity(self.sub_nodes.capacity());
                 }
 
                 // add to parent's sub node list
                 self.sub_nodes
                     .insert(name.to_string(), Arc::downgrade(&child));
-                Ok(child)
+                child
             })
     }
 
     #[inline]
     pub fn has_child(&self, name: &str) -> bool {
-        self.kids.iter().position(|ref c| c.name == name).is_some()
+        self.kids.iter().position(|c| c.name == name).is_some()
     }
 
     #[inline]
@@ -400,16 +439,21 @@ impl Fnode {
             .load_child(name, parent.clone(), cache, vol)
     }
 
-    fn children_names(&self) -> Vec<String> {
-        self.kids.iter().map(|ref k| k.name.clone()).collect()
+    #[inline]
+    pub fn children_names(&self) -> Vec<String> {
+        self.kids.iter().map(|k| k.name.clone()).collect()
     }
 
     /// Get children dir entry list
+    ///
+    /// In salvage mode, children failed to load are returned as damaged
+    /// entries rather than an error.
     pub fn read_dir(
         parent: FnodeRef,
         path: &Path,
         cache: &Cache,
         vol: &VolumeRef,
+        salvage: bool,
     ) -> Result<Vec<DirEntry>> {
         let mut par = parent.write().unwrap();
         let par = par.make_mut_naive();
@@ -433,15 +477,40 @@ impl Fnode {
         };
 
         let mut ret = Vec::new();
-        let child_names = par.children_names();
-
-        for name in child_names.iter() {
-            let child_ref = par.load_child(&name, parent.clone(), cache, vol)?;
-            let child = child_ref.read().unwrap();
+        let kids = par.kids.clone();
+
+        for kid in kids.iter() {
+            let name = &kid.name;
+            let metadata = match par.load_child(name, parent.clone(), cache, vol) {
+                Ok(child_ref) => {
+                    let child = child_ref.read().unwrap();
+                    child.metadata()
+                }
+                Err(err) => {
+                    if !salvage {
+                        return Err(err);
+                    }
+                    warn!("damaged fnode {:?} at {}: {}", kid.id, name, err);
+                    ret.push(DirEntry {
+                        path: parent_path.join(name),
+                        metadata: Metadata {
+                            ftype: kid.ftype,
+                            len: 0,
+                            curr_version: 0,
+                            ctime: Time::default(),
+                            mtime: Time::default(),
+                        },
+                        name: name.clone(),
+                        damaged: true,
+                    });
+                    continue;
+                }
+            };
             ret.push(DirEntry {
                 path: parent_path.join(name),
-                metadata: child.metadata(),
+                metadata,
                 name: name.clone(),
+                damaged: false,
             });
         }
 
@@ -478,7 +547,7 @@ impl Fnode {
                 let child_idx = par
                     .kids
                     .iter()
-                    .position(|ref c| c.id == *child.id())
+                    .position(|c| c.id == *child.id())
                     .ok_or(Error::NotFound)?;
                 {
                     let name = &par.kids[child_idx].name;
@@ -487,8 +556,28 @@ impl Fnode {
                 par.kids.remove(child_idx);
                 Ok(())
             }
-            None => return Err(Error::IsRoot),
+            None => Err(Error::IsRoot),
+        }
+    }
+
+    /// Get segments referred by fnode chunk map
+    #[inline]
+    pub fn chunk_segments(&self) -> Vec<Eid> {
+        self.chk_map.segments()
+    }
+
+    /// Remove segments from fnode chunk map
+    pub fn forget_segments(fnode: &FnodeRef, seg_ids: &[Eid]) -> Result<()> {
+        let mut fnode_cow = fnode.write().unwrap();
+        if !seg_ids.iter().any(|id| fnode_cow.chk_map.has_segment(id)) {
+            return Ok(());
         }
+
+        let fnode = fnode_cow.make_mut()?;
+        for seg_id in seg_ids.iter() {
+            fnode.chk_map.remove_segment(seg_id);
+        }
+        Ok(())
     }
 
     // get specified version
@@ -556,12 +645,6 @@ impl Fnode {
         self.mtime = ver.ctime;
         self.vers.push_back(ver);
 
-        // remove the oldest version, note that version limit is zero based
-        if self.vers.len() > self.opts.version_limit as usize {
-            let retire = self.vers.front().unwrap().num;
-            self.remove_ver(retire)?;
-        }
-
         if is_deduped {
             // duplicate content found
             Ok(None)
@@ -571,6 +654,19 @@ impl Fnode {
         }
     }
 
+    // remove the oldest version if version limit is exceeded, note that
+    // version limit is zero based
+    //
+    // this must be called after the new version content is linked, otherwise
+    // segments shared with the new content could be removed
+    fn retire_version(&mut self) -> Result<()> {
+        if self.vers.len() > self.opts.version_limit as usize {
+            let retire = self.vers.front().unwrap().num;
+            self.remove_ver(retire)?;
+        }
+        Ok(())
+    }
+
     /// Get reader for sepcified version number
     pub fn version_reader(&self, ver_num: usize) -> Result<ContentReader> {
         let ver = self.ver(ver_num).ok_or(Error::NoVersion)?;
@@ -628,6 +724,7 @@ impl Fnode {
                 // content is not duplicated
                 content.link(&handle.store)?;
             }
+            fnode.retire_version()?;
         }
 
         Ok(())
@@ -649,7 +746,16 @@ impl Debug for Fnode {
     }
 }
 
-impl Cowable for Fnode {}
+impl Cowable for Fnode {
+    fn heap_size(&self) -> usize {
+        let kids_size: usize = self
+            .kids
+            .iter()
+            .map(|kid| mem::size_of::<ChildEntry>() + kid.name.len())
+            .sum();
+        kids_size + self.vers.len() * mem::size_of::<Version>() + self.chk_map.heap_size()
+    }
+}
 
 impl<'de> IntoCow<'de> for Fnode {}
 
@@ -662,7 +768,6 @@ pub type FnodeWeakRef = CowWeakRef<Fnode>;
 /// Fnode Reader
 #[derive(Debug)]
 pub struct Reader {
-    ver: usize,
     rdr: ContentReader,
 }
 
@@ -671,7 +776,7 @@ impl Reader {
     pub fn new(fnode: FnodeRef, ver: usize) -> Result<Self> {
         let fnode = fnode.read().unwrap();
         let rdr = fnode.version_reader(ver)?;
-        Ok(Reader { ver, rdr })
+        Ok(Reader { rdr })
     }
 
     /// Create a reader for current version
@@ -679,7 +784,7 @@ impl Reader {
         let fnode = fnode.read().unwrap();
         let ver = fnode.curr_ver_num();
         let rdr = fnode.version_reader(ver)?;
-        Ok(Reader { ver, rdr })
+        Ok(Reader { rdr })
     }
 }
 
@@ -725,8 +830,11 @@ impl Writer {
             ctn
         };
 
-        // dedup content and add deduped content as a new version
+        // update fnode chunk map
         let fnode = fnode_cow.make_mut()?;
+        fnode.chk_map = chk_map;
+
+        // dedup content and add deduped content as a new version
         match fnode.add_version(merged_ctn)? {
             Some(content) => {
                 // content is not duplicated
@@ -737,9 +845,7 @@ impl Writer {
                 stg_ctn.unlink_weak(&mut fnode.chk_map, &handle.store)?;
             }
         }
-
-        // udpate fnode chunk map
-        fnode.chk_map = chk_map;
+        fnode.retire_version()?;
 
         Ok(stg_ctn.end_offset())
     }
@@ -1,21 +1,27 @@
-use std::io;
-use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use rmp_serde::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
 
+use super::check::{CheckOptions, CheckReport, Checker};
+use super::compact::{Background as BackgroundCompactor, CompactOptions, CompactReport, Compactor};
+use super::repair::{self, RepairReport};
 use super::fnode::{
     Cache as FnodeCache, DirEntry, FileType, Fnode, FnodeRef, Metadata, Reader as FnodeReader,
     Version, Writer as FnodeWriter,
 };
-use super::{Config, Handle, Options};
+use super::{CacheSizes, Config, Handle, Options};
 use crate::content::{Store, StoreRef};
 use crate::error::{Error, Result};
 use crate::trans::cow::IntoCow;
 use crate::trans::{Eid, Id, TxMgr, TxMgrRef};
-use crate::util::crypto::Cost;
+use crate::util::crypto::{Cost, Hash};
 use crate::util::IntoRef;
+use crate::volume::storage::MemStorage;
 use crate::volume::{Info as VolumeInfo, Volume, VolumeRef};
 
 /// File system information
@@ -76,7 +82,7 @@ impl Payload {
     }
 
     fn deseri(buf: &[u8]) -> Result<Self> {
-        let mut de = Deserializer::new(&buf[..]);
+        let mut de = Deserializer::new(buf);
         let ret: Self = Deserialize::deserialize(&mut de)?;
         Ok(ret)
     }
@@ -93,27 +99,38 @@ pub struct Fs {
     shutter: ShutterRef,
     opts: Options,
     read_only: bool,
+    salvage: bool,
+    compactor: Option<BackgroundCompactor>,
+    compact_cursor: Arc<Mutex<Option<Eid>>>,
 }
 
 impl Fs {
-    // default cache size
-    const FNODE_CACHE_SIZE: usize = 16;
-
     /// Check if fs exists
     pub fn exists(uri: &str) -> Result<bool> {
         let vol = Volume::new(uri)?;
         vol.exists()
     }
 
+    // create volume, optionally with a separate data storage
+    fn new_volume(uri: &str, data_uri: Option<&str>) -> Result<Volume> {
+        match data_uri {
+            Some(data_uri) => Volume::with_data(uri, data_uri),
+            None => Volume::new(uri),
+        }
+    }
+
     /// Create new fs
-    pub fn create(uri: &str, pwd: &str, cfg: &Config) -> Result<Fs> {
+    ///
+    /// If `data_uri` is specified, segment data is kept in a separate
+    /// storage at that location.
+    pub fn create(uri: &str, data_uri: Option<&str>, pwd: &str, cfg: &Config) -> Result<Fs> {
         let root_id = Eid::new();
         let walq_id = Eid::new();
         let store_id = Eid::new();
         let payload = Payload::new(&root_id, &walq_id, &store_id, cfg.opts);
 
         // create and initialise volume
-        let mut vol = Volume::new(uri)?;
+        let mut vol = Self::new_volume(uri, data_uri)?;
         debug!("create repo: {}", vol.info().uri);
 
         vol.init(pwd, cfg, &payload.seri()?)?;
@@ -122,7 +139,7 @@ impl Fs {
 
         // create tx manager and fnode cache
         let txmgr = TxMgr::new(&walq_id, &vol).into_ref();
-        let fcache = FnodeCache::new(Self::FNODE_CACHE_SIZE, &txmgr);
+        let fcache = FnodeCache::new(cfg.caches.fnode, &txmgr);
 
         // the initial transaction to create root fnode and save store,
         // it must be successful
@@ -139,7 +156,7 @@ impl Fs {
 
         debug!("repo created");
 
-        Ok(Fs {
+        let mut fs = Fs {
             root: root_ref.unwrap(),
             fcache,
             store: store_ref.unwrap(),
@@ -148,14 +165,49 @@ impl Fs {
             shutter: Shutter::new(),
             opts: cfg.opts,
             read_only: false,
-        })
+            salvage: false,
+            compactor: None,
+            compact_cursor: Arc::new(Mutex::new(None)),
+        };
+        fs.set_cache_sizes(&cfg.caches);
+
+        Ok(fs)
     }
 
     /// Open fs
-    pub fn open(uri: &str, pwd: &str, read_only: bool) -> Result<Fs> {
-        let mut vol = Volume::new(uri)?;
+    ///
+    /// In salvage mode, fs is opened as read-only and entities failed to
+    /// load are skipped wherever possible.
+    pub fn open(
+        uri: &str,
+        data_uri: Option<&str>,
+        pwd: &str,
+        read_only: bool,
+        salvage: bool,
+    ) -> Result<Fs> {
+        let vol = Self::new_volume(uri, data_uri)?;
+        Self::open_volume(vol, pwd, read_only, salvage)
+    }
+
+    /// Open fs from an image
+    ///
+    /// The image is loaded into an anonymous memory storage.
+    pub fn open_image(r: &mut dyn Read, pwd: &str, read_only: bool, salvage: bool) -> Result<Fs> {
+        let depot = MemStorage::import_image(r)?;
+        let vol = Volume::with_depot("mem://", Box::new(depot));
+        Self::open_volume(vol, pwd, read_only, salvage)
+    }
 
-        debug!("open repo: {}, read_only: {}", vol.info().uri, read_only);
+    // open fs on volume
+    fn open_volume(mut vol: Volume, pwd: &str, read_only: bool, salvage: bool) -> Result<Fs> {
+        let read_only = read_only || salvage;
+
+        debug!(
+            "open repo: {}, read_only: {}, salvage: {}",
+            vol.info().uri,
+            read_only,
+            salvage
+        );
 
         // open volume
         let payload = vol.open(pwd)?;
@@ -164,13 +216,24 @@ impl Fs {
         // deserialize payload
         let payload = Payload::deseri(&payload)?;
 
-        // open transaction manager
-        let txmgr = TxMgr::open(&payload.walq_id, &vol)?.into_ref();
+        // open transaction manager, in salvage mode no transaction will be
+        // made so wal queue can be skipped if it is damaged
+        let txmgr = match TxMgr::open(&payload.walq_id, &vol) {
+            Ok(txmgr) => txmgr,
+            Err(err) => {
+                if !salvage {
+                    return Err(err);
+                }
+                warn!("skip damaged wal queue: {}", err);
+                TxMgr::new(&payload.walq_id, &vol)
+            }
+        }
+        .into_ref();
 
         // create other file sytem components
-        let store = Store::open(&payload.store_id, &txmgr, &vol)?;
+        let store = Store::open(&payload.store_id, &txmgr, &vol, salvage)?;
         let root = Fnode::load_root(&payload.root_id, &txmgr, &store, &vol)?;
-        let fcache = FnodeCache::new(Self::FNODE_CACHE_SIZE, &txmgr);
+        let fcache = FnodeCache::new(CacheSizes::default().fnode, &txmgr);
 
         debug!("repo opened");
 
@@ -183,14 +246,39 @@ impl Fs {
             shutter: Shutter::new(),
             opts: payload.opts,
             read_only,
+            salvage,
+            compactor: None,
+            compact_cursor: Arc::new(Mutex::new(None)),
         })
     }
 
+    /// Set cache sizes
+    pub fn set_cache_sizes(&mut self, caches: &CacheSizes) {
+        {
+            let mut vol = self.vol.write().unwrap();
+            vol.set_cache_sizes(caches);
+        }
+        {
+            let store = self.store.read().unwrap();
+            store.set_seg_data_cache_size(caches.seg_data);
+        }
+        self.fcache.set_capacity(caches.fnode);
+
+        let mut root_cow = self.root.write().unwrap();
+        let root = root_cow.make_mut_naive();
+        root.set_sub_nodes_cache_size(caches.sub_nodes);
+    }
+
     #[inline]
     pub fn is_read_only(&self) -> bool {
         self.read_only
     }
 
+    #[inline]
+    pub fn is_salvage(&self) -> bool {
+        self.salvage
+    }
+
     #[inline]
     pub fn get_opts(&self) -> Options {
         self.opts
@@ -206,6 +294,15 @@ impl Fs {
         }
     }
 
+    /// Write whole volume content to an image
+    ///
+    /// Volume is flushed before it is exported, so the image has everything
+    /// committed so far.
+    pub fn export_image(&self, w: &mut dyn Write) -> Result<()> {
+        let mut vol = self.vol.write().unwrap();
+        vol.export_image(w)
+    }
+
     /// Reset volume password
     pub fn reset_password(&mut self, old_pwd: &str, new_pwd: &str, cost: Cost) -> Result<()> {
         if self.read_only {
@@ -307,7 +404,7 @@ impl Fs {
     /// Read directory entries
     pub fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
         let parent = self.resolve(path)?;
-        Fnode::read_dir(parent, path, &self.fcache, &self.vol)
+        Fnode::read_dir(parent, path, &self.fcache, &self.vol, self.salvage)
     }
 
     /// Get metadata of specified path
@@ -327,6 +424,142 @@ impl Fs {
         Ok(fnode.history())
     }
 
+    // walk through the fnode tree in depth-first order, starting from the
+    // specified fnode
+    fn walk<F>(&self, fnode: &FnodeRef, path: &Path, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&Path, &FnodeRef) -> Result<()>,
+    {
+        f(path, fnode)?;
+        let child_names = {
+            let fnode = fnode.read().unwrap();
+            fnode.children_names()
+        };
+
+        for name in child_names.iter() {
+            let child = Fnode::child(fnode, name, &self.fcache, &self.vol)?;
+            self.walk(&child, &path.join(name), f)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find all file versions whose content matches the hash
+    pub fn find_by_hash(&self, hash: &Hash) -> Result<Vec<(PathBuf, usize)>> {
+        let content_id = {
+            let store = self.store.read().unwrap();
+            match store.find_content(hash) {
+                Some(id) => id.clone(),
+                None => return Ok(Vec::new()),
+            }
+        };
+
+        let mut ret = Vec::new();
+        self.walk(&self.root, Path::new("/"), &mut |path, fnode| {
+            let fnode = fnode.read().unwrap();
+            for ver in fnode.history().iter() {
+                if *ver.content_id() == content_id {
+                    ret.push((path.to_path_buf(), ver.num()));
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(ret)
+    }
+
+    /// Find groups of files whose current versions have identical content
+    ///
+    /// Empty files are not reported.
+    pub fn duplicates(&self) -> Result<Vec<(Hash, Vec<PathBuf>)>> {
+        // group files by content id, same content always shares one id
+        // because of content dedup
+        let mut groups: HashMap<Eid, Vec<PathBuf>> = HashMap::new();
+        self.walk(&self.root, Path::new("/"), &mut |path, fnode| {
+            let fnode = fnode.read().unwrap();
+            if fnode.is_file() && fnode.curr_len() > 0 {
+                let vers = fnode.history();
+                let curr = vers.last().unwrap();
+                groups
+                    .entry(curr.content_id().clone())
+                    .or_default()
+                    .push(path.to_path_buf());
+            }
+            Ok(())
+        })?;
+
+        let store = self.store.read().unwrap();
+        let mut ret = Vec::new();
+        for (content_id, paths) in groups.into_iter().filter(|g| g.1.len() > 1) {
+            let ctn_ref = store.get_content(&content_id)?;
+            let ctn = ctn_ref.read().unwrap();
+            ret.push((ctn.hash().clone(), paths));
+        }
+
+        Ok(ret)
+    }
+
+    /// Check file system consistency
+    pub fn check(&self, opts: CheckOptions) -> Result<CheckReport> {
+        Checker::new(opts, &self.fcache, &self.store, &self.txmgr, &self.vol).run(&self.root)
+    }
+
+    /// Rebuild reference counts and remove unreachable contents and segments
+    pub fn repair(&mut self, dry_run: bool) -> Result<RepairReport> {
+        if self.read_only && !dry_run {
+            return Err(Error::ReadOnly);
+        }
+
+        let collect_fnodes = || {
+            let mut fnodes = Vec::new();
+            self.walk(&self.root, Path::new("/"), &mut |_, fnode| {
+                fnodes.push(fnode.clone());
+                Ok(())
+            })?;
+            Ok(fnodes)
+        };
+
+        repair::repair(collect_fnodes, &self.store, &self.txmgr, dry_run)
+    }
+
+    #[inline]
+    pub(super) fn compactor(&self) -> Compactor {
+        Compactor::new(
+            &self.root,
+            &self.fcache,
+            &self.store,
+            &self.txmgr,
+            &self.vol,
+            &self.compact_cursor,
+        )
+    }
+
+    /// Rewrite segments and storage sectors with most dead space
+    pub fn compact(&mut self, opts: CompactOptions) -> Result<CompactReport> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.compactor().run(opts)
+    }
+
+    /// Start background compactor, which runs compaction periodically
+    pub fn start_compactor(&mut self, opts: CompactOptions, interval: Duration) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        // stop the running one first
+        self.stop_compactor();
+        self.compactor = Some(BackgroundCompactor::start(self.compactor(), opts, interval));
+        Ok(())
+    }
+
+    /// Stop background compactor if it is running
+    #[inline]
+    pub fn stop_compactor(&mut self) {
+        self.compactor.take();
+    }
+
     /// Copy a regular file to another
     pub fn copy(&mut self, from: &Path, to: &Path) -> Result<()> {
         if self.read_only {
@@ -452,8 +685,8 @@ impl Fs {
         for child in self.read_dir(path)? {
             let child_path = child.path();
             match child.metadata().file_type() {
-                FileType::File => self.remove_file(&child_path)?,
-                FileType::Dir => self.remove_dir_all(&child_path)?,
+                FileType::File => self.remove_file(child_path)?,
+                FileType::Dir => self.remove_dir_all(child_path)?,
             }
         }
         match self.remove_dir(path) {
@@ -530,8 +763,795 @@ impl Fs {
 
 impl Drop for Fs {
     fn drop(&mut self) {
+        self.stop_compactor();
         let mut shutter = self.shutter.write().unwrap();
         shutter.close();
         debug!("repo closed");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::io::SeekFrom;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::file::File;
+    use crate::fs::check::Problem;
+    use crate::trans::Finish;
+    use crate::util::crypto::{Crypto, RandomSeed};
+    use crate::util::init_env;
+    use crate::volume::address::Span;
+    use crate::volume::storage::{Fault, FaultInjector, MemStorage};
+    use crate::volume::{Arm, Writer as VolWriter};
+
+    fn write_file(fs: &mut Fs, path: &str, buf: &[u8]) {
+        let opts = fs.get_opts();
+        fs.create_fnode(Path::new(path), FileType::File, opts).unwrap();
+        let handle = fs.open_fnode(Path::new(path)).unwrap();
+        let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+        f.write_all(buf).unwrap();
+        f.finish().unwrap();
+    }
+
+    // overwrite both arms of a fnode with garbage
+    fn damage_fnode(fs: &mut Fs, path: &str) {
+        let id = {
+            let fnode = fs.resolve(Path::new(path)).unwrap();
+            let fnode = fnode.read().unwrap();
+            fnode.id().clone()
+        };
+        damage_cow(fs, &id);
+    }
+
+    // overwrite both arms of the first segment of a file with garbage
+    fn damage_segment(fs: &mut Fs, path: &str) {
+        let content_id = {
+            let fnode = fs.resolve(Path::new(path)).unwrap();
+            let fnode = fnode.read().unwrap();
+            fnode.history().last().unwrap().content_id().clone()
+        };
+        let seg_id = {
+            let store = fs.store.read().unwrap();
+            let ctn_ref = store.get_content(&content_id).unwrap();
+            let ctn = ctn_ref.read().unwrap();
+            ctn.seg_chunks()[0].0.clone()
+        };
+        damage_cow(fs, &seg_id);
+    }
+
+    fn damage_cow(fs: &mut Fs, id: &Eid) {
+        for arm in [Arm::Left, Arm::Right].iter() {
+            let mut wtr = VolWriter::new(&arm.to_eid(id), &fs.vol).unwrap();
+            wtr.write_all(&[42u8; 64]).unwrap();
+            wtr.finish().unwrap();
+        }
+        let mut vol = fs.vol.write().unwrap();
+        vol.flush().unwrap();
+    }
+
+    fn read_file(fs: &mut Fs, path: &str) -> Result<Vec<u8>> {
+        let handle = fs.open_fnode(Path::new(path))?;
+        let mut f = File::new(handle, SeekFrom::Start(0), true, false);
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    // get content hash of file current version
+    fn file_hash(fs: &Fs, path: &str) -> Hash {
+        let content_id = {
+            let fnode = fs.resolve(Path::new(path)).unwrap();
+            let fnode = fnode.read().unwrap();
+            fnode.history().last().unwrap().content_id().clone()
+        };
+        let store = fs.store.read().unwrap();
+        let ctn = store.get_content(&content_id).unwrap();
+        let ctn = ctn.read().unwrap();
+        ctn.hash().clone()
+    }
+
+    fn found_paths(fs: &Fs, hash: &Hash) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = fs
+            .find_by_hash(hash)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn find_by_hash() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        fs.create_fnode(Path::new("/dir"), FileType::Dir, fs.get_opts())
+            .unwrap();
+        write_file(&mut fs, "/foo", b"foo");
+        write_file(&mut fs, "/dir/foo", b"foo");
+        write_file(&mut fs, "/bar", b"bar");
+        for path in ["/empty", "/empty2"].iter() {
+            fs.create_fnode(Path::new(path), FileType::File, fs.get_opts())
+                .unwrap();
+        }
+        let foo_hash = file_hash(&fs, "/foo");
+        let bar_hash = file_hash(&fs, "/bar");
+
+        // hash shared by several files
+        assert_eq!(
+            found_paths(&fs, &foo_hash),
+            vec![PathBuf::from("/dir/foo"), PathBuf::from("/foo")]
+        );
+        assert_eq!(found_paths(&fs, &bar_hash), vec![PathBuf::from("/bar")]);
+        let dups = fs.duplicates().unwrap();
+        assert_eq!(dups.len(), 1);
+        assert_eq!(dups[0].0, foo_hash);
+        let mut paths = dups[0].1.clone();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("/dir/foo"), PathBuf::from("/foo")]
+        );
+
+        // hash with no match
+        assert!(fs.find_by_hash(&Hash::new_empty()).unwrap().is_empty());
+        assert!(fs.find_by_hash(&Crypto::hash(b"baz")).unwrap().is_empty());
+
+        // rewrite file, its old version still uses the old hash
+        {
+            let handle = fs.open_fnode(Path::new("/bar")).unwrap();
+            let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+            f.write_all(b"foo").unwrap();
+            f.finish().unwrap();
+        }
+        assert_eq!(file_hash(&fs, "/bar"), foo_hash);
+        assert_eq!(found_paths(&fs, &bar_hash), vec![PathBuf::from("/bar")]);
+        let found = fs.find_by_hash(&foo_hash).unwrap();
+        assert_eq!(found.len(), 3);
+        let bar_vers: Vec<usize> = found
+            .iter()
+            .filter(|(path, _)| path == Path::new("/bar"))
+            .map(|&(_, ver)| ver)
+            .collect();
+        assert_eq!(bar_vers.len(), 1);
+        assert!(fs
+            .find_by_hash(&bar_hash)
+            .unwrap()
+            .iter()
+            .all(|&(_, ver)| ver < bar_vers[0]));
+        assert_eq!(fs.duplicates().unwrap()[0].1.len(), 3);
+
+        // remove all files using the hash, and then add it again
+        for path in ["/foo", "/dir/foo", "/bar"].iter() {
+            fs.remove_file(Path::new(path)).unwrap();
+        }
+        assert!(fs.find_by_hash(&foo_hash).unwrap().is_empty());
+        assert!(fs.find_by_hash(&bar_hash).unwrap().is_empty());
+        assert!(fs.duplicates().unwrap().is_empty());
+        write_file(&mut fs, "/dir/baz", b"foo");
+        assert_eq!(file_hash(&fs, "/dir/baz"), foo_hash);
+        assert_eq!(found_paths(&fs, &foo_hash), vec![PathBuf::from("/dir/baz")]);
+    }
+
+    #[test]
+    fn retire_versions() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let path = Path::new("/foo");
+        let mut opts = fs.get_opts();
+        opts.version_limit = 1;
+        fs.create_fnode(path, FileType::File, opts).unwrap();
+
+        // each write replaces the whole file, so segments of the retired
+        // version are removed and segment count does not grow
+        let mut seg_cnt = 0;
+        for seed in 0..5u8 {
+            let mut buf = vec![0u8; 512 * 1024];
+            Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+            let handle = fs.open_fnode(path).unwrap();
+            let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+            f.write_all(&buf).unwrap();
+            f.finish().unwrap();
+            assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+
+            let report = fs.check(CheckOptions::default()).unwrap();
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.content_cnt(), 1);
+            if seed == 0 {
+                seg_cnt = report.segment_cnt();
+            } else {
+                assert_eq!(report.segment_cnt(), seg_cnt);
+            }
+        }
+    }
+
+    #[test]
+    fn split_data() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("file://{}", tmpdir.path().join("meta").display());
+        let data_uri = format!("file://{}", tmpdir.path().join("data").display());
+        let buf = vec![42u8; 100 * 1024];
+
+        {
+            let mut fs = Fs::create(&uri, Some(&data_uri), "pwd", &Config::default()).unwrap();
+            write_file(&mut fs, "/foo", &buf);
+        }
+
+        {
+            let mut fs = Fs::open(&uri, Some(&data_uri), "pwd", false, false).unwrap();
+            assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+        }
+
+        // volume cannot be opened without its data storage or with a
+        // different one
+        assert_eq!(
+            Fs::open(&uri, None, "pwd", true, false).unwrap_err(),
+            Error::InvalidArgument
+        );
+        let other_uri = format!("file://{}", tmpdir.path().join("meta2").display());
+        let other_data_uri = format!("file://{}", tmpdir.path().join("data2").display());
+        Fs::create(&other_uri, Some(&other_data_uri), "pwd", &Config::default()).unwrap();
+        assert_eq!(
+            Fs::open(&uri, Some(&other_data_uri), "pwd", true, false).unwrap_err(),
+            Error::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn mem_reopen() {
+        init_env();
+        let uri = "mem://fs_mem_reopen";
+        let buf = vec![42u8; 100 * 1024];
+
+        assert!(!Fs::exists(uri).unwrap());
+        {
+            let mut fs = Fs::create(uri, None, "pwd", &Config::default()).unwrap();
+            write_file(&mut fs, "/foo", &buf);
+        }
+        assert!(Fs::exists(uri).unwrap());
+        assert_eq!(
+            Fs::create(uri, None, "pwd", &Config::default()).unwrap_err(),
+            Error::AlreadyExists
+        );
+
+        {
+            let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+            assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+        }
+
+        assert!(MemStorage::destroy("fs_mem_reopen"));
+        assert!(!Fs::exists(uri).unwrap());
+        assert!(Fs::open(uri, None, "pwd", false, false).is_err());
+    }
+
+    #[test]
+    fn custom_layout() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("file://{}", tmpdir.path().display());
+        let buf = vec![42u8; 300 * 1024];
+        let mut cfg = Config::default();
+        cfg.layout.blk_size = 4096;
+        cfg.layout.blks_per_frame = 8;
+        cfg.layout.blks_per_sector = 32;
+
+        {
+            let mut fs = Fs::create(&uri, None, "pwd", &cfg).unwrap();
+            write_file(&mut fs, "/foo", &buf);
+            assert_eq!(fs.info().vol_info.layout, cfg.layout);
+        }
+
+        // layout is loaded from super block on open
+        let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+        assert_eq!(fs.info().vol_info.layout, cfg.layout);
+        assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+        fs.remove_file(Path::new("/foo")).unwrap();
+        write_file(&mut fs, "/bar", &buf[..1000]);
+        drop(fs);
+
+        let mut fs = Fs::open(&uri, None, "pwd", true, false).unwrap();
+        assert_eq!(read_file(&mut fs, "/bar").unwrap(), &buf[..1000]);
+    }
+
+    #[test]
+    fn small_caches() {
+        init_env();
+        let uri = "mem://fs_small_caches";
+        let buf = vec![42u8; 300 * 1024];
+        let cfg = Config {
+            caches: CacheSizes {
+                frame: 1,
+                address: 1,
+                seg_data: 1,
+                fnode: 1,
+                sub_nodes: 1,
+            },
+            ..Default::default()
+        };
+
+        {
+            let mut fs = Fs::create(uri, None, "pwd", &cfg).unwrap();
+            let opts = fs.get_opts();
+            fs.create_fnode(Path::new("/dir"), FileType::Dir, opts)
+                .unwrap();
+            for i in 0..5 {
+                write_file(&mut fs, &format!("/dir/{}", i), &buf[i..]);
+            }
+            for i in 0..5 {
+                let path = format!("/dir/{}", i);
+                assert_eq!(read_file(&mut fs, &path).unwrap(), &buf[i..]);
+            }
+        }
+
+        let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+        fs.set_cache_sizes(&cfg.caches);
+        for i in 0..5 {
+            let path = format!("/dir/{}", i);
+            assert_eq!(read_file(&mut fs, &path).unwrap(), &buf[i..]);
+        }
+
+        MemStorage::destroy("fs_small_caches");
+    }
+
+    #[test]
+    fn image() {
+        init_env();
+        let buf = vec![42u8; 100 * 1024];
+        let mut img = Vec::new();
+
+        {
+            let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+            write_file(&mut fs, "/foo", &buf);
+            fs.export_image(&mut img).unwrap();
+        }
+
+        // image is encrypted
+        assert!(Fs::open_image(&mut &img[..], "wrong", false, false).is_err());
+
+        {
+            let mut fs = Fs::open_image(&mut &img[..], "pwd", true, false).unwrap();
+            assert!(fs.is_read_only());
+            assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+        }
+
+        // opened image can be changed and exported again
+        let mut img2 = Vec::new();
+        {
+            let mut fs = Fs::open_image(&mut &img[..], "pwd", false, false).unwrap();
+            write_file(&mut fs, "/bar", &buf[..100]);
+            fs.export_image(&mut img2).unwrap();
+        }
+        let mut fs = Fs::open_image(&mut &img2[..], "pwd", false, false).unwrap();
+        assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+        assert_eq!(read_file(&mut fs, "/bar").unwrap(), &buf[..100]);
+
+        // other storages cannot be exported
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("file://{}", tmpdir.path().display());
+        let fs = Fs::create(&uri, None, "pwd", &Config::default()).unwrap();
+        assert_eq!(
+            fs.export_image(&mut Vec::new()).unwrap_err(),
+            Error::NotSupported
+        );
+    }
+
+    fn crash_recovery_test(uri: &str) {
+        let faults = FaultInjector::get(uri);
+
+        {
+            let mut fs = Fs::create(uri, None, "pwd", &Config::default()).unwrap();
+            write_file(&mut fs, "/foo", b"foo");
+        }
+
+        // crash at every write operation when writing a new file, committed
+        // file must survive and repo can be opened and written again
+        let mut crash_at = 0;
+        loop {
+            faults.reset();
+            faults.inject(crash_at, Fault::Crash);
+            {
+                let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+                let path = Path::new("/bar");
+                let result = fs
+                    .create_fnode(path, FileType::File, fs.get_opts())
+                    .and_then(|_| fs.open_fnode(path))
+                    .and_then(|handle| {
+                        let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+                        f.write_all(b"bar")?;
+                        f.finish()
+                    });
+                if !faults.is_crashed() {
+                    result.unwrap();
+                    break;
+                }
+            }
+
+            faults.reset();
+            let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+            assert_eq!(read_file(&mut fs, "/foo").unwrap(), b"foo");
+            if fs.open_fnode(Path::new("/bar")).is_ok() {
+                fs.remove_file(Path::new("/bar")).unwrap();
+            }
+            crash_at += 1;
+        }
+        assert!(crash_at > 0);
+    }
+
+    #[test]
+    fn crash_recovery() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("faulty+file://{}", tmpdir.path().display());
+        crash_recovery_test(&uri);
+
+        // memory storage reuses deleted blocks
+        crash_recovery_test("faulty+mem://fs_crash_recovery");
+        MemStorage::destroy("fs_crash_recovery");
+    }
+
+    #[test]
+    fn block_reuse() {
+        init_env();
+        let uri = "mem://fs_block_reuse";
+        let buf = vec![42u8; 100 * 1024];
+        let allocator = |fs: &Fs| {
+            let vol = fs.vol.read().unwrap();
+            let allocator = vol.get_allocator();
+            let allocator = allocator.read().unwrap();
+            (allocator.block_wmark(), allocator.free_extents())
+        };
+        let rewrite = |fs: &mut Fs, cnt: usize| {
+            for _ in 0..cnt {
+                fs.remove_file(Path::new("/foo")).unwrap();
+                write_file(fs, "/foo", &buf);
+            }
+        };
+
+        let mut fs = Fs::create(uri, None, "pwd", &Config::default()).unwrap();
+        write_file(&mut fs, "/foo", &buf);
+        rewrite(&mut fs, 2);
+        let (wmark, _) = allocator(&fs);
+
+        // repeatedly rewriting a file should not grow block space
+        rewrite(&mut fs, 10);
+        assert!(allocator(&fs).0 <= wmark);
+        assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+        let saved = allocator(&fs);
+        drop(fs);
+
+        // free extents are kept after reopen
+        let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+        assert_eq!(allocator(&fs), saved);
+        rewrite(&mut fs, 2);
+        let (wmark, _) = allocator(&fs);
+        rewrite(&mut fs, 10);
+        assert!(allocator(&fs).0 <= wmark);
+        assert_eq!(read_file(&mut fs, "/foo").unwrap(), buf);
+
+        MemStorage::destroy("fs_block_reuse");
+    }
+
+    #[test]
+    fn many_free_extents() {
+        init_env();
+        let uri = "mem://fs_many_free_extents";
+        let allocator = |fs: &Fs| {
+            let vol = fs.vol.read().unwrap();
+            let allocator = vol.get_allocator();
+            let allocator = allocator.read().unwrap();
+            (allocator.block_wmark(), allocator.free_extents())
+        };
+
+        // fragment block space beyond the watermark
+        let mut fs = Fs::create(uri, None, "pwd", &Config::default()).unwrap();
+        {
+            let vol = fs.vol.read().unwrap();
+            let allocator = vol.get_allocator();
+            let mut allocator = allocator.write().unwrap();
+            let wmark = allocator.block_wmark();
+            let free: Vec<Span> = (0..2000)
+                .map(|i| Span::new(wmark + i * 8, i % 4 + 1))
+                .collect();
+            allocator.set_block_wmark(wmark + 16000);
+            allocator.set_free_extents(&free);
+        }
+        let foo = vec![42u8; 100 * 1024];
+        write_file(&mut fs, "/foo", &foo);
+        let saved = allocator(&fs);
+        assert!(saved.1.len() > 1024);
+        drop(fs);
+
+        // all free extents are kept after reopen
+        let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+        assert_eq!(allocator(&fs), saved);
+
+        // reuse some of the free extents and reopen again
+        write_file(&mut fs, "/bar", &foo);
+        let saved = allocator(&fs);
+        drop(fs);
+        let mut fs = Fs::open(uri, None, "pwd", false, false).unwrap();
+        assert_eq!(allocator(&fs), saved);
+        assert_eq!(read_file(&mut fs, "/foo").unwrap(), foo);
+        assert_eq!(read_file(&mut fs, "/bar").unwrap(), foo);
+
+        MemStorage::destroy("fs_many_free_extents");
+    }
+
+    #[test]
+    fn free_extents_crash() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("faulty+file://{}", tmpdir.path().display());
+        let faults = FaultInjector::get(&uri);
+        let mut foo = vec![0u8; 16 * 1024];
+        let bar = vec![255u8; 16 * 1024];
+
+        // free extents span several pages, blocks between them are never
+        // written so they are leaked
+        let fragmented = {
+            let mut fs = Fs::create(&uri, None, "pwd", &Config::default()).unwrap();
+            let fragmented = {
+                let vol = fs.vol.read().unwrap();
+                let allocator = vol.get_allocator();
+                let mut allocator = allocator.write().unwrap();
+                let wmark = allocator.block_wmark();
+                let free: Vec<Span> = (0..1000)
+                    .map(|i| Span::new(wmark + i * 8, i % 4 + 1))
+                    .collect();
+                allocator.set_block_wmark(wmark + 8000);
+                allocator.set_free_extents(&free);
+                Span::new(wmark, 8000)
+            };
+            write_file(&mut fs, "/foo", &foo);
+            fragmented
+        };
+
+        // crash at every write operation when rewriting a file, blocks
+        // still in use must never be reused after reopen
+        let mut crash_at = 0;
+        loop {
+            let new_foo = vec![crash_at as u8 + 1; foo.len()];
+            faults.reset();
+            faults.inject(crash_at, Fault::Crash);
+            {
+                let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+                let result = fs.remove_file(Path::new("/foo")).and_then(|_| {
+                    let opts = fs.get_opts();
+                    fs.create_fnode(Path::new("/foo"), FileType::File, opts)?;
+                    let handle = fs.open_fnode(Path::new("/foo"))?;
+                    let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+                    f.write_all(&new_foo)?;
+                    f.finish()
+                });
+                if !faults.is_crashed() {
+                    result.unwrap();
+                    break;
+                }
+            }
+
+            faults.reset();
+            let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+            match read_file(&mut fs, "/foo") {
+                Ok(ref buf) if !buf.is_empty() => {
+                    assert!(*buf == foo || *buf == new_foo, "crash at {}", crash_at);
+                    foo = buf.clone();
+                }
+                Ok(_) => {
+                    // crashed after the file was created
+                    fs.remove_file(Path::new("/foo")).unwrap();
+                    write_file(&mut fs, "/foo", &new_foo);
+                    foo = new_foo;
+                }
+                Err(_) => {
+                    // crashed after the file was removed
+                    write_file(&mut fs, "/foo", &new_foo);
+                    foo = new_foo;
+                }
+            }
+            write_file(&mut fs, "/bar", &bar);
+            assert_eq!(read_file(&mut fs, "/foo").unwrap(), foo);
+            assert_eq!(read_file(&mut fs, "/bar").unwrap(), bar);
+            fs.remove_file(Path::new("/bar")).unwrap();
+            let report = fs.check(CheckOptions::default()).unwrap();
+            let problems: Vec<&Problem> = report
+                .problems()
+                .iter()
+                .filter(|problem| match problem {
+                    Problem::LeakedBlock { span } => {
+                        span.begin < fragmented.begin || span.end() > fragmented.end()
+                    }
+                    _ => true,
+                })
+                .collect();
+            assert!(problems.is_empty(), "crash at {}: {:?}", crash_at, problems);
+            crash_at += 1;
+        }
+        assert!(crash_at > 0);
+    }
+
+    #[test]
+    fn cold_abort_flush() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("faulty+file://{}", tmpdir.path().display());
+        let faults = FaultInjector::get(&uri);
+
+        {
+            let mut fs = Fs::create(&uri, None, "pwd", &Config::default()).unwrap();
+            write_file(&mut fs, "/foo", b"foo");
+        }
+
+        // crash at every write operation when overwriting a file, then
+        // drop the repo right after it is opened, the cold aborted tx must
+        // not leave anything behind
+        let mut crash_at = 0;
+        loop {
+            faults.reset();
+            faults.inject(crash_at, Fault::Crash);
+            {
+                let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+                let result = fs.open_fnode(Path::new("/foo")).and_then(|handle| {
+                    let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+                    f.write_all(b"bar")?;
+                    f.finish()
+                });
+                if !faults.is_crashed() {
+                    result.unwrap();
+                    break;
+                }
+            }
+
+            // open the repo and drop it without any other write
+            faults.reset();
+            Fs::open(&uri, None, "pwd", false, false).unwrap();
+
+            faults.reset();
+            let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+            let buf = read_file(&mut fs, "/foo").unwrap();
+            assert!(buf == b"foo" || buf == b"bar");
+            let report = fs.check(CheckOptions::default()).unwrap();
+            assert!(report.is_ok(), "crash at {}: {:?}", crash_at, report);
+            crash_at += 1;
+        }
+        assert!(crash_at > 0);
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn commit_fail_point() {
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+
+        // transaction is aborted if commit fails
+        fail::cfg("trans::commit::before_flush", "1*return").unwrap();
+        let opts = fs.get_opts();
+        assert_eq!(
+            fs.create_fnode(Path::new("/foo"), FileType::File, opts)
+                .unwrap_err(),
+            Error::FailPoint
+        );
+        fail::remove("trans::commit::before_flush");
+        assert!(fs.open_fnode(Path::new("/foo")).is_err());
+        write_file(&mut fs, "/foo", b"foo");
+        assert_eq!(read_file(&mut fs, "/foo").unwrap(), b"foo");
+    }
+
+    #[test]
+    fn salvage_open() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("file://{}", tmpdir.path().display());
+
+        {
+            let mut fs = Fs::create(&uri, None, "pwd", &Config::default()).unwrap();
+            fs.create_dir_all(Path::new("/dir")).unwrap();
+            write_file(&mut fs, "/dir/foo", b"foo");
+            write_file(&mut fs, "/dir/bar", b"bar");
+            damage_fnode(&mut fs, "/dir/bar");
+        }
+        {
+            let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+            write_file(&mut fs, "/baz", b"baz");
+            damage_segment(&mut fs, "/baz");
+        }
+
+        // normal open fails to list the damaged directory and to read file
+        // with damaged segment
+        {
+            let mut fs = Fs::open(&uri, None, "pwd", false, false).unwrap();
+            assert!(fs.read_dir(Path::new("/dir")).is_err());
+            assert!(read_file(&mut fs, "/baz").is_err());
+        }
+
+        // salvage open reports damaged entry and can still read intact file
+        let mut fs = Fs::open(&uri, None, "pwd", false, true).unwrap();
+        assert!(fs.is_read_only());
+        let mut dirs = fs.read_dir(Path::new("/dir")).unwrap();
+        dirs.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].file_name(), "bar");
+        assert!(dirs[0].is_damaged());
+        assert!(dirs[0].metadata().is_file());
+        assert_eq!(dirs[1].file_name(), "foo");
+        assert!(!dirs[1].is_damaged());
+
+        let handle = fs.open_fnode(Path::new("/dir/foo")).unwrap();
+        let mut f = File::new(handle, SeekFrom::Start(0), true, false);
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"foo");
+        assert!(fs.open_fnode(Path::new("/dir/bar")).is_err());
+
+        // damaged segment is read as zeros
+        assert_eq!(&read_file(&mut fs, "/baz").unwrap()[..], &[0u8; 3]);
+
+        assert_eq!(
+            fs.create_fnode(Path::new("/qux"), FileType::File, Options::default())
+                .unwrap_err(),
+            Error::ReadOnly
+        );
+    }
+
+    // concurrent lookups of different fnodes, they should scale with
+    // threads as fnodes are in different cache shards
+    #[test]
+    #[ignore]
+    fn concurrent_fnode_perf() {
+        use std::thread;
+        use std::time::Instant;
+
+        const FILE_CNT: usize = 32;
+        const ROUNDS: usize = 1024 * 1024;
+        const MAX_THREADS: usize = 8;
+
+        init_env();
+        let mut fs = Fs::create("mem://", None, "pwd", &Config::default()).unwrap();
+        let ids: Vec<Vec<Eid>> = (0..MAX_THREADS)
+            .map(|t| {
+                (0..FILE_CNT)
+                    .map(|i| {
+                        let path = format!("/{}_{}", t, i);
+                        write_file(&mut fs, &path, b"foo");
+                        let fnode = fs.resolve(Path::new(&path)).unwrap();
+                        let fnode = fnode.read().unwrap();
+                        fnode.id().clone()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut thread_cnt = 1;
+        while thread_cnt <= MAX_THREADS {
+            let now = Instant::now();
+            let children: Vec<_> = ids[..thread_cnt]
+                .iter()
+                .map(|ids| {
+                    let ids = ids.clone();
+                    let fcache = fs.fcache.clone();
+                    let vol = fs.vol.clone();
+                    thread::spawn(move || {
+                        for i in 0..ROUNDS {
+                            fcache.get(&ids[i % FILE_CNT], &vol).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for child in children {
+                child.join().unwrap();
+            }
+            let time = now.elapsed();
+            let secs = time.as_secs() as f32 + time.subsec_nanos() as f32 / 1_000_000_000.0;
+
+            println!(
+                "Concurrent fnode perf ({} threads): {} ops/s",
+                thread_cnt,
+                (ROUNDS * thread_cnt) as f32 / secs
+            );
+            thread_cnt *= 2;
+        }
+    }
+}
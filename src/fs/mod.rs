@@ -1,11 +1,16 @@
+pub mod check;
+pub mod compact;
 pub mod fnode;
+#[allow(clippy::module_inception)]
 pub mod fs;
+pub mod repair;
 
 use crate::content::store::StoreRef;
 use crate::fs::fnode::FnodeRef;
 use crate::fs::fs::ShutterRef;
 use crate::trans::txmgr::TxMgrRef;
 use crate::util::crypto::{Cipher, Cost, Crypto};
+use crate::volume::layout::Layout;
 use crate::volume::volume::VolumeRef;
 
 // Default file versoin limit
@@ -27,13 +32,37 @@ impl Default for Options {
     }
 }
 
+// Cache sizes, in bytes
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct CacheSizes {
+    pub frame: usize,     // decrypted frame cache
+    pub address: usize,   // entity address cache
+    pub seg_data: usize,  // segment data cache
+    pub fnode: usize,     // fnode cache
+    pub sub_nodes: usize, // sub node cache of each directory
+}
+
+impl Default for CacheSizes {
+    fn default() -> Self {
+        CacheSizes {
+            frame: 4 * 1024 * 1024,
+            address: 64 * 1024,
+            seg_data: 16 * 1024 * 1024,
+            fnode: 256 * 1024,
+            sub_nodes: 1024,
+        }
+    }
+}
+
 // Configuration
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub cost: Cost,
     pub cipher: Cipher,
     pub compress: bool,
+    pub layout: Layout,
     pub opts: Options,
+    pub caches: CacheSizes,
 }
 
 impl Default for Config {
@@ -46,7 +75,9 @@ impl Default for Config {
                 Cipher::Xchacha
             },
             compress: false,
+            layout: Layout::default(),
             opts: Options::default(),
+            caches: CacheSizes::default(),
         }
     }
 }
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+
+use super::fnode::{Fnode, FnodeRef};
+use crate::content::StoreRef;
+use crate::error::{Error, Result};
+use crate::trans::{Eid, TxMgr, TxMgrRef};
+use crate::util::crypto::Hash;
+
+/// Repository repair report.
+///
+/// This structure is returned from [`Repo::repair`].
+///
+/// [`Repo::repair`]: ../../repo/struct.Repo.html#method.repair
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    dry_run: bool,
+    contents_fixed: usize,
+    contents_removed: usize,
+    segments_fixed: usize,
+    segments_removed: usize,
+    failures: Vec<(Eid, Error)>,
+}
+
+impl RepairReport {
+    /// Returns whether this is a dry run, nothing is changed in a dry run.
+    #[inline]
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns whether nothing needs to be repaired.
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.contents_fixed == 0
+            && self.contents_removed == 0
+            && self.segments_fixed == 0
+            && self.segments_removed == 0
+            && self.failures.is_empty()
+    }
+
+    /// Returns number of contents whose reference count is fixed.
+    #[inline]
+    pub fn contents_fixed(&self) -> usize {
+        self.contents_fixed
+    }
+
+    /// Returns number of unreachable contents removed.
+    #[inline]
+    pub fn contents_removed(&self) -> usize {
+        self.contents_removed
+    }
+
+    /// Returns number of segments whose chunk reference counts are fixed.
+    #[inline]
+    pub fn segments_fixed(&self) -> usize {
+        self.segments_fixed
+    }
+
+    /// Returns number of unreachable segments removed, along with their
+    /// segment data.
+    #[inline]
+    pub fn segments_removed(&self) -> usize {
+        self.segments_removed
+    }
+
+    /// Returns contents and segments which cannot be repaired.
+    ///
+    /// Each item is the content or segment id and the error. Items which
+    /// cannot be loaded are left untouched, when a content cannot be loaded,
+    /// chunk reference counts of segments are not changed either, as they
+    /// cannot be known.
+    #[inline]
+    pub fn failures(&self) -> &[(Eid, Error)] {
+        &self.failures
+    }
+}
+
+// repair plan, built from reachability of all fnodes
+#[derive(Debug, Default)]
+struct Plan {
+    // content hash and its actual reference count
+    fix_contents: Vec<(Hash, u32)>,
+
+    // unreachable content hashes
+    remove_contents: Vec<Hash>,
+
+    // segment id and its actual chunk reference counts
+    fix_segs: Vec<(Eid, Vec<u32>)>,
+
+    // unreachable segment ids
+    remove_segs: Vec<Eid>,
+
+    // reachable segment ids, they are registered to store
+    reachable_segs: Vec<Eid>,
+
+    // contents and segments cannot be repaired
+    failures: Vec<(Eid, Error)>,
+}
+
+impl Plan {
+    fn build(fnodes: &[FnodeRef], store: &StoreRef) -> Self {
+        let mut plan = Plan::default();
+
+        // count file versions referring to each content
+        let mut ver_refs: HashMap<Eid, u32> = HashMap::new();
+        for fnode in fnodes.iter() {
+            let fnode = fnode.read().unwrap();
+            for ver in fnode.history().iter() {
+                *ver_refs.entry(ver.content_id().clone()).or_insert(0) += 1;
+            }
+        }
+
+        let store = store.read().unwrap();
+
+        // count content references to each segment chunk, segments
+        // referred by unreachable contents and registered segments are
+        // removal candidates
+        let mut chk_refs: HashMap<Eid, Vec<u32>> = HashMap::new();
+        let mut candidates: HashSet<Eid> = store.segments().into_iter().collect();
+        for (hash, content_id, refcnt) in store.contents() {
+            let actual = ver_refs.remove(&content_id).unwrap_or(0);
+            let ctn_ref = match store.get_content(&content_id) {
+                Ok(ctn_ref) => ctn_ref,
+                Err(err) => {
+                    plan.failures.push((content_id, err));
+                    continue;
+                }
+            };
+            let ctn = ctn_ref.read().unwrap();
+
+            if actual == 0 {
+                plan.remove_contents.push(hash);
+                for (seg_id, _) in ctn.seg_chunks() {
+                    candidates.insert(seg_id);
+                }
+                continue;
+            }
+
+            if refcnt != actual {
+                plan.fix_contents.push((hash, actual));
+            }
+            for (seg_id, range) in ctn.seg_chunks() {
+                let refs = chk_refs.entry(seg_id).or_default();
+                if refs.len() < range.end {
+                    refs.resize(range.end, 0);
+                }
+                for idx in range {
+                    refs[idx] += 1;
+                }
+            }
+        }
+
+        // version referring to missing content cannot be repaired
+        for (content_id, _) in ver_refs {
+            plan.failures.push((content_id, Error::NoContent));
+        }
+
+        // if any content is not known, its chunk references are unknown as
+        // well, so segments cannot be repaired
+        if !plan.failures.is_empty() {
+            return plan;
+        }
+
+        for (seg_id, refs) in chk_refs {
+            candidates.remove(&seg_id);
+            let seg_ref = match store.get_seg(&seg_id) {
+                Ok(seg_ref) => seg_ref,
+                Err(err) => {
+                    plan.failures.push((seg_id, err));
+                    continue;
+                }
+            };
+            let seg = seg_ref.read().unwrap();
+            if refs.len() > seg.chunk_cnt() {
+                plan.failures.push((seg_id, Error::Corrupted));
+                continue;
+            }
+            let is_fixed = (0..seg.chunk_cnt())
+                .all(|idx| seg[idx].refcnt() == refs.get(idx).cloned().unwrap_or(0));
+            if !is_fixed {
+                plan.fix_segs.push((seg_id.clone(), refs));
+            }
+            plan.reachable_segs.push(seg_id);
+        }
+
+        for seg_id in candidates {
+            match store.get_seg(&seg_id) {
+                Ok(_) => plan.remove_segs.push(seg_id),
+                Err(err) => plan.failures.push((seg_id, err)),
+            }
+        }
+
+        plan
+    }
+
+    fn apply(&self, fnodes: &[FnodeRef], store: &StoreRef) -> Result<()> {
+        let mut store_cow = store.write().unwrap();
+        let store = store_cow.make_mut()?;
+
+        for &(ref hash, refcnt) in self.fix_contents.iter() {
+            store.reset_content_ref(hash, refcnt)?;
+        }
+
+        for hash in self.remove_contents.iter() {
+            if let Some(ctn_ref) = store.remove_content(hash)? {
+                let mut ctn = ctn_ref.write().unwrap();
+                ctn.make_del()?;
+            }
+        }
+
+        for (seg_id, refs) in self.fix_segs.iter() {
+            let seg_ref = store.get_seg(seg_id)?;
+            let mut seg_cow = seg_ref.write().unwrap();
+            seg_cow.make_mut()?.reset_refs(refs);
+        }
+
+        for seg_id in self.reachable_segs.iter() {
+            store.register_segment(seg_id);
+        }
+
+        for seg_id in self.remove_segs.iter() {
+            let seg_ref = store.get_seg(seg_id)?;
+            let mut seg_cow = seg_ref.write().unwrap();
+            store.remove_segment(&mut seg_cow)?;
+        }
+
+        // removed segments cannot be used for chunk dedup anymore
+        if !self.remove_segs.is_empty() {
+            for fnode in fnodes.iter() {
+                Fnode::forget_segments(fnode, &self.remove_segs)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn into_report(self, dry_run: bool) -> RepairReport {
+        RepairReport {
+            dry_run,
+            contents_fixed: self.fix_contents.len(),
+            contents_removed: self.remove_contents.len(),
+            segments_fixed: self.fix_segs.len(),
+            segments_removed: self.remove_segs.len(),
+            failures: self.failures,
+        }
+    }
+}
+
+// rebuild reference counts from all reachable fnodes and remove unreachable
+// contents and segments
+//
+// The plan is built and applied in one transaction. Store is added to the
+// transaction before fnodes are collected, so concurrent changes conflict
+// with the repair rather than making the plan stale.
+pub(super) fn repair<F>(
+    collect_fnodes: F,
+    store: &StoreRef,
+    txmgr: &TxMgrRef,
+    dry_run: bool,
+) -> Result<RepairReport>
+where
+    F: Fn() -> Result<Vec<FnodeRef>>,
+{
+    if dry_run {
+        let plan = Plan::build(&collect_fnodes()?, store);
+        return Ok(plan.into_report(dry_run));
+    }
+
+    let mut plan = Plan::default();
+    TxMgr::begin_trans(txmgr)?.run_all(|| {
+        {
+            let mut store_cow = store.write().unwrap();
+            store_cow.make_mut()?;
+        }
+        let fnodes = collect_fnodes()?;
+        plan = Plan::build(&fnodes, store);
+        debug!(
+            "repair plan: fix {} contents, remove {} contents, fix {} segments, \
+             remove {} segments, {} failures",
+            plan.fix_contents.len(),
+            plan.remove_contents.len(),
+            plan.fix_segs.len(),
+            plan.remove_segs.len(),
+            plan.failures.len()
+        );
+        plan.apply(&fnodes, store)
+    })?;
+
+    Ok(plan.into_report(dry_run))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, SeekFrom, Write};
+    use std::path::Path;
+
+    use super::*;
+    use crate::file::File;
+    use crate::fs::check::CheckOptions;
+    use crate::fs::fnode::FileType;
+    use crate::fs::fs::Fs;
+    use crate::fs::Config;
+    use crate::util::crypto::{Crypto, RandomSeed};
+    use crate::util::init_env;
+    use crate::volume::storage::MemStorage;
+
+    fn random_buf(len: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn write_file(fs: &mut Fs, path: &str, buf: &[u8]) {
+        let opts = fs.get_opts();
+        fs.create_fnode(Path::new(path), FileType::File, opts)
+            .unwrap();
+        let handle = fs.open_fnode(Path::new(path)).unwrap();
+        let mut f = File::new(handle, SeekFrom::Start(0), true, true);
+        f.write_all(buf).unwrap();
+        f.finish().unwrap();
+    }
+
+    fn verify_file(fs: &mut Fs, path: &str, expected: &[u8]) {
+        let handle = fs.open_fnode(Path::new(path)).unwrap();
+        let mut f = File::new(handle, SeekFrom::Start(0), true, false);
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], expected);
+    }
+
+    // add a bogus reference to current content of a file, optionally
+    // linking its segment chunks once more
+    fn add_bogus_ref(fs: &mut Fs, path: &str, relink: bool) {
+        let handle = fs.open_fnode(Path::new(path)).unwrap();
+        let content_id = {
+            let fnode = handle.fnode.read().unwrap();
+            fnode.history().last().unwrap().content_id().clone()
+        };
+        TxMgr::begin_trans(&handle.txmgr)
+            .unwrap()
+            .run_all(|| {
+                let ctn_ref = {
+                    let store = handle.store.read().unwrap();
+                    store.get_content(&content_id)?
+                };
+                let ctn = ctn_ref.read().unwrap();
+                {
+                    let mut store = handle.store.write().unwrap();
+                    store.make_mut()?.dedup_content(&ctn)?;
+                }
+                if relink {
+                    ctn.link(&handle.store)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn repair_fs() {
+        init_env();
+        let mut fs = Fs::create("mem://repair_fs", None, "pwd", &Config::default()).unwrap();
+        let foo = random_buf(1024 * 1024, 42);
+        let bar = random_buf(1024 * 1024, 43);
+        write_file(&mut fs, "/foo", &foo);
+        write_file(&mut fs, "/bar", &bar);
+
+        // nothing to repair on a healthy repo
+        let report = fs.repair(false).unwrap();
+        assert!(report.is_clean());
+
+        // inflate refcounts of foo and leak content of bar
+        add_bogus_ref(&mut fs, "/foo", true);
+        add_bogus_ref(&mut fs, "/bar", false);
+        fs.remove_file(Path::new("/bar")).unwrap();
+        assert!(!fs.check(CheckOptions::default()).unwrap().is_ok());
+
+        // dry run should not change anything
+        let report = fs.repair(true).unwrap();
+        assert!(report.is_dry_run());
+        assert_eq!(report.contents_fixed(), 1);
+        assert_eq!(report.contents_removed(), 1);
+        assert!(report.segments_fixed() > 0);
+        assert!(report.segments_removed() > 0);
+        assert!(!fs.check(CheckOptions::default()).unwrap().is_ok());
+
+        // now do the real repair
+        let report = fs.repair(false).unwrap();
+        assert!(!report.is_dry_run());
+        assert!(!report.is_clean());
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(fs.repair(true).unwrap().is_clean());
+        verify_file(&mut fs, "/foo", &foo);
+
+        // repaired repo should still work as usual
+        write_file(&mut fs, "/bar", &bar);
+        fs.remove_file(Path::new("/foo")).unwrap();
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        verify_file(&mut fs, "/bar", &bar);
+
+        MemStorage::destroy("repair_fs");
+    }
+
+    #[test]
+    fn reclaim_orphans() {
+        init_env();
+        let mut fs = Fs::create("mem://reclaim_orphans", None, "pwd", &Config::default()).unwrap();
+        let foo = random_buf(1024 * 1024, 42);
+        let bar = random_buf(1024 * 1024, 43);
+        write_file(&mut fs, "/foo", &foo);
+        write_file(&mut fs, "/bar", &bar);
+
+        // relink chunks of bar without referring its content, so its
+        // segments are left orphan after bar is removed
+        let handle = fs.open_fnode(Path::new("/bar")).unwrap();
+        let content_id = {
+            let fnode = handle.fnode.read().unwrap();
+            fnode.history().last().unwrap().content_id().clone()
+        };
+        TxMgr::begin_trans(&handle.txmgr)
+            .unwrap()
+            .run_all(|| {
+                let ctn_ref = {
+                    let store = handle.store.read().unwrap();
+                    store.get_content(&content_id)?
+                };
+                let ctn = ctn_ref.read().unwrap();
+                ctn.link(&handle.store)
+            })
+            .unwrap();
+        drop(handle);
+        fs.remove_file(Path::new("/bar")).unwrap();
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(fs.repair(true).unwrap().contents_removed(), 0);
+
+        let report = fs.repair(false).unwrap();
+        assert!(report.segments_removed() > 0);
+        assert!(report.failures().is_empty());
+        let report = fs.check(CheckOptions::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(fs.repair(true).unwrap().is_clean());
+        verify_file(&mut fs, "/foo", &foo);
+
+        MemStorage::destroy("reclaim_orphans");
+    }
+}
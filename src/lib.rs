@@ -1,10 +1,9 @@
-#![feature(integer_atomics)]
-#![feature(arbitrary_self_types, unsized_locals)]
-
 #[macro_use]
 extern crate log;
 extern crate hashbrown;
 extern crate indexmap;
+#[macro_use]
+extern crate lazy_static;
 extern crate linked_hash_map;
 extern crate lz4;
 extern crate serde;
@@ -14,10 +13,27 @@ extern crate bytes;
 extern crate env_logger;
 extern crate rmp_serde;
 extern crate crossbeam;
+extern crate reed_solomon_erasure;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(feature = "storage-sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "failpoints")]
+#[macro_use]
+extern crate fail;
 
 macro_rules! map_io_err {
     ($x:expr) => {
-        $x.map_err(|e| IoError::new(ErrorKind::Other, e.description()));
+        $x.map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))
+    };
+}
+
+// return FailPoint error from current function if the fail point is
+// configured, this is no-op if feature `failpoints` is not enabled
+macro_rules! fail_point_err {
+    ($name:expr) => {
+        #[cfg(feature = "failpoints")]
+        fail_point!($name, |_| Err(crate::error::Error::FailPoint));
     };
 }
 
@@ -26,18 +42,19 @@ pub mod diskptr;
 pub mod error;
 pub mod file;
 pub mod fs;
+mod parallel_io;
 pub mod repo;
 pub mod trans;
 pub mod util;
 pub mod version;
 pub mod volume;
 
-// block and frame size
+// default block and frame size, see volume::Layout for per-volume values
 pub const BLK_SIZE: usize = 8 * 1024;
 pub const BLKS_PER_FRAME: usize = 16;
 pub const FRAME_SIZE: usize = BLKS_PER_FRAME * BLK_SIZE;
 
-pub use self::error::{Error, Result};
+pub use self::error::{Error, ErrorContext, Result};
 
 /// An offset for a storage file segment.
 pub type SegmentId = usize;
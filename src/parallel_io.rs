@@ -1,7 +1,11 @@
-use super::*;
+use std::io;
 
 #[cfg(unix)]
 use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+use crate::LogId;
 
 /// Multithreaded IO support for Files
 pub(crate) trait Pio {
@@ -60,3 +64,51 @@ impl Pio for std::fs::File {
         Ok(())
     }
 }
+
+// On Windows, use seek_read/seek_write which may change the file offset,
+// callers must not rely on the offset.
+#[cfg(windows)]
+impl Pio for std::fs::File {
+    fn pread_exact(&self, mut buf: &mut [u8], mut offset: LogId) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.seek_read(buf, offset) {
+                Ok(0) => break,
+                Ok(n) => {
+                    offset += n as LogId;
+                    let tmp = buf;
+                    buf = &mut tmp[n..];
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !buf.is_empty() {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn pwrite_all(&self, mut buf: &[u8], mut offset: LogId) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.seek_write(buf, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ));
+                }
+                Ok(n) => {
+                    offset += n as LogId;
+                    buf = &buf[n..]
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
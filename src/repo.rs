@@ -1,23 +1,31 @@
 use std::fmt::{self, Debug};
-use std::io::SeekFrom;
-use std::path::Path;
-use std::time::SystemTime;
+use std::io::{Read, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use crate::error::{Error, Result};
 use crate::file::File;
+use crate::fs::check::{CheckOptions, CheckReport};
+use crate::fs::compact::{CompactOptions, CompactReport};
 use crate::fs::fnode::{DirEntry, FileType, Metadata, Version};
-use crate::fs::{fs::Fs, Config, Options};
+use crate::fs::repair::RepairReport;
+use crate::fs::{fs::Fs, CacheSizes, Config, Options};
 use crate::trans::eid::Eid;
-use crate::util::crypto::{Cipher, Cost, MemLimit, OpsLimit};
+use crate::util::crypto::{Cipher, Cost, Hash, MemLimit, OpsLimit};
+use crate::util::lru;
 use crate::util::time::Time;
 use crate::util::version;
+use crate::volume::Layout;
 
 #[derive(Debug, Default)]
 pub struct RepoOpener {
     cfg: Config,
+    data_uri: Option<String>,
+    mem_budget: Option<usize>,
     create: bool,
     create_new: bool,
     read_only: bool,
+    salvage: bool,
 }
 
 impl RepoOpener {
@@ -55,6 +63,34 @@ impl RepoOpener {
         self
     }
 
+    /// Sets the block size in bytes.
+    ///
+    /// This option is only used for creating a repository. The block size
+    /// must be a power of 2 within [1KB, 1MB], 8KB is the default.
+    pub fn block_size(&mut self, block_size: usize) -> &mut Self {
+        self.cfg.layout.blk_size = block_size;
+        self
+    }
+
+    /// Sets the number of blocks in a frame.
+    ///
+    /// This option is only used for creating a repository. Data is encrypted
+    /// frame by frame, frame size cannot exceed 4MB. 16 is the default.
+    pub fn blocks_per_frame(&mut self, blocks_per_frame: usize) -> &mut Self {
+        self.cfg.layout.blks_per_frame = blocks_per_frame;
+        self
+    }
+
+    /// Sets the number of blocks in a sector.
+    ///
+    /// This option is only used for creating a repository. Sector is the data
+    /// file unit in file storage, it must be within [1, 65535], 4096 is the
+    /// default.
+    pub fn blocks_per_sector(&mut self, blocks_per_sector: usize) -> &mut Self {
+        self.cfg.layout.blks_per_sector = blocks_per_sector;
+        self
+    }
+
     /// Sets the option for creating a new repository.
     ///
     /// This option indicates whether a new repository will be created if the
@@ -120,6 +156,133 @@ impl RepoOpener {
         self
     }
 
+    /// Sets the option for salvage mode.
+    ///
+    /// This option is used to copy intact files out of a partially corrupted
+    /// repository. In salvage mode, the repository is always opened as
+    /// read-only and entities which fail to load are skipped. Files which
+    /// cannot be loaded are reported as damaged entries by [`read_dir`],
+    /// other files can still be read as usual. File content stored in
+    /// segments which cannot be loaded is read as zeros.
+    ///
+    /// The super block, file system store and root directory must still be
+    /// intact to open a repository in salvage mode. This option cannot be
+    /// true with either `create` or `create_new` is true.
+    ///
+    /// [`read_dir`]: struct.Repo.html#method.read_dir
+    pub fn salvage(&mut self, salvage: bool) -> &mut Self {
+        self.salvage = salvage;
+        self
+    }
+
+    /// Sets the decrypted frame cache size, in bytes.
+    ///
+    /// Default is 4MB.
+    pub fn frame_cache_size(&mut self, size: usize) -> &mut Self {
+        self.cfg.caches.frame = size;
+        self
+    }
+
+    /// Sets the entity address cache size, in bytes.
+    ///
+    /// Default is 64KB.
+    pub fn address_cache_size(&mut self, size: usize) -> &mut Self {
+        self.cfg.caches.address = size;
+        self
+    }
+
+    /// Sets the segment data cache size, in bytes.
+    ///
+    /// Default is 16MB.
+    pub fn segment_cache_size(&mut self, size: usize) -> &mut Self {
+        self.cfg.caches.seg_data = size;
+        self
+    }
+
+    /// Sets the file node cache size, in bytes.
+    ///
+    /// Default is 256KB.
+    pub fn fnode_cache_size(&mut self, size: usize) -> &mut Self {
+        self.cfg.caches.fnode = size;
+        self
+    }
+
+    /// Sets the sub node cache size of each directory, in bytes.
+    ///
+    /// Sub node cache keeps recently accessed children of a directory for
+    /// fast path resolution. Default is 1KB.
+    pub fn sub_nodes_cache_size(&mut self, size: usize) -> &mut Self {
+        self.cfg.caches.sub_nodes = size;
+        self
+    }
+
+    /// Sets the memory budget of all caches, in bytes.
+    ///
+    /// The budget is process-wide, it is not per repository. It is shared
+    /// by all caches of all repositories opened in the same process, and
+    /// opening a repository with this option changes the budget for the
+    /// repositories already opened as well, the value set most recently
+    /// takes effect. When the budget is exceeded, caches using more than
+    /// their share of it remove their least recently used items, the share
+    /// is proportional to cache size. Default is unlimited, so each cache
+    /// is only limited by its own size.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::Result;
+    /// use f2ufs::repo::RepoOpener;
+    /// use f2ufs::util::init_env;
+    /// # fn foo() -> Result<()> {
+    /// init_env();
+    /// let mut repo = RepoOpener::new()
+    ///     .create(true)
+    ///     .segment_cache_size(4 * 1024 * 1024)
+    ///     .memory_budget(64 * 1024 * 1024)
+    ///     .open("mem://", "pwd")?;
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    pub fn memory_budget(&mut self, budget: usize) -> &mut Self {
+        self.mem_budget = Some(budget);
+        self
+    }
+
+    /// Sets the location of a separate data storage.
+    ///
+    /// By default, all data is kept in the storage at the URI passed to
+    /// [`open`]. If this option is set, file content data is kept in the
+    /// storage at `data_uri`, while file system metadata and WAL stay in the
+    /// main storage. For example, metadata can be kept on SSD and file
+    /// content on HDD or object storage.
+    ///
+    /// The same data URI must be used every time the repository is opened.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::Result;
+    /// use f2ufs::repo::RepoOpener;
+    /// use f2ufs::util::init_env;
+    /// # fn foo() -> Result<()> {
+    /// init_env();
+    /// let mut repo = RepoOpener::new()
+    ///     .create(true)
+    ///     .data_uri("file:///hdd/repo_data")
+    ///     .open("file:///ssd/repo", "pwd")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`open`]: #method.open
+    pub fn data_uri(&mut self, data_uri: &str) -> &mut Self {
+        self.data_uri = Some(data_uri.to_string());
+        self
+    }
+
     /// Opens a repository at URI with the password and options specified by
     /// `self`.
     ///
@@ -130,11 +293,21 @@ impl RepoOpener {
     ///   After the prefix is the path to a directory on OS file system. It can
     ///   be a relative or absolute path.
     ///
+    /// - Log-structured file storage, location prefix is `log://`
+    ///
+    ///   After the prefix is the path to a directory on OS file system, all
+    ///   data is kept in a single log file in that directory.
+    ///
     /// - Memory based storage, location prefix is `mem://`
     ///
-    ///   As memory stoage is volatile, it is always be used with `create`
-    ///   option. It doesn't make sense to open an existing memory storage,
-    ///   thus the string after prefix is arbitrary.
+    ///   After the prefix is an optional store name. Storage without name,
+    ///   i.e. `mem://`, is volatile and is gone once the repository is
+    ///   closed, thus it is always be used with `create` option.
+    ///
+    ///   Named storage, such as `mem://foo`, is kept in a process-global
+    ///   store. It persists after the repository is closed and can be opened
+    ///   again by the same name, until it is removed by
+    ///   [`MemStorage::destroy`].
     ///
     /// - SQLite based storage, location prefix is `sqlite://`
     ///
@@ -151,6 +324,46 @@ impl RepoOpener {
     ///
     ///   This storage can be enabled by feature `storage-redis`.
     ///
+    /// - Mirrored storage, location prefix is `mirror://`
+    ///
+    ///   After the prefix are URIs of two or more member storages separated
+    ///   by `|`, for example, `mirror://file:///a|file:///b`. Data is written
+    ///   to all members and read from the first member which can provide it
+    ///   intact. Members which are replaced or have missed writes are
+    ///   rebuilt from the others when the repository is opened.
+    ///
+    /// - Erasure coded storage, location prefix is `erasure://`
+    ///
+    ///   After the prefix are numbers of data and parity shards, followed by
+    ///   URIs of member storages separated by `|`, for example,
+    ///   `erasure://2+1/file:///a|file:///b|file:///c`. Blocks are spread
+    ///   over members with Reed-Solomon coding and can be read as long as
+    ///   no more members than parity shards have failed. Super blocks, WALs
+    ///   and addresses are replicated to all members. Members which are
+    ///   replaced or have missed writes are rebuilt from the others when the
+    ///   repository is opened.
+    ///
+    /// - Cached storage, location prefix is `cache://`
+    ///
+    ///   After the prefix is a local cache directory with optional size in
+    ///   MiB and write mode, followed by `|` and URI of the backend storage,
+    ///   for example, `cache:///tmp/cache?size=256&write=back|file:///a`.
+    ///   Blocks and addresses are served from the cache and evicted in LRU
+    ///   order. In write-back mode writes reach the backend on flush.
+    ///
+    /// - Fault injection storage, location prefix is `faulty+`
+    ///
+    ///   After the prefix is URI of the wrapped storage, for example,
+    ///   `faulty+mem://`. IO errors, torn writes and crashes can be injected
+    ///   at chosen write operations using [`FaultInjector`], it is used to
+    ///   test crash recovery.
+    ///
+    /// - Custom storage, location prefix is `<scheme>://`
+    ///
+    ///   Any type implementing [`Storable`] can be used as storage after
+    ///   its factory is registered for the scheme using
+    ///   [`register_storage`].
+    ///
     /// After a repository is opened, all of the other functions provided by
     /// ZboxFS will be thread-safe.
     ///
@@ -161,27 +374,116 @@ impl RepoOpener {
     ///
     /// Open a memory based repository without enable `create` option will
     /// return an error.
+    ///
+    /// [`Storable`]: ../volume/storage/trait.Storable.html
+    /// [`register_storage`]: ../volume/storage/fn.register_storage.html
+    /// [`FaultInjector`]: ../volume/storage/struct.FaultInjector.html
+    /// [`MemStorage::destroy`]: ../volume/storage/struct.MemStorage.html#method.destroy
     pub fn open(&self, uri: &str, pwd: &str) -> Result<Repo> {
         // version limit must be greater than 0
         if self.cfg.opts.version_limit == 0 {
             return Err(Error::InvalidArgument);
         }
+        self.apply_caches()?;
+
+        let data_uri = self.data_uri.as_deref();
 
         if self.create {
-            if self.read_only {
+            if self.read_only || self.salvage {
                 return Err(Error::InvalidArgument);
             }
+            self.cfg.layout.validate()?;
             if Repo::exists(uri)? {
                 if self.create_new {
                     return Err(Error::AlreadyExists);
                 }
-                Repo::open(uri, pwd, self.read_only)
+                Repo::open(uri, data_uri, pwd, self.read_only, false, &self.cfg.caches)
             } else {
-                Repo::create(uri, pwd, &self.cfg)
+                Repo::create(uri, data_uri, pwd, &self.cfg)
             }
         } else {
-            Repo::open(uri, pwd, self.read_only)
+            Repo::open(
+                uri,
+                data_uri,
+                pwd,
+                self.read_only,
+                self.salvage,
+                &self.cfg.caches,
+            )
+        }
+    }
+
+    // check cache sizes and apply memory budget
+    fn apply_caches(&self) -> Result<()> {
+        let caches = &self.cfg.caches;
+        if caches.frame == 0
+            || caches.address == 0
+            || caches.seg_data == 0
+            || caches.fnode == 0
+            || caches.sub_nodes == 0
+        {
+            return Err(Error::InvalidArgument);
+        }
+        if let Some(budget) = self.mem_budget {
+            let old_budget = lru::mem_budget();
+            if old_budget != usize::MAX && old_budget != budget {
+                warn!(
+                    "process-wide memory budget changed from {} to {}",
+                    old_budget, budget
+                );
+            }
+            lru::set_mem_budget(budget);
+        }
+        Ok(())
+    }
+
+    /// Opens a repository from an image with the password and options
+    /// specified by `self`.
+    ///
+    /// The image is written by [`Repo::export_image`] and is loaded into an
+    /// anonymous memory storage, so changes made to the opened repository
+    /// are not written back to the image.
+    ///
+    /// # Errors
+    ///
+    /// The `create` and [`data_uri`] options cannot be used with image,
+    /// otherwise an error will be returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::error::Result;
+    /// use f2ufs::repo::RepoOpener;
+    /// use f2ufs::util::init_env;
+    /// # fn foo() -> Result<()> {
+    /// init_env();
+    ///
+    /// let mut img = Vec::new();
+    /// {
+    ///     let mut repo = RepoOpener::new().create(true).open("mem://", "pwd")?;
+    ///     repo.create_dir("/foo")?;
+    ///     repo.export_image(&mut img)?;
+    /// }
+    ///
+    /// let repo = RepoOpener::new().open_image(&mut &img[..], "pwd")?;
+    /// assert!(repo.is_dir("/foo")?);
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    ///
+    /// [`Repo::export_image`]: struct.Repo.html#method.export_image
+    /// [`data_uri`]: #method.data_uri
+    pub fn open_image<R: Read>(&self, r: &mut R, pwd: &str) -> Result<Repo> {
+        if self.create || self.data_uri.is_some() {
+            return Err(Error::InvalidArgument);
         }
+        self.apply_caches()?;
+        let mut fs = Fs::open_image(r, pwd, self.read_only, self.salvage)
+            .map_err(|err| err.with_op("open_image"))?;
+        fs.set_cache_sizes(&self.cfg.caches);
+        Ok(Repo { fs: Some(fs) })
     }
 }
 
@@ -197,6 +499,13 @@ pub struct OpenOptions {
     dedup_chunk: Option<bool>,
 }
 
+impl Default for OpenOptions {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OpenOptions {
     /// Creates a blank new set of options ready for configuration.
     ///
@@ -309,7 +618,8 @@ impl OpenOptions {
             }
         }
         match repo.fs {
-            Some(ref mut fs) => open_file_with_options(fs, path, self),
+            Some(ref mut fs) => open_file_with_options(fs, path.as_ref(), self)
+                .map_err(|err| err.with_path(path.as_ref()).with_op("open")),
             None => Err(Error::Closed),
         }
     }
@@ -323,6 +633,7 @@ pub struct RepoInfo {
     uri: String,
     cost: Cost,
     cipher: Cipher,
+    layout: Layout,
     compress: bool,
     version_limit: u8,
     dedup_chunk: bool,
@@ -370,6 +681,24 @@ impl RepoInfo {
         self.cipher
     }
 
+    /// Returns the block size in bytes.
+    #[inline]
+    pub fn block_size(&self) -> usize {
+        self.layout.blk_size
+    }
+
+    /// Returns the number of blocks in a frame.
+    #[inline]
+    pub fn blocks_per_frame(&self) -> usize {
+        self.layout.blks_per_frame
+    }
+
+    /// Returns the number of blocks in a sector.
+    #[inline]
+    pub fn blocks_per_sector(&self) -> usize {
+        self.layout.blks_per_sector
+    }
+
     /// Returns whether compression is enabled.
     #[inline]
     pub fn compress(&self) -> bool {
@@ -475,9 +804,13 @@ fn open_file_with_options<P: AsRef<Path>>(
 /// below:
 ///
 /// * OS file system based storage, location prefix: `file://`
+/// * Log-structured file storage, location prefix: `log://`
 /// * Memory based storage, location prefix: `mem://`
 /// * SQLite based storage, location prefix: `sqlite://`
 /// * Redis based storage, location prefix: `redis://`
+/// * Mirrored storage, location prefix: `mirror://`
+/// * Erasure coded storage, location prefix: `erasure://`
+/// * Cached storage, location prefix: `cache://`
 ///
 /// Check details at: [RepoOpener](struct.RepoOpener.html#method.open)
 ///
@@ -544,8 +877,9 @@ impl Repo {
     /// Returns whether the URI points at an existing repository.
     ///
     /// Existence check depends on the underlying storage implementation, for
-    /// memory storage, it always returns false. For file storage, it will
-    /// return if the specified path exists on the OS file system.
+    /// memory storage, it returns if the named store exists in current
+    /// process. For file storage, it will return if the specified path exists
+    /// on the OS file system.
     #[inline]
     pub fn exists(uri: &str) -> Result<bool> {
         Fs::exists(uri)
@@ -553,15 +887,25 @@ impl Repo {
 
     // create repo
     #[inline]
-    fn create(uri: &str, pwd: &str, cfg: &Config) -> Result<Repo> {
-        let fs = Fs::create(uri, pwd, cfg)?;
+    fn create(uri: &str, data_uri: Option<&str>, pwd: &str, cfg: &Config) -> Result<Repo> {
+        let fs = Fs::create(uri, data_uri, pwd, cfg)
+            .map_err(|err| err.with_uri(uri).with_op("create"))?;
         Ok(Repo { fs: Some(fs) })
     }
 
     // open repo
     #[inline]
-    fn open(uri: &str, pwd: &str, read_only: bool) -> Result<Repo> {
-        let fs = Fs::open(uri, pwd, read_only)?;
+    fn open(
+        uri: &str,
+        data_uri: Option<&str>,
+        pwd: &str,
+        read_only: bool,
+        salvage: bool,
+        caches: &CacheSizes,
+    ) -> Result<Repo> {
+        let mut fs = Fs::open(uri, data_uri, pwd, read_only, salvage)
+            .map_err(|err| err.with_uri(uri).with_op("open"))?;
+        fs.set_cache_sizes(caches);
         Ok(Repo { fs: Some(fs) })
     }
 
@@ -574,13 +918,14 @@ impl Repo {
                     volume_id: meta.vol_info.id.clone(),
                     ver: meta.vol_info.ver.clone(),
                     uri: meta.vol_info.uri.clone(),
-                    cost: meta.vol_info.cost.clone(),
-                    cipher: meta.vol_info.cipher.clone(),
+                    cost: meta.vol_info.cost,
+                    cipher: meta.vol_info.cipher,
+                    layout: meta.vol_info.layout,
                     compress: meta.vol_info.compress,
                     version_limit: meta.opts.version_limit,
                     dedup_chunk: meta.opts.dedup_chunk,
                     read_only: meta.read_only,
-                    ctime: meta.vol_info.ctime.clone(),
+                    ctime: meta.vol_info.ctime,
                 })
             }
             None => Err(Error::Closed),
@@ -710,7 +1055,8 @@ impl Repo {
         match self.fs {
             Some(ref mut fs) => fs
                 .create_fnode(path.as_ref(), FileType::Dir, Options::default())
-                .map(|_| ()),
+                .map(|_| ())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("create_dir")),
             None => Err(Error::Closed),
         }
     }
@@ -722,7 +1068,9 @@ impl Repo {
     #[inline]
     pub fn create_dir_all<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         match self.fs {
-            Some(ref mut fs) => fs.create_dir_all(path.as_ref()),
+            Some(ref mut fs) => fs
+                .create_dir_all(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("create_dir_all")),
             None => Err(Error::Closed),
         }
     }
@@ -730,10 +1078,16 @@ impl Repo {
     /// Returns a vector of all the entries within a directory.
     ///
     /// `path` must be an absolute path.
+    ///
+    /// If the repository is opened in [`RepoOpener::salvage`] mode, entries
+    /// which cannot be loaded are returned as damaged, see
+    /// [`DirEntry::is_damaged`].
     #[inline]
     pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> Result<Vec<DirEntry>> {
         match self.fs {
-            Some(ref fs) => fs.read_dir(path.as_ref()),
+            Some(ref fs) => fs
+                .read_dir(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("read_dir")),
             None => Err(Error::Closed),
         }
     }
@@ -745,7 +1099,9 @@ impl Repo {
     #[inline]
     pub fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
         match self.fs {
-            Some(ref fs) => fs.metadata(path.as_ref()),
+            Some(ref fs) => fs
+                .metadata(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("metadata")),
             None => Err(Error::Closed),
         }
     }
@@ -756,7 +1112,285 @@ impl Repo {
     #[inline]
     pub fn history<P: AsRef<Path>>(&self, path: P) -> Result<Vec<Version>> {
         match self.fs {
-            Some(ref fs) => fs.history(path.as_ref()),
+            Some(ref fs) => fs
+                .history(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("history")),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Returns all the file versions whose content matches the hash.
+    ///
+    /// Each item is a pair of absolute file path and version number. An
+    /// empty vector is returned if no content has the specified hash.
+    #[inline]
+    pub fn find_by_hash(&self, hash: &Hash) -> Result<Vec<(PathBuf, usize)>> {
+        match self.fs {
+            Some(ref fs) => fs.find_by_hash(hash),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Returns groups of regular files which have identical content.
+    ///
+    /// Only the current version of each file is compared, empty files are
+    /// not reported. Each group contains the content hash and the absolute
+    /// paths of at least two files sharing that content. The hash can be
+    /// used by [`find_by_hash`] to find all history versions using it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::repo::RepoOpener;
+    /// # use f2ufs::util::init_env;
+    /// # use f2ufs::error::Result;
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// for (hash, paths) in repo.duplicates()? {
+    ///     let vers = repo.find_by_hash(&hash)?;
+    ///     println!("{:?}: {:?}, {} versions", hash, paths, vers.len());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`find_by_hash`]: struct.Repo.html#method.find_by_hash
+    #[inline]
+    pub fn duplicates(&self) -> Result<Vec<(Hash, Vec<PathBuf>)>> {
+        match self.fs {
+            Some(ref fs) => fs.duplicates(),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Checks consistency of the whole repository.
+    ///
+    /// This function walks from the root directory through all file versions,
+    /// contents, segments and their data. It verifies reference counts match
+    /// the actual references, every referenced entity can be loaded and
+    /// decrypted, content merkle root hashes match their data and entity
+    /// block addresses are valid. Allocated blocks are also checked against
+    /// block allocator and deletion marks of storage sectors, so blocks in
+    /// use must not be free or deleted, and blocks not in use must not be
+    /// leaked. Problems found are returned in the report rather than as
+    /// errors.
+    ///
+    /// The check can be slow for a large repository, use [`CheckOptions`] to
+    /// skip data and address verification.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::repo::RepoOpener;
+    /// # use f2ufs::util::init_env;
+    /// # use f2ufs::error::Result;
+    /// use f2ufs::fs::check::CheckOptions;
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// let report = repo.check(CheckOptions::default())?;
+    /// for problem in report.problems() {
+    ///     println!("{:?}", problem);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`CheckOptions`]: ../fs/check/struct.CheckOptions.html
+    #[inline]
+    pub fn check(&self, opts: CheckOptions) -> Result<CheckReport> {
+        match self.fs {
+            Some(ref fs) => fs.check(opts),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Repairs reference counts and reclaims unreachable space.
+    ///
+    /// This function walks from the root directory through all file versions
+    /// and recomputes every content and segment chunk reference count from
+    /// the live tree. Contents, segments and segment data which cannot be
+    /// reached are deleted. All changes are made in one transaction.
+    ///
+    /// If `dry_run` is `true`, nothing is changed and the report only
+    /// describes what would be repaired.
+    ///
+    /// # Errors
+    ///
+    /// Repair without `dry_run` on a read-only repository will return an
+    /// error. If a file version refers to a missing content, the repository
+    /// cannot be repaired and [`Error::NoContent`] is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::repo::RepoOpener;
+    /// # use f2ufs::util::init_env;
+    /// # use f2ufs::error::Result;
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// // see what would be repaired first
+    /// let report = repo.repair(true)?;
+    /// if !report.is_clean() {
+    ///     repo.repair(false)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Error::NoContent`]: ../error/enum.Error.html
+    #[inline]
+    pub fn repair(&mut self, dry_run: bool) -> Result<RepairReport> {
+        match self.fs {
+            Some(ref mut fs) => fs.repair(dry_run),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Compacts segments and storage sectors to reclaim unused space.
+    ///
+    /// Segments whose proportion of unused bytes and storage sectors whose
+    /// proportion of deleted blocks reach the thresholds in
+    /// [`CompactOptions`] are rewritten without the dead space, the ones
+    /// with most dead space first. Each segment is rewritten in its own
+    /// exclusive transaction, other transactions wait for it to complete. If
+    /// other transactions are in progress, such as a file being written, the
+    /// segment is skipped and left for next run. Rewriting is limited to the
+    /// IO rate in the options.
+    ///
+    /// Sector compaction only applies to file storage, other storages either
+    /// reuse or clean deleted blocks by themselves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::repo::RepoOpener;
+    /// # use f2ufs::util::init_env;
+    /// # use f2ufs::error::Result;
+    /// use f2ufs::fs::compact::CompactOptions;
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// let mut opts = CompactOptions::default();
+    /// opts.io_rate = 8 * 1024 * 1024;
+    /// let report = repo.compact(opts)?;
+    /// println!("reclaimed {} bytes", report.bytes_reclaimed());
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    ///
+    /// [`CompactOptions`]: ../fs/compact/struct.CompactOptions.html
+    #[inline]
+    pub fn compact(&mut self, opts: CompactOptions) -> Result<CompactReport> {
+        match self.fs {
+            Some(ref mut fs) => fs.compact(opts),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Starts a background compactor.
+    ///
+    /// The compactor runs [`compact`] with the options in a separate thread
+    /// once it is started and then every `interval`, until it is stopped by
+    /// [`stop_compactor`] or the repository is closed. Starting a compactor
+    /// stops the running one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::repo::RepoOpener;
+    /// # use f2ufs::util::init_env;
+    /// # use f2ufs::error::Result;
+    /// use std::time::Duration;
+    /// use f2ufs::fs::compact::CompactOptions;
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// repo.start_compactor(CompactOptions::default(), Duration::from_secs(60))?;
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    ///
+    /// [`compact`]: struct.Repo.html#method.compact
+    /// [`stop_compactor`]: struct.Repo.html#method.stop_compactor
+    #[inline]
+    pub fn start_compactor(&mut self, opts: CompactOptions, interval: Duration) -> Result<()> {
+        match self.fs {
+            Some(ref mut fs) => fs.start_compactor(opts, interval),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Stops the background compactor if it is running.
+    ///
+    /// This function waits for the compaction in progress to stop.
+    #[inline]
+    pub fn stop_compactor(&mut self) -> Result<()> {
+        match self.fs {
+            Some(ref mut fs) => {
+                fs.stop_compactor();
+                Ok(())
+            }
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Writes the whole repository to an image.
+    ///
+    /// The image is a single byte stream of all the underlying storage
+    /// content, which can be opened later by [`RepoOpener::open_image`]. As
+    /// content is encrypted in storage, the image is encrypted in the same
+    /// way and the repository password is needed to open it.
+    ///
+    /// The repository is flushed before it is exported, so everything
+    /// committed before this call is in the image.
+    ///
+    /// Only repository on memory storage can be exported, other storages
+    /// will return [`Error::NotSupported`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::repo::RepoOpener;
+    /// # use f2ufs::util::init_env;
+    /// # use f2ufs::error::Result;
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// let mut img = Vec::new();
+    /// repo.export_image(&mut img)?;
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    ///
+    /// [`RepoOpener::open_image`]: struct.RepoOpener.html#method.open_image
+    /// [`Error::NotSupported`]: ../error/enum.Error.html
+    pub fn export_image<W: Write>(&self, w: &mut W) -> Result<()> {
+        match self.fs {
+            Some(ref fs) => fs.export_image(w),
             None => Err(Error::Closed),
         }
     }
@@ -772,7 +1406,11 @@ impl Repo {
     #[inline]
     pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
         match self.fs {
-            Some(ref mut fs) => fs.copy(from.as_ref(), to.as_ref()),
+            Some(ref mut fs) => fs.copy(from.as_ref(), to.as_ref()).map_err(|err| {
+                err.with_path(from.as_ref())
+                    .with_to_path(to.as_ref())
+                    .with_op("copy")
+            }),
             None => Err(Error::Closed),
         }
     }
@@ -783,7 +1421,9 @@ impl Repo {
     #[inline]
     pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         match self.fs {
-            Some(ref mut fs) => fs.remove_file(path.as_ref()),
+            Some(ref mut fs) => fs
+                .remove_file(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("remove_file")),
             None => Err(Error::Closed),
         }
     }
@@ -794,7 +1434,9 @@ impl Repo {
     #[inline]
     pub fn remove_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         match self.fs {
-            Some(ref mut fs) => fs.remove_dir(path.as_ref()),
+            Some(ref mut fs) => fs
+                .remove_dir(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("remove_dir")),
             None => Err(Error::Closed),
         }
     }
@@ -806,7 +1448,9 @@ impl Repo {
     #[inline]
     pub fn remove_dir_all<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         match self.fs {
-            Some(ref mut fs) => fs.remove_dir_all(path.as_ref()),
+            Some(ref mut fs) => fs
+                .remove_dir_all(path.as_ref())
+                .map_err(|err| err.with_path(path.as_ref()).with_op("remove_dir_all")),
             None => Err(Error::Closed),
         }
     }
@@ -818,7 +1462,11 @@ impl Repo {
     #[inline]
     pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
         match self.fs {
-            Some(ref mut fs) => fs.rename(from.as_ref(), to.as_ref()),
+            Some(ref mut fs) => fs.rename(from.as_ref(), to.as_ref()).map_err(|err| {
+                err.with_path(from.as_ref())
+                    .with_to_path(to.as_ref())
+                    .with_op("rename")
+            }),
             None => Err(Error::Closed),
         }
     }
@@ -829,3 +1477,47 @@ impl Debug for Repo {
         f.debug_struct("Repo").finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::init_env;
+    use std::io::Seek;
+
+    #[test]
+    fn memory_budget_is_global() {
+        init_env();
+        let old_budget = lru::mem_budget();
+
+        let mut repo = RepoOpener::new().create(true).open("mem://", "pwd").unwrap();
+        let mut repo2 = RepoOpener::new()
+            .create(true)
+            .memory_budget(64 * 1024 * 1024)
+            .open("mem://", "pwd")
+            .unwrap();
+        assert_eq!(lru::mem_budget(), 64 * 1024 * 1024);
+
+        // the budget set most recently applies to all repos
+        let repo3 = RepoOpener::new()
+            .create(true)
+            .memory_budget(32 * 1024 * 1024)
+            .open("mem://", "pwd")
+            .unwrap();
+        assert_eq!(lru::mem_budget(), 32 * 1024 * 1024);
+        drop(repo3);
+        assert_eq!(lru::mem_budget(), 32 * 1024 * 1024);
+
+        // repos opened before keep working under the new budget
+        let buf = vec![42u8; 4096];
+        for repo in [&mut repo, &mut repo2].iter_mut() {
+            let mut f = OpenOptions::new().create(true).open(repo, "/foo").unwrap();
+            f.write_once(&buf).unwrap();
+            let mut dst = Vec::new();
+            f.seek(SeekFrom::Start(0)).unwrap();
+            f.read_to_end(&mut dst).unwrap();
+            assert_eq!(dst, buf);
+        }
+
+        lru::set_mem_budget(old_budget);
+    }
+}
@@ -1,6 +1,7 @@
 use std::clone::Clone;
 use std::default::Default;
 use std::fmt::{self, Debug};
+use std::mem;
 use std::ops::Deref;
 use std::sync::{Arc, RwLock, Weak};
 
@@ -9,12 +10,18 @@ use serde::{Deserialize, Serialize};
 use super::trans::{Action, Transable};
 use super::{Eid, EntityType, Id, TxMgrRef, Txid};
 use crate::error::{Error, Result};
-use crate::util::lru::{CountMeter, Lru, Pinnable};
+use crate::util::lru::{MemSize, Pinnable, ShardedLru, SizeMeter};
 use crate::util::IntoRef;
 use crate::volume::{Arm, ArmAccess, Armor, Seq, VolumeArmor, VolumeRef};
 
 /// Trait for entity can be wrapped in cow
-pub trait Cowable: Debug + Default + Clone + Send + Sync {}
+pub trait Cowable: Debug + Default + Clone + Send + Sync {
+    // approximate heap memory size used by the entity, in bytes
+    #[inline]
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
 
 /// Copy-on-write wrapper
 #[derive(Default, Deserialize, Serialize)]
@@ -35,6 +42,11 @@ pub struct Cow<T: Cowable> {
 
     #[serde(skip_serializing, skip_deserializing, default)]
     txmgr: TxMgrRef,
+
+    // cache holding this cow, the cow is measured again in it when it is
+    // released from transaction
+    #[serde(skip_serializing, skip_deserializing, default)]
+    cache: Weak<CowLru<T>>,
 }
 
 impl<'de, T> Cow<T>
@@ -60,6 +72,7 @@ where
             action: None,
             self_ref: Weak::default(),
             txmgr: txmgr.clone(),
+            cache: Weak::default(),
         }
     }
 
@@ -170,28 +183,32 @@ where
 
     fn inner_by(&self, arm: Arm) -> &T {
         match arm {
-            Arm::Left => match self.left {
-                Some(ref inner) => return inner,
-                None => {}
-            },
-            Arm::Right => match self.right {
-                Some(ref inner) => return inner,
-                None => {}
-            },
+            Arm::Left => {
+                if let Some(ref inner) = self.left {
+                    return inner;
+                }
+            }
+            Arm::Right => {
+                if let Some(ref inner) = self.right {
+                    return inner;
+                }
+            }
         }
         panic!("Cow is empty");
     }
 
     fn inner_mut_by(&mut self, arm: Arm) -> &mut T {
         match arm {
-            Arm::Left => match self.left {
-                Some(ref mut inner) => return inner,
-                None => {}
-            },
-            Arm::Right => match self.right {
-                Some(ref mut inner) => return inner,
-                None => {}
-            },
+            Arm::Left => {
+                if let Some(ref mut inner) = self.left {
+                    return inner;
+                }
+            }
+            Arm::Right => {
+                if let Some(ref mut inner) = self.right {
+                    return inner;
+                }
+            }
         }
         panic!("Cow is empty");
     }
@@ -221,7 +238,7 @@ where
     // load cow from volume
     pub fn load(id: &Eid, txmgr: &TxMgrRef, vol: &VolumeRef) -> Result<CowRef<T>> {
         let vol_armor = VolumeArmor::<Cow<T>>::new(vol);
-        let cow = vol_armor.load_item(id)?;
+        let cow = vol_armor.load_item(id).map_err(|err| err.with_eid(id))?;
         let cow_ref = cow.into_ref();
         {
             let mut c = cow_ref.write().unwrap();
@@ -255,6 +272,23 @@ where
     }
 }
 
+impl<T: Cowable> Cow<T> {
+    // approximate memory size of the cow, in bytes
+    fn mem_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.left.as_ref().map_or(0, T::heap_size)
+            + self.right.as_ref().map_or(0, T::heap_size)
+    }
+
+    // measure cow size again in its cache, the cow is locked by caller so
+    // it cannot be measured by the cache itself
+    fn remeasure(&self) {
+        if let Some(lru) = self.cache.upgrade() {
+            lru.shard(&self.id).update_size(&self.id, self.mem_size());
+        }
+    }
+}
+
 impl<T> Debug for Cow<T>
 where
     T: Cowable,
@@ -325,7 +359,7 @@ where
 {
     #[inline]
     fn action(&self) -> Action {
-        self.action.clone().unwrap()
+        self.action.unwrap()
     }
 
     fn commit(&mut self, vol: &VolumeRef) -> Result<()> {
@@ -335,11 +369,11 @@ where
                     // toggle arm temporarily because save() will toggle it
                     self.arm.toggle();
 
-                    self.save(vol).or_else(|err| {
+                    self.save(vol).map_err(|err| {
                         // if saving cow failed, arm will not be switched,
                         // so we need to switch it here
                         self.arm.toggle();
-                        Err(err)
+                        err
                     })
                 }
                 Action::Update => {
@@ -347,11 +381,10 @@ where
                     let old = self.curr_mut().take();
 
                     // save cow and restore the old inner object
-                    let result = self.save(vol).and_then(|_| {
+                    let result = self.save(vol).map(|_| {
                         // toggle the arm back because save() has
                         // already toggled it
                         self.arm.toggle();
-                        Ok(())
                     });
 
                     // restore the old inner object
@@ -371,33 +404,33 @@ where
 
     fn complete_commit(&mut self) {
         match self.action {
-            Some(action) => match action {
-                Action::Update => {
+            Some(action) => {
+                if action == Action::Update {
                     // toggle arm and discard the old inner object
                     self.arm.toggle();
                     self.other_mut().take();
                 }
-                _ => {}
-            },
+            }
             None => unreachable!(),
         }
         self.txid = None;
         self.action = None;
+        self.remeasure();
     }
 
     fn abort(&mut self) {
         match self.action {
-            Some(action) => match action {
-                Action::Update => {
+            Some(action) => {
+                if action == Action::Update {
                     // discard the new inner object
                     self.other_mut().take();
                 }
-                _ => {}
-            },
+            }
             None => unreachable!(),
         }
         self.txid = None;
         self.action = None;
+        self.remeasure();
     }
 }
 
@@ -429,6 +462,17 @@ where
     }
 }
 
+impl<T: Cowable> MemSize for CowRef<T> {
+    fn mem_size(&self) -> usize {
+        // if cannot read the inner cow entity, only count its own size, it
+        // will be measured again when it is released from transaction
+        match self.try_read() {
+            Ok(cow) => cow.mem_size(),
+            Err(_) => mem::size_of::<Cow<T>>(),
+        }
+    }
+}
+
 /// Cow cache pin checker
 #[derive(Debug, Clone, Default)]
 pub struct CowPinChecker {}
@@ -447,10 +491,12 @@ where
     }
 }
 
+type CowLru<T> = ShardedLru<Eid, CowRef<T>, SizeMeter<CowRef<T>>, CowPinChecker>;
+
 /// Cow LRU cache
 #[derive(Debug, Clone, Default)]
 pub struct CowCache<T: Cowable> {
-    lru: Arc<RwLock<Lru<Eid, CowRef<T>, CountMeter<CowRef<T>>, CowPinChecker>>>,
+    lru: Arc<CowLru<T>>,
     txmgr: TxMgrRef,
 }
 
@@ -460,13 +506,21 @@ where
 {
     pub fn new(capacity: usize, txmgr: &TxMgrRef) -> Self {
         CowCache {
-            lru: Arc::new(RwLock::new(Lru::new(capacity))),
+            lru: Arc::new(ShardedLru::new(capacity)),
             txmgr: txmgr.clone(),
         }
     }
 
+    // set cache size, in bytes
+    #[inline]
+    pub fn set_capacity(&self, capacity: usize) {
+        self.lru.set_capacity(capacity);
+    }
+
     pub fn get(&self, id: &Eid, vol: &VolumeRef) -> Result<CowRef<T>> {
-        let mut lru = self.lru.write().unwrap();
+        // only lock the shard the id belongs to, so that loading of the
+        // same entity is not duplicated
+        let mut lru = self.lru.shard(id);
 
         // get from cache first
         if let Some(val) = lru.get_refresh(id) {
@@ -476,22 +530,26 @@ where
         // if not in cache, load it from volume
         // then insert into cache
         let cow_ref = Cow::<T>::load(id, &self.txmgr, vol)?;
+        {
+            let mut cow = cow_ref.write().unwrap();
+            cow.cache = Arc::downgrade(&self.lru);
+        }
         lru.insert(id.clone(), cow_ref.clone());
         Ok(cow_ref)
     }
 
     pub fn insert(&self, cow: &CowRef<T>) {
-        let mut lru = self.lru.write().unwrap();
         let id = {
-            let cow = cow.read().unwrap();
+            let mut cow = cow.write().unwrap();
+            cow.cache = Arc::downgrade(&self.lru);
             cow.id.clone()
         };
-        lru.insert(id, cow.clone());
+        self.lru.insert(id, cow.clone());
     }
 
+    #[inline]
     pub fn remove(&self, id: &Eid) -> Option<CowRef<T>> {
-        let mut lru = self.lru.write().unwrap();
-        lru.remove(id)
+        self.lru.remove(id)
     }
 }
 
@@ -504,13 +562,18 @@ mod tests {
     use crate::fs::Config;
     use crate::trans::{Eid, TxMgr};
     use crate::util::init_env;
+    use crate::volume::storage::MemStorage;
     use crate::volume::Volume;
 
     fn setup_vol() -> VolumeRef {
         init_env();
-        let uri = "mem://foo".to_string();
+        let name = format!("foo_{}", Eid::new());
+        let uri = format!("mem://{}", name);
         let mut vol = Volume::new(&uri).unwrap();
         vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
+
+        // volume keeps using the store after it is removed from registry
+        MemStorage::destroy(&name);
         vol.into_ref()
     }
 
@@ -582,4 +645,49 @@ mod tests {
             let _ = t.join();
         }
     }
+
+    #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+    struct Blob {
+        data: Vec<u8>,
+    }
+
+    impl Cowable for Blob {
+        fn heap_size(&self) -> usize {
+            self.data.capacity()
+        }
+    }
+
+    impl<'de> IntoCow<'de> for Blob {}
+
+    #[test]
+    fn remeasure_on_release() {
+        let vol = setup_vol();
+        let txmgr = TxMgr::new(&Eid::new(), &vol).into_ref();
+        let cache = CowCache::<Blob>::new(1024 * 1024, &txmgr);
+
+        let tx = TxMgr::begin_trans(&txmgr).unwrap();
+        let mut cow_ref = None;
+        tx.run_all(|| {
+            let cow = Blob::default().into_cow(&txmgr)?;
+            cache.insert(&cow);
+            cow_ref = Some(cow);
+            Ok(())
+        })
+        .unwrap();
+        let cow_ref = cow_ref.unwrap();
+        let id = cow_ref.read().unwrap().id.clone();
+        let used = || cache.lru.shard(&id).used();
+        assert_eq!(used(), cow_ref.read().unwrap().mem_size());
+
+        // cow grown in transaction is measured again after commit
+        let tx = TxMgr::begin_trans(&txmgr).unwrap();
+        tx.run_all(|| {
+            let mut cow = cow_ref.write().unwrap();
+            cow.make_mut()?.data = vec![42u8; 1000];
+            Ok(())
+        })
+        .unwrap();
+        assert!(used() > 1000);
+        assert_eq!(used(), cow_ref.read().unwrap().mem_size());
+    }
 }
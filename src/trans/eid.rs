@@ -1,4 +1,4 @@
-use std::fmt::{self, Debug};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::ops::Index;
 use std::path::{Path, PathBuf};
 
@@ -14,6 +14,9 @@ impl Eid {
     /// Entity ID size
     pub(crate) const EID_SIZE: usize = 32;
 
+    /// Reserved entity ID, used by storages to save their own metadata
+    pub(crate) const RESERVED: Eid = Eid([0xff; Eid::EID_SIZE]);
+
     /// Create an empty entity ID
     #[inline]
     pub(crate) fn new_empty() -> Self {
@@ -47,7 +50,7 @@ impl Eid {
     #[allow(dead_code)]
     pub(crate) fn to_rel_path(&self) -> PathBuf {
         let base = Path::new("");
-        self.to_path_buf(&base)
+        self.to_path_buf(base)
     }
 }
 
@@ -73,10 +76,12 @@ impl Debug for Eid {
     }
 }
 
-impl ToString for Eid {
-    fn to_string(&self) -> String {
-        let strs: Vec<String> = self.0.iter().map(|b| format!("{:02x}", b)).collect();
-        strs.join("")
+impl Display for Eid {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
     }
 }
 
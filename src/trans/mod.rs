@@ -1,5 +1,6 @@
 pub mod cow;
 pub mod eid;
+#[allow(clippy::module_inception)]
 pub mod trans;
 mod txid;
 pub mod txmgr;
@@ -25,7 +25,7 @@ pub trait Transable: Debug + Id + Send + Sync {
     fn abort(&mut self);
 }
 
-pub type TransableRef = Arc<RwLock<Transable>>;
+pub type TransableRef = Arc<RwLock<dyn Transable>>;
 
 /// Transaction
 pub struct Trans {
@@ -66,9 +66,9 @@ impl Trans {
     ) -> Result<()> {
         // add a wal entry and save wal, if this failed remove entry from wal
         self.wal.add_entry(id, action, ent_type, arm);
-        self.wal_armor.save_item(&mut self.wal).or_else(|err| {
+        self.wal_armor.save_item(&mut self.wal).map_err(|err| {
             self.wal.remove_entry(id);
-            Err(err)
+            err
         })?;
 
         // add entity to cohorts
@@ -89,7 +89,7 @@ impl Trans {
 
             // make sure deleted entity is not in use
             if ent.action() == Action::Delete {
-                let using_cnt = Arc::strong_count(&entity);
+                let using_cnt = Arc::strong_count(entity);
                 if using_cnt > 1 {
                     error!("cannot delete entity in use (using: {})", using_cnt,);
                     return Err(Error::InUse);
@@ -97,14 +97,17 @@ impl Trans {
             }
 
             // commit entity
-            ent.commit(&vol)?;
+            fail_point_err!("trans::commit::before_entity");
+            ent.commit(vol)?;
         }
 
         // flush volume
+        fail_point_err!("trans::commit::before_flush");
         {
             let mut vol = vol.write().unwrap();
             vol.flush()?;
         }
+        fail_point_err!("trans::commit::after_flush");
 
         Ok(self.wal.clone())
     }
@@ -1,7 +1,6 @@
 use std::cell::RefCell;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::result::Result as StdResult;
-use std::u64;
 
 use bytes::BufMut;
 use serde::de::{self, Deserializer};
@@ -59,6 +58,7 @@ impl Txid {
     }
 
     /// Get next txid by increase one
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Txid {
         self.0 = self.0.checked_add(1).unwrap();
         TXID.with(|t| *t.borrow_mut() = self.0);
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::fmt::{self, Debug};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 
 use linked_hash_map::LinkedHashMap;
 
@@ -11,6 +11,24 @@ use crate::error::{Error, Result};
 use crate::util::IntoRef;
 use crate::volume::volume::VolumeRef;
 use crate::volume::Arm;
+
+// gate for exclusive transaction, it is closed while an exclusive
+// transaction is running and no other transactions can begin until it is
+// opened again
+#[derive(Debug, Default)]
+struct Gate {
+    closed: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Gate {
+    fn open(&self) {
+        let mut closed = self.closed.lock().unwrap();
+        *closed = false;
+        self.cond.notify_all();
+    }
+}
+
 /// Tranaction manager
 #[derive(Default)]
 pub struct TxMgr {
@@ -23,6 +41,9 @@ pub struct TxMgr {
     // wal queue manager
     walq_mgr: WalQueueMgr,
 
+    // exclusive transaction gate
+    gate: Arc<Gate>,
+
     vol: VolumeRef,
 }
 
@@ -32,6 +53,7 @@ impl TxMgr {
             txs: LinkedHashMap::new(),
             ents: HashMap::new(),
             walq_mgr: WalQueueMgr::new(walq_id, vol),
+            gate: Arc::new(Gate::default()),
             vol: vol.clone(),
         }
     }
@@ -44,13 +66,42 @@ impl TxMgr {
     }
 
     /// Begin a transaction
+    #[inline]
     pub fn begin_trans(txmgr: &TxMgrRef) -> Result<TxHandle> {
+        Self::begin(txmgr, false)
+    }
+
+    /// Begin an exclusive transaction
+    ///
+    /// An exclusive transaction runs alone, it fails with `Error::InUse` if
+    /// any other transactions are in progress, and other transactions wait
+    /// until it is completed.
+    #[inline]
+    pub fn begin_exclusive_trans(txmgr: &TxMgrRef) -> Result<TxHandle> {
+        Self::begin(txmgr, true)
+    }
+
+    fn begin(txmgr: &TxMgrRef, exclusive: bool) -> Result<TxHandle> {
         // check if current thread is already in transaction
         if Txid::is_in_trans() {
             return Err(Error::InTrans);
         }
 
+        // wait for running exclusive transaction to complete, the gate is
+        // held until this transaction is added to transaction manager
+        let gate = {
+            let tm = txmgr.read().unwrap();
+            tm.gate.clone()
+        };
+        let mut closed = gate.closed.lock().unwrap();
+        while *closed {
+            closed = gate.cond.wait(closed).unwrap();
+        }
+
         let mut tm = txmgr.write().unwrap();
+        if exclusive && !tm.txs.is_empty() {
+            return Err(Error::InUse);
+        }
         let vol = tm.vol.clone();
 
         // try to redo abort tx if any tx failed abortion before,
@@ -61,11 +112,11 @@ impl TxMgr {
         debug!("begin tx#{}", txid);
 
         // begin a transaction in wal queue
-        tm.walq_mgr.begin_trans(txid).or_else(|err| {
+        tm.walq_mgr.begin_trans(txid).map_err(|err| {
             // if failed, remove the thread tx mark
             Txid::reset_current();
             debug!("tx#{} aborted before start", txid);
-            Err(err)
+            err
         })?;
 
         // create a new transaction and add it to transaction manager
@@ -82,9 +133,12 @@ impl TxMgr {
             return Err(err);
         }
 
+        *closed = exclusive;
+
         Ok(TxHandle {
             txid,
             txmgr: txmgr.clone(),
+            gate: if exclusive { Some(gate.clone()) } else { None },
         })
     }
 
@@ -110,6 +164,15 @@ impl TxMgr {
         tx.add_entity(id, entity, action, ent_type, arm)
     }
 
+    /// Get entities deleted by committed transactions
+    ///
+    /// Deleted entities keep their storage space until the transaction is
+    /// retired and its wal is recycled.
+    #[inline]
+    pub fn retired_entities(&self) -> Result<Vec<(Eid, EntityType)>> {
+        self.walq_mgr.retired_entities()
+    }
+
     #[inline]
     fn remove_trans(&mut self, txid: Txid) {
         self.txs.remove(&txid);
@@ -126,7 +189,7 @@ impl TxMgr {
             // commit tx, if any errors then abort the tx
             match tx
                 .commit(&self.vol)
-                .and_then(|wal| self.walq_mgr.commit_trans(wal))
+                .and_then(|wal| self.walq_mgr.commit_trans(wal, &self.vol))
             {
                 Ok(_) => {
                     tx.complete_commit();
@@ -193,6 +256,9 @@ pub type TxMgrRef = Arc<RwLock<TxMgr>>;
 pub struct TxHandle {
     pub txid: Txid,
     pub txmgr: TxMgrRef,
+
+    // gate to open when exclusive transaction is completed
+    gate: Option<Arc<Gate>>,
 }
 
 impl TxHandle {
@@ -220,24 +286,43 @@ impl TxHandle {
 
     /// Commit a transaction
     pub fn commit(&self) -> Result<()> {
-        let mut tm = self.txmgr.write().unwrap();
-        tm.commit_trans(self.txid)
+        let result = {
+            let mut tm = self.txmgr.write().unwrap();
+            tm.commit_trans(self.txid)
+        };
+        self.open_gate();
+        result
     }
 
     /// Abort a transaction
     fn abort(&self, err: Error) -> Result<()> {
-        let mut tm = self.txmgr.write().unwrap();
-        tm.abort_trans(self.txid);
+        {
+            let mut tm = self.txmgr.write().unwrap();
+            tm.abort_trans(self.txid);
+        }
+        self.open_gate();
 
         // return the original error
         Err(err)
     }
+
+    // let other transactions begin after exclusive transaction is
+    // completed, transaction manager must not be locked here
+    #[inline]
+    fn open_gate(&self) {
+        if let Some(ref gate) = self.gate {
+            gate.open();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     extern crate tempdir;
 
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
     use self::tempdir::TempDir;
     use super::*;
 
@@ -245,13 +330,18 @@ mod tests {
     use crate::trans::cow::{CowRef, Cowable, IntoCow};
     use crate::trans::TxMgr;
     use crate::util::init_env;
+    use crate::volume::storage::MemStorage;
     use crate::volume::{ArmAccess, Volume};
 
     fn setup_mem_vol() -> VolumeRef {
         init_env();
-        let uri = "mem://foo".to_string();
+        let name = format!("foo_{}", Eid::new());
+        let uri = format!("mem://{}", name);
         let mut vol = Volume::new(&uri).unwrap();
         vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
+
+        // volume keeps using the store after it is removed from registry
+        MemStorage::destroy(&name);
         vol.into_ref()
     }
 
@@ -264,13 +354,13 @@ mod tests {
         (vol.into_ref(), tmpdir)
     }
 
-    #[cfg(feature = "storage-f2ufs")]
-    fn setup_f2ufs_vol() -> VolumeRef {
+    fn setup_f2ufs_vol() -> (VolumeRef, TempDir) {
         init_env();
-        let uri = "f2ufs://accessKey456@repo456?cache_type=mem&cache_size=1".to_string();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("log://{}", tmpdir.path().display());
         let mut vol = Volume::new(&uri).unwrap();
         vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
-        vol.into_ref()
+        (vol.into_ref(), tmpdir)
     }
 
     #[derive(Debug, Default, Clone, Deserialize, Serialize)]
@@ -447,15 +537,42 @@ mod tests {
         }
     }
 
-    #[cfg(feature = "storage-f2ufs")]
+    #[test]
+    fn exclusive_trans() {
+        let vol = setup_mem_vol();
+        let tm = TxMgr::new(&Eid::new(), &vol).into_ref();
+
+        // exclusive tx cannot begin when other tx is in progress
+        let tx = TxMgr::begin_trans(&tm).unwrap();
+        let tm2 = tm.clone();
+        let result = thread::spawn(move || TxMgr::begin_exclusive_trans(&tm2).map(|_| ()))
+            .join()
+            .unwrap();
+        assert_eq!(result.unwrap_err(), Error::InUse);
+        tx.run_all(|| Ok(())).unwrap();
+
+        // other tx waits until exclusive tx is completed
+        let completed = Arc::new(AtomicBool::new(false));
+        let tx = TxMgr::begin_exclusive_trans(&tm).unwrap();
+        let (tm2, completed2) = (tm.clone(), completed.clone());
+        let waiter = thread::spawn(move || {
+            let tx = TxMgr::begin_trans(&tm2).unwrap();
+            assert!(completed2.load(Ordering::SeqCst));
+            tx.run_all(|| Ok(())).unwrap();
+        });
+        completed.store(true, Ordering::SeqCst);
+        tx.run_all(|| Ok(())).unwrap();
+        waiter.join().unwrap();
+    }
+
     #[test]
     fn test_trans_f2ufs() {
         {
-            let vol = setup_f2ufs_vol();
+            let (vol, _tmpdir) = setup_f2ufs_vol();
             trans_oper(vol);
         }
         {
-            let vol = setup_f2ufs_vol();
+            let (vol, _tmpdir) = setup_f2ufs_vol();
             trans_abort(vol);
         }
     }
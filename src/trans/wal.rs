@@ -1,15 +1,22 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{self, Debug};
 use std::hash::{Hash, Hasher};
+use std::io::{ErrorKind, Read, Write};
+
+use bytes::BufMut;
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
 
 use super::trans::Action;
-use super::{Eid, Id, Txid};
+use super::{Eid, Finish, Id, Txid};
 use crate::error::{Error, Result};
-use crate::util::crypto::{HashKey, HASHKEY_SIZE};
+use crate::util::crypto::{Crypto, HashKey, HASHKEY_SIZE};
+use crate::volume::address::Span;
+use crate::volume::volume::{WalReader, WalWriter};
 use crate::volume::{AllocatorRef, Arm, ArmAccess, Armor, Seq, VolumeRef, VolumeWalArmor};
 
 /// Wal entry entity type
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum EntityType {
     Cow,
     Direct,
@@ -82,19 +89,32 @@ impl Wal {
     }
 
     // recylce a wal
-    fn recyle(&self, wal_armor: &VolumeWalArmor<Self>) -> Result<()> {
+    fn recyle(&self, vol: &VolumeRef) -> Result<()> {
         debug!("recycle tx#{}", self.txid);
         for ent in self.entries.values() {
             match ent.action {
                 Action::New | Action::Update => {} // do nothing
-                Action::Delete => {
-                    wal_armor.remove_all_arms(&ent.id)?;
-                }
+                Action::Delete => match ent.ent_type {
+                    EntityType::Cow => Arm::remove_all(&ent.id, vol)?,
+                    EntityType::Direct => {
+                        let mut vol = vol.write().unwrap();
+                        vol.del_with_type(&ent.id, EntityType::Direct)?;
+                    }
+                },
             }
         }
         Ok(())
     }
 
+    // entities deleted in this wal, they are removed when it is recycled
+    fn deleted_entities(&self) -> Vec<(Eid, EntityType)> {
+        self.entries
+            .values()
+            .filter(|ent| ent.action == Action::Delete)
+            .map(|ent| (ent.id.clone(), ent.ent_type))
+            .collect()
+    }
+
     // clean each aborted entry in wal
     pub fn clean_aborted(&self, vol: &VolumeRef) -> Result<()> {
         for ent in self.entries.values() {
@@ -103,14 +123,14 @@ impl Wal {
                     EntityType::Cow => Arm::remove_all(&ent.id, vol)?,
                     EntityType::Direct => {
                         let mut vol = vol.write().unwrap();
-                        vol.del(&ent.id)?;
+                        vol.del_with_type(&ent.id, EntityType::Direct)?;
                     }
                 },
                 Action::Update => match ent.ent_type {
                     EntityType::Cow => ent.arm.remove_arm(&ent.id, vol)?,
                     EntityType::Direct => {
                         let mut vol = vol.write().unwrap();
-                        vol.del(&ent.id)?;
+                        vol.del_with_type(&ent.id, EntityType::Direct)?;
                     }
                 },
                 Action::Delete => {} // do nothing
@@ -171,7 +191,10 @@ impl<'de> ArmAccess<'de> for Wal {
 /// Wal queue
 ///
 /// The whole wal queue should be able to fit into one block, so
-/// the persisted size should less than one block size.
+/// the persisted size should less than one block size. Free extents are
+/// saved in separate pages for that reason, see [`WalQueueMgr`].
+///
+/// [`WalQueueMgr`]: struct.WalQueueMgr.html
 #[derive(Default, Clone, Deserialize, Serialize)]
 struct WalQueue {
     id: Eid,
@@ -188,24 +211,25 @@ struct WalQueue {
     // in-progress tx id list
     doing: HashSet<Txid>,
 
+    // generation and number of the pages holding free block extents
+    free_gen: u64,
+    free_pages: usize,
+
+    // free block extents saved in the pages
     #[serde(skip_serializing, skip_deserializing, default)]
-    aborting: HashMap<Txid, Wal>,
+    free: Vec<Span>,
 
     #[serde(skip_serializing, skip_deserializing, default)]
-    wal_armor: VolumeWalArmor<Wal>,
+    aborting: HashMap<Txid, Wal>,
 
     #[serde(skip_serializing, skip_deserializing, default)]
-    allocator: AllocatorRef,
+    wal_armor: VolumeWalArmor<Wal>,
 }
 
 impl WalQueue {
     const COMMITTED_QUEUE_SIZE: usize = 2;
 
     pub fn new(id: &Eid, vol: &VolumeRef) -> Self {
-        let allocator = {
-            let vol = vol.read().unwrap();
-            vol.get_allocator()
-        };
         WalQueue {
             id: id.clone(),
             seq: 0,
@@ -214,9 +238,11 @@ impl WalQueue {
             blk_wmark: 0,
             done: VecDeque::new(),
             doing: HashSet::new(),
+            free_gen: 0,
+            free_pages: 0,
+            free: Vec::new(),
             aborting: HashMap::new(),
             wal_armor: VolumeWalArmor::new(vol),
-            allocator,
         }
     }
 
@@ -252,7 +278,7 @@ impl WalQueue {
         self.doing.insert(txid);
     }
 
-    fn commit_trans(&mut self, wal: Wal) -> Result<()> {
+    fn commit_trans(&mut self, wal: Wal, vol: &VolumeRef) -> Result<()> {
         // recycle the retired trans
         while self.done.len() >= Self::COMMITTED_QUEUE_SIZE {
             {
@@ -261,10 +287,12 @@ impl WalQueue {
                 let retiree_id = Wal::derive_id(*retiree_txid);
 
                 // load the retired wal
+                fail_point_err!("walq::commit_trans::before_recycle");
                 match self.wal_armor.load_item(&retiree_id) {
                     Ok(retiree) => {
                         // recycle and remove the wal
-                        retiree.recyle(&self.wal_armor)?;
+                        retiree.recyle(vol)?;
+                        fail_point_err!("walq::commit_trans::before_remove_wal");
                         self.wal_armor.remove_all_arms(&retiree_id)?;
                     }
                     Err(ref err) if *err == Error::NotFound => {
@@ -279,12 +307,26 @@ impl WalQueue {
         }
 
         // remove txid from doing list and enqueue it
+        fail_point_err!("walq::commit_trans::before_enqueue");
         self.doing.remove(&wal.txid);
         self.done.push_back(wal.txid);
 
         Ok(())
     }
 
+    // entities deleted by completed txs whose wals are not recycled yet
+    fn retired_entities(&self) -> Result<Vec<(Eid, EntityType)>> {
+        let mut ents = Vec::new();
+        for txid in self.done.iter() {
+            match self.wal_armor.load_item(&Wal::derive_id(*txid)) {
+                Ok(wal) => ents.append(&mut wal.deleted_entities()),
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(ents)
+    }
+
     #[inline]
     fn begin_abort(&mut self, wal: &Wal) {
         self.aborting.insert(wal.txid, wal.clone());
@@ -344,6 +386,8 @@ impl Debug for WalQueue {
             .field("arm", &self.arm)
             .field("done", &self.done)
             .field("doing", &self.doing)
+            .field("free_gen", &self.free_gen)
+            .field("free_pages", &self.free_pages)
             .field("aborting", &self.aborting)
             .finish()
     }
@@ -386,6 +430,11 @@ impl<'de> ArmAccess<'de> for WalQueue {
 }
 
 /// WalQueue Manager
+///
+/// Free block extents are saved in pages apart from the wal queue, each
+/// page fits in one block. When free extents are changed, they are saved to
+/// pages of a new generation before the wal queue referring to them is
+/// saved, and pages of the old generation are removed afterwards.
 #[derive(Default)]
 pub struct WalQueueMgr {
     // txid watermark
@@ -398,9 +447,20 @@ pub struct WalQueueMgr {
 
     // block allocator
     allocator: AllocatorRef,
+
+    vol: VolumeRef,
 }
 
 impl WalQueueMgr {
+    // hash key for free extents page id derivation
+    const FREE_PAGE_HASH_KEY: [u8; HASHKEY_SIZE] = [43u8; HASHKEY_SIZE];
+
+    // max persisted size of a free extent, an array marker and two integers
+    const FREE_SPAN_SIZE: usize = 19;
+
+    // room for page array marker and encryption overhead
+    const FREE_PAGE_RESERVED: usize = 64;
+
     pub fn new(walq_id: &Eid, vol: &VolumeRef) -> Self {
         let allocator = {
             let vol = vol.read().unwrap();
@@ -412,13 +472,16 @@ impl WalQueueMgr {
             walq_backup: None,
             walq_armor: VolumeWalArmor::new(vol),
             allocator,
+            vol: vol.clone(),
         }
     }
 
     pub fn open(&mut self, walq_id: &Eid, vol: &VolumeRef) -> Result<()> {
-        // load wal queue
+        // load wal queue and free extents
         self.walq = self.walq_armor.load_item(walq_id)?;
         self.walq.open(vol);
+        self.load_free_pages()?;
+        self.clean_free_pages()?;
 
         // restore watermarks
         let (txid_wmark, blk_wmark) = self.walq.watermarks();
@@ -426,16 +489,24 @@ impl WalQueueMgr {
         {
             let mut allocator = self.allocator.write().unwrap();
             allocator.set_block_wmark(blk_wmark);
+            allocator.set_free_extents(&self.walq.free);
         }
 
         // now redo abort tx if any
         if self.walq.has_doing() {
             self.backup_walq();
-            self.walq.cold_redo_abort(vol).or_else(|err| {
-                // if failed, restore the walq backup
-                self.restore_walq();
-                Err(err)
-            })?;
+            self.walq
+                .cold_redo_abort(vol)
+                .and_then(|_| {
+                    // make sure cleaning is persistent before saving walq
+                    let mut vol = vol.write().unwrap();
+                    vol.flush()
+                })
+                .map_err(|err| {
+                    // if failed, restore the walq backup
+                    self.restore_walq();
+                    err
+                })?;
             self.save_walq()?;
             debug!("cold abort completed");
         }
@@ -465,20 +536,154 @@ impl WalQueueMgr {
         self.walq = self.walq_backup.take().unwrap();
     }
 
+    // derive free extents page id from its generation and index
+    fn free_page_id(gen: u64, idx: usize) -> Eid {
+        let mut buf = Vec::new();
+        buf.put_u64_le(gen);
+        buf.put_u64_le(idx as u64);
+        let mut hash_key = HashKey::new_empty();
+        hash_key.copy(&Self::FREE_PAGE_HASH_KEY[..]);
+        let hash = Crypto::hash_with_key(&buf, &hash_key);
+        Eid::from_slice(&hash)
+    }
+
+    // number of free extents in a page, so that the page fits in one block
+    #[inline]
+    fn free_page_cap(blk_size: usize) -> usize {
+        (blk_size - Self::FREE_PAGE_RESERVED) / Self::FREE_SPAN_SIZE
+    }
+
+    fn load_free_page(&self, gen: u64, idx: usize) -> Result<Vec<Span>> {
+        let id = Self::free_page_id(gen, idx);
+        let mut rdr = WalReader::new(&id, &self.vol);
+        let mut buf = Vec::new();
+        rdr.read_to_end(&mut buf).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                Error::NotFound
+            } else {
+                Error::from(err)
+            }
+        })?;
+        let mut de = Deserializer::new(&buf[..]);
+        let free: Vec<Span> = Deserialize::deserialize(&mut de)?;
+        Ok(free)
+    }
+
+    fn save_free_page(&self, gen: u64, idx: usize, free: &[Span]) -> Result<()> {
+        let id = Self::free_page_id(gen, idx);
+        let mut buf = Vec::new();
+        free.serialize(&mut Serializer::new(&mut buf))?;
+        let mut wtr = WalWriter::new(&id, &self.vol);
+        wtr.write_all(&buf[..])?;
+        wtr.finish()
+    }
+
+    fn del_free_pages(&self, gen: u64, cnt: usize) -> Result<()> {
+        let mut vol = self.vol.write().unwrap();
+        for idx in 0..cnt {
+            vol.del_wal(&Self::free_page_id(gen, idx))?;
+        }
+        Ok(())
+    }
+
+    fn load_free_pages(&mut self) -> Result<()> {
+        let mut free = Vec::new();
+        for idx in 0..self.walq.free_pages {
+            free.append(&mut self.load_free_page(self.walq.free_gen, idx)?);
+        }
+        self.walq.free = free;
+        Ok(())
+    }
+
+    // save free extents to pages of the next generation
+    fn save_free_pages(&mut self, free: Vec<Span>) -> Result<()> {
+        let gen = self.walq.free_gen + 1;
+        let blk_size = {
+            let vol = self.vol.read().unwrap();
+            vol.info().layout.blk_size
+        };
+        let pages: Vec<&[Span]> = free.chunks(Self::free_page_cap(blk_size)).collect();
+        for (idx, page) in pages.iter().enumerate() {
+            if let Err(err) = self.save_free_page(gen, idx, page) {
+                let _ = self.del_free_pages(gen, idx + 1);
+                return Err(err);
+            }
+        }
+        self.walq.free_gen = gen;
+        self.walq.free_pages = pages.len();
+        self.walq.free = free;
+        Ok(())
+    }
+
+    // Remove pages left over by a crash, they can only be pages of the next
+    // generation written before the wal queue was saved, or pages of the
+    // previous generation not removed after it was saved. Pages are written
+    // in index order, so the first page cannot be loaded is the last one.
+    fn clean_free_pages(&self) -> Result<()> {
+        let gen = self.walq.free_gen;
+        let mut gens = vec![gen + 1];
+        if gen > 0 {
+            gens.push(gen - 1);
+        }
+        for gen in gens {
+            let mut idx = 0;
+            loop {
+                let loaded = self.load_free_page(gen, idx);
+                if let Err(ref err) = loaded {
+                    if *err == Error::NotFound {
+                        break;
+                    }
+                }
+                let mut vol = self.vol.write().unwrap();
+                vol.del_wal(&Self::free_page_id(gen, idx))?;
+                if loaded.is_err() {
+                    break;
+                }
+                idx += 1;
+            }
+        }
+        Ok(())
+    }
+
     fn save_walq(&mut self) -> Result<()> {
-        // get current block watermark and set it to wal queue
-        let blk_wmark = {
+        // get current block watermark and free extents and set them to
+        // wal queue
+        let (blk_wmark, free) = {
             let allocator = self.allocator.read().unwrap();
-            allocator.block_wmark()
+            (allocator.block_wmark(), allocator.free_extents())
         };
         self.walq.set_watermarks(self.txid_wmark.val(), blk_wmark);
 
+        // save changed free extents to new pages
+        let old_pages = if free != self.walq.free {
+            let old_pages = (self.walq.free_gen, self.walq.free_pages);
+            self.save_free_pages(free).map_err(|err| {
+                self.restore_walq();
+                err
+            })?;
+            Some(old_pages)
+        } else {
+            None
+        };
+
         // save wal queue
-        self.walq_armor.save_item(&mut self.walq).or_else(|err| {
-            // if save failed, restore the walq backup
+        if let Err(err) = self.walq_armor.save_item(&mut self.walq) {
+            // if save failed, remove the new pages and restore the walq
+            // backup
+            if old_pages.is_some() {
+                let _ = self.del_free_pages(self.walq.free_gen, self.walq.free_pages);
+            }
             self.restore_walq();
-            Err(err)
-        })?;
+            return Err(err);
+        }
+
+        // old pages are not referred any more, those failed to be removed
+        // will be removed when wal queue is opened next time
+        if let Some((gen, cnt)) = old_pages {
+            if let Err(err) = self.del_free_pages(gen, cnt) {
+                warn!("remove free extents pages failed: {}", err);
+            }
+        }
 
         Ok(())
     }
@@ -489,16 +694,21 @@ impl WalQueueMgr {
         self.save_walq()
     }
 
-    pub fn commit_trans(&mut self, wal: Wal) -> Result<()> {
+    pub fn commit_trans(&mut self, wal: Wal, vol: &VolumeRef) -> Result<()> {
         self.backup_walq();
-        self.walq.commit_trans(wal).or_else(|err| {
+        self.walq.commit_trans(wal, vol).map_err(|err| {
             // if commit failed, restore the walq backup
             self.restore_walq();
-            Err(err)
+            err
         })?;
         self.save_walq()
     }
 
+    #[inline]
+    pub fn retired_entities(&self) -> Result<Vec<(Eid, EntityType)>> {
+        self.walq.retired_entities()
+    }
+
     #[inline]
     pub fn begin_abort(&mut self, wal: &Wal) {
         self.walq.begin_abort(wal)
@@ -516,14 +726,13 @@ impl WalQueueMgr {
         }
 
         self.backup_walq();
-        self.walq.hot_redo_abort(vol).or_else(|err| {
+        self.walq.hot_redo_abort(vol).map_err(|err| {
             // if failed, restore the walq backup
             self.restore_walq();
-            Err(err)
+            err
         })?;
-        self.save_walq().and_then(|_| {
+        self.save_walq().map(|_| {
             debug!("hot abort completed");
-            Ok(())
         })
     }
 }
@@ -536,3 +745,48 @@ impl Debug for WalQueueMgr {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rmp_serde::Serializer;
+    use serde::Serialize;
+
+    use super::*;
+    use crate::util::crypto::Crypto;
+    use crate::volume::Layout;
+
+    #[test]
+    fn walq_size() {
+        let mut walq = WalQueue::default();
+        walq.set_watermarks(u64::MAX, usize::MAX);
+        for i in 0..WalQueue::COMMITTED_QUEUE_SIZE {
+            walq.done.push_back(Txid::from(u64::MAX - i as u64));
+        }
+        walq.free_gen = u64::MAX;
+        walq.free_pages = usize::MAX;
+
+        // free extents are not saved with wal queue
+        walq.free = vec![Span::new(0, 1); 10_000];
+
+        // persisted wal queue should fit in the smallest block
+        let mut buf = Vec::new();
+        walq.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        let len = Crypto::default().encrypted_len(buf.len());
+        assert!(len < Layout::MIN_BLK_SIZE, "walq size {}", len);
+    }
+
+    #[test]
+    fn free_page_size() {
+        // a full page of the largest extents should fit in one block
+        let mut blk_size = Layout::MIN_BLK_SIZE;
+        while blk_size <= Layout::MAX_BLK_SIZE {
+            let cap = WalQueueMgr::free_page_cap(blk_size);
+            let free = vec![Span::new(usize::MAX, usize::MAX); cap];
+            let mut buf = Vec::new();
+            free.serialize(&mut Serializer::new(&mut buf)).unwrap();
+            let len = Crypto::default().encrypted_len(buf.len());
+            assert!(len <= blk_size, "page size {} > {}", len, blk_size);
+            blk_size *= 2;
+        }
+    }
+}
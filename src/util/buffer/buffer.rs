@@ -50,7 +50,7 @@ impl<T> Buffer<T> {
             for i in 0..cap {
                 // Index `i + 1` for the `i`-th entry is invalid; only the indexes of the form `i +
                 // N * cap` is valid.
-                (*ptr.offset(i as isize)).index = AtomicUsize::new(i + 1);
+                (*ptr.add(i)).index = AtomicUsize::new(i + 1);
             }
         }
 
@@ -72,14 +72,22 @@ impl<T> Buffer<T> {
     }
 
     /// Returns a pointer to the slot at the specified `index`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not have been dropped while the pointer is in use.
     pub unsafe fn at(&self, index: usize) -> *mut Slot<T> {
         // `array.size()` is always a power of two.
-        self.ptr.offset((index & (self.cap - 1)) as isize)
+        self.ptr.add(index & (self.cap - 1))
     }
 
     /// Reads a value from the specified `index`.
     ///
     /// Returns `Some(v)` if `v` is at `index`; or `None` if there's no valid value for `index`.
+    ///
+    /// # Safety
+    ///
+    /// The caller takes ownership of the returned value and must not read it twice.
     pub unsafe fn read(&self, index: usize) -> Option<mem::ManuallyDrop<T>> {
         let slot = self.at(index);
 
@@ -98,6 +106,10 @@ impl<T> Buffer<T> {
     /// Reads a value from the specified `index` without checking the index.
     ///
     /// Returns the value at `index` regardless or whether it's valid or not.
+    ///
+    /// # Safety
+    ///
+    /// The slot at `index` must hold an initialized value.
     pub unsafe fn read_unchecked(&self, index: usize) -> mem::ManuallyDrop<T> {
         let slot = self.at(index);
 
@@ -106,6 +118,10 @@ impl<T> Buffer<T> {
     }
 
     /// Writes `value` into the specified `index`.
+    ///
+    /// # Safety
+    ///
+    /// No other thread may access the slot at `index` concurrently.
     pub unsafe fn write(&self, index: usize, value: T) {
         let slot = self.at(index);
 
@@ -117,18 +133,30 @@ impl<T> Buffer<T> {
     }
 
     /// Reads the index from the specified slot.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not have been dropped.
     pub unsafe fn read_index(&self, offset: usize, ord: Ordering) -> usize {
         let slot = self.at(offset);
         (*slot).index.load(ord)
     }
 
     /// Writes the specified `index` in the slot.
+    ///
+    /// # Safety
+    ///
+    /// No other thread may access the slot at `index` concurrently.
     pub unsafe fn write_index(&self, index: usize, ord: Ordering) {
         let slot = self.at(index);
         (*slot).index.store(index, ord);
     }
 
     /// Reads the value from the specified slot.
+    ///
+    /// # Safety
+    ///
+    /// The slot at `offset` must hold an initialized value.
     pub unsafe fn read_value(&self, offset: usize) -> mem::ManuallyDrop<T> {
         let slot = self.at(offset);
         (*slot).data.get().read_volatile()
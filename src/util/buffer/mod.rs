@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod buffer;
 pub mod mp;
 pub mod sp;
@@ -729,10 +729,7 @@ impl<T> Receiver<T> {
         let value = {
             let guard = &epoch::pin();
             let buffer = self.inner.buffer.load_consume(guard);
-            match buffer.deref().read(rx) {
-                None => return None,
-                Some(value) => value,
-            }
+            buffer.deref().read(rx)?
         };
 
         // Increments rx to receive the value.
@@ -48,9 +48,7 @@ pub mod bounded {
         /// let c = Queue::<u32>::new(16);
         /// ```
         pub fn new(cap: usize) -> Self {
-            Queue {
-                0: internal::CircBuf::new(cap),
-            }
+            Queue(internal::CircBuf::new(cap))
         }
 
         /// Attempts to send a value to the queue.
@@ -108,9 +106,7 @@ pub mod bounded {
         /// let r = c.receiver();
         /// ```
         pub fn receiver(&self) -> Receiver<T> {
-            Receiver {
-                0: self.0.receiver(),
-            }
+            Receiver(self.0.receiver())
         }
     }
 
@@ -173,7 +169,7 @@ pub mod bounded {
     impl<T> Clone for Receiver<T> {
         /// Creates another receiver.
         fn clone(&self) -> Self {
-            Self { 0: self.0.clone() }
+            Self(self.0.clone())
         }
     }
 }
@@ -224,9 +220,7 @@ pub mod unbounded {
         /// let c = Queue::<u32>::new();
         /// ```
         pub fn new() -> Self {
-            Queue {
-                0: internal::DynamicCircBuf::new(),
-            }
+            Queue(internal::DynamicCircBuf::new())
         }
 
         /// Creates an unbounded SPMC queue with the specified minimal capacity.
@@ -242,9 +236,7 @@ pub mod unbounded {
         /// let c = Queue::<u32>::with_min_capacity(1000);
         /// ```
         pub fn with_min_capacity(min_cap: usize) -> Self {
-            Queue {
-                0: internal::DynamicCircBuf::with_min_capacity(min_cap),
-            }
+            Queue(internal::DynamicCircBuf::with_min_capacity(min_cap))
         }
 
         /// Attempts to send a value to the queue.
@@ -299,9 +291,7 @@ pub mod unbounded {
         /// let r = c.receiver();
         /// ```
         pub fn receiver(&self) -> Receiver<T> {
-            Receiver {
-                0: self.0.receiver(),
-            }
+            Receiver(self.0.receiver())
         }
     }
 
@@ -370,7 +360,7 @@ pub mod unbounded {
     impl<T> Clone for Receiver<T> {
         /// Creates another receiver.
         fn clone(&self) -> Self {
-            Self { 0: self.0.clone() }
+            Self(self.0.clone())
         }
     }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use std::cmp::min;
-use std::fmt::{self, Debug, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::Deref;
@@ -163,7 +163,9 @@ impl<T: Sized> SafeBox<T> {
         unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
     }
 
+    // size of the boxed value, it is never empty
     #[inline]
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         mem::size_of::<T>()
     }
@@ -181,11 +183,12 @@ impl<T: Sized> SafeBox<T> {
         }
     }
 
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     pub fn copy_raw_at(&mut self, buf: *const u8, buf_len: usize, pos: usize) {
         assert!(pos < self.len());
         let len = min(self.len() - pos, buf_len);
         unsafe {
-            ptr::copy(buf, self.as_mut_ptr().offset(pos as isize), len);
+            ptr::copy(buf, self.as_mut_ptr().add(pos), len);
         }
     }
 }
@@ -201,7 +204,7 @@ impl<T: Sized> fmt::Debug for SafeBox<T> {
         write!(f, "SafeBox(")?;
         unsafe {
             for i in 0..min(4, self.len()) {
-                write!(f, "{:x}", *self.as_ptr().offset(i as isize))?;
+                write!(f, "{:x}", *self.as_ptr().add(i))?;
             }
         }
         if self.len() > 4 {
@@ -310,7 +313,7 @@ impl RandomSeed {
 
     #[inline]
     pub fn as_ptr(&self) -> *const u8 {
-        (&self.0).as_ptr()
+        self.0.as_ptr()
     }
 }
 
@@ -356,13 +359,12 @@ impl Debug for Hash {
     }
 }
 
-impl ToString for Hash {
-    fn to_string(&self) -> String {
-        self.0
-            .iter()
-            .map(|b| format!("{:x}", b))
-            .collect::<Vec<String>>()
-            .join("")
+impl Display for Hash {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:x}", b)?;
+        }
+        Ok(())
     }
 }
 
@@ -373,7 +375,7 @@ pub type HashKey = SafeBox<[u8; HASHKEY_SIZE]>;
 impl HashKey {
     #[inline]
     pub fn derive(&self, subkey_id: u64) -> Self {
-        Crypto::derive_from_key(&self, subkey_id).unwrap()
+        Crypto::derive_from_key(self, subkey_id).unwrap()
     }
 }
 
@@ -392,6 +394,7 @@ impl Salt {
     }
 
     #[inline]
+    #[allow(clippy::should_implement_trait)]
     pub fn as_ref(&self) -> &[u8] {
         &self.0
     }
@@ -421,7 +424,7 @@ impl HashState {
 
     #[inline]
     fn as_mut_ptr(&mut self) -> *mut u8 {
-        (&mut self.state).as_mut_ptr()
+        self.state.as_mut_ptr()
     }
 }
 
@@ -445,9 +448,9 @@ impl Default for OpsLimit {
     }
 }
 
-impl Into<i32> for OpsLimit {
-    fn into(self) -> i32 {
-        match self {
+impl From<OpsLimit> for i32 {
+    fn from(val: OpsLimit) -> Self {
+        match val {
             OpsLimit::Interactive => 0,
             OpsLimit::Moderate => 1,
             OpsLimit::Sensitive => 2,
@@ -491,9 +494,9 @@ impl Default for MemLimit {
     }
 }
 
-impl Into<i32> for MemLimit {
-    fn into(self) -> i32 {
-        match self {
+impl From<MemLimit> for i32 {
+    fn from(val: MemLimit) -> Self {
+        match val {
             MemLimit::Interactive => 0,
             MemLimit::Moderate => 1,
             MemLimit::Sensitive => 2,
@@ -614,18 +617,18 @@ impl Cipher {
     }
 }
 
-impl Into<u8> for Cipher {
-    fn into(self) -> u8 {
-        match self {
+impl From<Cipher> for u8 {
+    fn from(val: Cipher) -> Self {
+        match val {
             Cipher::Xchacha => 0,
             Cipher::Aes => 1,
         }
     }
 }
 
-impl Into<i32> for Cipher {
-    fn into(self) -> i32 {
-        match self {
+impl From<Cipher> for i32 {
+    fn from(val: Cipher) -> Self {
+        match val {
             Cipher::Xchacha => 0,
             Cipher::Aes => 1,
         }
@@ -766,6 +769,7 @@ impl Crypto {
     // Generic Hash
     // -------------
     /// Generic purpose hashing on raw pointer
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     pub fn hash_raw(inbuf: *const u8, len: usize, key: *const u8, keylen: usize) -> Hash {
         let mut ret = Hash::new_empty();
         unsafe {
@@ -948,11 +952,11 @@ impl Crypto {
         let result = match self.cipher {
             Cipher::Xchacha => unsafe {
                 (self.enc_fn)(
-                    p_ctxt.offset(nonce_size as isize),
+                    p_ctxt.add(nonce_size),
                     &mut clen as *mut u64,
-                    msg.as_ptr() as *const u8,
+                    msg.as_ptr(),
                     msg.len() as u64,
-                    ad.as_ptr() as *const u8,
+                    ad.as_ptr(),
                     ad.len() as u64,
                     ptr::null(),
                     nonce.as_ptr(),
@@ -963,11 +967,11 @@ impl Crypto {
                 let (subnonce, subkey) = self.extend_nonce(nonce.as_ptr(), key);
                 unsafe {
                     (self.enc_fn)(
-                        p_ctxt.offset(nonce_size as isize),
+                        p_ctxt.add(nonce_size),
                         &mut clen as *mut u64,
-                        msg.as_ptr() as *const u8,
+                        msg.as_ptr(),
                         msg.len() as u64,
-                        ad.as_ptr() as *const u8,
+                        ad.as_ptr(),
                         ad.len() as u64,
                         ptr::null(),
                         subnonce,
@@ -1020,9 +1024,9 @@ impl Crypto {
                     msg.as_mut_ptr(),
                     &mut msglen as *mut u64,
                     ptr::null(),
-                    ctxt.as_ptr().offset(nonce_size as isize),
+                    ctxt.as_ptr().add(nonce_size),
                     (ctxt.len() - nonce_size) as u64,
-                    ad.as_ptr() as *const u8,
+                    ad.as_ptr(),
                     ad.len() as u64,
                     nonce.as_ptr(),
                     key.as_ptr(),
@@ -1035,9 +1039,9 @@ impl Crypto {
                         msg.as_mut_ptr(),
                         &mut msglen as *mut u64,
                         ptr::null(),
-                        ctxt.as_ptr().offset(nonce_size as isize),
+                        ctxt.as_ptr().add(nonce_size),
                         (ctxt.len() - nonce_size) as u64,
-                        ad.as_ptr() as *const u8,
+                        ad.as_ptr(),
                         ad.len() as u64,
                         subnonce,
                         subkey.as_ptr(),
@@ -1099,7 +1103,7 @@ mod tests {
 
         // encryption
         let out = crypto.encrypt_with_ad(&msg, &key, &ad).unwrap();
-        assert!(out.len() > 0);
+        assert!(!out.is_empty());
 
         // decryption
         let ret = crypto.decrypt_with_ad(&out, &key, &ad);
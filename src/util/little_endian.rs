@@ -166,8 +166,8 @@ mod tests {
         let mut buf = [0; 32];
         write(&mut buf, n);
 
-        for i in 0..len {
-            assert_eq!(T::from(buf[i]), (n >> T::from(i as u8 * 8)) & T::from(0xFF));
+        for (i, b) in buf.iter().take(len).enumerate() {
+            assert_eq!(T::from(*b), (n >> T::from(i as u8 * 8)) & T::from(0xFF));
         }
 
         assert_eq!(read::<T>(&buf), n);
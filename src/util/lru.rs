@@ -1,13 +1,151 @@
 use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{self, Debug};
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use crossbeam::utils::CachePadded;
 use linked_hash_map::LinkedHashMap;
 use log::warn;
 
+/// Memory budget shared by a group of caches
+///
+/// Caches measured in bytes are charged to a memory budget. When the budget
+/// is exceeded, a cache removes its least recently used items only if it
+/// uses more than its share of the budget, which is proportional to its
+/// capacity, so a busy small cache is not drained because of a big one.
+/// Cloned budgets are the same group. By default, caches are charged to the
+/// process-wide budget shared by all repos opened in this process.
+#[derive(Debug, Clone)]
+pub struct MemBudget {
+    inner: Arc<BudgetInner>,
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    budget: AtomicUsize,
+    used: AtomicUsize,
+
+    // total capacity of all caches in this group, in bytes
+    capacity: AtomicUsize,
+}
+
+lazy_static! {
+    // process-wide memory budget
+    static ref GLOBAL_BUDGET: MemBudget = MemBudget::new(usize::MAX);
+}
+
+impl MemBudget {
+    /// Create a new memory budget group, in bytes
+    pub fn new(budget: usize) -> Self {
+        MemBudget {
+            inner: Arc::new(BudgetInner {
+                budget: AtomicUsize::new(budget),
+                used: AtomicUsize::new(0),
+                capacity: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Get the process-wide memory budget
+    #[inline]
+    pub fn global() -> Self {
+        GLOBAL_BUDGET.clone()
+    }
+
+    #[inline]
+    pub fn budget(&self) -> usize {
+        self.inner.budget.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_budget(&self, budget: usize) {
+        self.inner.budget.store(budget, Ordering::Relaxed);
+    }
+
+    /// Get memory used by all caches in this group, in bytes
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn is_over(&self) -> bool {
+        self.used() > self.budget()
+    }
+
+    // share of the budget for a cache with the capacity
+    fn share(&self, capacity: usize) -> usize {
+        let total = self.inner.capacity.load(Ordering::Relaxed).max(capacity);
+        if total == 0 {
+            return 0;
+        }
+        (self.budget() as u128 * capacity as u128 / total as u128) as usize
+    }
+
+    #[inline]
+    fn add_capacity(&self, capacity: usize) {
+        self.inner.capacity.fetch_add(capacity, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn sub_capacity(&self, capacity: usize) {
+        self.inner.capacity.fetch_sub(capacity, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn charge(&self, size: usize) {
+        self.inner.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn refund(&self, size: usize) {
+        self.inner.used.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+impl Default for MemBudget {
+    #[inline]
+    fn default() -> Self {
+        Self::global()
+    }
+}
+
+/// Set memory budget shared by all caches in this process, in bytes
+#[inline]
+pub fn set_mem_budget(budget: usize) {
+    GLOBAL_BUDGET.set_budget(budget);
+}
+
+/// Get memory budget shared by all caches in this process, in bytes
+#[inline]
+pub fn mem_budget() -> usize {
+    GLOBAL_BUDGET.budget()
+}
+
+/// Get memory used by all caches in this process, in bytes
+#[inline]
+pub fn mem_used() -> usize {
+    GLOBAL_BUDGET.used()
+}
+
 pub trait Meter<T> {
     fn measure(&self, item: &T) -> isize;
+
+    // if measure is in bytes, cache using this meter is charged to the
+    // memory budget
+    #[inline]
+    fn is_bytes(&self) -> bool {
+        true
+    }
+}
+
+/// Approximate memory size of an object, in bytes
+pub trait MemSize {
+    fn mem_size(&self) -> usize;
 }
 
 pub trait Pinnable<T> {
@@ -18,7 +156,14 @@ pub trait Pinnable<T> {
 }
 
 /// LRU
-#[derive(Clone, Default)]
+///
+/// Item size is measured when it is inserted, it can be measured again by
+/// `update_size` if it has changed. If the meter measures in bytes, the
+/// cache is also charged to a [`MemBudget`] and its least recently used
+/// items are removed when the budget is exceeded.
+///
+/// [`MemBudget`]: struct.MemBudget.html
+#[derive(Default)]
 pub struct Lru<K, V, M, P>
 where
     K: Eq + Hash,
@@ -27,9 +172,10 @@ where
 {
     capacity: usize,
     used: usize,
-    map: LinkedHashMap<K, V>,
+    map: LinkedHashMap<K, (V, usize)>,
     meter: M,
     pin_ckr: P,
+    budget: MemBudget,
 }
 
 impl<K, V, M, P> Lru<K, V, M, P>
@@ -39,60 +185,165 @@ where
     P: Pinnable<V> + Default + Clone,
 {
     pub fn new(capacity: usize) -> Self {
-        Lru {
-            capacity: capacity,
+        Self::with_budget(capacity, MemBudget::global())
+    }
+
+    /// Create LRU charged to the specified memory budget
+    pub fn with_budget(capacity: usize, budget: MemBudget) -> Self {
+        let lru = Lru {
+            capacity,
             used: 0,
             map: LinkedHashMap::new(),
             meter: M::default(),
             pin_ckr: P::default(),
+            budget,
+        };
+        if lru.meter.is_bytes() {
+            lru.budget.add_capacity(capacity);
+        }
+        lru
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // change capacity and remove items if it is exceeded
+    pub fn set_capacity(&mut self, capacity: usize) {
+        if self.meter.is_bytes() {
+            self.budget.sub_capacity(self.capacity);
+            self.budget.add_capacity(capacity);
+        }
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // add size to used and memory budget
+    fn charge(&mut self, size: usize) {
+        self.used += size;
+        if self.meter.is_bytes() {
+            self.budget.charge(size);
+        }
+    }
+
+    // remove size from used and memory budget
+    fn refund(&mut self, size: usize) {
+        self.used -= size;
+        if self.meter.is_bytes() {
+            self.budget.refund(size);
+        }
+    }
+
+    // whether this cache should give up memory as the memory budget is
+    // exceeded and it uses more than its share
+    #[inline]
+    fn is_over_budget(&self) -> bool {
+        self.meter.is_bytes()
+            && self.budget.is_over()
+            && self.used > self.budget.share(self.capacity)
+    }
+
+    // remove least recently used items until both capacity and memory
+    // budget are satisfied, the most recently used item is always kept
+    fn evict(&mut self) {
+        while self.map.len() > 1 && (self.used > self.capacity || self.is_over_budget()) {
+            if self.remove_lru().is_none() {
+                break;
+            }
         }
     }
 
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
         debug_assert!(self.capacity > 0);
 
-        let mut delta: isize = self.meter.measure(&v);
+        let size = self.meter.measure(&v).max(0) as usize;
         let mut ret: Option<V> = None;
 
-        if let Some(old_val) = self.map.insert(k, v) {
-            delta -= self.meter.measure(&old_val);
+        if let Some((old_val, old_size)) = self.map.insert(k, (v, size)) {
+            self.refund(old_size);
             ret = Some(old_val);
         }
 
-        self.used = (self.used as isize + delta) as usize;
-        if self.used > self.capacity {
-            self.remove_lru();
-        }
+        self.charge(size);
+        self.evict();
 
         ret
     }
 
     #[inline]
-    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
     where
         K: Borrow<Q>,
-        Q: Eq + Hash,
+        Q: ?Sized + Eq + Hash,
     {
         self.map.contains_key(k)
     }
 
     #[inline]
-    pub fn get_refresh<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+    pub fn get_refresh<Q>(&mut self, k: &Q) -> Option<&mut V>
     where
         K: Borrow<Q>,
-        Q: Eq + Hash,
+        Q: ?Sized + Eq + Hash,
     {
-        self.map.get_refresh(k)
+        self.map.get_refresh(k).map(|ent| &mut ent.0)
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+    // set size of an item whose size has changed since it was measured,
+    // used when the item cannot be measured by the meter at that time
+    pub fn update_size<Q>(&mut self, k: &Q, size: usize)
     where
         K: Borrow<Q>,
-        Q: Eq + Hash,
+        Q: ?Sized + Eq + Hash,
     {
-        self.map.remove(k).and_then(|v| {
-            self.used = (self.used as isize - self.meter.measure(&v)) as usize;
-            Some(v)
+        let old_size = match self.map.get_mut(k) {
+            Some(ent) => mem::replace(&mut ent.1, size),
+            None => return,
+        };
+        self.refund(old_size);
+        self.charge(size);
+        self.evict();
+    }
+
+    // iterate items from least to most recently used
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map.iter().map(|(k, ent)| (k, &ent.0))
+    }
+
+    // remove all items and return them from least to most recently used
+    fn drain(&mut self) -> Vec<(K, V)> {
+        let used = self.used;
+        self.refund(used);
+        mem::replace(&mut self.map, LinkedHashMap::new())
+            .into_iter()
+            .map(|(k, (v, _))| (k, v))
+            .collect()
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.map.remove(k).map(|(v, size)| {
+            self.refund(size);
+            v
         })
     }
 
@@ -103,7 +354,7 @@ where
             .map
             .entries()
             .enumerate()
-            .find(|&(_, ref ent)| !pin_ckr.is_pinned(ent.get()))
+            .find(|(_, ent)| !pin_ckr.is_pinned(&ent.get().0))
             .and_then(|(idx, ent)| {
                 if idx < capacity {
                     Some(ent.remove())
@@ -112,10 +363,42 @@ where
                     None
                 }
             });
-        if let Some(ref v) = ret {
-            self.used = (self.used as isize - self.meter.measure(v)) as usize;
+        ret.map(|(v, size)| {
+            self.refund(size);
+            v
+        })
+    }
+}
+
+impl<K, V, M, P> Clone for Lru<K, V, M, P>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+    M: Meter<V> + Default + Clone,
+    P: Pinnable<V> + Default + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut lru = Lru::with_budget(self.capacity, self.budget.clone());
+        lru.map = self.map.clone();
+        lru.meter = self.meter.clone();
+        lru.pin_ckr = self.pin_ckr.clone();
+        lru.charge(self.used);
+        lru
+    }
+}
+
+impl<K, V, M, P> Drop for Lru<K, V, M, P>
+where
+    K: Eq + Hash,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default + Clone,
+{
+    fn drop(&mut self) {
+        let used = self.used;
+        self.refund(used);
+        if self.meter.is_bytes() {
+            self.budget.sub_capacity(self.capacity);
         }
-        ret
     }
 }
 
@@ -135,6 +418,202 @@ where
     }
 }
 
+// a shard is an LRU padded to its own cache line
+type Shard<K, V, M, P> = CachePadded<Mutex<Lru<K, V, M, P>>>;
+
+// max number of shards in a sharded LRU
+const SHARD_CNT: usize = 16;
+
+// min capacity of a shard, in items or in bytes depending on the meter
+const MIN_SHARD_ITEMS: usize = 16;
+const MIN_SHARD_BYTES: usize = 16 * 1024;
+
+/// Sharded LRU
+///
+/// Items are distributed to shards by key hash, each shard is an
+/// independent LRU protected by its own lock. Accessing items in
+/// different shards doesn't contend, so it can be shared by threads
+/// without an outer lock.
+///
+/// Capacity is evenly divided among shards. As each shard keeps at least
+/// its most recently used item, fewer shards are used for small capacity
+/// so that the total size doesn't exceed capacity by too much.
+pub struct ShardedLru<K, V, M, P>
+where
+    K: Eq + Hash,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default + Clone,
+{
+    shards: Vec<Shard<K, V, M, P>>,
+
+    // number of shards in use, it is only changed when all shards are
+    // locked
+    shard_cnt: AtomicUsize,
+}
+
+impl<K, V, M, P> ShardedLru<K, V, M, P>
+where
+    K: Eq + Hash,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self::with_budget(capacity, MemBudget::global())
+    }
+
+    /// Create sharded LRU whose shards are charged to the specified memory
+    /// budget
+    pub fn with_budget(capacity: usize, budget: MemBudget) -> Self {
+        let lru = ShardedLru {
+            shards: (0..SHARD_CNT)
+                .map(|_| CachePadded::new(Mutex::new(Lru::with_budget(0, budget.clone()))))
+                .collect(),
+            shard_cnt: AtomicUsize::new(1),
+        };
+        lru.set_capacity(capacity);
+        lru
+    }
+
+    // number of shards used for capacity
+    fn shard_count(capacity: usize) -> usize {
+        let min_shard_cap = if M::default().is_bytes() {
+            MIN_SHARD_BYTES
+        } else {
+            MIN_SHARD_ITEMS
+        };
+        (capacity / min_shard_cap).max(1).min(SHARD_CNT)
+    }
+
+    #[inline]
+    fn shard_index<Q: ?Sized + Hash>(k: &Q, shard_cnt: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        hasher.finish() as usize % shard_cnt
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().capacity())
+            .sum()
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        // lock all shards, so items can be moved among them
+        let mut shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap())
+            .collect();
+        let shard_cnt = Self::shard_count(capacity);
+        let old_shard_cnt = self.shard_cnt.load(Ordering::Acquire);
+
+        // divide capacity evenly, the remainder goes to leading shards
+        for (idx, shard) in shards.iter_mut().enumerate() {
+            let shard_cap = if idx < shard_cnt {
+                capacity / shard_cnt + (idx < capacity % shard_cnt) as usize
+            } else {
+                0
+            };
+            shard.set_capacity(shard_cap);
+        }
+
+        // move items to the shards they belong to now
+        if shard_cnt != old_shard_cnt {
+            let mut items = Vec::new();
+            for shard in shards[..old_shard_cnt].iter_mut() {
+                items.append(&mut shard.drain());
+            }
+            self.shard_cnt.store(shard_cnt, Ordering::Release);
+            for (k, v) in items {
+                let idx = Self::shard_index(&k, shard_cnt);
+                shards[idx].insert(k, v);
+            }
+        }
+    }
+
+    /// Lock and return the shard which the key belongs to
+    pub fn shard<Q>(&self, k: &Q) -> MutexGuard<'_, Lru<K, V, M, P>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        loop {
+            let shard_cnt = self.shard_cnt.load(Ordering::Acquire);
+            let shard = self.shards[Self::shard_index(k, shard_cnt)].lock().unwrap();
+
+            // shard count might be changed before the shard is locked
+            if self.shard_cnt.load(Ordering::Acquire) == shard_cnt {
+                return shard;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        self.shard(&k).insert(k, v)
+    }
+
+    #[inline]
+    pub fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        V: Clone,
+    {
+        self.shard(k).get_refresh(k).map(|v| v.clone())
+    }
+
+    #[inline]
+    pub fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.shard(k).remove(k)
+    }
+
+    /// Return a copy of all items in all shards
+    pub fn to_vec(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut ret = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            ret.extend(shard.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        ret
+    }
+}
+
+impl<K, V, M, P> Default for ShardedLru<K, V, M, P>
+where
+    K: Eq + Hash,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default + Clone,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<K, V, M, P> Debug for ShardedLru<K, V, M, P>
+where
+    K: Debug + Eq + Hash,
+    V: Debug,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShardedLru")
+            .field("shards", &self.shard_cnt.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
 /// Count meter, measured by object count
 #[derive(Debug, Clone)]
 pub struct CountMeter<T> {
@@ -146,6 +625,11 @@ impl<T> Meter<T> for CountMeter<T> {
     fn measure(&self, _: &T) -> isize {
         1
     }
+
+    #[inline]
+    fn is_bytes(&self) -> bool {
+        false
+    }
 }
 
 impl<T> Default for CountMeter<T> {
@@ -156,6 +640,34 @@ impl<T> Default for CountMeter<T> {
     }
 }
 
+/// Size meter, measured by object memory size in bytes
+#[derive(Debug, Clone)]
+pub struct SizeMeter<T> {
+    _marker: PhantomData<T>,
+}
+
+impl<T: MemSize> Meter<T> for SizeMeter<T> {
+    #[inline]
+    fn measure(&self, item: &T) -> isize {
+        item.mem_size() as isize
+    }
+}
+
+impl<T> Default for SizeMeter<T> {
+    fn default() -> Self {
+        SizeMeter {
+            _marker: PhantomData::<T>,
+        }
+    }
+}
+
+impl MemSize for Vec<u8> {
+    #[inline]
+    fn mem_size(&self) -> usize {
+        mem::size_of::<Self>() + self.capacity()
+    }
+}
+
 /// Default pin checker
 #[derive(Debug, Clone)]
 pub struct PinChecker<T> {
@@ -171,3 +683,220 @@ impl<T> Default for PinChecker<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ByteLru = Lru<usize, Vec<u8>, SizeMeter<Vec<u8>>, PinChecker<Vec<u8>>>;
+
+    #[test]
+    fn byte_meter() {
+        let item_size = vec![0u8; 100].mem_size();
+        let mut lru = ByteLru::new(item_size * 3);
+
+        for i in 0..3 {
+            lru.insert(i, vec![0u8; 100]);
+        }
+        assert_eq!(lru.len(), 3);
+        assert_eq!(lru.used(), item_size * 3);
+
+        // item size is kept as measured when it is inserted
+        lru.get_refresh(&0).unwrap().clear();
+        lru.insert(3, vec![0u8; 100]);
+        assert_eq!(lru.len(), 3);
+        assert!(!lru.contains_key(&1));
+        assert_eq!(lru.used(), item_size * 3);
+
+        // shrink capacity
+        lru.set_capacity(item_size);
+        assert_eq!(lru.len(), 1);
+        assert!(lru.contains_key(&3));
+        assert_eq!(lru.used(), item_size);
+
+        // the most recently used item is kept even it exceeds capacity
+        lru.insert(4, vec![0u8; 1000]);
+        assert_eq!(lru.len(), 1);
+        assert!(lru.contains_key(&4));
+
+        let lru2 = lru.clone();
+        assert_eq!(lru2.used(), lru.used());
+        lru.remove(&4);
+        assert_eq!(lru.used(), 0);
+    }
+
+    #[test]
+    fn mem_budget() {
+        let budget = MemBudget::new(usize::MAX);
+        let mut lru = ByteLru::with_budget(1024 * 1024, budget.clone());
+        for i in 0..3 {
+            lru.insert(i, vec![0u8; 100]);
+        }
+        assert_eq!(lru.len(), 3);
+        assert_eq!(budget.used(), lru.used());
+
+        // items are removed when memory budget is exceeded
+        budget.set_budget(1);
+        lru.insert(3, vec![0u8; 100]);
+        assert_eq!(lru.len(), 1);
+        assert!(lru.contains_key(&3));
+        assert_eq!(budget.used(), lru.used());
+
+        // budget is shared by caches in the same group
+        budget.set_budget(lru.used() * 3);
+        let mut lru2 = ByteLru::with_budget(1024 * 1024, budget.clone());
+        for i in 0..3 {
+            lru2.insert(i, vec![0u8; 100]);
+        }
+        assert_eq!(lru2.len(), 2);
+        assert_eq!(budget.used(), lru.used() + lru2.used());
+        drop(lru2);
+        assert_eq!(budget.used(), lru.used());
+
+        // count meter is not charged to memory budget
+        let mut lru: Lru<usize, usize, CountMeter<usize>, PinChecker<usize>> =
+            Lru::with_budget(3, budget.clone());
+        budget.set_budget(1);
+        for i in 0..3 {
+            lru.insert(i, i);
+        }
+        assert_eq!(lru.len(), 3);
+    }
+
+    #[test]
+    fn mem_budget_share() {
+        let item_size = vec![0u8; 100].mem_size();
+        let budget = MemBudget::new(usize::MAX);
+        let mut big = ByteLru::with_budget(item_size * 30, budget.clone());
+        let mut small = ByteLru::with_budget(item_size * 10, budget.clone());
+        for i in 0..30 {
+            big.insert(i, vec![0u8; 100]);
+        }
+        for i in 0..10 {
+            small.insert(i, vec![0u8; 100]);
+        }
+
+        // budget is shared in proportion to capacity, busy small cache only
+        // gives up memory beyond its share
+        budget.set_budget(item_size * 20);
+        for i in 10..20 {
+            small.insert(i, vec![0u8; 100]);
+        }
+        assert_eq!(small.len(), 5);
+        assert!(budget.used() > budget.budget());
+
+        // and the big cache gives up the rest when it is used
+        big.insert(30, vec![0u8; 100]);
+        assert_eq!(big.len(), 15);
+        assert_eq!(budget.used(), budget.budget());
+
+        // capacity is returned to budget when cache is dropped
+        drop(big);
+        assert_eq!(budget.share(item_size * 10), budget.budget());
+    }
+
+    #[test]
+    fn update_size() {
+        let item_size = vec![0u8; 100].mem_size();
+        let budget = MemBudget::new(usize::MAX);
+        let mut lru = ByteLru::with_budget(item_size * 3, budget.clone());
+        for i in 0..3 {
+            lru.insert(i, vec![0u8; 100]);
+        }
+
+        // item grown after insert is measured again
+        let item = lru.get_refresh(&2).unwrap();
+        item.reserve_exact(item_size);
+        let size = item.mem_size();
+        lru.update_size(&2, size);
+        assert_eq!(lru.len(), 2);
+        assert!(!lru.contains_key(&0));
+        assert_eq!(lru.used(), size + item_size);
+        assert_eq!(budget.used(), lru.used());
+
+        // missing item is ignored
+        lru.update_size(&0, 1);
+        assert_eq!(lru.used(), size + item_size);
+    }
+
+    #[test]
+    fn sharded_lru() {
+        use std::sync::Arc;
+        use std::thread;
+
+        type CountLru = ShardedLru<usize, usize, CountMeter<usize>, PinChecker<usize>>;
+
+        let lru = CountLru::new(SHARD_CNT * 4);
+        assert_eq!(lru.capacity(), SHARD_CNT * 4);
+
+        let lru = Arc::new(lru);
+        let children: Vec<_> = (0..4)
+            .map(|t| {
+                let lru = lru.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let k = t * 1000 + i;
+                        let mut shard = lru.shard(&k);
+                        shard.insert(k, k);
+                        assert_eq!(shard.get_refresh(&k).map(|v| *v), Some(k));
+                    }
+                })
+            })
+            .collect();
+        for child in children {
+            child.join().unwrap();
+        }
+
+        let len: usize = (0..4000).filter(|k| lru.get(k).is_some()).count();
+        assert!(len > 0 && len <= SHARD_CNT * 4);
+
+        lru.insert(42, 42);
+        assert_eq!(lru.remove(&42), Some(42));
+        assert!(lru.get(&42).is_none());
+
+        lru.set_capacity(SHARD_CNT);
+        assert_eq!(lru.capacity(), SHARD_CNT);
+        assert!(lru.shard(&0).len() <= SHARD_CNT);
+    }
+
+    #[test]
+    fn sharded_lru_capacity() {
+        type CountLru = ShardedLru<usize, usize, CountMeter<usize>, PinChecker<usize>>;
+        type ByteLru = ShardedLru<usize, Vec<u8>, SizeMeter<Vec<u8>>, PinChecker<Vec<u8>>>;
+
+        let count = |lru: &CountLru| (0..1000).filter(|k| lru.get(k).is_some()).count();
+
+        // small capacity is not exceeded by keeping one item in each shard
+        let lru = CountLru::new(3);
+        assert_eq!(lru.capacity(), 3);
+        for i in 0..1000 {
+            lru.insert(i, i);
+        }
+        assert_eq!(count(&lru), 3);
+
+        let item_size = vec![0u8; 4096].mem_size();
+        let lru = ByteLru::new(item_size * 2);
+        for i in 0..100 {
+            lru.insert(i, vec![0u8; 4096]);
+        }
+        assert_eq!((0..100).filter(|k| lru.get(k).is_some()).count(), 2);
+
+        // items are kept when they are moved to more shards
+        let lru = CountLru::new(MIN_SHARD_ITEMS);
+        for i in 0..MIN_SHARD_ITEMS {
+            lru.insert(i, i);
+        }
+        lru.set_capacity(MIN_SHARD_ITEMS * SHARD_CNT);
+        assert_eq!(lru.capacity(), MIN_SHARD_ITEMS * SHARD_CNT);
+        assert_eq!(count(&lru), MIN_SHARD_ITEMS);
+        for i in 0..1000 {
+            lru.insert(i, i);
+        }
+        assert!(count(&lru) > MIN_SHARD_ITEMS);
+
+        // and removed when they are moved to fewer shards
+        lru.set_capacity(5);
+        assert_eq!(lru.capacity(), 5);
+        assert_eq!(count(&lru), 5);
+    }
+}
@@ -11,7 +11,7 @@ pub use self::time::Time;
 
 use std::fs;
 use std::path::Path;
-use std::sync::{Arc, Once, RwLock, ONCE_INIT};
+use std::sync::{Arc, Once, RwLock};
 use std::time::Duration;
 
 use crate::error::Result;
@@ -91,7 +91,7 @@ pub fn remove_empty_parent_dir(path: &Path) -> Result<()> {
         if fs::read_dir(parent)?.count() > 0 {
             break;
         }
-        fs::remove_dir(&parent)?;
+        fs::remove_dir(parent)?;
     }
     Ok(())
 }
@@ -105,7 +105,7 @@ use android_logger::{self, Filter};
 #[cfg(not(target_os = "android"))]
 use env_logger;
 
-static INIT: Once = ONCE_INIT;
+static INIT: Once = Once::new();
 
 /// Initialise ZboxFS environment.
 ///
@@ -13,25 +13,24 @@ impl RefCnt {
         self.0
     }
 
+    #[inline]
+    pub fn reset(&mut self, val: u32) {
+        self.0 = val;
+    }
+
     #[inline]
     pub fn inc_ref(&mut self) -> Result<u32> {
-        self.0
-            .checked_add(1)
-            .ok_or(Error::RefOverflow)
-            .and_then(|r| {
-                self.0 = r;
-                Ok(r)
-            })
+        self.0.checked_add(1).ok_or(Error::RefOverflow).map(|r| {
+            self.0 = r;
+            r
+        })
     }
 
     #[inline]
     pub fn dec_ref(&mut self) -> Result<u32> {
-        self.0
-            .checked_sub(1)
-            .ok_or(Error::RefUnderflow)
-            .and_then(|r| {
-                self.0 = r;
-                Ok(r)
-            })
+        self.0.checked_sub(1).ok_or(Error::RefUnderflow).map(|r| {
+            self.0 = r;
+            r
+        })
     }
 }
@@ -38,7 +38,7 @@ impl Version {
 
 impl Debug for Version {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Version({})", self.to_string())
+        write!(f, "Version({})", self)
     }
 }
 
@@ -1,8 +1,12 @@
+use std::cmp::max;
+use std::collections::BTreeMap;
 use std::iter::IntoIterator;
+use std::mem;
 use std::ops::Index;
 use std::slice::Iter;
 
-use crate::{BLKS_PER_FRAME, BLK_SIZE, FRAME_SIZE};
+use super::layout::Layout;
+use crate::util::lru::MemSize;
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
 pub struct Span {
@@ -22,8 +26,8 @@ impl Span {
     }
 
     #[inline]
-    pub fn bytes_len(&self) -> usize {
-        self.cnt * BLK_SIZE
+    pub fn bytes_len(&self, blk_size: usize) -> usize {
+        self.cnt * blk_size
     }
 
     #[inline]
@@ -49,7 +53,7 @@ impl Span {
             return ret;
         }
 
-        let mut span = self.clone();
+        let mut span = *self;
         let mut at = span.begin + size - span.begin % size;
         while at < span.end() {
             let split = span.split_to(at);
@@ -86,12 +90,12 @@ impl LocSpan {
         }
     }
 
-    pub fn split_to(&mut self, at: usize) -> LocSpan {
+    pub fn split_to(&mut self, at: usize, blk_size: usize) -> LocSpan {
         let ret = LocSpan {
             span: self.span.split_to(at),
             offset: self.offset,
         };
-        self.offset += ret.span.bytes_len();
+        self.offset += ret.span.bytes_len(blk_size);
         ret
     }
 }
@@ -105,7 +109,7 @@ pub struct Addr {
 
 impl Addr {
     #[inline]
-    pub fn iter(&self) -> Iter<LocSpan> {
+    pub fn iter(&self) -> Iter<'_, LocSpan> {
         self.list.iter()
     }
 
@@ -127,18 +131,19 @@ impl Addr {
         self.len += len;
     }
 
-    // divide address to frames
-    pub fn divide_to_frames(&self) -> Vec<Addr> {
+    // divide address to frames of the layout
+    pub fn divide_to_frames(&self, layout: &Layout) -> Vec<Addr> {
+        let frm_size = layout.frame_size();
         let mut frames = vec![Addr::default()];
         let mut frm_idx = 0;
         let mut blk_cnt = 0;
 
         for loc_span in self.list.iter() {
-            let mut loc_span = loc_span.clone();
-            loc_span.offset = frm_idx * FRAME_SIZE + blk_cnt * BLK_SIZE;
+            let mut loc_span = *loc_span;
+            loc_span.offset = frm_idx * frm_size + blk_cnt * layout.blk_size;
 
             loop {
-                let blk_left = BLKS_PER_FRAME - blk_cnt;
+                let blk_left = layout.blks_per_frame - blk_cnt;
 
                 if loc_span.span.cnt <= blk_left {
                     // span can fit into frame
@@ -149,11 +154,11 @@ impl Addr {
 
                 // span cannot fit into frame, must split span first
                 let at = loc_span.span.begin + blk_left;
-                let split = loc_span.split_to(at);
+                let split = loc_span.split_to(at, layout.blk_size);
 
                 // finish current frame and start a new frame
                 frames[frm_idx].list.push(split);
-                frames[frm_idx].len = FRAME_SIZE;
+                frames[frm_idx].len = frm_size;
                 frames.push(Addr::default());
                 frm_idx += 1;
                 blk_cnt = 0;
@@ -161,13 +166,20 @@ impl Addr {
         }
 
         // fix the last frame's length
-        frames.last_mut().unwrap().len = self.len - frm_idx * FRAME_SIZE;
+        frames.last_mut().unwrap().len = self.len - frm_idx * frm_size;
         assert_eq!(self.len, frames.iter().map(|a| a.len).sum::<usize>());
 
         frames
     }
 }
 
+impl MemSize for Addr {
+    #[inline]
+    fn mem_size(&self) -> usize {
+        mem::size_of::<Addr>() + self.list.capacity() * mem::size_of::<LocSpan>()
+    }
+}
+
 impl IntoIterator for Addr {
     type Item = LocSpan;
     type IntoIter = ::std::vec::IntoIter<LocSpan>;
@@ -185,19 +197,105 @@ impl Index<usize> for Addr {
     }
 }
 
+// set of block indexes, saved as ranges, key is range begin and value is
+// range end
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct BlockSet(BTreeMap<usize, usize>);
+
+impl BlockSet {
+    pub fn contains(&self, idx: usize) -> bool {
+        self.0
+            .range(..=idx)
+            .next_back()
+            .map_or(false, |(_, &end)| idx < end)
+    }
+
+    // check if all blocks in span are in the set
+    pub fn contains_span(&self, span: Span) -> bool {
+        self.0
+            .range(..=span.begin)
+            .next_back()
+            .map_or(false, |(_, &end)| span.end() <= end)
+    }
+
+    // check if any block in span is in the set
+    pub fn intersects(&self, span: Span) -> bool {
+        self.contains(span.begin) || self.0.range(span.begin..span.end()).next().is_some()
+    }
+
+    pub fn insert(&mut self, span: Span) {
+        let mut begin = span.begin;
+        let mut end = span.end();
+
+        // merge with the range before it
+        if let Some((&b, &e)) = self.0.range(..begin).next_back() {
+            if e >= begin {
+                begin = b;
+                end = max(end, e);
+            }
+        }
+
+        // merge with ranges overlapped or adjacent after it
+        let merged: Vec<usize> = self.0.range(begin..=end).map(|(&b, _)| b).collect();
+        for b in merged {
+            let e = self.0.remove(&b).unwrap();
+            end = max(end, e);
+        }
+
+        self.0.insert(begin, end);
+    }
+
+    pub fn remove(&mut self, span: Span) {
+        let begin = span.begin;
+        let end = span.end();
+
+        // split the range before it
+        if let Some((&b, &e)) = self.0.range(..begin).next_back() {
+            if e > begin {
+                self.0.insert(b, begin);
+                if e > end {
+                    self.0.insert(end, e);
+                }
+            }
+        }
+
+        // remove ranges inside it and keep their tails
+        let inside: Vec<usize> = self.0.range(begin..end).map(|(&b, _)| b).collect();
+        for b in inside {
+            let e = self.0.remove(&b).unwrap();
+            if e > end {
+                self.0.insert(end, e);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // iterate ranges as spans in ascending order
+    pub fn spans<'a>(&'a self) -> impl DoubleEndedIterator<Item = Span> + 'a {
+        self.0.iter().map(|(&begin, &end)| Span::new(begin, end - begin))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{BLKS_PER_FRAME, BLK_SIZE, FRAME_SIZE};
 
     #[test]
     fn split_addr() {
+        let layout = Layout::default();
+
         // #1, address is smaller than a frame
         let lspan = LocSpan::new(0, 1, 0);
         let addr = Addr {
             len: 3,
-            list: vec![lspan.clone()],
+            list: vec![lspan],
         };
-        let frms = addr.divide_to_frames();
+        let frms = addr.divide_to_frames(&layout);
         assert_eq!(frms.len(), 1);
         assert_eq!(frms[0].len, addr.len);
         assert_eq!(frms[0].list[0], lspan);
@@ -206,9 +304,9 @@ mod tests {
         let lspan = LocSpan::new(0, BLKS_PER_FRAME, 0);
         let addr = Addr {
             len: FRAME_SIZE,
-            list: vec![lspan.clone()],
+            list: vec![lspan],
         };
-        let frms = addr.divide_to_frames();
+        let frms = addr.divide_to_frames(&layout);
         assert_eq!(frms.len(), 1);
         assert_eq!(frms[0].len, addr.len);
         assert_eq!(frms[0].list[0], lspan);
@@ -217,9 +315,9 @@ mod tests {
         let lspan = LocSpan::new(0, BLKS_PER_FRAME + 1, 0);
         let addr = Addr {
             len: FRAME_SIZE + 3,
-            list: vec![lspan.clone()],
+            list: vec![lspan],
         };
-        let frms = addr.divide_to_frames();
+        let frms = addr.divide_to_frames(&layout);
         assert_eq!(frms.len(), 2);
         assert_eq!(frms[0].len, FRAME_SIZE);
         assert_eq!(frms[0].list[0], LocSpan::new(0, BLKS_PER_FRAME, 0));
@@ -231,9 +329,9 @@ mod tests {
         let lspan2 = LocSpan::new(3, 1, BLK_SIZE);
         let addr = Addr {
             len: BLK_SIZE + 3,
-            list: vec![lspan.clone(), lspan2.clone()],
+            list: vec![lspan, lspan2],
         };
-        let frms = addr.divide_to_frames();
+        let frms = addr.divide_to_frames(&layout);
         assert_eq!(frms.len(), 1);
         assert_eq!(frms[0].len, addr.len);
         assert_eq!(frms[0].list.len(), 2);
@@ -245,9 +343,9 @@ mod tests {
         let lspan2 = LocSpan::new(3, BLKS_PER_FRAME, BLK_SIZE);
         let addr = Addr {
             len: BLK_SIZE + FRAME_SIZE,
-            list: vec![lspan.clone(), lspan2.clone()],
+            list: vec![lspan, lspan2],
         };
-        let frms = addr.divide_to_frames();
+        let frms = addr.divide_to_frames(&layout);
         assert_eq!(frms.len(), 2);
         assert_eq!(frms[0].len, FRAME_SIZE);
         assert_eq!(frms[0].list.len(), 2);
@@ -267,9 +365,9 @@ mod tests {
         let lspan = LocSpan::new(0, BLKS_PER_FRAME * 2 + 1, 0);
         let addr = Addr {
             len: FRAME_SIZE * 2 + 3,
-            list: vec![lspan.clone()],
+            list: vec![lspan],
         };
-        let frms = addr.divide_to_frames();
+        let frms = addr.divide_to_frames(&layout);
         assert_eq!(frms.len(), 3);
         assert_eq!(frms[0].len, FRAME_SIZE);
         assert_eq!(frms[0].list.len(), 1);
@@ -286,5 +384,42 @@ mod tests {
             frms[2].list[0],
             LocSpan::new(BLKS_PER_FRAME * 2, 1, FRAME_SIZE * 2)
         );
+
+        // #7, address is greater than a frame of non-default layout
+        let layout = Layout {
+            blk_size: 4096,
+            blks_per_frame: 2,
+            blks_per_sector: 16,
+        };
+        let lspan = LocSpan::new(5, 3, 0);
+        let addr = Addr {
+            len: 4096 * 2 + 3,
+            list: vec![lspan],
+        };
+        let frms = addr.divide_to_frames(&layout);
+        assert_eq!(frms.len(), 2);
+        assert_eq!(frms[0].len, 4096 * 2);
+        assert_eq!(frms[0].list[0], LocSpan::new(5, 2, 0));
+        assert_eq!(frms[1].len, 3);
+        assert_eq!(frms[1].list[0], LocSpan::new(7, 1, 4096 * 2));
+    }
+
+    #[test]
+    fn block_set() {
+        let mut set = BlockSet::default();
+        set.insert(Span::new(0, 3));
+        set.insert(Span::new(5, 2));
+        set.insert(Span::new(3, 2));
+        assert_eq!(set.0.len(), 1);
+        assert!(set.contains_span(Span::new(0, 7)));
+        set.remove(Span::new(2, 2));
+        assert!(set.contains_span(Span::new(0, 2)));
+        assert!(!set.contains(2));
+        assert!(!set.contains(3));
+        assert!(set.contains_span(Span::new(4, 3)));
+        assert!(!set.intersects(Span::new(2, 2)));
+        assert!(set.intersects(Span::new(3, 2)));
+        set.remove(Span::new(0, 10));
+        assert!(set.0.is_empty());
     }
 }
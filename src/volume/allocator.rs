@@ -1,12 +1,24 @@
+use std::cmp::min;
 use std::sync::{Arc, RwLock};
 
 use crate::util::IntoRef;
-use crate::volume::address::Span;
+use crate::volume::address::{BlockSet, Span};
 
 /// Block allocator
+///
+/// Blocks are allocated from free extents first, and then from the block
+/// watermark. Freed blocks are pending until they are released, which
+/// should be done after the deletion is persistent, so that a block is
+/// never reused while its old content may still be referred.
 #[derive(Debug, Default)]
 pub struct Allocator {
     blk_wmark: usize,
+
+    // free extents can be allocated
+    free: BlockSet,
+
+    // freed blocks not released yet
+    pending: BlockSet,
 }
 
 impl Allocator {
@@ -25,16 +37,111 @@ impl Allocator {
         self.blk_wmark = blk_wmark;
     }
 
-    // allocate continuous blocks, return the start block index
+    // free extents, in ascending order of block index
     #[inline]
+    pub fn free_extents(&self) -> Vec<Span> {
+        self.free.spans().collect()
+    }
+
+    pub fn set_free_extents(&mut self, extents: &[Span]) {
+        self.free = BlockSet::default();
+        for span in extents {
+            self.free.insert(*span);
+        }
+    }
+
+    // check if any block in span is free, including freed blocks not
+    // released yet
+    #[inline]
+    pub fn is_free(&self, span: Span) -> bool {
+        self.free.intersects(span) || self.pending.intersects(span)
+    }
+
+    // allocate continuous blocks, return the start block index
     pub fn allocate(&mut self, blk_cnt: usize) -> Span {
+        // use the first free extent large enough
+        let extent = self.free.spans().find(|s| s.cnt >= blk_cnt);
+        if let Some(extent) = extent {
+            let span = Span::new(extent.begin, blk_cnt);
+            self.free.remove(span);
+            return span;
+        }
+
         let begin = self.blk_wmark;
         self.blk_wmark += blk_cnt;
         Span::new(begin, blk_cnt)
     }
+
+    // free blocks, they cannot be allocated until released
+    pub fn free(&mut self, span: Span) {
+        // blocks beyond watermark are not allocated yet, this happens when
+        // watermark is restored from a previous state
+        if span.begin >= self.blk_wmark {
+            return;
+        }
+        let end = min(span.end(), self.blk_wmark);
+        self.pending.insert(Span::new(span.begin, end - span.begin));
+    }
+
+    // release freed blocks so they can be allocated again
+    pub fn release(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        for span in self.pending.spans() {
+            self.free.insert(span);
+        }
+        self.pending = BlockSet::default();
+
+        // free extent at the end lowers the watermark
+        let last = self.free.spans().next_back();
+        if let Some(last) = last {
+            if last.end() == self.blk_wmark {
+                self.free.remove(last);
+                self.blk_wmark = last.begin;
+            }
+        }
+    }
 }
 
 impl IntoRef for Allocator {}
 
 /// Block allocator reference type
 pub type AllocatorRef = Arc<RwLock<Allocator>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_free_blocks() {
+        let mut allocator = Allocator::new();
+        assert_eq!(allocator.allocate(3), Span::new(0, 3));
+        assert_eq!(allocator.allocate(4), Span::new(3, 4));
+        assert_eq!(allocator.allocate(2), Span::new(7, 2));
+
+        // freed blocks are not reused until released
+        allocator.free(Span::new(0, 3));
+        assert_eq!(allocator.allocate(1), Span::new(9, 1));
+        allocator.release();
+        assert_eq!(allocator.free_extents(), vec![Span::new(0, 3)]);
+
+        // allocate from free extent large enough
+        assert_eq!(allocator.allocate(4), Span::new(10, 4));
+        assert_eq!(allocator.allocate(2), Span::new(0, 2));
+        assert_eq!(allocator.free_extents(), vec![Span::new(2, 1)]);
+
+        // adjacent and duplicated free blocks are merged
+        allocator.free(Span::new(3, 4));
+        allocator.free(Span::new(3, 2));
+        allocator.release();
+        assert_eq!(allocator.free_extents(), vec![Span::new(2, 5)]);
+
+        // free extent at the end lowers watermark
+        allocator.free(Span::new(10, 4));
+        allocator.free(Span::new(20, 4));
+        allocator.release();
+        assert_eq!(allocator.block_wmark(), 10);
+        assert_eq!(allocator.free_extents(), vec![Span::new(2, 5)]);
+    }
+}
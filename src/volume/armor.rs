@@ -1,9 +1,11 @@
 use std::fmt::Debug;
 use std::io::{ErrorKind, Read, Write};
 use std::marker::PhantomData;
+use std::sync::Mutex;
 
 use bytes::BufMut;
 use rmp_serde::{Deserializer, Serializer};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::error::{Error, Result};
@@ -11,7 +13,10 @@ use crate::trans::{
     eid::{Eid, Id},
     Finish,
 };
+use crate::util::collections::HashMap;
 use crate::util::crypto::Crypto;
+use crate::util::lru::{CountMeter, PinChecker, ShardedLru};
+use crate::volume::storage::storage::{self, StorageRef};
 use crate::volume::volume::{self, VolumeRef};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
@@ -21,7 +26,7 @@ pub enum Arm {
 }
 
 impl Arm {
-    fn to_eid(&self, id: &Eid) -> Eid {
+    pub fn to_eid(&self, id: &Eid) -> Eid {
         // serialize arm
         let mut arm_buf = Vec::new();
         self.serialize(&mut Serializer::new(&mut arm_buf)).unwrap();
@@ -55,12 +60,14 @@ impl Arm {
     pub fn remove_arm(&self, id: &Eid, vol: &VolumeRef) -> Result<()> {
         let mut vol = vol.write().unwrap();
         let arm_id = self.to_eid(id);
+        vol.set_active_arm(id, None);
         vol.del(&arm_id)
     }
 
     pub fn remove_all(id: &Eid, vol: &VolumeRef) -> Result<()> {
         let mut vol = vol.write().unwrap();
         let (left_arm_id, right_arm_id) = Arm::to_both_eid(id);
+        vol.set_active_arm(id, None);
         vol.del(&left_arm_id).and(vol.del(&right_arm_id))
     }
 }
@@ -72,6 +79,226 @@ impl Default for Arm {
     }
 }
 
+// max number of entities in active arm map
+const ARM_MAP_CAPACITY: usize = 64 * 1024;
+
+// max number of arm map deltas saved after its base, when it is reached
+// the whole map is saved as a new base
+const ARM_MAP_MAX_DELTAS: u64 = 64;
+
+type ArmLru = ShardedLru<Eid, Arm, CountMeter<Arm>, PinChecker<Arm>>;
+
+// arm map base and deltas are saved as WALs whose ids are derived from
+// volume id, deltas also have their sequence in the id. Unlike entities,
+// WALs don't use blocks, so they are not affected by block allocator state
+// being restored to an earlier one after crash
+fn arm_map_id(vol_id: &Eid, seq: Option<u64>) -> Eid {
+    let mut buf = Vec::new();
+    buf.put(vol_id.as_ref());
+    buf.put(&b"arm_map"[..]);
+    if let Some(seq) = seq {
+        buf.put_u64_le(seq);
+    }
+    Eid::from_slice(&Crypto::hash(&buf))
+}
+
+// arm map base, deltas in the range `[gc_from, seq)` are merged into it
+// and can be removed
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ArmMapBase {
+    seq: u64,
+    gc_from: u64,
+    arms: Vec<(Eid, Arm)>,
+}
+
+// arm map delta, it records active arms set and forgotten since previous
+// save
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ArmMapDelta {
+    seq: u64,
+    arms: Vec<(Eid, Arm)>,
+    dels: Vec<Eid>,
+}
+
+/// Active arm map
+///
+/// It records which arm holds the latest version of an entity, so loading
+/// that entity only needs to read one arm. Both arms are still read for
+/// entities not in the map.
+///
+/// The map is saved incrementally when volume is flushed. Every save writes
+/// a delta with changes since previous save, and the whole map is saved as
+/// a new base when there are too many deltas.
+///
+/// The map is saved before the underlying storage is flushed, so a recorded
+/// arm never lags behind the entity written to it. If the entity write is
+/// lost by crash, it belongs to an uncommitted transaction whose arms are
+/// removed and forgotten by cold abort when the volume is opened again.
+#[derive(Debug)]
+pub struct ArmMap {
+    lru: ArmLru,
+
+    // changes since last save, None means the arm is forgotten
+    changes: Mutex<HashMap<Eid, Option<Arm>>>,
+
+    // sequence of last save, last base and the first delta not removed
+    seq: u64,
+    base_seq: u64,
+    gc_from: u64,
+}
+
+impl ArmMap {
+    pub fn new() -> Self {
+        ArmMap {
+            lru: ArmLru::new(ARM_MAP_CAPACITY),
+            changes: Mutex::new(HashMap::new()),
+            seq: 0,
+            base_seq: 0,
+            gc_from: 0,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, id: &Eid) -> Option<Arm> {
+        self.lru.get(id)
+    }
+
+    pub fn set(&self, id: &Eid, arm: Option<Arm>) {
+        match arm {
+            Some(arm) => {
+                self.lru.insert(id.clone(), arm);
+            }
+            None => {
+                self.lru.remove(id);
+            }
+        }
+        let mut changes = self.changes.lock().unwrap();
+        changes.insert(id.clone(), arm);
+    }
+
+    fn read<T>(id: &Eid, storage: &StorageRef) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let mut rdr = storage::WalReader::new(id, storage);
+        let mut buf = Vec::new();
+        rdr.read_to_end(&mut buf).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                Error::NotFound
+            } else {
+                Error::from(err)
+            }
+        })?;
+        let mut de = Deserializer::new(&buf[..]);
+        let ret: T = Deserialize::deserialize(&mut de)?;
+        Ok(ret)
+    }
+
+    fn write<T: Serialize>(id: &Eid, obj: &T, storage: &StorageRef) -> Result<()> {
+        let mut buf = Vec::new();
+        obj.serialize(&mut Serializer::new(&mut buf))?;
+        let mut wtr = storage::WalWriter::new(id, storage);
+        wtr.write_all(&buf[..])?;
+        wtr.finish()
+    }
+
+    /// Load arm map base and all its deltas saved for a volume
+    pub fn load(&mut self, vol_id: &Eid, storage: &StorageRef) -> Result<()> {
+        let base: ArmMapBase = match Self::read(&arm_map_id(vol_id, None), storage) {
+            Ok(base) => base,
+            Err(ref err) if *err == Error::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for (id, arm) in base.arms {
+            self.lru.insert(id, arm);
+        }
+        self.seq = base.seq;
+        self.base_seq = base.seq;
+        self.gc_from = base.gc_from;
+
+        loop {
+            let delta_id = arm_map_id(vol_id, Some(self.seq + 1));
+            let delta: ArmMapDelta = match Self::read(&delta_id, storage) {
+                Ok(delta) => delta,
+                Err(ref err) if *err == Error::NotFound => break,
+                Err(err) => return Err(err),
+            };
+            for (id, arm) in delta.arms {
+                self.lru.insert(id, arm);
+            }
+            for id in delta.dels {
+                self.lru.remove(&id);
+            }
+            self.seq = delta.seq;
+        }
+
+        self.changes.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    // save changes since last save, the whole map is saved if a new base
+    // is due
+    fn save_changes(&mut self, vol_id: &Eid, storage: &StorageRef) -> Result<()> {
+        let seq = self.seq + 1;
+
+        if self.seq == 0 || seq - self.base_seq > ARM_MAP_MAX_DELTAS {
+            // save a new base and then remove all deltas merged into it
+            let base = ArmMapBase {
+                seq,
+                gc_from: self.gc_from,
+                arms: self.lru.to_vec(),
+            };
+            Self::write(&arm_map_id(vol_id, None), &base, storage)?;
+            {
+                let mut storage = storage.write().unwrap();
+                for gc_seq in self.gc_from..seq {
+                    match storage.del_wal(&arm_map_id(vol_id, Some(gc_seq))) {
+                        Err(ref err) if *err == Error::NotFound => {}
+                        result => result?,
+                    }
+                }
+            }
+            self.base_seq = seq;
+            self.gc_from = seq + 1;
+        } else {
+            let mut delta = ArmMapDelta {
+                seq,
+                ..Default::default()
+            };
+            for (id, arm) in self.changes.lock().unwrap().iter() {
+                match *arm {
+                    Some(arm) => delta.arms.push((id.clone(), arm)),
+                    None => delta.dels.push(id.clone()),
+                }
+            }
+            Self::write(&arm_map_id(vol_id, Some(seq)), &delta, storage)?;
+        }
+
+        self.seq = seq;
+        self.changes.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    /// Save arm map for a volume if it has changed since last save
+    ///
+    /// This must be called before the storage is flushed.
+    pub fn save(&mut self, vol_id: &Eid, storage: &StorageRef) -> Result<()> {
+        if self.seq > 0 && self.changes.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        self.save_changes(vol_id, storage)
+    }
+}
+
+impl Default for ArmMap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Seq trait
 pub trait Seq {
     fn seq(&self) -> u64;
@@ -94,6 +321,16 @@ pub trait Armor<'de> {
     fn get_item_writer(&self, arm_id: &Eid) -> Result<Self::ItemWriter>;
     fn del_arm(&self, arm_id: &Eid) -> Result<()>;
 
+    // get recorded active arm of an item, None if it is unknown
+    #[inline]
+    fn active_arm(&self, _id: &Eid) -> Option<Arm> {
+        None
+    }
+
+    // record active arm of an item, None to forget it
+    #[inline]
+    fn set_active_arm(&self, _id: &Eid, _arm: Option<Arm>) {}
+
     fn load_one_arm(&self, id: &Eid, arm: Arm) -> Result<Self::Item> {
         let arm_id = arm.to_eid(id);
         let mut rdr = self.get_item_reader(&arm_id)?;
@@ -107,10 +344,18 @@ pub trait Armor<'de> {
         })?;
         let mut de = Deserializer::new(&buf[..]);
         let item: Self::Item = Deserialize::deserialize(&mut de)?;
+
+        // arm holding another item is corrupted
+        if item.id() != id {
+            warn!("arm {:?} of {:?} has another item {:?}", arm, id, item.id());
+            return Err(Error::Corrupted);
+        }
+
         Ok(item)
     }
 
     // try to load both left and right arms
+    #[allow(clippy::type_complexity)]
     fn load_arms(&self, id: &Eid) -> Result<(Option<Self::Item>, Option<Self::Item>)> {
         // load left and right arms
         let left_arm = self.load_one_arm(id, Arm::Left);
@@ -123,18 +368,26 @@ pub trait Armor<'de> {
                     Ok((Some(left), Some(right)))
                 }
                 Err(ref err) if *err == Error::NotFound => Ok((Some(left), None)),
-                Err(err) => return Err(err),
+                Err(err) => Err(err),
             },
             Err(ref err) if *err == Error::NotFound => match right_arm {
                 Ok(right) => Ok((None, Some(right))),
                 Err(ref err) if *err == Error::NotFound => Ok((None, None)),
-                Err(err) => return Err(err),
+                Err(err) => Err(err),
             },
-            Err(err) => return Err(err),
+            Err(err) => Err(err),
         }
     }
 
     fn load_item(&self, id: &Eid) -> Result<Self::Item> {
+        // read the recorded active arm only, both arms are loaded if the
+        // active arm is unknown or it cannot be loaded
+        if let Some(arm) = self.active_arm(id) {
+            if let Ok(item) = self.load_one_arm(id, arm) {
+                return Ok(item);
+            }
+        }
+
         let (left_arm, right_arm) = self.load_arms(id)?;
 
         let item = match left_arm {
@@ -150,6 +403,7 @@ pub trait Armor<'de> {
             },
             None => right_arm.ok_or(Error::NotFound)?,
         };
+        self.set_active_arm(id, Some(item.arm()));
 
         Ok(item)
     }
@@ -169,12 +423,13 @@ pub trait Armor<'de> {
             let mut wtr = self.get_item_writer(&arm_id)?;
             wtr.write_all(&buf[..])?;
             wtr.finish()?;
+            self.set_active_arm(item.id(), Some(item.arm()));
             Ok(())
         })()
-        .or_else(|err| {
+        .map_err(|err| {
             // if save item failed, revert its arm back
             item.arm_mut().toggle();
-            Err(err)
+            err
         })
     }
 
@@ -187,6 +442,7 @@ pub trait Armor<'de> {
     // Remove all arms without order, this deletion cannot be recovered.
     fn remove_all_arms(&self, id: &Eid) -> Result<()> {
         let (left_arm_id, right_arm_id) = Arm::to_both_eid(id);
+        self.set_active_arm(id, None);
         self.del_arm(&left_arm_id).and(self.del_arm(&right_arm_id))
     }
 }
@@ -258,12 +514,12 @@ where
 
     #[inline]
     fn get_item_reader(&self, arm_id: &Eid) -> Result<Self::ItemReader> {
-        Ok(volume::Reader::new(arm_id, &self.vol)?)
+        volume::Reader::new(arm_id, &self.vol)
     }
 
     #[inline]
     fn get_item_writer(&self, arm_id: &Eid) -> Result<Self::ItemWriter> {
-        Ok(volume::Writer::new(arm_id, &self.vol)?)
+        volume::Writer::new(arm_id, &self.vol)
     }
 
     #[inline]
@@ -271,13 +527,28 @@ where
         let mut vol = self.vol.write().unwrap();
         vol.del(arm_id)
     }
+
+    #[inline]
+    fn active_arm(&self, id: &Eid) -> Option<Arm> {
+        let vol = self.vol.read().unwrap();
+        vol.active_arm(id)
+    }
+
+    #[inline]
+    fn set_active_arm(&self, id: &Eid, arm: Option<Arm>) {
+        let vol = self.vol.read().unwrap();
+        vol.set_active_arm(id, arm);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use super::*;
     use crate::fs::Config;
     use crate::util::{init_env, IntoRef};
+    use crate::volume::storage::MemStorage;
     use crate::volume::Volume;
 
     #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -336,7 +607,7 @@ mod tests {
     #[test]
     fn volume_armor() {
         init_env();
-        let mut vol = Volume::new("mem://foo").unwrap();
+        let mut vol = Volume::new("mem://foo_armor").unwrap();
         vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
         let varm = VolumeArmor::<Item>::new(&vol.into_ref());
 
@@ -362,5 +633,134 @@ mod tests {
         varm.remove_all_arms(item2.id()).unwrap();
         assert_eq!(varm.load_item(item.id()).unwrap_err(), Error::NotFound);
         assert_eq!(varm.load_item(item2.id()).unwrap_err(), Error::NotFound);
+        assert!(MemStorage::destroy("foo_armor"));
+    }
+
+    #[test]
+    fn active_arm() {
+        init_env();
+        let mut vol = Volume::new("mem://").unwrap();
+        vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
+        let vol = vol.into_ref();
+        let varm = VolumeArmor::<Item>::new(&vol);
+
+        let mut item = Item::new();
+        varm.save_item(&mut item).unwrap();
+        varm.save_item(&mut item).unwrap();
+        assert_eq!(varm.active_arm(item.id()), Some(item.arm));
+
+        // damage the inactive arm, load only reads the active arm
+        let other_id = item.arm.other().to_eid(item.id());
+        {
+            let mut wtr = volume::Writer::new(&other_id, &vol).unwrap();
+            wtr.write_all(&[42u8; 16]).unwrap();
+            wtr.finish().unwrap();
+        }
+        assert_eq!(varm.load_item(item.id()).unwrap(), item);
+
+        // both arms are read if active arm is unknown
+        varm.set_active_arm(item.id(), None);
+        assert!(varm.load_item(item.id()).is_err());
+
+        // fall back to both arms if active arm cannot be loaded
+        let mut item2 = Item::new();
+        varm.save_item(&mut item2).unwrap();
+        let item2_bk = item2.clone();
+        varm.save_item(&mut item2).unwrap();
+        varm.del_arm(&item2.arm.to_eid(item2.id())).unwrap();
+        assert_eq!(varm.load_item(item2.id()).unwrap(), item2_bk);
+        assert_eq!(varm.active_arm(item2.id()), Some(item2_bk.arm));
+
+        // arm holding another item is corrupted
+        let mut item3 = Item::new();
+        varm.save_item(&mut item3).unwrap();
+        varm.save_item(&mut item3).unwrap();
+        {
+            let mut buf = Vec::new();
+            item.serialize(&mut Serializer::new(&mut buf)).unwrap();
+            let mut wtr = volume::Writer::new(&item3.arm.to_eid(item3.id()), &vol).unwrap();
+            wtr.write_all(&buf).unwrap();
+            wtr.finish().unwrap();
+        }
+        assert_eq!(varm.load_item(item3.id()).unwrap_err(), Error::Corrupted);
+    }
+
+    // volume armor which counts arm reads
+    struct CountingArmor {
+        inner: VolumeArmor<Item>,
+        reads: AtomicUsize,
+    }
+
+    impl<'de> Armor<'de> for CountingArmor {
+        type Item = Item;
+        type ItemReader = volume::Reader;
+        type ItemWriter = volume::Writer;
+
+        fn get_item_reader(&self, arm_id: &Eid) -> Result<Self::ItemReader> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_item_reader(arm_id)
+        }
+
+        fn get_item_writer(&self, arm_id: &Eid) -> Result<Self::ItemWriter> {
+            self.inner.get_item_writer(arm_id)
+        }
+
+        fn del_arm(&self, arm_id: &Eid) -> Result<()> {
+            self.inner.del_arm(arm_id)
+        }
+
+        fn active_arm(&self, id: &Eid) -> Option<Arm> {
+            self.inner.active_arm(id)
+        }
+
+        fn set_active_arm(&self, id: &Eid, arm: Option<Arm>) {
+            self.inner.set_active_arm(id, arm)
+        }
+    }
+
+    #[test]
+    fn active_arm_reopen() {
+        init_env();
+        let uri = "mem://foo_arm_map";
+        let mut vol = Volume::new(uri).unwrap();
+        vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
+        let vol = vol.into_ref();
+        let varm = VolumeArmor::<Item>::new(&vol);
+
+        // save items and flush volume enough times to save both arm map
+        // deltas and new bases
+        let mut items: Vec<Item> = (0..10).map(|_| Item::new()).collect();
+        for i in 0..ARM_MAP_MAX_DELTAS as usize + 10 {
+            let item = &mut items[i % 10];
+            varm.save_item(item).unwrap();
+            if i % 3 == 0 {
+                varm.save_item(item).unwrap();
+            }
+            vol.write().unwrap().flush().unwrap();
+        }
+        let removed = items.pop().unwrap();
+        varm.remove_all_arms(removed.id()).unwrap();
+        vol.write().unwrap().flush().unwrap();
+        drop(varm);
+        drop(vol);
+
+        // re-open volume, loading each item only reads its active arm
+        let mut vol = Volume::new(uri).unwrap();
+        vol.open("pwd").unwrap();
+        let carm = CountingArmor {
+            inner: VolumeArmor::new(&vol.into_ref()),
+            reads: AtomicUsize::new(0),
+        };
+        for item in items.iter() {
+            assert_eq!(&carm.load_item(item.id()).unwrap(), item);
+        }
+        assert_eq!(carm.reads.load(Ordering::SeqCst), items.len());
+
+        // removed item is forgotten, both its arms are read
+        assert_eq!(carm.load_item(removed.id()).unwrap_err(), Error::NotFound);
+        assert_eq!(carm.reads.load(Ordering::SeqCst), items.len() + 2);
+
+        drop(carm);
+        assert!(MemStorage::destroy("foo_arm_map"));
     }
 }
@@ -0,0 +1,108 @@
+use crate::error::{Error, Result};
+use crate::{BLKS_PER_FRAME, BLK_SIZE};
+
+/// Default number of blocks in a file storage sector
+pub const BLKS_PER_SECTOR: usize = 4 * 1024;
+
+/// Volume block layout
+///
+/// Block, frame and sector sizes are chosen when volume is created and
+/// cannot be changed afterwards. Data is encrypted frame by frame and each
+/// frame is stored in blocks, sector is the unit of file storage data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Layout {
+    /// block size in bytes
+    pub blk_size: usize,
+
+    /// number of blocks in a frame
+    pub blks_per_frame: usize,
+
+    /// number of blocks in a sector
+    pub blks_per_sector: usize,
+}
+
+impl Layout {
+    // block size range, block size must also be power of 2
+    pub(crate) const MIN_BLK_SIZE: usize = 1024;
+    pub(crate) const MAX_BLK_SIZE: usize = 1024 * 1024;
+
+    // max frame size, in bytes
+    const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+    // max number of blocks in a sector, block offset in sector is u16 and
+    // u16::MAX is reserved for deleted block
+    const MAX_BLKS_PER_SECTOR: usize = u16::MAX as usize;
+
+    #[inline]
+    pub fn frame_size(&self) -> usize {
+        self.blk_size * self.blks_per_frame
+    }
+
+    #[inline]
+    pub fn sector_size(&self) -> usize {
+        self.blk_size * self.blks_per_sector
+    }
+
+    // check if the layout is valid
+    pub fn validate(&self) -> Result<()> {
+        if !self.blk_size.is_power_of_two()
+            || self.blk_size < Self::MIN_BLK_SIZE
+            || self.blk_size > Self::MAX_BLK_SIZE
+            || self.blks_per_frame == 0
+            || self.frame_size() > Self::MAX_FRAME_SIZE
+            || self.blks_per_sector == 0
+            || self.blks_per_sector > Self::MAX_BLKS_PER_SECTOR
+        {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Layout {
+    #[inline]
+    fn default() -> Self {
+        Layout {
+            blk_size: BLK_SIZE,
+            blks_per_frame: BLKS_PER_FRAME,
+            blks_per_sector: BLKS_PER_SECTOR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_layout() {
+        let layout = Layout::default();
+        assert!(layout.validate().is_ok());
+        assert_eq!(layout.frame_size(), crate::FRAME_SIZE);
+
+        let mut layout = Layout {
+            blk_size: 3000,
+            ..Default::default()
+        };
+        assert_eq!(layout.validate().unwrap_err(), Error::InvalidArgument);
+        layout.blk_size = 512;
+        assert_eq!(layout.validate().unwrap_err(), Error::InvalidArgument);
+
+        let mut layout = Layout {
+            blk_size: 1024 * 1024,
+            blks_per_frame: 4,
+            ..Default::default()
+        };
+        assert!(layout.validate().is_ok());
+        layout.blks_per_frame = 5;
+        assert_eq!(layout.validate().unwrap_err(), Error::InvalidArgument);
+
+        let mut layout = Layout {
+            blks_per_sector: 0,
+            ..Default::default()
+        };
+        assert_eq!(layout.validate().unwrap_err(), Error::InvalidArgument);
+        layout.blks_per_sector = 1 << 16;
+        assert_eq!(layout.validate().unwrap_err(), Error::InvalidArgument);
+    }
+}
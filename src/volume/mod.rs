@@ -1,11 +1,14 @@
 pub mod address;
 pub mod allocator;
 pub mod armor;
+pub mod layout;
 pub mod storage;
 pub mod super_block;
+#[allow(clippy::module_inception)]
 pub mod volume;
 
 pub use self::allocator::{Allocator, AllocatorRef};
 pub use self::armor::{Arm, ArmAccess, Armor, Seq, VolumeArmor, VolumeWalArmor};
+pub use self::layout::Layout;
 pub use self::storage::StorageRef;
 pub use self::volume::{Info, Reader, Volume, VolumeRef, Writer};
@@ -0,0 +1,847 @@
+use std::cmp::min;
+use std::fmt::{self, Debug};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use linked_hash_map::LinkedHashMap;
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+use crate::volume::storage::{CompactUnit, FileStorage, Storable};
+
+// max number of blocks read or written in one go when moving blocks
+// between cache and backend
+const MAX_RUN_BLKS: usize = 256;
+
+// file storage directory in cache directory
+const STORE_DIR: &str = "store";
+
+// marker file in cache directory, it exists only when the cache index
+// saved in file storage is up to date
+const CLEAN_MARKER: &str = "clean";
+
+/// Options for cache storage.
+///
+/// See [`CacheStorage`] for more details.
+///
+/// [`CacheStorage`]: struct.CacheStorage.html
+#[derive(Debug, Clone, Copy)]
+pub struct CacheOptions {
+    /// Cache capacity in bytes, default is 256 MiB.
+    pub capacity: usize,
+
+    /// Keep writes in cache and write them to backend on flush, rather
+    /// than write to backend immediately. Default is `false`.
+    pub write_back: bool,
+}
+
+impl CacheOptions {
+    // parse cache location in uri, which is the cache directory optionally
+    // followed by options, for example, /tmp/cache?size=256&write=back,
+    // size is in MiB
+    pub(crate) fn parse(loc: &str) -> Result<(&str, Self)> {
+        let mut opts = CacheOptions::default();
+        let (dir, query) = match loc.find('?') {
+            Some(pos) => (&loc[..pos], &loc[pos + 1..]),
+            None => (loc, ""),
+        };
+        if dir.is_empty() {
+            return Err(Error::InvalidUri);
+        }
+        if query.is_empty() {
+            return Ok((dir, opts));
+        }
+        for param in query.split('&') {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("size"), Some(size)) => {
+                    let size: usize = size.parse().map_err(|_| Error::InvalidUri)?;
+                    opts.capacity = size * 1024 * 1024;
+                }
+                (Some("write"), Some("back")) => opts.write_back = true,
+                (Some("write"), Some("through")) => opts.write_back = false,
+                _ => return Err(Error::InvalidUri),
+            }
+        }
+        if opts.capacity == 0 {
+            return Err(Error::InvalidUri);
+        }
+        Ok((dir, opts))
+    }
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            capacity: 256 * 1024 * 1024,
+            write_back: false,
+        }
+    }
+}
+
+// cached item key
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+enum CacheKey {
+    Address(Eid),
+    Block(usize),
+}
+
+// cached item, dirty item is not written to backend yet, block location is
+// the block index in cache and is not used by address
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+struct CacheEntry {
+    size: usize,
+    dirty: bool,
+    loc: usize,
+}
+
+// group blocks into runs which are consecutive both in backend and in
+// cache, blocks are given as pairs of backend index and cache location,
+// each run is a backend span and its cache location
+fn block_runs(mut blks: Vec<(usize, usize)>) -> Vec<(Span, usize)> {
+    blks.sort();
+    blks.dedup();
+    let mut runs: Vec<(Span, usize)> = Vec::new();
+    for (blk_idx, loc) in blks {
+        match runs.last_mut() {
+            Some((run, run_loc))
+                if run.end() == blk_idx && *run_loc + run.cnt == loc && run.cnt < MAX_RUN_BLKS =>
+            {
+                run.cnt += 1
+            }
+            _ => runs.push((Span::new(blk_idx, 1), loc)),
+        }
+    }
+    runs
+}
+
+/// Cache Storage
+///
+/// This storage puts a persistent cache tier in a local directory in front
+/// of a slower backend storage. Addresses and blocks are served from cache
+/// if they are cached, otherwise they are read from backend and then added
+/// to cache. Super blocks and WALs always go to backend directly.
+///
+/// Writes go to both cache and backend in write-through mode. In write-back
+/// mode, writes only go to cache and are written to backend when the
+/// storage is flushed or they are evicted from cache. Cached items are
+/// evicted in LRU order when cache size exceeds its capacity.
+///
+/// The cache tier is a [`FileStorage`] in the cache directory. As file
+/// storage only supports appending blocks, cached blocks are appended to it
+/// and mapped to their backend block indexes by the cache index. Cache
+/// content survives restart if the storage was flushed before exit,
+/// otherwise cache is cleared when it is opened. The cache directory must
+/// be used exclusively by one backend.
+///
+/// [`FileStorage`]: struct.FileStorage.html
+pub struct CacheStorage {
+    dir: PathBuf,
+    cache: FileStorage,
+    backend: Box<dyn Storable>,
+    opts: CacheOptions,
+
+    // cached items in LRU order and their total size
+    entries: LinkedHashMap<CacheKey, CacheEntry>,
+    used: usize,
+
+    // next block location to be appended in cache
+    next_loc: usize,
+
+    // if cache index saved in cache is up to date, it must be invalidated
+    // before cache is changed
+    index_saved: bool,
+
+    // volume block layout
+    layout: Layout,
+}
+
+impl CacheStorage {
+    pub fn new(dir: &Path, backend: Box<dyn Storable>, opts: CacheOptions) -> Self {
+        CacheStorage {
+            dir: dir.to_path_buf(),
+            cache: FileStorage::new(&dir.join(STORE_DIR)),
+            backend,
+            opts,
+            entries: LinkedHashMap::new(),
+            used: 0,
+            next_loc: 0,
+            index_saved: false,
+            layout: Layout::default(),
+        }
+    }
+
+    /// Returns total size of cached items, in bytes.
+    #[inline]
+    pub fn cached_size(&self) -> usize {
+        self.used
+    }
+
+    // clear cache and start from an empty cache
+    fn reset(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.remove_clean_marker()?;
+        self.index_saved = false;
+        let store_dir = self.dir.join(STORE_DIR);
+        match fs::remove_dir_all(&store_dir) {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::from(err)),
+        }
+        self.cache = FileStorage::new(&store_dir);
+        self.cache.set_layout(self.layout)?;
+        self.cache.connect()?;
+        self.cache.init(crypto, key)?;
+        self.entries.clear();
+        self.used = 0;
+        self.next_loc = 0;
+        Ok(())
+    }
+
+    // load cache index, which is saved as an address with the reserved id
+    // in cache, return false if it is not found or not up to date
+    fn load_index(&mut self) -> Result<bool> {
+        if !self.dir.join(CLEAN_MARKER).exists() {
+            return Ok(false);
+        }
+        let buf = match self.cache.get_address(&Eid::RESERVED) {
+            Ok(buf) => buf,
+            Err(ref err) if *err == Error::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let mut de = Deserializer::new(&buf[..]);
+        let (next_loc, entries): (usize, Vec<(CacheKey, CacheEntry)>) =
+            Deserialize::deserialize(&mut de)?;
+        self.entries = entries.into_iter().collect();
+        self.used = self.entries.values().map(|ent| ent.size).sum();
+        self.next_loc = next_loc;
+        self.index_saved = true;
+        Ok(true)
+    }
+
+    fn save_index(&mut self) -> Result<()> {
+        let entries: Vec<(&CacheKey, &CacheEntry)> = self.entries.iter().collect();
+        let mut buf = Vec::new();
+        (self.next_loc, entries).serialize(&mut Serializer::new(&mut buf))?;
+        self.cache.put_address(&Eid::RESERVED, &buf)?;
+        self.cache.flush()?;
+        File::create(self.dir.join(CLEAN_MARKER))?;
+        self.index_saved = true;
+        Ok(())
+    }
+
+    fn remove_clean_marker(&self) -> Result<()> {
+        match fs::remove_file(self.dir.join(CLEAN_MARKER)) {
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    // mark saved cache index as out of date before changing cache, so that
+    // cache is cleared if it is not flushed before exit, only the marker
+    // file is removed and nothing is synced
+    fn invalidate_index(&mut self) -> Result<()> {
+        if self.index_saved {
+            self.remove_clean_marker()?;
+            self.index_saved = false;
+        }
+        Ok(())
+    }
+
+    // append blocks to cache and add them to cache index
+    fn append_blocks(&mut self, span: Span, blks: &[u8], dirty: bool) -> Result<()> {
+        let blk_size = self.layout.blk_size;
+        let loc = self.next_loc;
+        self.invalidate_index()?;
+        self.cache.put_blocks(Span::new(loc, span.cnt), blks)?;
+        self.next_loc += span.cnt;
+
+        let mut replaced = Vec::new();
+        for (i, blk_idx) in span.into_iter().enumerate() {
+            let key = CacheKey::Block(blk_idx);
+            let ent = CacheEntry {
+                size: blk_size,
+                dirty,
+                loc: loc + i,
+            };
+            if let Some(old) = self.entries.insert(key.clone(), ent) {
+                self.used -= old.size;
+                replaced.push((key, old));
+            }
+            self.used += blk_size;
+        }
+        self.remove_cached(replaced)
+    }
+
+    fn remove_entry(&mut self, key: &CacheKey) -> Option<CacheEntry> {
+        let ent = self.entries.remove(key);
+        if let Some(ref ent) = ent {
+            self.used -= ent.size;
+        }
+        ent
+    }
+
+    // write cached items to backend
+    fn write_back(&mut self, items: Vec<(CacheKey, CacheEntry)>) -> Result<()> {
+        let mut blks = Vec::new();
+        for (key, ent) in items {
+            match key {
+                CacheKey::Address(id) => {
+                    let addr = self.cache.get_address(&id)?;
+                    self.backend.put_address(&id, &addr)?;
+                }
+                CacheKey::Block(blk_idx) => blks.push((blk_idx, ent.loc)),
+            }
+        }
+        for (run, loc) in block_runs(blks) {
+            let mut buf = vec![0u8; run.bytes_len(self.layout.blk_size)];
+            self.cache.get_blocks(&mut buf, Span::new(loc, run.cnt))?;
+            self.backend.put_blocks(run, &buf)?;
+        }
+        Ok(())
+    }
+
+    // remove items from cache
+    fn remove_cached(&mut self, items: Vec<(CacheKey, CacheEntry)>) -> Result<()> {
+        let mut blks = Vec::new();
+        for (key, ent) in items {
+            match key {
+                CacheKey::Address(id) => self.cache.del_address(&id)?,
+                CacheKey::Block(blk_idx) => blks.push((blk_idx, ent.loc)),
+            }
+        }
+        for (run, loc) in block_runs(blks) {
+            self.cache.del_blocks(Span::new(loc, run.cnt))?;
+        }
+        Ok(())
+    }
+
+    // evict least recently used items until cache size is within capacity,
+    // dirty items are written to backend before they are evicted
+    fn evict(&mut self) -> Result<()> {
+        let mut evicted = Vec::new();
+        let mut dirty = Vec::new();
+        while self.used > self.opts.capacity {
+            let (key, ent) = match self.entries.pop_front() {
+                Some(item) => item,
+                None => break,
+            };
+            self.used -= ent.size;
+            if ent.dirty {
+                dirty.push((key.clone(), ent));
+            }
+            evicted.push((key, ent));
+        }
+        if evicted.is_empty() {
+            return Ok(());
+        }
+        self.write_back(dirty)?;
+        self.remove_cached(evicted)
+    }
+
+    // remove cached blocks in a span
+    fn remove_blocks(&mut self, span: Span) -> Result<()> {
+        let items: Vec<(CacheKey, CacheEntry)> = span
+            .into_iter()
+            .map(CacheKey::Block)
+            .filter_map(|key| self.entries.get(&key).map(|ent| (key.clone(), *ent)))
+            .collect();
+        if items.is_empty() {
+            return Ok(());
+        }
+        for (key, _) in items.iter() {
+            self.remove_entry(key);
+        }
+        self.invalidate_index()?;
+        self.remove_cached(items)
+    }
+
+    // read cached blocks, they might not be consecutive in cache
+    fn read_cached(&mut self, dst: &mut [u8], run: Span) -> Result<()> {
+        let blks = run
+            .into_iter()
+            .map(|blk_idx| (blk_idx, self.entries[&CacheKey::Block(blk_idx)].loc))
+            .collect();
+        let blk_size = self.layout.blk_size;
+        for (span, loc) in block_runs(blks) {
+            let offset = (span.begin - run.begin) * blk_size;
+            let dst = &mut dst[offset..offset + span.bytes_len(blk_size)];
+            self.cache.get_blocks(dst, Span::new(loc, span.cnt))?;
+        }
+        Ok(())
+    }
+
+    // read blocks from cache or backend, blocks read from backend are
+    // added to cache
+    fn read_run(&mut self, dst: &mut [u8], run: Span, cached: bool) -> Result<()> {
+        if cached {
+            match self.read_cached(dst, run) {
+                Ok(_) => {
+                    for blk_idx in run {
+                        self.entries.get_refresh(&CacheKey::Block(blk_idx));
+                    }
+                    return Ok(());
+                }
+                Err(err) => {
+                    // dirty blocks only exist in cache
+                    if run.into_iter().any(|blk_idx| {
+                        self.entries
+                            .get(&CacheKey::Block(blk_idx))
+                            .map_or(false, |ent| ent.dirty)
+                    }) {
+                        return Err(err);
+                    }
+                    warn!("read cached blocks {:?} failed: {}", run, err);
+                    self.remove_blocks(run)?;
+                }
+            }
+        }
+
+        self.backend.get_blocks(dst, run)?;
+
+        // add blocks to cache
+        if run.bytes_len(self.layout.blk_size) <= self.opts.capacity {
+            self.append_blocks(run, dst, false)?;
+            self.evict()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Storable for CacheStorage {
+    #[inline]
+    fn exists(&self) -> Result<bool> {
+        self.backend.exists()
+    }
+
+    #[inline]
+    fn connect(&mut self) -> Result<()> {
+        self.backend.connect()
+    }
+
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.backend.set_layout(layout)?;
+        self.cache.set_layout(layout)?;
+        self.layout = layout;
+        Ok(())
+    }
+
+    fn init(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.backend.init(crypto.clone(), key.clone())?;
+        self.reset(crypto, key)
+    }
+
+    fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.backend.open(crypto.clone(), key.clone())?;
+
+        // use existing cache if its index is saved
+        if self.cache.exists()? {
+            let loaded = self
+                .cache
+                .connect()
+                .and_then(|_| self.cache.open(crypto.clone(), key.clone()))
+                .and_then(|_| self.load_index());
+            match loaded {
+                Ok(true) => return Ok(()),
+                Ok(false) => warn!("cache was not flushed, clear it"),
+                Err(err) => warn!("cannot load cache, clear it: {}", err),
+            }
+        }
+        self.reset(crypto, key)
+    }
+
+    #[inline]
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        self.backend.get_super_block(suffix)
+    }
+
+    #[inline]
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        self.backend.put_super_block(super_blk, suffix)
+    }
+
+    #[inline]
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.backend.get_wal(id)
+    }
+
+    #[inline]
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        self.backend.put_wal(id, wal)
+    }
+
+    #[inline]
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        self.backend.del_wal(id)
+    }
+
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        let key = CacheKey::Address(id.clone());
+
+        if let Some(ent) = self.entries.get_refresh(&key).cloned() {
+            match self.cache.get_address(id) {
+                Ok(addr) => return Ok(addr),
+                Err(err) => {
+                    // dirty address only exists in cache
+                    if ent.dirty {
+                        return Err(err);
+                    }
+                    warn!("read cached address failed: {}", err);
+                    self.remove_entry(&key);
+                }
+            }
+        }
+
+        // read from backend and add it to cache
+        let addr = self.backend.get_address(id)?;
+        self.invalidate_index()?;
+        self.cache.put_address(id, &addr)?;
+        let ent = CacheEntry {
+            size: addr.len(),
+            dirty: false,
+            loc: 0,
+        };
+        self.entries.insert(key, ent);
+        self.used += addr.len();
+        self.evict()?;
+
+        Ok(addr)
+    }
+
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        if !self.opts.write_back {
+            self.backend.put_address(id, addr)?;
+        }
+        self.invalidate_index()?;
+        self.cache.put_address(id, addr)?;
+        let ent = CacheEntry {
+            size: addr.len(),
+            dirty: self.opts.write_back,
+            loc: 0,
+        };
+        if let Some(old) = self.entries.insert(CacheKey::Address(id.clone()), ent) {
+            self.used -= old.size;
+        }
+        self.used += addr.len();
+        self.evict()
+    }
+
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        self.backend.del_address(id)?;
+        let key = CacheKey::Address(id.clone());
+        if self.remove_entry(&key).is_some() {
+            self.invalidate_index()?;
+            self.cache.del_address(id)?;
+        }
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        let blk_size = self.layout.blk_size;
+        assert_eq!(dst.len(), span.bytes_len(blk_size));
+
+        // read runs of blocks which are all cached or all not cached
+        let mut begin = span.begin;
+        while begin < span.end() {
+            let cached = self.entries.contains_key(&CacheKey::Block(begin));
+            let mut end = begin + 1;
+            while end < min(span.end(), begin + MAX_RUN_BLKS)
+                && self.entries.contains_key(&CacheKey::Block(end)) == cached
+            {
+                end += 1;
+            }
+            let run = Span::new(begin, end - begin);
+            let offset = (begin - span.begin) * blk_size;
+            let len = run.bytes_len(blk_size);
+            self.read_run(&mut dst[offset..offset + len], run, cached)?;
+            begin = end;
+        }
+
+        Ok(())
+    }
+
+    fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        // write directly to backend if it cannot fit in cache
+        let blk_size = self.layout.blk_size;
+        if span.bytes_len(blk_size) > self.opts.capacity {
+            self.backend.put_blocks(span, blks)?;
+            return self.remove_blocks(span);
+        }
+
+        if !self.opts.write_back {
+            self.backend.put_blocks(span, blks)?;
+        }
+        let dirty = self.opts.write_back;
+        self.append_blocks(span, blks, dirty)?;
+        self.evict()
+    }
+
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        self.backend.del_blocks(span)?;
+        self.remove_blocks(span)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // write dirty items to backend
+        let dirty: Vec<(CacheKey, CacheEntry)> = self
+            .entries
+            .iter()
+            .filter(|(_, ent)| ent.dirty)
+            .map(|(key, ent)| (key.clone(), *ent))
+            .collect();
+        if !dirty.is_empty() {
+            self.write_back(dirty)?;
+            for (_, ent) in self.entries.iter_mut() {
+                ent.dirty = false;
+            }
+        }
+
+        self.backend.flush()?;
+
+        if !self.index_saved {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        self.backend.can_reuse_blocks()
+    }
+
+    #[inline]
+    fn compact_candidates(
+        &mut self,
+        blk_wmark: usize,
+        dead_ratio: f32,
+    ) -> Result<Vec<CompactUnit>> {
+        self.backend.compact_candidates(blk_wmark, dead_ratio)
+    }
+
+    #[inline]
+    fn compact_unit(&mut self, idx: usize) -> Result<(usize, usize)> {
+        self.backend.compact_unit(idx)
+    }
+
+    #[inline]
+    fn deleted_blocks(&mut self, blk_wmark: usize) -> Result<Option<Vec<Span>>> {
+        self.backend.deleted_blocks(blk_wmark)
+    }
+}
+
+impl Debug for CacheStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CacheStorage")
+            .field("dir", &self.dir)
+            .field("backend", &self.backend)
+            .field("opts", &self.opts)
+            .field("used", &self.used)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::util::crypto::RandomSeed;
+    use crate::util::init_env;
+    use crate::volume::storage::{MemObjectClient, ObjectClient, ObjectStorage};
+    use crate::BLK_SIZE;
+
+    fn blocks(cnt: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; cnt * BLK_SIZE];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn read_blocks(cs: &mut CacheStorage, span: Span) -> Result<Vec<u8>> {
+        let mut dst = vec![0u8; span.bytes_len(BLK_SIZE)];
+        cs.get_blocks(&mut dst, span)?;
+        Ok(dst)
+    }
+
+    fn new_storage(dir: &Path, client: &MemObjectClient, opts: CacheOptions) -> CacheStorage {
+        let backend = Box::new(ObjectStorage::new(Box::new(client.clone())));
+        CacheStorage::new(dir, backend, opts)
+    }
+
+    // remove all objects in backend
+    fn clear_backend(client: &MemObjectClient) {
+        for key in client.list("").unwrap() {
+            client.delete(&key).unwrap();
+        }
+    }
+
+    #[test]
+    fn cache_options() {
+        let (dir, opts) = CacheOptions::parse("/tmp/foo").unwrap();
+        assert_eq!(dir, "/tmp/foo");
+        assert_eq!(opts.capacity, 256 * 1024 * 1024);
+        assert!(!opts.write_back);
+
+        let (dir, opts) = CacheOptions::parse("/tmp/foo?write=back&size=2").unwrap();
+        assert_eq!(dir, "/tmp/foo");
+        assert_eq!(opts.capacity, 2 * 1024 * 1024);
+        assert!(opts.write_back);
+
+        assert!(CacheOptions::parse("?size=2").is_err());
+        assert!(CacheOptions::parse("/tmp/foo?size=x").is_err());
+        assert!(CacheOptions::parse("/tmp/foo?bar=1").is_err());
+    }
+
+    #[test]
+    fn cache_storage() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let dir = tmpdir.path().join("cache");
+        let client = MemObjectClient::new();
+        let opts = CacheOptions {
+            capacity: 4 * BLK_SIZE,
+            write_back: false,
+        };
+        let blks = blocks(6, 42);
+        let id = Eid::new();
+
+        let mut cs = new_storage(&dir, &client, opts);
+        cs.init(Crypto::default(), Key::new_empty()).unwrap();
+        cs.put_address(&id, &[1, 2, 3]).unwrap();
+        cs.put_blocks(Span::new(0, 2), &blks[..2 * BLK_SIZE])
+            .unwrap();
+        cs.flush().unwrap();
+        assert!(!client.list("addr/").unwrap().is_empty());
+
+        // cached items are served without backend
+        clear_backend(&client);
+        assert_eq!(cs.get_address(&id).unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            read_blocks(&mut cs, Span::new(0, 2)).unwrap(),
+            &blks[..2 * BLK_SIZE]
+        );
+
+        // least recently used items are evicted
+        cs.put_blocks(Span::new(2, 4), &blks[2 * BLK_SIZE..])
+            .unwrap();
+        assert!(cs.cached_size() <= opts.capacity);
+        assert!(cs.get_address(&id).is_err());
+        assert!(read_blocks(&mut cs, Span::new(0, 1)).is_err());
+        assert_eq!(
+            read_blocks(&mut cs, Span::new(2, 4)).unwrap(),
+            &blks[2 * BLK_SIZE..]
+        );
+
+        // cache persists after flush
+        cs.flush().unwrap();
+        let cached_size = cs.cached_size();
+        drop(cs);
+        let mut cs = new_storage(&dir, &client, opts);
+        cs.connect().unwrap();
+        cs.open(Crypto::default(), Key::new_empty()).unwrap();
+        assert_eq!(cs.cached_size(), cached_size);
+        clear_backend(&client);
+        assert_eq!(
+            read_blocks(&mut cs, Span::new(3, 2)).unwrap(),
+            &blks[3 * BLK_SIZE..5 * BLK_SIZE]
+        );
+
+        // cache is cleared if not flushed
+        cs.put_address(&id, &[4, 5]).unwrap();
+        drop(cs);
+        let mut cs = new_storage(&dir, &client, opts);
+        cs.connect().unwrap();
+        cs.open(Crypto::default(), Key::new_empty()).unwrap();
+        assert_eq!(cs.cached_size(), 0);
+        assert_eq!(cs.get_address(&id).unwrap(), vec![4, 5]);
+    }
+
+    #[test]
+    fn cache_random_blocks() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let dir = tmpdir.path().join("cache");
+        let client = MemObjectClient::new();
+        let opts = CacheOptions {
+            capacity: 64 * BLK_SIZE,
+            write_back: true,
+        };
+        let blks = blocks(16, 42);
+
+        // blocks are cached in any order and can be overwritten
+        let mut cs = new_storage(&dir, &client, opts);
+        cs.init(Crypto::default(), Key::new_empty()).unwrap();
+        for blk_idx in (0..16).rev() {
+            let offset = blk_idx * BLK_SIZE;
+            cs.put_blocks(Span::new(blk_idx, 1), &blks[offset..offset + BLK_SIZE])
+                .unwrap();
+        }
+        assert!(!dir.join(CLEAN_MARKER).exists());
+        let blks2 = blocks(4, 43);
+        cs.put_blocks(Span::new(6, 4), &blks2).unwrap();
+        cs.flush().unwrap();
+        assert!(dir.join(CLEAN_MARKER).exists());
+        assert_eq!(cs.cached_size(), 16 * BLK_SIZE);
+
+        // cached blocks are read after reopen without backend
+        drop(cs);
+        let mut cs = new_storage(&dir, &client, opts);
+        cs.connect().unwrap();
+        cs.open(Crypto::default(), Key::new_empty()).unwrap();
+        clear_backend(&client);
+        let dst = read_blocks(&mut cs, Span::new(0, 16)).unwrap();
+        assert_eq!(&dst[..6 * BLK_SIZE], &blks[..6 * BLK_SIZE]);
+        assert_eq!(&dst[6 * BLK_SIZE..10 * BLK_SIZE], &blks2[..]);
+        assert_eq!(&dst[10 * BLK_SIZE..], &blks[10 * BLK_SIZE..]);
+
+        // changing cache invalidates saved index
+        cs.del_blocks(Span::new(0, 1)).unwrap();
+        assert!(!dir.join(CLEAN_MARKER).exists());
+    }
+
+    #[test]
+    fn cache_write_back() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let client = MemObjectClient::new();
+        let opts = CacheOptions {
+            capacity: 4 * BLK_SIZE,
+            write_back: true,
+        };
+        let blks = blocks(6, 42);
+        let id = Eid::new();
+        let id2 = Eid::new();
+
+        let mut cs = new_storage(tmpdir.path(), &client, opts);
+        cs.init(Crypto::default(), Key::new_empty()).unwrap();
+
+        // writes stay in cache until flush
+        cs.put_address(&id, &[1, 2, 3]).unwrap();
+        assert!(client.list("addr/").unwrap().is_empty());
+        assert_eq!(cs.get_address(&id).unwrap(), vec![1, 2, 3]);
+        cs.flush().unwrap();
+        assert_eq!(client.list("addr/").unwrap().len(), 1);
+
+        // evicted dirty items are written to backend
+        cs.put_address(&id2, &[4]).unwrap();
+        cs.put_blocks(Span::new(0, 4), &blks[..4 * BLK_SIZE])
+            .unwrap();
+        assert_eq!(client.list("addr/").unwrap().len(), 2);
+        cs.put_blocks(Span::new(4, 2), &blks[4 * BLK_SIZE..])
+            .unwrap();
+        assert!(cs.cached_size() <= opts.capacity);
+        assert_eq!(read_blocks(&mut cs, Span::new(0, 6)).unwrap(), blks);
+
+        // spans larger than cache bypass it
+        let blks2 = blocks(5, 43);
+        cs.put_blocks(Span::new(0, 5), &blks2).unwrap();
+        cs.flush().unwrap();
+        assert_eq!(read_blocks(&mut cs, Span::new(0, 5)).unwrap(), blks2);
+    }
+}
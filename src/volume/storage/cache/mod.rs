@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+mod cache;
+
+pub use self::cache::{CacheOptions, CacheStorage};
@@ -0,0 +1,1009 @@
+use std::fmt::{self, Debug};
+use std::io::ErrorKind;
+use std::ops::Range;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::{BlockSet, Span};
+use crate::volume::layout::Layout;
+use crate::volume::storage::manifest::{del_address_if_exists, Manifest};
+use crate::volume::storage::Storable;
+use crate::BLK_SIZE;
+
+// super block suffixes
+const SUPER_BLK_SUFFIXES: [u64; 2] = [0, 1];
+
+// write journal is saved as an address with this id, which is the reserved
+// id with its last byte changed
+fn journal_id() -> Eid {
+    let mut buf = [0xff; Eid::EID_SIZE];
+    buf[Eid::EID_SIZE - 1] = 0xfe;
+    Eid::from_slice(&buf)
+}
+
+// check if an error means the member itself has failed, such as I/O error
+// or corrupted data, other errors like NotFound don't fail the member
+fn is_member_failure(err: &Error) -> bool {
+    match *err.inner() {
+        Error::Io(ref err) => err.kind() != ErrorKind::NotFound,
+        Error::Corrupted | Error::InvalidSuperBlk | Error::Decrypt | Error::Decode(_) => true,
+        _ => false,
+    }
+}
+
+/// Erasure Coded Storage
+///
+/// This storage spreads blocks over `k + m` member storages using
+/// Reed-Solomon erasure coding. Every `k` consecutive blocks form a stripe,
+/// block `i` of a stripe is stored in member `i` and `m` parity blocks
+/// calculated from the stripe are stored in the last `m` members. Blocks
+/// can be reconstructed from any `k` members, so the storage can survive
+/// failure of up to `m` members.
+///
+/// Super blocks, WALs and addresses are small, they are fully replicated to
+/// all members.
+///
+/// A member failed with I/O error or corrupted data is marked as failed and
+/// is no longer used, the storage then runs in degraded state until the
+/// member is rebuilt by [`rebuild`]. When the storage is opened, members
+/// which are replaced or have missed writes are rebuilt automatically.
+///
+/// Stripes are recorded in a write journal before they are updated in
+/// place, if the storage is not flushed after that, their parity blocks are
+/// recalculated when it is opened again. This protects blocks in a stripe
+/// from being reconstructed with stale parity blocks.
+///
+/// [`rebuild`]: struct.ErasureStorage.html#method.rebuild
+pub struct ErasureStorage {
+    members: Vec<Box<dyn Storable>>,
+    data_shards: usize,
+    codec: ReedSolomon,
+
+    // failed members
+    failed: Vec<bool>,
+
+    // erasure manifest, its block information is the set of live blocks,
+    // blocks not live are treated as zeros when calculating parity shards
+    manifest: Manifest<BlockSet>,
+
+    // stripes updated since last flush, saved as the write journal
+    journal: BlockSet,
+
+    // crypto context, used to initialise replaced members
+    ctx: Option<(Crypto, Key)>,
+
+    blk_size: usize,
+}
+
+impl ErasureStorage {
+    /// Create erasure coded storage with `data_shards` data members and
+    /// `parity_shards` parity members.
+    ///
+    /// Returns [`InvalidArgument`] if number of members is not
+    /// `data_shards + parity_shards` or the shard numbers are not
+    /// supported.
+    ///
+    /// [`InvalidArgument`]: ../../error/enum.Error.html#variant.InvalidArgument
+    pub fn new(
+        data_shards: usize,
+        parity_shards: usize,
+        members: Vec<Box<dyn Storable>>,
+    ) -> Result<Self> {
+        if members.len() != data_shards + parity_shards {
+            return Err(Error::InvalidArgument);
+        }
+        let codec =
+            ReedSolomon::new(data_shards, parity_shards).map_err(|_| Error::InvalidArgument)?;
+        Ok(ErasureStorage {
+            failed: vec![false; members.len()],
+            members,
+            data_shards,
+            codec,
+            manifest: Manifest::default(),
+            journal: BlockSet::default(),
+            ctx: None,
+            blk_size: BLK_SIZE,
+        })
+    }
+
+    /// Returns whether a member has failed.
+    #[inline]
+    pub fn is_failed(&self, idx: usize) -> bool {
+        self.failed[idx]
+    }
+
+    /// Returns whether the storage is in degraded state, that is, some of
+    /// its members have failed.
+    #[inline]
+    pub fn is_degraded(&self) -> bool {
+        self.failed.iter().any(|f| *f)
+    }
+
+    // mark member as failed if the error is a member failure
+    fn check_failure(&mut self, idx: usize, err: &Error) {
+        if !self.failed[idx] && is_member_failure(err) {
+            warn!(
+                "erasure member#{} failed, storage is degraded: {}",
+                idx, err
+            );
+            self.failed[idx] = true;
+        }
+    }
+
+    #[inline]
+    fn alive_cnt(&self) -> usize {
+        self.failed.iter().filter(|f| !**f).count()
+    }
+
+    // member span of stripes which cover the block span
+    #[inline]
+    fn stripe_span(&self, span: Span) -> Span {
+        let k = self.data_shards;
+        let begin = span.begin / k;
+        Span::new(begin, (span.end() + k - 1) / k - begin)
+    }
+
+    // read replicated data from the first alive member which has it
+    fn read_replica<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Box<dyn Storable>) -> Result<T>,
+    {
+        let mut first_err = None;
+        for (idx, member) in self.members.iter_mut().enumerate() {
+            if self.failed[idx] {
+                continue;
+            }
+            match f(member) {
+                Ok(ret) => return Ok(ret),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        Err(first_err.unwrap_or(Error::NoMember))
+    }
+
+    // write to alive members in the range, members failed to write are
+    // marked as failed, other errors are returned immediately
+    fn write_members<F>(&mut self, range: Range<usize>, mut f: F) -> Result<Option<Error>>
+    where
+        F: FnMut(usize, &mut Box<dyn Storable>) -> Result<()>,
+    {
+        let mut first_err = None;
+        for idx in range {
+            if self.failed[idx] {
+                continue;
+            }
+            if let Err(err) = f(idx, &mut self.members[idx]) {
+                if !is_member_failure(&err) {
+                    return Err(err);
+                }
+                self.check_failure(idx, &err);
+                first_err.get_or_insert(err);
+            }
+        }
+        Ok(first_err)
+    }
+
+    // write replicated data, it is successful if at least one member is
+    // still alive
+    fn write_replica<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Box<dyn Storable>) -> Result<()>,
+    {
+        let n = self.members.len();
+        let err = self.write_members(0..n, |_, m| f(m))?;
+        match err {
+            Some(err) if self.alive_cnt() == 0 => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    // write shards, it is successful if there are still enough members
+    // alive to reconstruct data
+    fn write_shards<F>(&mut self, range: Range<usize>, f: F) -> Result<()>
+    where
+        F: FnMut(usize, &mut Box<dyn Storable>) -> Result<()>,
+    {
+        let err = self.write_members(range, f)?;
+        if self.alive_cnt() < self.data_shards {
+            return Err(err.unwrap_or(Error::NoMember));
+        }
+        Ok(())
+    }
+
+    // save manifest changes since last flush to all alive members
+    fn save_manifest(&mut self) -> Result<()> {
+        for (id, buf) in self.manifest.writes()? {
+            match buf {
+                Some(buf) => self.write_replica(|m| m.put_address(&id, &buf))?,
+                None => self.write_replica(|m| del_address_if_exists(m, &id))?,
+            }
+        }
+        self.manifest.saved();
+        Ok(())
+    }
+
+    // record stripes in write journal before they are updated in place,
+    // the journal must be persistent before any of the stripes is written
+    fn journal_stripes(&mut self, stripes: Span) -> Result<()> {
+        if self.journal.contains_span(stripes) {
+            return Ok(());
+        }
+        self.journal.insert(stripes);
+        let mut buf = Vec::new();
+        self.journal.serialize(&mut Serializer::new(&mut buf))?;
+        self.write_replica(|m| m.put_address(&journal_id(), &buf))?;
+        self.write_replica(|m| m.flush())
+    }
+
+    // recalculate parity shards of stripes left in write journal, which
+    // might be partially updated when the storage was not flushed
+    fn recover_journal(&mut self) -> Result<()> {
+        let buf = match self.read_replica(|m| m.get_address(&journal_id())) {
+            Ok(buf) => buf,
+            Err(ref err) if *err == Error::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut de = Deserializer::new(&buf[..]);
+        let journal: BlockSet = Deserialize::deserialize(&mut de)?;
+
+        let k = self.data_shards;
+        let n = self.members.len();
+        for stripe in journal.spans().flatten() {
+            let stripe_span = Span::new(stripe, 1);
+            let mut shards = vec![vec![0u8; self.blk_size]; n];
+            let mut is_complete = true;
+            for (idx, shard) in shards.iter_mut().take(k).enumerate() {
+                if !self.manifest.blks().contains(stripe * k + idx) {
+                    continue;
+                }
+                if self.failed[idx] {
+                    is_complete = false;
+                    break;
+                }
+                if let Err(err) = self.members[idx].get_blocks(shard, stripe_span) {
+                    self.check_failure(idx, &err);
+                    is_complete = false;
+                    break;
+                }
+            }
+            if !is_complete {
+                warn!("erasure stripe#{} cannot be recovered from journal", stripe);
+                continue;
+            }
+            self.encode(&mut shards)?;
+            self.write_shards(k..n, |idx, m| m.put_blocks(stripe_span, &shards[idx]))?;
+        }
+
+        self.write_replica(|m| m.flush())?;
+        self.write_replica(|m| del_address_if_exists(m, &journal_id()))
+    }
+
+    // calculate parity shards, all shards have the same length
+    fn encode(&self, shards: &mut [Vec<u8>]) -> Result<()> {
+        self.codec.encode(shards).map_err(|err| {
+            error!("erasure encode failed: {:?}", err);
+            Error::Corrupted
+        })
+    }
+
+    // reconstruct data blocks in a stripe from alive members
+    fn reconstruct(&mut self, stripe: usize) -> Result<Vec<Vec<u8>>> {
+        let k = self.data_shards;
+        let blk_size = self.blk_size;
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(self.members.len());
+        let mut present = 0;
+
+        for idx in 0..self.members.len() {
+            let shard = if idx < k && !self.manifest.blks().contains(stripe * k + idx) {
+                // block not live is zero
+                Some(vec![0u8; blk_size])
+            } else if self.failed[idx] {
+                None
+            } else {
+                let mut buf = vec![0u8; blk_size];
+                match self.members[idx].get_blocks(&mut buf, Span::new(stripe, 1)) {
+                    Ok(_) => Some(buf),
+                    Err(err) => {
+                        self.check_failure(idx, &err);
+                        None
+                    }
+                }
+            };
+            if shard.is_some() {
+                present += 1;
+            }
+            shards.push(shard);
+        }
+
+        if present < k {
+            return Err(Error::Corrupted);
+        }
+        self.codec.reconstruct_data(&mut shards).map_err(|err| {
+            error!("erasure reconstruct failed: {:?}", err);
+            Error::Corrupted
+        })?;
+
+        Ok(shards.into_iter().take(k).map(|s| s.unwrap()).collect())
+    }
+
+    // read blocks, blocks in the span must be live
+    fn read_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        let k = self.data_shards;
+        let blk_size = self.blk_size;
+        let last = span.end() - 1;
+
+        for idx in 0..k {
+            // blocks in span stored in this member
+            let first = span.begin + (idx + k - span.begin % k) % k;
+            if first > last {
+                continue;
+            }
+            let end = last - (last % k + k - idx) % k;
+            let mspan = Span::new(first / k, end / k - first / k + 1);
+
+            let mut buf = vec![0u8; mspan.bytes_len(blk_size)];
+            let is_read = !self.failed[idx]
+                && match self.members[idx].get_blocks(&mut buf, mspan) {
+                    Ok(_) => true,
+                    Err(err) => {
+                        self.check_failure(idx, &err);
+                        false
+                    }
+                };
+
+            for stripe in mspan {
+                let offset = (stripe * k + idx - span.begin) * blk_size;
+                let dst = &mut dst[offset..offset + blk_size];
+                if is_read {
+                    let offset = (stripe - mspan.begin) * blk_size;
+                    dst.copy_from_slice(&buf[offset..offset + blk_size]);
+                } else {
+                    let data = self.reconstruct(stripe)?;
+                    dst.copy_from_slice(&data[idx]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild a member from the other members.
+    ///
+    /// Super blocks, WALs and addresses are copied from the other members
+    /// and blocks of the member are reconstructed from them, the member is
+    /// then marked as alive. It is used to rebuild a member which has been
+    /// replaced, has missed writes or has failed.
+    ///
+    /// The storage must be initialised or opened before rebuild, and at
+    /// least `data_shards` other members must be alive.
+    pub fn rebuild(&mut self, idx: usize) -> Result<()> {
+        let (crypto, key) = self.ctx.clone().ok_or(Error::Closed)?;
+        let k = self.data_shards;
+        let n = self.members.len();
+
+        let was_failed = self.failed[idx];
+
+        // don't read from the member being rebuilt
+        self.failed[idx] = true;
+        if self.alive_cnt() < k {
+            self.failed[idx] = was_failed;
+            return Err(Error::NoMember);
+        }
+
+        let old = {
+            let member = &mut self.members[idx];
+            if member.exists()? {
+                member.open(crypto, key)?;
+                Manifest::<BlockSet>::load(member)?
+            } else {
+                member.init(crypto, key)?;
+                None
+            }
+        };
+
+        // super blocks, wals and addresses, they might be deleted after
+        // manifest is saved, so those not found are skipped
+        for suffix in SUPER_BLK_SUFFIXES.iter() {
+            match self.read_replica(|m| m.get_super_block(*suffix)) {
+                Ok(buf) => self.members[idx].put_super_block(&buf, *suffix)?,
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let manifest = self.manifest.clone();
+        for id in manifest.wals().iter() {
+            match self.read_replica(|m| m.get_wal(id)) {
+                Ok(buf) => self.members[idx].put_wal(id, &buf)?,
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        for id in manifest.addrs().iter() {
+            match self.read_replica(|m| m.get_address(id)) {
+                Ok(buf) => self.members[idx].put_address(id, &buf)?,
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // remove stale wals, addresses and manifest deltas left in the member
+        if let Some(old) = old {
+            let member = &mut self.members[idx];
+            for id in old.wals().difference(manifest.wals()) {
+                member.del_wal(id)?;
+            }
+            for id in old.addrs().difference(manifest.addrs()) {
+                member.del_address(id)?;
+            }
+            for id in old.delta_ids() {
+                del_address_if_exists(member, &id)?;
+            }
+        }
+
+        // reconstruct shards of the member in stripes which have live blocks
+        let mut last_stripe = None;
+        for span in manifest.blks().spans() {
+            for stripe in self.stripe_span(span) {
+                if last_stripe == Some(stripe) {
+                    continue;
+                }
+                last_stripe = Some(stripe);
+                if idx < k && !manifest.blks().contains(stripe * k + idx) {
+                    continue;
+                }
+                let mut shards = self.reconstruct(stripe)?;
+                shards.resize(n, vec![0u8; self.blk_size]);
+                self.encode(&mut shards)?;
+                self.members[idx].put_blocks(Span::new(stripe, 1), &shards[idx])?;
+            }
+        }
+
+        // save manifest as a new base and flush the member
+        let member = &mut self.members[idx];
+        member.put_address(&Eid::RESERVED, &manifest.seri()?)?;
+        member.flush()?;
+        self.failed[idx] = false;
+        debug!("erasure member#{} rebuilt", idx);
+
+        Ok(())
+    }
+}
+
+impl Storable for ErasureStorage {
+    fn exists(&self) -> Result<bool> {
+        for member in self.members.iter() {
+            if member.exists()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.connect()?;
+        }
+        Ok(())
+    }
+
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.set_layout(layout)?;
+        }
+        self.blk_size = layout.blk_size;
+        Ok(())
+    }
+
+    fn init(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.init(crypto.clone(), key.clone())?;
+        }
+        self.ctx = Some((crypto, key));
+        self.failed = vec![false; self.members.len()];
+        self.manifest = Manifest::new();
+        self.journal = BlockSet::default();
+        Ok(())
+    }
+
+    fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.ctx = Some((crypto.clone(), key.clone()));
+        self.journal = BlockSet::default();
+
+        // open existing members and load their manifests, replaced members
+        // don't exist and have no manifest
+        let mut manifests = Vec::new();
+        for member in self.members.iter_mut() {
+            if member.exists()? {
+                member.open(crypto.clone(), key.clone())?;
+                manifests.push(Manifest::load(member)?);
+            } else {
+                manifests.push(None);
+            }
+        }
+
+        // use the latest manifest, if no member has manifest the storage
+        // has never been flushed and there is nothing to rebuild
+        let latest = manifests
+            .iter()
+            .filter_map(|m| m.as_ref())
+            .max_by_key(|m| m.seq())
+            .cloned();
+        let latest = match latest {
+            Some(latest) => latest,
+            None => {
+                self.failed = vec![false; self.members.len()];
+                self.manifest = Manifest::default();
+                return Ok(());
+            }
+        };
+
+        // members behind the latest manifest have missed writes
+        self.failed = manifests
+            .iter()
+            .map(|m| m.as_ref().map_or(true, |m| m.seq() < latest.seq()))
+            .collect();
+        self.manifest = latest;
+
+        // fix interrupted stripe updates before rebuilding any member, as
+        // rebuild relies on parity shards
+        self.recover_journal()?;
+
+        for idx in 0..self.members.len() {
+            if self.failed[idx] {
+                warn!("erasure member#{} missed writes, rebuild it", idx);
+                self.rebuild(idx)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        self.read_replica(|m| m.get_super_block(suffix))
+    }
+
+    #[inline]
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        self.write_replica(|m| m.put_super_block(super_blk, suffix))
+    }
+
+    #[inline]
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.read_replica(|m| m.get_wal(id))
+    }
+
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        self.write_replica(|m| m.put_wal(id, wal))?;
+        self.manifest.put_wal(id);
+        Ok(())
+    }
+
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        self.write_replica(|m| m.del_wal(id))?;
+        self.manifest.del_wal(id);
+        Ok(())
+    }
+
+    #[inline]
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.read_replica(|m| m.get_address(id))
+    }
+
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        self.write_replica(|m| m.put_address(id, addr))?;
+        self.manifest.put_address(id);
+        Ok(())
+    }
+
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        self.write_replica(|m| m.del_address(id))?;
+        self.manifest.del_address(id);
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        assert_eq!(dst.len(), span.bytes_len(self.blk_size));
+        if !self.manifest.blks().contains_span(span) {
+            return Err(Error::NotFound);
+        }
+        self.read_blocks(dst, span)
+    }
+
+    fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        let k = self.data_shards;
+        let blk_size = self.blk_size;
+        assert_eq!(blks.len(), span.bytes_len(blk_size));
+        let stripes = self.stripe_span(span);
+
+        // stripes having live blocks are updated in place
+        if self
+            .manifest
+            .blks()
+            .intersects(Span::new(stripes.begin * k, stripes.cnt * k))
+        {
+            self.journal_stripes(stripes)?;
+        }
+
+        // fill data shards with blocks in the stripes, blocks not in the
+        // span are read from storage if they are live, otherwise are zeros
+        let mut shards = vec![vec![0u8; stripes.bytes_len(blk_size)]; self.members.len()];
+        for stripe in stripes {
+            let offset = (stripe - stripes.begin) * blk_size;
+            for (idx, shard) in shards.iter_mut().take(k).enumerate() {
+                let blk_idx = stripe * k + idx;
+                let dst = &mut shard[offset..offset + blk_size];
+                if blk_idx >= span.begin && blk_idx < span.end() {
+                    let pos = (blk_idx - span.begin) * blk_size;
+                    dst.copy_from_slice(&blks[pos..pos + blk_size]);
+                } else if self.manifest.blks().contains(blk_idx) {
+                    self.read_blocks(dst, Span::new(blk_idx, 1))?;
+                }
+            }
+        }
+        self.encode(&mut shards)?;
+
+        let n = self.members.len();
+        self.write_shards(0..n, |idx, m| m.put_blocks(stripes, &shards[idx]))?;
+
+        self.manifest.blks_mut().insert(span);
+        Ok(())
+    }
+
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        let k = self.data_shards;
+        let n = self.members.len();
+        let stripes = self.stripe_span(span);
+        self.journal_stripes(stripes)?;
+
+        // blocks are still live until the parity shards are updated, as
+        // they are needed to reconstruct blocks from failed members
+        let mut live = self.manifest.blks().clone();
+        live.remove(span);
+
+        for stripe in stripes {
+            let stripe_span = Span::new(stripe, 1);
+
+            // stripe has no live blocks, remove it from all members
+            if !live.intersects(Span::new(stripe * k, k)) {
+                self.write_shards(0..n, |_, m| m.del_blocks(stripe_span))?;
+                continue;
+            }
+
+            // otherwise re-calculate its parity shards
+            let mut shards = vec![vec![0u8; self.blk_size]; n];
+            for (idx, shard) in shards.iter_mut().take(k).enumerate() {
+                let blk_idx = stripe * k + idx;
+                if live.contains(blk_idx) {
+                    self.read_blocks(shard, Span::new(blk_idx, 1))?;
+                }
+            }
+            self.encode(&mut shards)?;
+            self.write_shards(k..n, |idx, m| m.put_blocks(stripe_span, &shards[idx]))?;
+        }
+
+        *self.manifest.blks_mut() = live;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.manifest.is_changed() {
+            self.save_manifest()?;
+        }
+        self.write_replica(|m| m.flush())?;
+
+        // all stripe updates are persistent now, clear the write journal
+        if !self.journal.is_empty() {
+            self.journal = BlockSet::default();
+            self.write_replica(|m| del_address_if_exists(m, &journal_id()))?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        self.members.iter().all(|m| m.can_reuse_blocks())
+    }
+}
+
+impl Debug for ErasureStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErasureStorage")
+            .field("members", &self.members)
+            .field("data_shards", &self.data_shards)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::repo::RepoOpener;
+    use crate::util::init_env;
+    use crate::volume::storage::MemStorage;
+
+    // memory storage which fails all block operations when it is broken
+    #[derive(Debug)]
+    struct BrokenStorage {
+        inner: MemStorage,
+        broken: Arc<AtomicBool>,
+    }
+
+    impl BrokenStorage {
+        fn check(&self) -> Result<()> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(Error::Corrupted);
+            }
+            Ok(())
+        }
+    }
+
+    impl Storable for BrokenStorage {
+        fn exists(&self) -> Result<bool> {
+            self.inner.exists()
+        }
+
+        fn connect(&mut self) -> Result<()> {
+            self.inner.connect()
+        }
+
+        fn init(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+            self.inner.init(crypto, key)
+        }
+
+        fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+            self.inner.open(crypto, key)
+        }
+
+        fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+            self.inner.get_super_block(suffix)
+        }
+
+        fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+            self.inner.put_super_block(super_blk, suffix)
+        }
+
+        fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+            self.inner.get_wal(id)
+        }
+
+        fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+            self.inner.put_wal(id, wal)
+        }
+
+        fn del_wal(&mut self, id: &Eid) -> Result<()> {
+            self.inner.del_wal(id)
+        }
+
+        fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+            self.inner.get_address(id)
+        }
+
+        fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+            self.inner.put_address(id, addr)
+        }
+
+        fn del_address(&mut self, id: &Eid) -> Result<()> {
+            self.inner.del_address(id)
+        }
+
+        fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+            self.check()?;
+            self.inner.get_blocks(dst, span)
+        }
+
+        fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+            self.check()?;
+            self.inner.put_blocks(span, blks)
+        }
+
+        fn del_blocks(&mut self, span: Span) -> Result<()> {
+            self.check()?;
+            self.inner.del_blocks(span)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    fn blocks(cnt: usize, seed: u8) -> Vec<u8> {
+        (0..cnt * BLK_SIZE)
+            .map(|i| (i / BLK_SIZE) as u8 ^ seed)
+            .collect()
+    }
+
+    #[test]
+    fn erasure_degraded() {
+        init_env();
+        let broken = Arc::new(AtomicBool::new(false));
+        let members: Vec<Box<dyn Storable>> = vec![
+            Box::new(BrokenStorage {
+                inner: MemStorage::new(),
+                broken: broken.clone(),
+            }),
+            Box::new(MemStorage::new()),
+            Box::new(MemStorage::new()),
+        ];
+        assert_eq!(
+            ErasureStorage::new(3, 1, Vec::new()).unwrap_err(),
+            Error::InvalidArgument
+        );
+        let mut es = ErasureStorage::new(2, 1, members).unwrap();
+        es.connect().unwrap();
+        es.init(Crypto::default(), Key::new_empty()).unwrap();
+
+        // partial stripe writes and deletes
+        let blks = blocks(3, 1);
+        let blks2 = blocks(2, 2);
+        es.put_super_block(&[1, 2, 3], 0).unwrap();
+        es.put_blocks(Span::new(0, 3), &blks).unwrap();
+        es.put_blocks(Span::new(3, 2), &blks2).unwrap();
+        es.del_blocks(Span::new(0, 1)).unwrap();
+        es.flush().unwrap();
+        assert!(!es.is_degraded());
+
+        // member 0 keeps blocks 0, 2 and 4, they are reconstructed from
+        // other members
+        broken.store(true, Ordering::SeqCst);
+        let mut dst = vec![0u8; 4 * BLK_SIZE];
+        es.get_blocks(&mut dst, Span::new(1, 4)).unwrap();
+        assert_eq!(&dst[..2 * BLK_SIZE], &blks[BLK_SIZE..]);
+        assert_eq!(&dst[2 * BLK_SIZE..], &blks2[..]);
+        assert!(es.is_degraded());
+        assert!(es.is_failed(0));
+        assert_eq!(
+            es.get_blocks(&mut dst[..BLK_SIZE], Span::new(0, 1))
+                .unwrap_err(),
+            Error::NotFound
+        );
+        assert_eq!(es.get_super_block(0).unwrap(), vec![1, 2, 3]);
+
+        // write in degraded state
+        let blks3 = blocks(3, 3);
+        es.put_blocks(Span::new(5, 3), &blks3).unwrap();
+        es.del_blocks(Span::new(3, 1)).unwrap();
+        let mut dst = vec![0u8; BLK_SIZE];
+        es.get_blocks(&mut dst, Span::new(2, 1)).unwrap();
+        assert_eq!(&dst[..], &blks[2 * BLK_SIZE..]);
+        let mut dst = vec![0u8; 4 * BLK_SIZE];
+        es.get_blocks(&mut dst, Span::new(4, 4)).unwrap();
+        assert_eq!(&dst[..BLK_SIZE], &blks2[BLK_SIZE..]);
+        assert_eq!(&dst[BLK_SIZE..], &blks3[..]);
+
+        // rebuild the failed member
+        broken.store(false, Ordering::SeqCst);
+        es.rebuild(0).unwrap();
+        assert!(!es.is_degraded());
+        let mut dst = vec![0u8; BLK_SIZE];
+        es.members[0].get_blocks(&mut dst, Span::new(1, 1)).unwrap();
+        assert_eq!(&dst[..], &blks[2 * BLK_SIZE..]);
+        es.members[0].get_blocks(&mut dst, Span::new(3, 1)).unwrap();
+        assert_eq!(&dst[..], &blks3[BLK_SIZE..2 * BLK_SIZE]);
+        assert_eq!(es.members[0].get_super_block(0).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn erasure_not_found() {
+        init_env();
+        let members: Vec<Box<dyn Storable>> = vec![
+            Box::new(MemStorage::new()),
+            Box::new(MemStorage::new()),
+            Box::new(MemStorage::new()),
+        ];
+        let mut es = ErasureStorage::new(2, 1, members).unwrap();
+        es.connect().unwrap();
+        es.init(Crypto::default(), Key::new_empty()).unwrap();
+        let blks = blocks(2, 1);
+        es.put_blocks(Span::new(0, 2), &blks).unwrap();
+        es.flush().unwrap();
+
+        // missing block is reconstructed but doesn't fail the member
+        es.members[0].del_blocks(Span::new(0, 1)).unwrap();
+        let mut dst = vec![0u8; 2 * BLK_SIZE];
+        es.get_blocks(&mut dst, Span::new(0, 2)).unwrap();
+        assert_eq!(dst, blks);
+        assert!(!es.is_degraded());
+    }
+
+    #[test]
+    fn erasure_write_hole() {
+        init_env();
+        let broken = Arc::new(AtomicBool::new(false));
+        let names = [
+            "erasure_write_hole0",
+            "erasure_write_hole1",
+            "erasure_write_hole2",
+        ];
+        let open_members = || -> Vec<Box<dyn Storable>> {
+            vec![
+                Box::new(BrokenStorage {
+                    inner: MemStorage::with_name(names[0]),
+                    broken: broken.clone(),
+                }),
+                Box::new(MemStorage::with_name(names[1])),
+                Box::new(MemStorage::with_name(names[2])),
+            ]
+        };
+        let mut es = ErasureStorage::new(2, 1, open_members()).unwrap();
+        es.connect().unwrap();
+        es.init(Crypto::default(), Key::new_empty()).unwrap();
+        let blks = blocks(2, 1);
+        es.put_blocks(Span::new(0, 2), &blks).unwrap();
+        es.flush().unwrap();
+
+        // update a block in place, but parity shard is not updated as the
+        // storage crashed before it is written
+        let mut parity = vec![0u8; BLK_SIZE];
+        es.members[2]
+            .get_blocks(&mut parity, Span::new(0, 1))
+            .unwrap();
+        let blks2 = blocks(1, 2);
+        es.put_blocks(Span::new(0, 1), &blks2).unwrap();
+        es.members[2].put_blocks(Span::new(0, 1), &parity).unwrap();
+        drop(es);
+
+        // parity shard is recalculated when the storage is opened, so the
+        // updated block can be reconstructed
+        let mut es = ErasureStorage::new(2, 1, open_members()).unwrap();
+        es.connect().unwrap();
+        es.open(Crypto::default(), Key::new_empty()).unwrap();
+        broken.store(true, Ordering::SeqCst);
+        let mut dst = vec![0u8; BLK_SIZE];
+        es.get_blocks(&mut dst, Span::new(0, 1)).unwrap();
+        assert_eq!(dst, blks2);
+        assert!(es.is_failed(0));
+
+        for name in names.iter() {
+            MemStorage::destroy(name);
+        }
+    }
+
+    #[test]
+    fn erasure_repo() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let dirs: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| tmpdir.path().join(name))
+            .collect();
+        let uri = format!(
+            "erasure://2+1/file://{}|file://{}|file://{}",
+            dirs[0].display(),
+            dirs[1].display(),
+            dirs[2].display()
+        );
+        let buf: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        {
+            let mut repo = RepoOpener::new().create(true).open(&uri, "pwd").unwrap();
+            let mut f = repo.create_file("/file").unwrap();
+            f.write_all(&buf).unwrap();
+            f.finish().unwrap();
+        }
+
+        // replace a member and the data can still be read
+        fs::remove_dir_all(&dirs[1]).unwrap();
+        let mut repo = RepoOpener::new().open(&uri, "pwd").unwrap();
+        let mut f = repo.open_file("/file").unwrap();
+        let mut dst = Vec::new();
+        f.read_to_end(&mut dst).unwrap();
+        assert_eq!(dst, buf);
+    }
+}
@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+mod erasure;
+
+pub use self::erasure::ErasureStorage;
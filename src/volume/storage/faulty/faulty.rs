@@ -0,0 +1,651 @@
+use std::cmp::min;
+use std::fmt::{self, Debug};
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::collections::HashMap;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+use crate::volume::storage::{CompactUnit, Storable};
+use crate::BLK_SIZE;
+
+/// Fault injected into a storage operation.
+///
+/// See [`FaultInjector`] for more details.
+///
+/// [`FaultInjector`]: struct.FaultInjector.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails with an IO error and has no effect, storage
+    /// keeps working afterwards.
+    Error,
+
+    /// Only the leading half of blocks is written, then storage crashes.
+    ///
+    /// This is the same as `Crash` if the operation is not `put_blocks`.
+    PartialWrite,
+
+    /// All blocks are written but the second half of the last block is
+    /// garbage, then storage crashes.
+    ///
+    /// This is the same as `Crash` if the operation is not `put_blocks`.
+    TornWrite,
+
+    /// Storage crashes before the operation, all addresses and blocks
+    /// written after the last flush are lost.
+    Crash,
+}
+
+#[derive(Debug, Default)]
+struct InjectorState {
+    // number of write operations made so far
+    op_cnt: usize,
+
+    // faults to be triggered, key is the operation count
+    faults: HashMap<usize, Fault>,
+
+    // if any storage using this injector has crashed
+    crashed: bool,
+}
+
+/// Fault injector for [`FaultyStorage`].
+///
+/// A fault injector schedules faults at chosen write operation counts.
+/// Write operations are counted from when the injector is created or
+/// reset, each call to `put_*`, `del_*` or `flush` on the storage counts
+/// as one operation.
+///
+/// Cloned injectors share the same state. The injector used by storage
+/// created from URI `faulty+<uri>` can be obtained by [`get`] with the same
+/// URI, as long as the storage or any clone of the injector is alive. Each
+/// anonymous storage, such as `faulty+mem://`, has its own injector which
+/// cannot be obtained by URI.
+///
+/// [`FaultyStorage`]: struct.FaultyStorage.html
+/// [`get`]: #method.get
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<InjectorState>>,
+}
+
+lazy_static! {
+    // process-global injectors by storage uri, an injector is removed after
+    // all its clones are dropped
+    static ref INJECTORS: Mutex<HashMap<String, Weak<Mutex<InjectorState>>>> =
+        Mutex::new(HashMap::new());
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the process-global injector for storage URI, the injector is
+    /// created on first use.
+    ///
+    /// A new injector is returned every time if the storage location is
+    /// empty, because each anonymous storage is a different storage.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::error::Result;
+    /// use f2ufs::repo::RepoOpener;
+    /// use f2ufs::util::init_env;
+    /// use f2ufs::volume::storage::{Fault, FaultInjector};
+    /// # fn foo() -> Result<()> {
+    /// init_env();
+    ///
+    /// let mut repo = RepoOpener::new()
+    ///     .create(true)
+    ///     .open("faulty+mem://foo", "pwd")?;
+    ///
+    /// // crash storage at the next write operation
+    /// let faults = FaultInjector::get("faulty+mem://foo");
+    /// faults.inject(faults.op_count(), Fault::Crash);
+    /// assert!(repo.create_file("/foo").is_err());
+    /// assert!(faults.is_crashed());
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    pub fn get(uri: &str) -> Self {
+        if uri.ends_with("://") {
+            return Self::new();
+        }
+
+        let mut injectors = INJECTORS.lock().unwrap();
+        injectors.retain(|_, state| state.strong_count() > 0);
+        if let Some(state) = injectors.get(uri).and_then(Weak::upgrade) {
+            return FaultInjector { state };
+        }
+        let injector = Self::new();
+        injectors.insert(uri.to_string(), Arc::downgrade(&injector.state));
+        injector
+    }
+
+    /// Schedules a fault at a write operation count.
+    ///
+    /// The fault is triggered by the write operation made when operation
+    /// count equals to `op_cnt`.
+    pub fn inject(&self, op_cnt: usize, fault: Fault) {
+        let mut state = self.state.lock().unwrap();
+        state.faults.insert(op_cnt, fault);
+    }
+
+    /// Returns number of write operations made so far.
+    pub fn op_count(&self) -> usize {
+        self.state.lock().unwrap().op_cnt
+    }
+
+    /// Returns whether any storage using this injector has crashed.
+    pub fn is_crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// Removes all scheduled faults and resets operation count and crash
+    /// status.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = InjectorState::default();
+    }
+
+    // count a write operation and return the fault scheduled for it
+    fn next_op(&self) -> Option<Fault> {
+        let mut state = self.state.lock().unwrap();
+        let op_cnt = state.op_cnt;
+        state.op_cnt += 1;
+        let fault = state.faults.remove(&op_cnt);
+        if let Some(fault) = fault {
+            warn!("inject fault {:?} at op#{}", fault, op_cnt);
+            if fault != Fault::Error {
+                state.crashed = true;
+            }
+        }
+        fault
+    }
+}
+
+#[inline]
+fn fault_err() -> Error {
+    Error::Io(IoError::new(ErrorKind::Other, "Injected fault"))
+}
+
+#[inline]
+fn crashed_err() -> Error {
+    Error::Io(IoError::new(ErrorKind::Other, "Storage crashed"))
+}
+
+/// Faulty Storage
+///
+/// This storage wraps another storage and injects faults scheduled by a
+/// [`FaultInjector`], it is used to test crash recovery.
+///
+/// Addresses and blocks are kept in memory until the storage is flushed,
+/// so they are lost when storage crashes or is dropped without flush, this
+/// is allowed by [`Storable`]. Super blocks and WALs are written
+/// to the inner storage directly. After crash, all operations on this
+/// storage fail, but a new storage can be created to open the inner storage
+/// again.
+///
+/// [`FaultInjector`]: struct.FaultInjector.html
+/// [`Storable`]: trait.Storable.html
+pub struct FaultyStorage {
+    inner: Box<dyn Storable>,
+    faults: FaultInjector,
+    crashed: bool,
+
+    // addresses and blocks not flushed yet, None means deleted
+    addrs: HashMap<Eid, Option<Vec<u8>>>,
+    blks: HashMap<usize, Option<Vec<u8>>>,
+
+    blk_size: usize,
+}
+
+impl FaultyStorage {
+    pub fn new(inner: Box<dyn Storable>, faults: FaultInjector) -> Self {
+        FaultyStorage {
+            inner,
+            faults,
+            crashed: false,
+            addrs: HashMap::new(),
+            blks: HashMap::new(),
+            blk_size: BLK_SIZE,
+        }
+    }
+
+    #[inline]
+    fn check_crashed(&self) -> Result<()> {
+        if self.crashed {
+            Err(crashed_err())
+        } else {
+            Ok(())
+        }
+    }
+
+    // crash storage, unflushed writes are lost
+    fn crash(&mut self) -> Error {
+        self.crashed = true;
+        self.addrs.clear();
+        self.blks.clear();
+        crashed_err()
+    }
+
+    // start a write operation and trigger its fault if there is any, the
+    // partial and torn writes are handled by put_blocks
+    fn begin_write(&mut self) -> Result<Option<Fault>> {
+        self.check_crashed()?;
+        match self.faults.next_op() {
+            Some(Fault::Error) => Err(fault_err()),
+            Some(Fault::Crash) => Err(self.crash()),
+            fault => Ok(fault),
+        }
+    }
+
+    // write leading blocks directly to inner storage and then crash
+    fn write_torn(&mut self, span: Span, blks: &[u8], fault: Fault) -> Error {
+        let blk_cnt = if fault == Fault::PartialWrite {
+            span.cnt / 2
+        } else {
+            span.cnt
+        };
+        let mut buf = blks[..blk_cnt * self.blk_size].to_vec();
+        if fault == Fault::TornWrite {
+            let len = buf.len();
+            Crypto::random_buf(&mut buf[len - self.blk_size / 2..]);
+        }
+        if blk_cnt > 0 {
+            let result = self
+                .inner
+                .put_blocks(Span::new(span.begin, blk_cnt), &buf)
+                .and_then(|_| self.inner.flush());
+            if let Err(err) = result {
+                warn!("write torn blocks failed: {}", err);
+            }
+        }
+        self.crash()
+    }
+}
+
+impl Storable for FaultyStorage {
+    #[inline]
+    fn exists(&self) -> Result<bool> {
+        self.check_crashed()?;
+        self.inner.exists()
+    }
+
+    #[inline]
+    fn connect(&mut self) -> Result<()> {
+        self.check_crashed()?;
+        self.inner.connect()
+    }
+
+    #[inline]
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.inner.set_layout(layout)?;
+        self.blk_size = layout.blk_size;
+        Ok(())
+    }
+
+    #[inline]
+    fn init(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.check_crashed()?;
+        self.inner.init(crypto, key)
+    }
+
+    #[inline]
+    fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.check_crashed()?;
+        self.inner.open(crypto, key)
+    }
+
+    #[inline]
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        self.check_crashed()?;
+        self.inner.get_super_block(suffix)
+    }
+
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+        self.inner.put_super_block(super_blk, suffix)
+    }
+
+    #[inline]
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.check_crashed()?;
+        self.inner.get_wal(id)
+    }
+
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+        self.inner.put_wal(id, wal)
+    }
+
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+        self.inner.del_wal(id)
+    }
+
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.check_crashed()?;
+        match self.addrs.get(id) {
+            Some(Some(addr)) => Ok(addr.clone()),
+            Some(None) => Err(Error::NotFound),
+            None => self.inner.get_address(id),
+        }
+    }
+
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+        self.addrs.insert(id.clone(), Some(addr.to_vec()));
+        Ok(())
+    }
+
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+        self.addrs.insert(id.clone(), None);
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        self.check_crashed()?;
+        if !span
+            .into_iter()
+            .any(|blk_idx| self.blks.contains_key(&blk_idx))
+        {
+            return self.inner.get_blocks(dst, span);
+        }
+
+        // read block by block if any block is not flushed yet
+        for (blk_idx, blk) in span.into_iter().zip(dst.chunks_mut(self.blk_size)) {
+            match self.blks.get(&blk_idx) {
+                Some(Some(data)) => blk.copy_from_slice(data),
+                Some(None) => return Err(Error::NotFound),
+                None => self.inner.get_blocks(blk, Span::new(blk_idx, 1))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        if let Some(fault) = self.begin_write()? {
+            return Err(self.write_torn(span, blks, fault));
+        }
+        for (blk_idx, blk) in span.into_iter().zip(blks.chunks(self.blk_size)) {
+            self.blks.insert(blk_idx, Some(blk.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+        for blk_idx in span {
+            self.blks.insert(blk_idx, None);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.begin_write()?.is_some() {
+            return Err(self.crash());
+        }
+
+        // write addresses to inner storage
+        for (id, addr) in self.addrs.iter() {
+            match addr {
+                Some(addr) => self.inner.put_address(id, addr)?,
+                None => self.inner.del_address(id)?,
+            }
+        }
+
+        // write blocks to inner storage in runs of continuous blocks
+        let mut blk_idxs: Vec<usize> = self.blks.keys().cloned().collect();
+        blk_idxs.sort();
+        let mut begin = 0;
+        while begin < blk_idxs.len() {
+            let first = &self.blks[&blk_idxs[begin]];
+            let mut end = begin + 1;
+            while end < min(blk_idxs.len(), begin + 256)
+                && blk_idxs[end] == blk_idxs[end - 1] + 1
+                && self.blks[&blk_idxs[end]].is_some() == first.is_some()
+            {
+                end += 1;
+            }
+            let span = Span::new(blk_idxs[begin], end - begin);
+            if first.is_some() {
+                let mut buf = Vec::with_capacity(span.bytes_len(self.blk_size));
+                for blk_idx in span {
+                    buf.extend_from_slice(self.blks[&blk_idx].as_ref().unwrap());
+                }
+                self.inner.put_blocks(span, &buf)?;
+            } else {
+                self.inner.del_blocks(span)?;
+            }
+            begin = end;
+        }
+
+        self.inner.flush()?;
+        self.addrs.clear();
+        self.blks.clear();
+        Ok(())
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        self.inner.can_reuse_blocks()
+    }
+
+    #[inline]
+    fn compact_candidates(
+        &mut self,
+        blk_wmark: usize,
+        dead_ratio: f32,
+    ) -> Result<Vec<CompactUnit>> {
+        self.inner.compact_candidates(blk_wmark, dead_ratio)
+    }
+
+    #[inline]
+    fn compact_unit(&mut self, idx: usize) -> Result<(usize, usize)> {
+        self.inner.compact_unit(idx)
+    }
+
+    #[inline]
+    fn deleted_blocks(&mut self, blk_wmark: usize) -> Result<Option<Vec<Span>>> {
+        self.inner.deleted_blocks(blk_wmark)
+    }
+}
+
+impl Debug for FaultyStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FaultyStorage")
+            .field("inner", &self.inner)
+            .field("crashed", &self.crashed)
+            .field("addrs", &self.addrs.len())
+            .field("blks", &self.blks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::crypto::RandomSeed;
+    use crate::util::init_env;
+    use crate::volume::storage::{MemObjectClient, ObjectStorage};
+
+    fn blocks(cnt: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; cnt * BLK_SIZE];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn open_storage(client: &MemObjectClient, faults: &FaultInjector) -> FaultyStorage {
+        let inner = Box::new(ObjectStorage::new(Box::new(client.clone())));
+        let mut fs = FaultyStorage::new(inner, faults.clone());
+        fs.connect().unwrap();
+        fs.open(Crypto::default(), Key::new_empty()).unwrap();
+        fs
+    }
+
+    fn read_blocks(fs: &mut FaultyStorage, span: Span) -> Result<Vec<u8>> {
+        let mut dst = vec![0u8; span.bytes_len(BLK_SIZE)];
+        fs.get_blocks(&mut dst, span)?;
+        Ok(dst)
+    }
+
+    #[test]
+    fn faulty_storage() {
+        init_env();
+        let client = MemObjectClient::new();
+        let faults = FaultInjector::new();
+        let blks = blocks(4, 42);
+        let id = Eid::new();
+        let id2 = Eid::new();
+
+        let mut fs = open_storage(&client, &faults);
+        fs.put_address(&id, &[1, 2, 3]).unwrap();
+        fs.put_blocks(Span::new(0, 2), &blks[..2 * BLK_SIZE])
+            .unwrap();
+        fs.flush().unwrap();
+        assert_eq!(faults.op_count(), 3);
+
+        // error fault doesn't stop storage
+        faults.inject(faults.op_count(), Fault::Error);
+        assert!(fs.put_address(&id2, &[4]).is_err());
+        assert!(!faults.is_crashed());
+        fs.put_address(&id2, &[4]).unwrap();
+        fs.put_blocks(Span::new(2, 2), &blks[2 * BLK_SIZE..])
+            .unwrap();
+        assert_eq!(fs.get_address(&id2).unwrap(), vec![4]);
+        assert_eq!(read_blocks(&mut fs, Span::new(0, 4)).unwrap(), blks);
+
+        // crash loses unflushed writes
+        faults.inject(faults.op_count(), Fault::Crash);
+        assert!(fs.flush().is_err());
+        assert!(faults.is_crashed());
+        assert!(fs.get_address(&id).is_err());
+        let mut fs = open_storage(&client, &faults);
+        assert_eq!(fs.get_address(&id).unwrap(), vec![1, 2, 3]);
+        assert_eq!(fs.get_address(&id2).unwrap_err(), Error::NotFound);
+        assert_eq!(
+            read_blocks(&mut fs, Span::new(0, 2)).unwrap(),
+            &blks[..2 * BLK_SIZE]
+        );
+        assert!(read_blocks(&mut fs, Span::new(2, 1)).is_err());
+    }
+
+    #[test]
+    fn faulty_torn_write() {
+        init_env();
+        let client = MemObjectClient::new();
+        let faults = FaultInjector::new();
+        let blks = blocks(4, 42);
+
+        // partial write only writes leading blocks
+        let mut fs = open_storage(&client, &faults);
+        faults.inject(0, Fault::PartialWrite);
+        assert!(fs.put_blocks(Span::new(0, 4), &blks).is_err());
+        assert!(fs.put_blocks(Span::new(0, 4), &blks).is_err());
+        let mut fs = open_storage(&client, &faults);
+        assert_eq!(
+            read_blocks(&mut fs, Span::new(0, 2)).unwrap(),
+            &blks[..2 * BLK_SIZE]
+        );
+        assert!(read_blocks(&mut fs, Span::new(2, 1)).is_err());
+
+        // torn write has garbage in the last block
+        faults.reset();
+        faults.inject(0, Fault::TornWrite);
+        assert!(fs
+            .put_blocks(Span::new(4, 2), &blks[..2 * BLK_SIZE])
+            .is_err());
+        let mut fs = open_storage(&client, &faults);
+        let torn = read_blocks(&mut fs, Span::new(4, 2)).unwrap();
+        let half = BLK_SIZE + BLK_SIZE / 2;
+        assert_eq!(&torn[..half], &blks[..half]);
+        assert_ne!(&torn[half..], &blks[half..2 * BLK_SIZE]);
+    }
+
+    #[test]
+    fn faulty_repos() {
+        use crate::repo::RepoOpener;
+        use crate::volume::storage::MemStorage;
+        use std::thread;
+
+        init_env();
+
+        let is_registered = |uri: &str| INJECTORS.lock().unwrap().contains_key(uri);
+
+        // concurrent repos have their own injectors, crash in one repo
+        // doesn't affect the others
+        let children: Vec<_> = (0..2)
+            .map(|i| {
+                thread::spawn(move || {
+                    let uri = format!("faulty+mem://faulty_repos_{}", i);
+                    let faults = FaultInjector::get(&uri);
+                    let mut repo = RepoOpener::new().create(true).open(&uri, "pwd").unwrap();
+                    assert!(faults.op_count() > 0);
+                    if i == 0 {
+                        faults.inject(faults.op_count(), Fault::Crash);
+                        assert!(repo.create_file("/foo").is_err());
+                        assert!(faults.is_crashed());
+                    } else {
+                        for j in 0..10 {
+                            repo.create_file(format!("/foo{}", j)).unwrap();
+                        }
+                        assert!(!faults.is_crashed());
+                    }
+                })
+            })
+            .collect();
+        for child in children {
+            child.join().unwrap();
+        }
+
+        // injectors are removed after repos and all handles are dropped
+        let uri = "faulty+mem://faulty_repos_0";
+        let faults = FaultInjector::get(uri);
+        assert_eq!(faults.op_count(), 0);
+        let repo = RepoOpener::new().open(uri, "pwd").unwrap();
+        drop(faults);
+        assert!(is_registered(uri));
+        drop(repo);
+        FaultInjector::get("faulty+mem://faulty_repos_1");
+        assert!(!is_registered(uri));
+
+        // anonymous repos don't share injector
+        let faults = FaultInjector::get("faulty+mem://");
+        faults.inject(0, Fault::Crash);
+        let mut repo = RepoOpener::new()
+            .create(true)
+            .open("faulty+mem://", "pwd")
+            .unwrap();
+        let mut repo2 = RepoOpener::new()
+            .create(true)
+            .open("faulty+mem://", "pwd")
+            .unwrap();
+        repo.create_file("/foo").unwrap();
+        repo2.create_file("/foo").unwrap();
+        assert_eq!(faults.op_count(), 0);
+        assert!(!is_registered("faulty+mem://"));
+
+        MemStorage::destroy("faulty_repos_0");
+        MemStorage::destroy("faulty_repos_1");
+    }
+}
@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+mod faulty;
+
+pub use self::faulty::{Fault, FaultInjector, FaultyStorage};
@@ -8,8 +8,9 @@ use crate::trans::eid::Eid;
 use crate::util;
 use crate::util::crypto::{Crypto, Key};
 use crate::volume::address::Span;
+use crate::volume::layout::Layout;
 use crate::volume::storage::file::{index::IndexMgr, sector::SectorMgr};
-use crate::volume::storage::Storable;
+use crate::volume::storage::{CompactUnit, Storable};
 
 /// File Storage
 #[derive(Debug)]
@@ -97,6 +98,7 @@ impl Storable for FileStorage {
 
         // set crypto context
         self.set_crypto_ctx(crypto, key);
+        self.idx_mgr.init();
 
         Ok(())
     }
@@ -104,6 +106,12 @@ impl Storable for FileStorage {
     #[inline]
     fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
         self.set_crypto_ctx(crypto, key);
+        self.idx_mgr.open()
+    }
+
+    #[inline]
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.sec_mgr.set_layout(layout);
         Ok(())
     }
 
@@ -190,9 +198,30 @@ impl Storable for FileStorage {
         self.sec_mgr.del_blocks(span)
     }
 
-    #[inline]
     fn flush(&mut self) -> Result<()> {
-        self.idx_mgr.flush()
+        self.idx_mgr.flush()?;
+
+        // deletion is persistent now, release space of deleted blocks
+        self.sec_mgr.punch_pending_holes()
+    }
+
+    #[inline]
+    fn compact_candidates(
+        &mut self,
+        blk_wmark: usize,
+        dead_ratio: f32,
+    ) -> Result<Vec<CompactUnit>> {
+        self.sec_mgr.compact_candidates(blk_wmark, dead_ratio)
+    }
+
+    #[inline]
+    fn compact_unit(&mut self, idx: usize) -> Result<(usize, usize)> {
+        self.sec_mgr.compact_sector(idx)
+    }
+
+    #[inline]
+    fn deleted_blocks(&mut self, blk_wmark: usize) -> Result<Option<Vec<Span>>> {
+        self.sec_mgr.deleted_blocks(blk_wmark).map(Some)
     }
 }
 
@@ -1,5 +1,4 @@
 use std::cmp::min;
-use std::error::Error as StdError;
 use std::fmt::Debug;
 use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
 use std::marker::PhantomData;
@@ -72,6 +71,7 @@ impl Read for CryptoReader {
                 &self.key
             ))?;
             self.enc_frame_len = 0;
+            self.read = 0;
         }
 
         // copy decrypted to destination
@@ -1,4 +1,6 @@
 use std::fmt::{self, Debug};
+use std::io::ErrorKind;
+use std::mem;
 use std::path::Path;
 
 use bytes::BufMut;
@@ -7,6 +9,7 @@ use crate::error::{Error, Result};
 use crate::trans::eid::{Eid, Id};
 use crate::util::collections::HashMap;
 use crate::util::crypto::{Crypto, HashKey, Key};
+use crate::util::lru::{CountMeter, Lru, PinChecker};
 use crate::volume::armor::{Arm, ArmAccess, Armor, Seq};
 use crate::volume::storage::file::file_armor::FileArmor;
 
@@ -103,95 +106,412 @@ impl Debug for Bucket {
     }
 }
 
+// index meta, which keeps linear hashing state of buckets
+//
+// Buckets are split one by one in round robin order, the number of buckets
+// is (INIT_BUCKET_NUM << level) + split.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Meta {
+    id: Eid,
+    seq: u64,
+    arm: Arm,
+    level: u8,
+    split: usize,
+    len: usize, // number of entries in index
+
+    // legacy buckets might be left over by an interrupted migration
+    #[serde(default)]
+    migrating: bool,
+
+    #[serde(skip_serializing, skip_deserializing, default)]
+    is_changed: bool,
+}
+
+impl Meta {
+    fn new(id: Eid) -> Self {
+        Meta {
+            id,
+            seq: 0,
+            arm: Arm::default(),
+            level: 0,
+            split: 0,
+            len: 0,
+            migrating: false,
+            is_changed: true,
+        }
+    }
+
+    // number of buckets in current round before split
+    #[inline]
+    fn round_size(&self) -> usize {
+        IndexMgr::INIT_BUCKET_NUM << self.level
+    }
+
+    #[inline]
+    fn bucket_cnt(&self) -> usize {
+        self.round_size() + self.split
+    }
+
+    // get bucket index for an entity
+    fn bucket_idx(&self, id: &Eid) -> usize {
+        let hash = entity_hash(id);
+        let idx = (hash % self.round_size() as u64) as usize;
+        if idx < self.split {
+            // bucket has been split in current round
+            (hash % (self.round_size() << 1) as u64) as usize
+        } else {
+            idx
+        }
+    }
+}
+
+impl Id for Meta {
+    #[inline]
+    fn id(&self) -> &Eid {
+        &self.id
+    }
+
+    #[inline]
+    fn id_mut(&mut self) -> &mut Eid {
+        &mut self.id
+    }
+}
+
+impl Seq for Meta {
+    #[inline]
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    #[inline]
+    fn inc_seq(&mut self) {
+        self.seq += 1
+    }
+}
+
+impl<'de> ArmAccess<'de> for Meta {
+    #[inline]
+    fn arm(&self) -> Arm {
+        self.arm
+    }
+
+    #[inline]
+    fn arm_mut(&mut self) -> &mut Arm {
+        &mut self.arm
+    }
+}
+
+// entity id hash used for bucket addressing, entity id is already random
+#[inline]
+fn entity_hash(id: &Eid) -> u64 {
+    (0..8).fold(0u64, |hash, i| hash | u64::from(id[i]) << (i * 8))
+}
+
 // entity index manager
+//
+// Entity addresses are kept in buckets addressed by linear hashing, a bucket
+// is split when average number of entries in buckets exceeds the bucket
+// load, so bucket size is bounded no matter how many entities are there.
+// Only changed buckets are kept in memory until flush, the others are kept
+// in a LRU cache.
 pub struct IndexMgr {
+    meta_armor: FileArmor<Meta>,
     bkt_armor: FileArmor<Bucket>,
-    buckets: HashMap<u8, Bucket>,
+    meta: Meta,
+
+    // number of buckets in persisted meta
+    saved_bkt_cnt: usize,
+
+    // changed buckets since last flush
+    dirty: HashMap<usize, Bucket>,
+
+    // unchanged bucket cache
+    cache: Lru<usize, Bucket, CountMeter<Bucket>, PinChecker<Bucket>>,
+
     hash_key: HashKey,
 }
 
 impl IndexMgr {
-    // number of buckets
-    const BUCKET_NUM: u8 = 8;
+    // initial number of buckets
+    const INIT_BUCKET_NUM: usize = 8;
+
+    // average number of entries in a bucket before splitting
+    const BUCKET_LOAD: usize = 512;
+
+    // unchanged bucket cache size
+    const BUCKET_CACHE_SIZE: usize = 64;
+
+    // number of buckets in legacy fixed bucket layout
+    const LEGACY_BUCKET_NUM: u8 = 8;
 
     pub fn new(base: &Path) -> Self {
         IndexMgr {
+            meta_armor: FileArmor::new(base),
             bkt_armor: FileArmor::new(base),
-            buckets: HashMap::new(),
+            meta: Meta::new(Eid::new_empty()),
+            saved_bkt_cnt: 0,
+            dirty: HashMap::new(),
+            cache: Lru::new(Self::BUCKET_CACHE_SIZE),
             hash_key: HashKey::new_empty(),
         }
     }
 
     #[inline]
     pub fn set_crypto_ctx(&mut self, crypto: Crypto, key: Key, hash_key: HashKey) {
+        self.meta_armor.set_crypto_ctx(crypto.clone(), key.clone());
         self.bkt_armor.set_crypto_ctx(crypto, key);
         self.hash_key = hash_key;
     }
 
+    // initialise an empty index
+    pub fn init(&mut self) {
+        self.meta = Meta::new(self.meta_id());
+        self.saved_bkt_cnt = 0;
+    }
+
+    // open index, migrate it from legacy layout if necessary
+    pub fn open(&mut self) -> Result<()> {
+        match self.meta_armor.load_item(&self.meta_id()) {
+            Ok(meta) => {
+                self.saved_bkt_cnt = meta.bucket_cnt();
+                self.meta = meta;
+                if self.meta.migrating {
+                    self.remove_legacy()?;
+                }
+                Ok(())
+            }
+            Err(ref err) if *err == Error::NotFound => {
+                self.init();
+                self.migrate()
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // meta id
+    fn meta_id(&self) -> Eid {
+        let hash = Crypto::hash_with_key(b"meta", &self.hash_key);
+        Eid::from_slice(&hash)
+    }
+
     // convert bucket index to id
-    fn bucket_idx_to_eid(&self, bucket_idx: u8) -> Eid {
+    fn bucket_idx_to_eid(&self, bucket_idx: usize) -> Eid {
+        let mut buf = Vec::with_capacity(8);
+        buf.put_u64_le(bucket_idx as u64);
+        let hash = Crypto::hash_with_key(&buf, &self.hash_key);
+        Eid::from_slice(&hash)
+    }
+
+    // convert legacy bucket index to id
+    fn legacy_bucket_idx_to_eid(&self, bucket_idx: u8) -> Eid {
         let mut buf = Vec::with_capacity(1);
         buf.put_u8(bucket_idx);
         let hash = Crypto::hash_with_key(&buf, &self.hash_key);
         Eid::from_slice(&hash)
     }
 
-    // open bucket for an entity, if not exists then create it
-    fn open_bucket_raw(&mut self, id: &Eid, create: bool) -> Result<&mut Bucket> {
-        let bucket_idx = id[0] % Self::BUCKET_NUM;
-        let bucket_id = self.bucket_idx_to_eid(bucket_idx);
-
-        if !self.buckets.contains_key(&bucket_idx) {
-            // load bucket
+    // migrate addresses from legacy fixed bucket layout
+    //
+    // The legacy buckets are removed only after all the new buckets and meta
+    // are saved. If migration is interrupted before meta is saved, it will
+    // start over next time, otherwise the meta is marked as migrating and
+    // the legacy buckets left over are removed on next open.
+    fn migrate(&mut self) -> Result<()> {
+        let mut legacy_bkts = Vec::new();
+        for bucket_idx in 0..Self::LEGACY_BUCKET_NUM {
+            let bucket_id = self.legacy_bucket_idx_to_eid(bucket_idx);
             match self.bkt_armor.load_item(&bucket_id) {
-                Ok(bucket) => {
-                    self.buckets.insert(bucket_idx, bucket);
+                Ok(bucket) => legacy_bkts.push(bucket),
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if legacy_bkts.is_empty() {
+            return Ok(());
+        }
+
+        // discard buckets left over by interrupted migration
+        for bucket_idx in 0..self.meta.bucket_cnt() {
+            let bucket = self.new_bucket(bucket_idx)?;
+            self.dirty.insert(bucket_idx, bucket);
+        }
+
+        for bucket in legacy_bkts.iter_mut() {
+            for (id, addr) in mem::replace(&mut bucket.map, HashMap::new()) {
+                self.insert(id, addr)?;
+            }
+        }
+        self.meta.migrating = true;
+        self.flush()?;
+        self.remove_legacy()?;
+
+        debug!(
+            "index migrated {} entities into {} buckets",
+            self.meta.len,
+            self.meta.bucket_cnt()
+        );
+
+        Ok(())
+    }
+
+    // remove legacy buckets after migration and clear migrating mark
+    fn remove_legacy(&mut self) -> Result<()> {
+        for bucket_idx in 0..Self::LEGACY_BUCKET_NUM {
+            let bucket_id = self.legacy_bucket_idx_to_eid(bucket_idx);
+
+            // legacy bucket might have only one arm or was removed already
+            for arm in [Arm::Left, Arm::Right].iter() {
+                match self.bkt_armor.del_arm(&arm.to_eid(&bucket_id)) {
+                    Ok(_) => {}
+                    Err(Error::Io(ref err)) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
                 }
-                Err(ref err) if *err == Error::NotFound && create => {
-                    // create a new bucket
-                    let bucket = Bucket::new(bucket_id);
-                    self.buckets.insert(bucket_idx, bucket);
+            }
+        }
+
+        self.meta.migrating = false;
+        self.meta.is_changed = true;
+        self.save_meta()
+    }
+
+    // create an empty bucket, the bucket might exist already if it was
+    // created by an interrupted flush, in that case it will be overwritten
+    fn new_bucket(&self, bucket_idx: usize) -> Result<Bucket> {
+        let bucket_id = self.bucket_idx_to_eid(bucket_idx);
+        let mut bucket = match self.bkt_armor.load_item(&bucket_id) {
+            Ok(bucket) => bucket,
+            Err(ref err) if *err == Error::NotFound => Bucket::new(bucket_id),
+            Err(err) => return Err(err),
+        };
+        bucket.map.clear();
+        bucket.is_changed = true;
+        Ok(bucket)
+    }
+
+    // make sure bucket is loaded in memory
+    fn load_bucket(&mut self, bucket_idx: usize, create: bool) -> Result<()> {
+        if self.dirty.contains_key(&bucket_idx) || self.cache.contains_key(&bucket_idx) {
+            return Ok(());
+        }
+
+        let bucket_id = self.bucket_idx_to_eid(bucket_idx);
+        let bucket = match self.bkt_armor.load_item(&bucket_id) {
+            Ok(bucket) => bucket,
+            Err(ref err) if *err == Error::NotFound && create => Bucket::new(bucket_id),
+            Err(err) => return Err(err),
+        };
+        self.cache.insert(bucket_idx, bucket);
+
+        Ok(())
+    }
+
+    // open bucket for read
+    fn open_bucket(&mut self, bucket_idx: usize) -> Result<&Bucket> {
+        self.load_bucket(bucket_idx, false)?;
+        if self.dirty.contains_key(&bucket_idx) {
+            return Ok(&self.dirty[&bucket_idx]);
+        }
+        Ok(self.cache.get_refresh(&bucket_idx).unwrap())
+    }
+
+    // open bucket for write, the bucket is moved to dirty buckets
+    fn open_bucket_mut(&mut self, bucket_idx: usize, create: bool) -> Result<&mut Bucket> {
+        self.load_bucket(bucket_idx, create)?;
+        if let Some(bucket) = self.cache.remove(&bucket_idx) {
+            self.dirty.insert(bucket_idx, bucket);
+        }
+        Ok(self.dirty.get_mut(&bucket_idx).unwrap())
+    }
+
+    // insert an entity address and split bucket if necessary
+    fn insert(&mut self, id: Eid, addr: Vec<u8>) -> Result<()> {
+        let bucket_idx = self.meta.bucket_idx(&id);
+        let bucket = self.open_bucket_mut(bucket_idx, true)?;
+        if bucket.insert(id, addr).is_none() {
+            self.meta.len += 1;
+            self.meta.is_changed = true;
+            while self.meta.len > Self::BUCKET_LOAD * self.meta.bucket_cnt() {
+                self.split_bucket()?;
+            }
+        }
+        Ok(())
+    }
+
+    // split the next bucket in current round
+    fn split_bucket(&mut self) -> Result<()> {
+        let src_idx = self.meta.split;
+        let tgt_idx = src_idx + self.meta.round_size();
+        let modulus = (self.meta.round_size() << 1) as u64;
+        let mut tgt = self.new_bucket(tgt_idx)?;
+
+        {
+            let src = self.open_bucket_mut(src_idx, true)?;
+            for (id, addr) in mem::replace(&mut src.map, HashMap::new()) {
+                let bucket_idx = (entity_hash(&id) % modulus) as usize;
+                if bucket_idx == src_idx {
+                    src.map.insert(id, addr);
+                } else if bucket_idx == tgt_idx {
+                    tgt.map.insert(id, addr);
                 }
-                Err(err) => return Err(err),
+                // otherwise it is a stale entry which has been moved to
+                // another bucket by an interrupted flush, just drop it
             }
+            src.is_changed = true;
         }
+        self.dirty.insert(tgt_idx, tgt);
 
-        let ret = self.buckets.get_mut(&bucket_idx).unwrap();
+        self.meta.split += 1;
+        if self.meta.split == self.meta.round_size() {
+            self.meta.level += 1;
+            self.meta.split = 0;
+        }
+        self.meta.is_changed = true;
 
-        Ok(ret)
+        Ok(())
     }
 
-    #[inline]
-    fn open_bucket_create(&mut self, id: &Eid) -> Result<&mut Bucket> {
-        self.open_bucket_raw(id, true)
+    // save changed buckets, either the ones created after last meta saving
+    // or the ones already existed
+    fn save_buckets(&mut self, is_new: bool) -> Result<()> {
+        for (bucket_idx, bucket) in self.dirty.iter_mut() {
+            if (*bucket_idx >= self.saved_bkt_cnt) == is_new && bucket.is_changed {
+                self.bkt_armor.save_item(bucket)?;
+                bucket.is_changed = false;
+            }
+        }
+        Ok(())
     }
 
-    #[inline]
-    fn open_bucket(&mut self, id: &Eid) -> Result<&mut Bucket> {
-        self.open_bucket_raw(id, false)
+    fn save_meta(&mut self) -> Result<()> {
+        if self.meta.is_changed {
+            self.meta_armor.save_item(&mut self.meta)?;
+            self.meta.is_changed = false;
+            self.saved_bkt_cnt = self.meta.bucket_cnt();
+        }
+        Ok(())
     }
 
     // read entity address
     pub fn read_addr(&mut self, id: &Eid) -> Result<Vec<u8>> {
-        let bucket = self.open_bucket(id)?;
-        bucket
-            .get(id)
-            .ok_or(Error::NotFound)
-            .map(|addr| addr.clone())
+        let bucket_idx = self.meta.bucket_idx(id);
+        let bucket = self.open_bucket(bucket_idx)?;
+        bucket.get(id).cloned().ok_or(Error::NotFound)
     }
 
     // write entity address
     pub fn write_addr(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
-        let bucket = self.open_bucket_create(id)?;
-        bucket.insert(id.clone(), addr.to_vec());
-        Ok(())
+        self.insert(id.clone(), addr.to_vec())
     }
 
     // delete entity address
     pub fn del_address(&mut self, id: &Eid) -> Result<()> {
-        match self.open_bucket(id) {
+        let bucket_idx = self.meta.bucket_idx(id);
+        match self.open_bucket_mut(bucket_idx, false) {
             Ok(bucket) => {
-                bucket.remove(id);
+                if bucket.remove(id).is_some() {
+                    self.meta.len = self.meta.len.saturating_sub(1);
+                    self.meta.is_changed = true;
+                }
                 Ok(())
             }
             Err(ref err) if *err == Error::NotFound => Ok(()),
@@ -199,12 +519,18 @@ impl IndexMgr {
         }
     }
 
+    // Save all changes. Buckets created by splits are saved before meta and
+    // the existing buckets are saved after it, so that every entry is always
+    // reachable from the persisted meta. An interrupted flush could leave
+    // stale copies of moved entries in split buckets, they are not reachable
+    // and will be dropped when the bucket is split again.
     pub fn flush(&mut self) -> Result<()> {
-        for bucket in self.buckets.values_mut() {
-            if bucket.is_changed {
-                self.bkt_armor.save_item(bucket)?;
-                bucket.is_changed = false;
-            }
+        self.save_buckets(true)?;
+        self.save_meta()?;
+        self.save_buckets(false)?;
+
+        for (bucket_idx, bucket) in self.dirty.drain() {
+            self.cache.insert(bucket_idx, bucket);
         }
         Ok(())
     }
@@ -213,8 +539,173 @@ impl IndexMgr {
 impl Debug for IndexMgr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("IndexMgr")
-            .field("buckets", &self.buckets)
+            .field("meta", &self.meta)
+            .field("dirty.len", &self.dirty.len())
             .field("hash_key", &self.hash_key)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::util::init_env;
+
+    fn open_index(base: &Path) -> IndexMgr {
+        let mut idx_mgr = IndexMgr::new(base);
+        idx_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+        idx_mgr.open().unwrap();
+        idx_mgr
+    }
+
+    fn addr_of(id: &Eid) -> Vec<u8> {
+        id.as_ref()[..4].to_vec()
+    }
+
+    #[test]
+    fn split_buckets() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut idx_mgr = IndexMgr::new(tmpdir.path());
+        idx_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+        idx_mgr.init();
+
+        let ids: Vec<Eid> = (0..20_000).map(|_| Eid::new()).collect();
+        for id in ids.iter() {
+            idx_mgr.write_addr(id, &addr_of(id)).unwrap();
+        }
+        idx_mgr.flush().unwrap();
+        let bkt_cnt = idx_mgr.meta.bucket_cnt();
+        assert!(bkt_cnt > IndexMgr::INIT_BUCKET_NUM);
+        assert_eq!(idx_mgr.meta.len, ids.len());
+
+        // delete some entities and re-open index
+        for id in ids[..1000].iter() {
+            idx_mgr.del_address(id).unwrap();
+        }
+        idx_mgr.flush().unwrap();
+        drop(idx_mgr);
+
+        let mut idx_mgr = open_index(tmpdir.path());
+        assert_eq!(idx_mgr.meta.bucket_cnt(), bkt_cnt);
+        assert_eq!(idx_mgr.meta.len, ids.len() - 1000);
+        for id in ids[..1000].iter() {
+            assert_eq!(idx_mgr.read_addr(id).unwrap_err(), Error::NotFound);
+        }
+        for id in ids[1000..].iter() {
+            assert_eq!(idx_mgr.read_addr(id).unwrap(), addr_of(id));
+        }
+    }
+
+    #[test]
+    fn interrupted_flush() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut idx_mgr = IndexMgr::new(tmpdir.path());
+        idx_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+        idx_mgr.init();
+
+        let ids: Vec<Eid> = (0..5000).map(|_| Eid::new()).collect();
+        for id in ids.iter() {
+            idx_mgr.write_addr(id, &addr_of(id)).unwrap();
+        }
+        idx_mgr.flush().unwrap();
+
+        // add more entities to split buckets, but only save the new buckets
+        // and meta as if flush is interrupted
+        let bkt_cnt = idx_mgr.meta.bucket_cnt();
+        let more: Vec<Eid> = (0..5000).map(|_| Eid::new()).collect();
+        for id in more.iter() {
+            idx_mgr.write_addr(id, &addr_of(id)).unwrap();
+        }
+        assert!(idx_mgr.meta.bucket_cnt() > bkt_cnt);
+        idx_mgr.save_buckets(true).unwrap();
+        idx_mgr.save_meta().unwrap();
+        drop(idx_mgr);
+
+        // all the flushed entities are still reachable
+        let mut idx_mgr = open_index(tmpdir.path());
+        for id in ids.iter() {
+            assert_eq!(idx_mgr.read_addr(id).unwrap(), addr_of(id));
+        }
+
+        // index should keep working after further splits
+        let more: Vec<Eid> = (0..20_000).map(|_| Eid::new()).collect();
+        for id in more.iter() {
+            idx_mgr.write_addr(id, &addr_of(id)).unwrap();
+        }
+        idx_mgr.flush().unwrap();
+        drop(idx_mgr);
+
+        let mut idx_mgr = open_index(tmpdir.path());
+        for id in ids.iter().chain(more.iter()) {
+            assert_eq!(idx_mgr.read_addr(id).unwrap(), addr_of(id));
+        }
+    }
+
+    #[test]
+    fn migrate_legacy() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let idx_mgr = open_index(tmpdir.path());
+
+        // write entities in legacy fixed bucket layout
+        let ids: Vec<Eid> = (0..10_000).map(|_| Eid::new()).collect();
+        let mut legacy_bkts: Vec<Bucket> = (0..IndexMgr::LEGACY_BUCKET_NUM)
+            .map(|idx| Bucket::new(idx_mgr.legacy_bucket_idx_to_eid(idx)))
+            .collect();
+        for id in ids.iter() {
+            let idx = (id[0] % IndexMgr::LEGACY_BUCKET_NUM) as usize;
+            legacy_bkts[idx].insert(id.clone(), addr_of(id));
+        }
+        for bucket in legacy_bkts.iter_mut() {
+            idx_mgr.bkt_armor.save_item(bucket).unwrap();
+        }
+        drop(idx_mgr);
+
+        // open index should migrate legacy buckets and remove them
+        let mut idx_mgr = open_index(tmpdir.path());
+        assert_eq!(idx_mgr.meta.len, ids.len());
+        for bucket in legacy_bkts.iter() {
+            assert_eq!(
+                idx_mgr.bkt_armor.load_item(bucket.id()).unwrap_err(),
+                Error::NotFound
+            );
+        }
+        for id in ids.iter() {
+            assert_eq!(idx_mgr.read_addr(id).unwrap(), addr_of(id));
+        }
+
+        // simulate migration interrupted after new index is saved, legacy
+        // buckets left over should be removed on next open
+        for bucket in legacy_bkts.iter_mut() {
+            idx_mgr.bkt_armor.save_item(bucket).unwrap();
+        }
+        idx_mgr.meta.migrating = true;
+        idx_mgr.meta.is_changed = true;
+        idx_mgr.save_meta().unwrap();
+        drop(idx_mgr);
+
+        let mut idx_mgr = open_index(tmpdir.path());
+        assert!(!idx_mgr.meta.migrating);
+        for bucket in legacy_bkts.iter() {
+            assert_eq!(
+                idx_mgr.bkt_armor.load_item(bucket.id()).unwrap_err(),
+                Error::NotFound
+            );
+        }
+        for id in ids.iter() {
+            assert_eq!(idx_mgr.read_addr(id).unwrap(), addr_of(id));
+        }
+        drop(idx_mgr);
+
+        let mut idx_mgr = open_index(tmpdir.path());
+        assert!(!idx_mgr.meta.migrating);
+        for id in ids.iter() {
+            assert_eq!(idx_mgr.read_addr(id).unwrap(), addr_of(id));
+        }
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod file;
 mod file_armor;
 mod index;
@@ -1,7 +1,7 @@
+use std::collections::BTreeMap;
 use std::fmt::{self, Debug};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::u16;
 
 use bytes::BufMut;
 use linked_hash_map::LinkedHashMap;
@@ -15,14 +15,9 @@ use crate::util::lru::{CountMeter, Lru, PinChecker};
 use crate::util::remove_empty_parent_dir;
 use crate::volume::address::Span;
 use crate::volume::armor::{Arm, ArmAccess, Armor, Seq};
+use crate::volume::layout::Layout;
 use crate::volume::storage::file::file_armor::FileArmor;
-use crate::BLK_SIZE;
-
-// how many blocks in a sector, must be 2^n and less than u16::MAX
-pub const BLKS_PER_SECTOR: usize = 4 * 1024;
-
-// sector size, in bytes
-pub const SECTOR_SIZE: usize = BLK_SIZE * BLKS_PER_SECTOR;
+use crate::volume::storage::CompactUnit;
 
 // block deletion mark
 const BLK_DELETE_MARK: u16 = u16::MAX;
@@ -48,13 +43,14 @@ struct Sector {
     // sector actual size in bytes, including deleted blocks
     actual_size: usize,
 
-    // block offset map, length is BLKS_PER_SECTOR, u16::MAX means deleted
+    // block offset map, length is number of blocks in sector, u16::MAX
+    // means deleted
     blk_map: Vec<u16>,
 }
 
 impl Sector {
     #[inline]
-    fn new(id: &Eid, idx: usize) -> Self {
+    fn new(id: &Eid, idx: usize, blks_per_sector: usize) -> Self {
         Sector {
             id: id.clone(),
             seq: 0,
@@ -62,7 +58,7 @@ impl Sector {
             idx,
             curr_size: 0,
             actual_size: 0,
-            blk_map: (0..BLKS_PER_SECTOR as u16).collect(),
+            blk_map: (0..blks_per_sector as u16).collect(),
         }
     }
 
@@ -77,16 +73,42 @@ impl Sector {
         self.actual_size <= self.curr_size >> 2
     }
 
-    // mark blocks as deleted
-    fn mark_blocks_deletion(&mut self, span: Span) {
-        let insec_idx = span.begin % BLKS_PER_SECTOR;
+    // get the deleted block runs in sector data file of a sector which is
+    // never shrunk, span unit is block
+    fn deleted_holes(&self) -> Vec<Span> {
+        let mut holes: Vec<Span> = Vec::new();
+        for (idx, data_idx) in self.blk_map.iter().enumerate() {
+            if *data_idx != BLK_DELETE_MARK {
+                continue;
+            }
+            match holes.last_mut() {
+                Some(ref mut hole) if hole.end() == idx => hole.cnt += 1,
+                _ => holes.push(Span::new(idx, 1)),
+            }
+        }
+        holes
+    }
+
+    // mark blocks as deleted, return the deleted block runs in sector data
+    // file, span unit is block
+    fn mark_blocks_deletion(&mut self, span: Span, blk_size: usize) -> Vec<Span> {
+        let insec_idx = span.begin % self.blk_map.len();
         let mut deleted_size = 0;
+        let mut holes: Vec<Span> = Vec::new();
 
         // mark blocks as deleted
         for idx in insec_idx..insec_idx + span.cnt {
-            if self.blk_map[idx] != BLK_DELETE_MARK {
+            let data_idx = self.blk_map[idx];
+            if data_idx != BLK_DELETE_MARK {
                 self.blk_map[idx] = BLK_DELETE_MARK;
-                deleted_size += BLK_SIZE;
+                deleted_size += blk_size;
+
+                // merge continuous blocks in data file
+                let data_idx = data_idx as usize;
+                match holes.last_mut() {
+                    Some(ref mut hole) if hole.end() == data_idx => hole.cnt += 1,
+                    _ => holes.push(Span::new(data_idx, 1)),
+                }
             }
         }
 
@@ -94,6 +116,8 @@ impl Sector {
         if self.is_finished() {
             self.actual_size -= deleted_size;
         }
+
+        holes
     }
 }
 
@@ -150,6 +174,9 @@ impl Debug for Sector {
 pub struct SectorMgr {
     base: PathBuf,
 
+    // volume block layout
+    layout: Layout,
+
     sec_armor: FileArmor<Sector>,
 
     // sector cache
@@ -159,6 +186,14 @@ pub struct SectorMgr {
     sec_data_cache: LinkedHashMap<usize, vio::File>,
 
     hash_key: HashKey,
+
+    // whether to punch holes in sector data file for deleted blocks, it is
+    // turned off if file system doesn't support it
+    punch_hole: bool,
+
+    // holes of deleted blocks in finished sectors, they are punched when
+    // storage is flushed and the deletion is persistent
+    pending_holes: BTreeMap<usize, Vec<Span>>,
 }
 
 impl SectorMgr {
@@ -169,10 +204,13 @@ impl SectorMgr {
     pub fn new(base: &Path) -> Self {
         SectorMgr {
             base: base.to_path_buf(),
+            layout: Layout::default(),
             sec_armor: FileArmor::new(base),
             sec_cache: Lru::new(SECTOR_CACHE_SIZE),
             sec_data_cache: LinkedHashMap::new(),
             hash_key: HashKey::new_empty(),
+            punch_hole: true,
+            pending_holes: BTreeMap::new(),
         }
     }
 
@@ -182,6 +220,11 @@ impl SectorMgr {
         self.hash_key = hash_key;
     }
 
+    #[inline]
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
     // convert sector index to Eid
     fn sector_idx_to_id(&self, sec_idx: usize) -> Eid {
         let mut buf = Vec::with_capacity(8);
@@ -212,14 +255,14 @@ impl SectorMgr {
                     if create {
                         // if sector doesn't exist, create a new sector
                         // and save it to cache
-                        let mut sec = Sector::new(&sec_id, sec_idx);
+                        let mut sec = Sector::new(&sec_id, sec_idx, self.layout.blks_per_sector);
                         self.sec_armor.save_item(&mut sec)?;
                         self.sec_cache.insert(sec_idx, sec);
                     } else {
                         return Err(Error::NotFound);
                     }
                 }
-                Err(err) => return Err(Error::from(err)),
+                Err(err) => return Err(err),
             }
         }
 
@@ -230,8 +273,8 @@ impl SectorMgr {
 
     // save sector to file
     fn save_sector(&mut self, sec_idx: usize) -> Result<()> {
-        let mut sec = self.sec_cache.get_refresh(&sec_idx).unwrap();
-        self.sec_armor.save_item(&mut sec)
+        let sec = self.sec_cache.get_refresh(&sec_idx).unwrap();
+        self.sec_armor.save_item(sec)
     }
 
     // open sector data file
@@ -247,6 +290,7 @@ impl SectorMgr {
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(&path)?;
             self.sec_data_cache.insert(sec_idx, data_file);
             if self.sec_data_cache.len() >= SECTOR_DATA_CACHE_SIZE {
@@ -261,27 +305,29 @@ impl SectorMgr {
 
     // read data blocks
     pub fn read_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
-        assert_eq!(dst.len(), span.bytes_len());
+        let Layout {
+            blk_size,
+            blks_per_sector,
+            ..
+        } = self.layout;
+        assert_eq!(dst.len(), span.bytes_len(blk_size));
 
         let mut read = 0;
-        for sec_span in span.divide_by(BLKS_PER_SECTOR) {
-            let sec_idx = sec_span.begin / BLKS_PER_SECTOR;
+        for sec_span in span.divide_by(blks_per_sector) {
+            let sec_idx = sec_span.begin / blks_per_sector;
             let mut sec_data = self.open_sector_data(sec_idx, false)?;
             let blk_offset = {
                 let sec = self.open_sector(sec_idx, false)?;
-                let map_idx = sec_span.begin % BLKS_PER_SECTOR;
+                let map_idx = sec_span.begin % blks_per_sector;
                 let insec_idx = sec.blk_map[map_idx];
-                if sec.blk_map[map_idx..map_idx + sec_span.cnt]
-                    .iter()
-                    .any(|b| *b == BLK_DELETE_MARK)
-                {
+                if sec.blk_map[map_idx..map_idx + sec_span.cnt].contains(&BLK_DELETE_MARK) {
                     return Err(Error::NotFound);
                 }
-                insec_idx as u64 * BLK_SIZE as u64
+                insec_idx as u64 * blk_size as u64
             };
 
             // read blocks bytes
-            let read_len = sec_span.bytes_len();
+            let read_len = sec_span.bytes_len(blk_size);
             sec_data.seek(SeekFrom::Start(blk_offset))?;
             sec_data.read_exact(&mut dst[read..read + read_len])?;
             read += read_len;
@@ -292,15 +338,20 @@ impl SectorMgr {
 
     // write data blocks to sector
     pub fn write_blocks(&mut self, span: Span, mut blks: &[u8]) -> Result<()> {
-        assert_eq!(blks.len(), span.bytes_len());
-
-        for sec_span in span.divide_by(BLKS_PER_SECTOR) {
-            let sec_idx = sec_span.begin / BLKS_PER_SECTOR;
+        let Layout {
+            blk_size,
+            blks_per_sector,
+            ..
+        } = self.layout;
+        assert_eq!(blks.len(), span.bytes_len(blk_size));
+
+        for sec_span in span.divide_by(blks_per_sector) {
+            let sec_idx = sec_span.begin / blks_per_sector;
             let mut sec_data = self.open_sector_data(sec_idx, true)?;
-            let blk_offset = (sec_span.begin % BLKS_PER_SECTOR) * BLK_SIZE;
+            let blk_offset = (sec_span.begin % blks_per_sector) * blk_size;
 
             // write blocks bytes to sector data file
-            let write_len = sec_span.bytes_len();
+            let write_len = sec_span.bytes_len(blk_size);
             sec_data.seek(SeekFrom::Start(blk_offset as u64))?;
             sec_data.write_all(&blks[..write_len])?;
             blks = &blks[write_len..];
@@ -315,7 +366,7 @@ impl SectorMgr {
                 let sec = self.open_sector(sec_idx, true)?;
 
                 assert!(!sec.is_finished());
-                let map_idx = sec_span.begin % BLKS_PER_SECTOR;
+                let map_idx = sec_span.begin % blks_per_sector;
                 let mut corrected = 0;
                 for i in map_idx..map_idx + sec_span.cnt {
                     if sec.blk_map[i] == BLK_DELETE_MARK {
@@ -334,25 +385,28 @@ impl SectorMgr {
             }
 
             // if we reached the end of sector, mark it as finished
-            if sec_span.end() % BLKS_PER_SECTOR == 0 {
-                let is_shrinkable = {
+            if sec_span.end() % blks_per_sector == 0 {
+                let sector_size = self.layout.sector_size();
+                let (is_shrinkable, holes) = {
                     let sec = self.open_sector(sec_idx, false)?;
-                    sec.curr_size = SECTOR_SIZE;
-                    sec.actual_size = BLK_SIZE
+                    sec.curr_size = sector_size;
+                    sec.actual_size = blk_size
                         * sec
                             .blk_map
                             .iter()
                             .filter(|b| **b != BLK_DELETE_MARK)
-                            .count() as usize;
-                    sec.is_shrinkable()
+                            .count();
+                    (sec.is_shrinkable(), sec.deleted_holes())
                 };
 
                 if is_shrinkable {
                     // shrink sector
                     self.shrink_sector(sec_idx)?;
                 } else {
-                    // save sector
+                    // save sector, blocks deleted while it was being written
+                    // cannot be reverted any more
                     self.save_sector(sec_idx)?;
+                    self.add_pending_holes(sec_idx, holes);
                 }
             }
         }
@@ -362,6 +416,9 @@ impl SectorMgr {
 
     // shrink a sector
     fn shrink_sector(&mut self, sec_idx: usize) -> Result<()> {
+        // shrinking reclaims space of all deleted blocks
+        self.pending_holes.remove(&sec_idx);
+
         let mut sec = self.open_sector(sec_idx, false)?.clone();
         let mut sec_data = self.open_sector_data(sec_idx, false)?;
 
@@ -376,7 +433,8 @@ impl SectorMgr {
             .open(&dst_path)?;
 
         // copy all not deleted blocks to destination file
-        let mut buf = vec![0u8; BLK_SIZE];
+        let blk_size = self.layout.blk_size;
+        let mut buf = vec![0u8; blk_size];
         let mut written_blk_cnt = 0;
         for insec_idx in sec.blk_map.iter_mut() {
             // skip deleted block
@@ -384,7 +442,7 @@ impl SectorMgr {
                 continue;
             }
 
-            let data_offset = *insec_idx as usize * BLK_SIZE;
+            let data_offset = *insec_idx as usize * blk_size;
             if data_offset >= sec.curr_size {
                 break;
             }
@@ -399,7 +457,7 @@ impl SectorMgr {
         }
 
         // set sector new size, save sector and update sector in cache
-        sec.actual_size = written_blk_cnt as usize * BLK_SIZE;
+        sec.actual_size = written_blk_cnt as usize * blk_size;
         sec.curr_size = sec.actual_size;
         self.sec_armor.save_item(&mut sec)?;
         self.sec_cache.insert(sec.idx, sec);
@@ -412,20 +470,156 @@ impl SectorMgr {
         Ok(())
     }
 
+    // add holes to be punched when the deletion is persistent
+    fn add_pending_holes(&mut self, sec_idx: usize, holes: Vec<Span>) {
+        if self.punch_hole && !holes.is_empty() {
+            self.pending_holes.entry(sec_idx).or_default().extend(holes);
+        }
+    }
+
+    // punch holes in sector data file to release disk space of deleted
+    // blocks, this must be called after the deletion is persistent
+    pub fn punch_pending_holes(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending_holes);
+        if !self.punch_hole {
+            return Ok(());
+        }
+
+        let blk_size = self.layout.blk_size;
+        for (sec_idx, holes) in pending {
+            let sec_data = match self.open_sector_data(sec_idx, false) {
+                Ok(sec_data) => sec_data,
+                Err(ref err) if *err == Error::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            for hole in holes {
+                let offset = (hole.begin * blk_size) as u64;
+                let len = hole.bytes_len(blk_size) as u64;
+                if let Err(err) = vio::punch_hole(&sec_data, offset, len) {
+                    if vio::is_unsupported(&err) {
+                        // fall back to reclaim space by shrinking sector only
+                        warn!("punch hole not supported, disabled: {}", err);
+                        self.punch_hole = false;
+                        return Ok(());
+                    }
+                    return Err(Error::from(err));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // get finished sectors whose proportion of deleted blocks is not less
+    // than the dead ratio, sectors are scanned up to block watermark
+    pub fn compact_candidates(
+        &mut self,
+        blk_wmark: usize,
+        dead_ratio: f32,
+    ) -> Result<Vec<CompactUnit>> {
+        let mut units = Vec::new();
+        let blks_per_sector = self.layout.blks_per_sector;
+        let sec_cnt = (blk_wmark + blks_per_sector - 1) / blks_per_sector;
+
+        for sec_idx in 0..sec_cnt {
+            let unit = match self.open_sector(sec_idx, false) {
+                Ok(sec) => CompactUnit {
+                    idx: sec_idx,
+                    size: sec.curr_size,
+                    dead: sec.curr_size - sec.actual_size,
+                },
+                Err(ref err) if *err == Error::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            // sector still being written cannot be compacted
+            if unit.size == 0 || unit.dead == 0 {
+                continue;
+            }
+            if unit.dead as f32 >= unit.size as f32 * dead_ratio {
+                units.push(unit);
+            }
+        }
+
+        Ok(units)
+    }
+
+    // compact a finished sector by shrinking it, return number of bytes
+    // written and reclaimed
+    pub fn compact_sector(&mut self, sec_idx: usize) -> Result<(usize, usize)> {
+        let (curr_size, actual_size) = match self.open_sector(sec_idx, false) {
+            Ok(sec) => (sec.curr_size, sec.actual_size),
+            Err(ref err) if *err == Error::NotFound => return Ok((0, 0)),
+            Err(err) => return Err(err),
+        };
+
+        if curr_size == 0 || actual_size == curr_size {
+            return Ok((0, 0));
+        }
+
+        self.shrink_sector(sec_idx)?;
+        debug!(
+            "sector#{} compacted from {} to {}",
+            sec_idx, curr_size, actual_size
+        );
+
+        Ok((actual_size, curr_size - actual_size))
+    }
+
+    // get blocks marked as deleted in sectors up to block watermark, all
+    // blocks of a removed sector are deleted
+    pub fn deleted_blocks(&mut self, blk_wmark: usize) -> Result<Vec<Span>> {
+        let blks_per_sector = self.layout.blks_per_sector;
+        let sec_cnt = (blk_wmark + blks_per_sector - 1) / blks_per_sector;
+        let mut deleted: Vec<Span> = Vec::new();
+
+        for sec_idx in 0..sec_cnt {
+            let base = sec_idx * blks_per_sector;
+            let holes = match self.open_sector(sec_idx, false) {
+                Ok(sec) => sec.deleted_holes(),
+                Err(ref err) if *err == Error::NotFound => {
+                    vec![Span::new(0, blks_per_sector)]
+                }
+                Err(err) => return Err(err),
+            };
+
+            for hole in holes {
+                let begin = base + hole.begin;
+                let end = (begin + hole.cnt).min(blk_wmark);
+                if begin >= end {
+                    break;
+                }
+                match deleted.last_mut() {
+                    Some(ref mut span) if span.end() == begin => span.cnt += end - begin,
+                    _ => deleted.push(Span::new(begin, end - begin)),
+                }
+            }
+        }
+
+        Ok(deleted)
+    }
+
     // delete data blocks
     pub fn del_blocks(&mut self, span: Span) -> Result<()> {
-        for sec_span in span.divide_by(BLKS_PER_SECTOR) {
-            let sec_idx = sec_span.begin / BLKS_PER_SECTOR;
+        let Layout {
+            blk_size,
+            blks_per_sector,
+            ..
+        } = self.layout;
+        for sec_span in span.divide_by(blks_per_sector) {
+            let sec_idx = sec_span.begin / blks_per_sector;
             let sec_id;
             let actual_size;
             let is_finished;
             let is_shrinkable;
+            let holes;
 
             {
                 match self.open_sector(sec_idx, false) {
                     Ok(sec) => {
                         // mark blocks as deleted
-                        sec.mark_blocks_deletion(sec_span);
+                        holes = sec.mark_blocks_deletion(sec_span, blk_size);
 
                         sec_id = sec.id.clone();
                         actual_size = sec.actual_size;
@@ -437,7 +631,9 @@ impl SectorMgr {
                 }
             }
 
-            // if this sector is not finished yet, save the sector
+            // if this sector is not finished yet, save the sector only as
+            // the deletion will be reverted by writing the blocks again if
+            // its tx is aborted
             if !is_finished {
                 self.save_sector(sec_idx)?;
                 continue;
@@ -451,12 +647,15 @@ impl SectorMgr {
                 vio::remove_file(&sec_data_path)?;
                 remove_empty_parent_dir(&sec_data_path)?;
                 self.sec_cache.remove(&sec_idx);
+                self.pending_holes.remove(&sec_idx);
             } else if is_shrinkable {
                 // shrink sector if possible
                 self.shrink_sector(sec_idx)?;
             } else {
-                // otherwise, save the sector
+                // otherwise, save the sector and release space of the
+                // deleted blocks later
                 self.save_sector(sec_idx)?;
+                self.add_pending_holes(sec_idx, holes);
             }
         }
 
@@ -471,3 +670,236 @@ impl Debug for SectorMgr {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::util::init_env;
+    use crate::volume::layout::BLKS_PER_SECTOR;
+    use crate::BLK_SIZE;
+
+    const SECTOR_SIZE: usize = BLK_SIZE * BLKS_PER_SECTOR;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn punch_hole() {
+        use std::os::unix::fs::MetadataExt;
+
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut sec_mgr = SectorMgr::new(tmpdir.path());
+        sec_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+
+        let blks: Vec<u8> = (0..BLKS_PER_SECTOR * BLK_SIZE)
+            .map(|i| (i / BLK_SIZE) as u8)
+            .collect();
+        let mut dst = vec![0u8; 16 * BLK_SIZE];
+        let path = sec_mgr.sector_data_path(0);
+        let disk_usage = |sec_mgr: &mut SectorMgr| {
+            sec_mgr
+                .open_sector_data(0, false)
+                .unwrap()
+                .sync_all()
+                .unwrap();
+            vio::metadata(&path).unwrap().blocks() * 512
+        };
+
+        // deletion in unfinished sector can be reverted, so no holes
+        sec_mgr
+            .write_blocks(Span::new(0, 256), &blks[..256 * BLK_SIZE])
+            .unwrap();
+        sec_mgr.del_blocks(Span::new(200, 8)).unwrap();
+        let used = disk_usage(&mut sec_mgr);
+        sec_mgr.punch_pending_holes().unwrap();
+        assert_eq!(disk_usage(&mut sec_mgr), used);
+
+        // deleted blocks can be written again
+        sec_mgr
+            .write_blocks(Span::new(200, 8), &blks[200 * BLK_SIZE..208 * BLK_SIZE])
+            .unwrap();
+        sec_mgr
+            .read_blocks(&mut dst[..8 * BLK_SIZE], Span::new(200, 8))
+            .unwrap();
+        assert_eq!(&dst[..8 * BLK_SIZE], &blks[200 * BLK_SIZE..208 * BLK_SIZE]);
+        sec_mgr.del_blocks(Span::new(208, 8)).unwrap();
+
+        // finish the sector, blocks deleted before are punched when flushed
+        sec_mgr
+            .write_blocks(
+                Span::new(256, BLKS_PER_SECTOR - 256),
+                &blks[256 * BLK_SIZE..],
+            )
+            .unwrap();
+        let used = disk_usage(&mut sec_mgr);
+        sec_mgr.punch_pending_holes().unwrap();
+        if sec_mgr.punch_hole {
+            assert!(used - disk_usage(&mut sec_mgr) >= 8 * BLK_SIZE as u64);
+        }
+
+        // deleted blocks should release disk space only after flush
+        let used = disk_usage(&mut sec_mgr);
+        sec_mgr.del_blocks(Span::new(16, 128)).unwrap();
+        assert_eq!(disk_usage(&mut sec_mgr), used);
+        sec_mgr.punch_pending_holes().unwrap();
+        if sec_mgr.punch_hole {
+            assert!(used - disk_usage(&mut sec_mgr) >= 128 * BLK_SIZE as u64);
+        }
+
+        // blocks not deleted are intact
+        sec_mgr.read_blocks(&mut dst, Span::new(0, 16)).unwrap();
+        assert_eq!(&dst[..], &blks[..16 * BLK_SIZE]);
+        sec_mgr.read_blocks(&mut dst, Span::new(144, 16)).unwrap();
+        assert_eq!(&dst[..], &blks[144 * BLK_SIZE..160 * BLK_SIZE]);
+        assert_eq!(
+            sec_mgr
+                .read_blocks(&mut dst, Span::new(16, 16))
+                .unwrap_err(),
+            Error::NotFound
+        );
+    }
+
+    #[test]
+    fn deleted_blocks() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut sec_mgr = SectorMgr::new(tmpdir.path());
+        sec_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+
+        let blks = vec![42u8; SECTOR_SIZE];
+        sec_mgr
+            .write_blocks(Span::new(0, BLKS_PER_SECTOR), &blks)
+            .unwrap();
+        sec_mgr
+            .write_blocks(Span::new(BLKS_PER_SECTOR, BLKS_PER_SECTOR), &blks)
+            .unwrap();
+        sec_mgr.del_blocks(Span::new(8, 8)).unwrap();
+        sec_mgr.del_blocks(Span::new(32, 1)).unwrap();
+        assert_eq!(
+            sec_mgr.deleted_blocks(BLKS_PER_SECTOR * 2).unwrap(),
+            vec![Span::new(8, 8), Span::new(32, 1)]
+        );
+
+        // removed and never written sectors are all deleted, blocks beyond
+        // watermark are not included
+        sec_mgr
+            .del_blocks(Span::new(BLKS_PER_SECTOR, BLKS_PER_SECTOR))
+            .unwrap();
+        assert_eq!(
+            sec_mgr.deleted_blocks(BLKS_PER_SECTOR * 2 + 10).unwrap(),
+            vec![
+                Span::new(8, 8),
+                Span::new(32, 1),
+                Span::new(BLKS_PER_SECTOR, BLKS_PER_SECTOR + 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_sector() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut sec_mgr = SectorMgr::new(tmpdir.path());
+        sec_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+
+        let blks: Vec<u8> = (0..BLKS_PER_SECTOR * BLK_SIZE)
+            .map(|i| (i / BLK_SIZE) as u8)
+            .collect();
+        let mut dst = vec![0u8; 16 * BLK_SIZE];
+
+        // unfinished sector cannot be compacted
+        sec_mgr
+            .write_blocks(Span::new(0, 256), &blks[..256 * BLK_SIZE])
+            .unwrap();
+        sec_mgr.del_blocks(Span::new(0, 128)).unwrap();
+        assert!(sec_mgr
+            .compact_candidates(BLKS_PER_SECTOR, 0.0)
+            .unwrap()
+            .is_empty());
+
+        // finish the sector and delete half of its blocks
+        sec_mgr
+            .write_blocks(
+                Span::new(256, BLKS_PER_SECTOR - 256),
+                &blks[256 * BLK_SIZE..],
+            )
+            .unwrap();
+        sec_mgr
+            .del_blocks(Span::new(128, BLKS_PER_SECTOR / 2 - 128))
+            .unwrap();
+        let dead = BLKS_PER_SECTOR / 2 * BLK_SIZE;
+
+        assert!(sec_mgr
+            .compact_candidates(BLKS_PER_SECTOR, 0.6)
+            .unwrap()
+            .is_empty());
+        let units = sec_mgr.compact_candidates(BLKS_PER_SECTOR, 0.5).unwrap();
+        assert_eq!(
+            units,
+            vec![CompactUnit {
+                idx: 0,
+                size: SECTOR_SIZE,
+                dead,
+            }]
+        );
+
+        // compact the sector and verify blocks not deleted are intact
+        assert_eq!(
+            sec_mgr.compact_sector(0).unwrap(),
+            (SECTOR_SIZE - dead, dead)
+        );
+        assert_eq!(sec_mgr.compact_sector(0).unwrap(), (0, 0));
+        assert!(sec_mgr
+            .compact_candidates(BLKS_PER_SECTOR, 0.0)
+            .unwrap()
+            .is_empty());
+        let offset = BLKS_PER_SECTOR / 2;
+        sec_mgr
+            .read_blocks(&mut dst, Span::new(offset, 16))
+            .unwrap();
+        assert_eq!(&dst[..], &blks[offset * BLK_SIZE..(offset + 16) * BLK_SIZE]);
+        assert_eq!(
+            sec_mgr.read_blocks(&mut dst, Span::new(0, 16)).unwrap_err(),
+            Error::NotFound
+        );
+    }
+
+    #[test]
+    fn custom_layout() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut sec_mgr = SectorMgr::new(tmpdir.path());
+        sec_mgr.set_crypto_ctx(Crypto::default(), Key::new_empty(), HashKey::new_empty());
+        let layout = Layout {
+            blk_size: 1024,
+            blks_per_frame: 4,
+            blks_per_sector: 16,
+        };
+        sec_mgr.set_layout(layout);
+
+        // write blocks across 3 sectors
+        let blks: Vec<u8> = (0..40 * 1024).map(|i| (i / 1024) as u8).collect();
+        sec_mgr.write_blocks(Span::new(0, 40), &blks).unwrap();
+        let mut dst = vec![0u8; 40 * 1024];
+        sec_mgr.read_blocks(&mut dst, Span::new(0, 40)).unwrap();
+        assert_eq!(dst, blks);
+
+        // the 1st sector is finished and can be compacted
+        sec_mgr.del_blocks(Span::new(2, 6)).unwrap();
+        let units = sec_mgr.compact_candidates(40, 0.3).unwrap();
+        assert_eq!(
+            units,
+            vec![CompactUnit {
+                idx: 0,
+                size: layout.sector_size(),
+                dead: 6 * 1024,
+            }]
+        );
+        assert_eq!(sec_mgr.compact_sector(0).unwrap(), (10 * 1024, 6 * 1024));
+        let mut dst = vec![0u8; 8 * 1024];
+        sec_mgr.read_blocks(&mut dst, Span::new(8, 8)).unwrap();
+        assert_eq!(&dst[..], &blks[8 * 1024..16 * 1024]);
+    }
+}
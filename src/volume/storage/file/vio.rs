@@ -2,7 +2,98 @@
 //!
 //! This module is to provide a zero-cost abstraction for OS file system API.
 
+// the whole file system API is provided even if some of it is not used yet
+#[allow(unused_imports)]
 pub use std::fs::{
     copy, create_dir, create_dir_all, metadata, read_dir, remove_dir, remove_dir_all, remove_file,
     rename, File, OpenOptions, ReadDir,
 };
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{Error, Result};
+
+// error returned by `punch_hole` on platforms which don't support it
+#[derive(Debug)]
+struct Unsupported;
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "punch hole is not supported")
+    }
+}
+
+impl StdError for Unsupported {}
+
+/// Deallocates a byte range in file, the range reads as zeros afterwards
+/// and file size is not changed.
+///
+/// Use [`is_unsupported`] to check if the returned error is because the file
+/// system doesn't support it.
+///
+/// [`is_unsupported`]: fn.is_unsupported.html
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Deallocates a byte range in file, not supported on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> Result<()> {
+    Err(Error::new(std::io::ErrorKind::Other, Unsupported))
+}
+
+/// Checks if an error returned by `punch_hole` means it is not supported,
+/// any other error is a real failure.
+pub fn is_unsupported(err: &Error) -> bool {
+    match err.raw_os_error() {
+        #[cfg(target_os = "linux")]
+        Some(code) => code == libc::EOPNOTSUPP || code == libc::ENOSYS,
+
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => false,
+
+        None => err
+            .get_ref()
+            .map_or(false, |inner| inner.is::<Unsupported>()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn unsupported_error() {
+        assert!(is_unsupported(&Error::new(ErrorKind::Other, Unsupported)));
+
+        #[cfg(target_os = "linux")]
+        {
+            assert!(is_unsupported(&Error::from_raw_os_error(libc::EOPNOTSUPP)));
+            assert!(is_unsupported(&Error::from_raw_os_error(libc::ENOSYS)));
+        }
+
+        // I/O error is not about support
+        assert!(!is_unsupported(&Error::from_raw_os_error(5)));
+
+        // neither is an error not from OS
+        assert!(!is_unsupported(&Error::new(
+            ErrorKind::Other,
+            "punch hole failed"
+        )));
+    }
+}
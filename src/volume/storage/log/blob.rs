@@ -0,0 +1,129 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+use crate::parallel_io::Pio;
+use crate::util::collections::HashMap;
+use crate::BlobPointer;
+
+/// Blob store
+///
+/// Large values are stored off-log in blob files under the blob directory,
+/// each blob file is named by its blob pointer. Blobs are reference counted
+/// by the log index, a blob is removed when all of its references are gone
+/// and the log has been persisted.
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+
+    // blob pointer and number of index slots referring to it
+    refs: HashMap<BlobPointer, usize>,
+
+    // unreferenced blobs, they will be removed in next collection
+    dead: Vec<BlobPointer>,
+}
+
+impl BlobStore {
+    // blob directory name
+    const DIR_NAME: &'static str = "blobs";
+
+    pub fn new(base: &Path) -> Self {
+        BlobStore {
+            dir: base.join(Self::DIR_NAME),
+            refs: HashMap::new(),
+            dead: Vec::new(),
+        }
+    }
+
+    #[inline]
+    fn blob_path(&self, ptr: BlobPointer) -> PathBuf {
+        self.dir.join(format!("{:016x}", ptr))
+    }
+
+    #[inline]
+    pub fn init(&mut self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        self.reset();
+        Ok(())
+    }
+
+    #[inline]
+    pub fn reset(&mut self) {
+        self.refs.clear();
+        self.dead.clear();
+    }
+
+    // write a blob, blob is persistent when this function returns
+    pub fn write(&self, ptr: BlobPointer, data: &[u8]) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.blob_path(ptr))?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // read data in a blob at offset
+    pub fn read(&self, ptr: BlobPointer, dst: &mut [u8], offset: usize) -> Result<()> {
+        let file = File::open(self.blob_path(ptr))?;
+        file.pread_exact(dst, offset as u64)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn inc_ref(&mut self, ptr: BlobPointer) {
+        *self.refs.entry(ptr).or_insert(0) += 1;
+    }
+
+    pub fn dec_ref(&mut self, ptr: BlobPointer) {
+        let refcnt = self.refs.get_mut(&ptr).unwrap();
+        *refcnt -= 1;
+        if *refcnt == 0 {
+            self.refs.remove(&ptr);
+            self.dead.push(ptr);
+        }
+    }
+
+    // remove unreferenced blobs, must be called after the log records
+    // which released them have been persisted
+    pub fn collect(&mut self) -> Result<usize> {
+        let cnt = self.dead.len();
+        while let Some(ptr) = self.dead.pop() {
+            match fs::remove_file(self.blob_path(ptr)) {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(cnt)
+    }
+
+    // remove blob files not referred by log, they are left by failed
+    // writes or crash before log record is written
+    pub fn remove_orphans(&mut self) -> Result<usize> {
+        self.dead.clear();
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let ptr = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| BlobPointer::from_str_radix(name, 16).ok());
+            if let Some(ptr) = ptr {
+                if !self.refs.contains_key(&ptr) {
+                    self.dead.push(ptr);
+                }
+            }
+        }
+        self.collect()
+    }
+
+    #[cfg(test)]
+    pub fn blob_cnt(&self) -> usize {
+        fs::read_dir(&self.dir).map(|rd| rd.count()).unwrap_or(0)
+    }
+}
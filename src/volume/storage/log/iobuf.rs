@@ -0,0 +1,1193 @@
+//! Lock-free IO buffers for appending messages to log.
+//!
+//! Writers reserve space in the current IO buffer by bumping its header
+//! atomically, copy their message into the reserved space and then leave
+//! the buffer. The buffer is sealed when it is full or flushed, the last
+//! writer leaving a sealed buffer writes it to log file.
+//!
+//! An IO buffer header is a 64 bits word:
+//!
+//! ```text
+//! +-----------+--------+-----------+-----------+
+//! | salt (32) | sealed | writers   | offset    |
+//! |           | (1)    | (7)       | (24)      |
+//! +-----------+--------+-----------+-----------+
+//! ```
+
+use std::cell::UnsafeCell;
+use std::fmt::{self, Debug};
+use std::fs::File;
+use std::hint::spin_loop;
+use std::slice;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::segment::{
+    MessageHeader, MessageKey, MessageKind, SegmentAccountant, SegmentHeader, SegmentTrailer,
+    MSG_HEADER_LEN, SEG_HEADER_LEN, SEG_TRAILER_LEN,
+};
+use crate::error::{Error, Result};
+use crate::parallel_io::Pio;
+use crate::{LogId, Lsn};
+
+// This is the most writers in a single IO buffer that we have space to
+// accomodate in the counter for writers in the IO buffer header.
+const MAX_WRITERS: Header = 127;
+
+// log offset of an IO buffer which is not initialised
+const UNINIT_LID: LogId = !0;
+
+type Header = u64;
+
+// return FailPoint error and mark IO buffers as crashing, so no more IO is
+// done by them
+macro_rules! io_fail {
+    ($self:expr, $e:expr) => {
+        #[cfg(feature = "failpoints")]
+        fail_point!($e, |_| {
+            $self._failpoint_crashing.store(true, SeqCst);
+            // wake up any waiting threads so they don't stall forever
+            $self.interval_updated.notify_all();
+            Err(Error::FailPoint)
+        });
+    };
+}
+
+struct IoBuf {
+    // reservations write disjoint ranges concurrently, so each byte is in
+    // its own cell rather than the whole buffer in one
+    buf: Box<[UnsafeCell<u8>]>,
+    header: AtomicU64,
+    lid: AtomicU64,
+    lsn: AtomicI64,
+    capacity: AtomicUsize,
+    maxed: AtomicBool,
+    linearizer: Mutex<()>,
+}
+
+// SAFETY: buffer bytes are only written through reservations, which claim
+// disjoint ranges by CAS on the header, or by the sealer while the buffer
+// is uninitialized and cannot be reserved. They are only read when the
+// buffer is sealed and has no writers left. Other fields are atomics or
+// a mutex.
+unsafe impl Sync for IoBuf {}
+
+/// A reserved space in IO buffer
+///
+/// Dropping a reservation without completing it aborts it, the reserved
+/// space is filled with a pad message which is skipped when reading log.
+pub(super) struct Reservation<'a> {
+    iobufs: &'a IoBufs,
+    idx: usize,
+    data: Vec<u8>,
+    destination: &'a mut [u8],
+    header: MessageHeader,
+    flushed: bool,
+    lid: LogId,
+}
+
+impl<'a> Reservation<'a> {
+    /// Header of the reserved message
+    #[inline]
+    pub(super) fn header(&self) -> &MessageHeader {
+        &self.header
+    }
+
+    /// Log offset of the reserved message
+    #[inline]
+    pub(super) fn lid(&self) -> LogId {
+        self.lid
+    }
+
+    /// Copy message to the reserved space
+    #[inline]
+    pub(super) fn complete(mut self) -> Result<()> {
+        self.flush(true)
+    }
+
+    fn flush(&mut self, valid: bool) -> Result<()> {
+        assert!(!self.flushed, "flushing already-flushed reservation");
+        self.flushed = true;
+
+        if valid {
+            self.destination.copy_from_slice(&self.data);
+        } else {
+            let pad = vec![0u8; self.data.len() - MSG_HEADER_LEN];
+            let key = MessageKey::from_suffix(0);
+            let hdr = MessageHeader::new(MessageKind::Pad, self.header.lsn, key, &pad);
+            self.destination[..MSG_HEADER_LEN].copy_from_slice(&hdr.to_bytes());
+            self.destination[MSG_HEADER_LEN..].copy_from_slice(&pad);
+        }
+
+        self.iobufs.exit_reservation(self.idx)
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        if !self.flushed {
+            if let Err(err) = self.flush(false) {
+                error!("failed to abort reservation: {}", err);
+            }
+        }
+    }
+}
+
+/// `IoBufs` is a set of lock-free buffers for coordinating writes to log
+/// file.
+pub(super) struct IoBufs {
+    file: Arc<File>,
+    io_buf_size: usize,
+
+    // We have a fixed number of io buffers. Sometimes they will all be
+    // full, and in order to prevent threads from having to spin in
+    // the reserve function, we can have them block until a buffer becomes
+    // available.
+    buf_mu: Mutex<()>,
+    buf_updated: Condvar,
+    bufs: Vec<IoBuf>,
+    current_buf: AtomicUsize,
+    written_bufs: AtomicUsize,
+
+    // Pending intervals that have been written to stable storage, but may be
+    // higher than the current value of `stable` due to interesting thread
+    // interleavings.
+    intervals: Mutex<Vec<(Lsn, Lsn)>>,
+    interval_updated: Condvar,
+
+    // The highest CONTIGUOUS log sequence number that has been written to
+    // stable storage. This may be lower than the length of the underlying
+    // file, and there may be buffers that have been written out-of-order
+    // to stable storage due to interesting thread interleavings.
+    stable_lsn: AtomicI64,
+    max_reserved_lsn: AtomicI64,
+    segment_accountant: Arc<Mutex<SegmentAccountant>>,
+
+    // used for signifying that we're simulating a crash
+    #[cfg(feature = "failpoints")]
+    _failpoint_crashing: AtomicBool,
+}
+
+impl IoBufs {
+    /// Start IO buffers at the recovered log tip.
+    ///
+    /// If `next_lsn` is at segment boundary, a new segment is allocated
+    /// from segment accountant, otherwise writing continues at `next_lid`
+    /// in the tip segment.
+    pub(super) fn start(
+        file: Arc<File>,
+        io_buf_size: usize,
+        io_bufs: usize,
+        segment_accountant: Arc<Mutex<SegmentAccountant>>,
+        next_lsn: Lsn,
+        next_lid: LogId,
+    ) -> Result<IoBufs> {
+        let bufs: Vec<IoBuf> = (0..io_bufs).map(|_| IoBuf::new(io_buf_size)).collect();
+        let current_buf = 0;
+        let iobuf = &bufs[current_buf];
+
+        if next_lsn % io_buf_size as Lsn == 0 {
+            // recovering at segment boundary
+            let lid = {
+                let mut sa = segment_accountant.lock().unwrap();
+                sa.next(next_lsn)?
+            };
+            iobuf.set_lid(lid);
+            iobuf.set_capacity(io_buf_size - SEG_TRAILER_LEN);
+            iobuf.store_segment_header(0, next_lsn);
+
+            debug!(
+                "starting log at clean offset {}, recovered lsn {}",
+                lid, next_lsn
+            );
+        } else {
+            // the tip segment is not completely full yet, reuse it
+            let offset = next_lid % io_buf_size as LogId;
+            assert_eq!(offset as Lsn, next_lsn % io_buf_size as Lsn);
+            iobuf.set_lid(next_lid);
+            iobuf.set_capacity(io_buf_size - offset as usize - SEG_TRAILER_LEN);
+            iobuf.set_lsn(next_lsn);
+
+            debug!(
+                "starting log at split offset {}, recovered lsn {}",
+                next_lid, next_lsn
+            );
+        }
+
+        // stable begins at -1 when the 0th byte of log has not been written
+        let stable = next_lsn - 1;
+
+        Ok(IoBufs {
+            file,
+            io_buf_size,
+            buf_mu: Mutex::new(()),
+            buf_updated: Condvar::new(),
+            bufs,
+            current_buf: AtomicUsize::new(current_buf),
+            written_bufs: AtomicUsize::new(0),
+            intervals: Mutex::new(vec![]),
+            interval_updated: Condvar::new(),
+            stable_lsn: AtomicI64::new(stable),
+            max_reserved_lsn: AtomicI64::new(stable),
+            segment_accountant,
+            #[cfg(feature = "failpoints")]
+            _failpoint_crashing: AtomicBool::new(false),
+        })
+    }
+
+    fn idx(&self) -> usize {
+        let current_buf = self.current_buf.load(SeqCst);
+        current_buf % self.bufs.len()
+    }
+
+    /// Returns the last stable lsn in log.
+    #[inline]
+    pub(super) fn stable(&self) -> Lsn {
+        self.stable_lsn.load(SeqCst)
+    }
+
+    /// Returns the lsn of the last reservation.
+    #[inline]
+    pub(super) fn max_reserved_lsn(&self) -> Lsn {
+        self.max_reserved_lsn.load(SeqCst)
+    }
+
+    /// Returns max payload length can be reserved in current IO buffer
+    /// without rolling to a new segment.
+    pub(super) fn room(&self) -> usize {
+        let iobuf = &self.bufs[self.idx()];
+        let header = iobuf.get_header();
+        if is_sealed(header) {
+            return 0;
+        }
+        iobuf
+            .get_capacity()
+            .saturating_sub(offset(header) as usize + MSG_HEADER_LEN)
+    }
+
+    // Adds a header to the front of the payload
+    fn encapsulate(
+        kind: MessageKind,
+        key: MessageKey,
+        payload: &[u8],
+        lsn: Lsn,
+    ) -> (MessageHeader, Vec<u8>) {
+        let header = MessageHeader::new(kind, lsn, key, payload);
+        let mut out = Vec::with_capacity(MSG_HEADER_LEN + payload.len());
+        out.extend_from_slice(&header.to_bytes());
+        out.extend_from_slice(payload);
+        (header, out)
+    }
+
+    /// Tries to claim a reservation for writing a message to a particular
+    /// location in log, which may either be completed or aborted later.
+    ///
+    /// Returns `InvalidArgument` error if the message cannot fit in an
+    /// empty segment.
+    pub(super) fn reserve(
+        &self,
+        kind: MessageKind,
+        key: MessageKey,
+        payload: &[u8],
+    ) -> Result<Reservation<'_>> {
+        let io_bufs = self.bufs.len();
+        let buf_len = MSG_HEADER_LEN + payload.len();
+        if buf_len > self.io_buf_size - SEG_HEADER_LEN - SEG_TRAILER_LEN {
+            return Err(Error::InvalidArgument);
+        }
+
+        trace!("reserving buf of len {}", buf_len);
+
+        let mut spins = 0;
+        loop {
+            #[cfg(feature = "failpoints")]
+            {
+                if self._failpoint_crashing.load(SeqCst) {
+                    return Err(Error::FailPoint);
+                }
+            }
+
+            let written_bufs = self.written_bufs.load(SeqCst);
+            let current_buf = self.current_buf.load(SeqCst);
+            let idx = current_buf % io_bufs;
+
+            spins += 1;
+            if spins > 1_000_000 {
+                debug!("stalling in reserve, idx {}, buf len {}", idx, buf_len);
+                spins = 0;
+            }
+
+            if written_bufs > current_buf {
+                // This can happen because a reservation can finish up
+                // before the sealing thread gets around to bumping
+                // current_buf.
+                spin_loop();
+                continue;
+            }
+
+            if current_buf - written_bufs >= io_bufs {
+                // if written is too far behind, we need to wait until it
+                // catches up to avoid overlap
+                let mut buf_mu = self.buf_mu.lock().unwrap();
+                while written_bufs == self.written_bufs.load(SeqCst) {
+                    buf_mu = self.buf_updated.wait(buf_mu).unwrap();
+                }
+                continue;
+            }
+
+            // load current header value
+            let iobuf = &self.bufs[idx];
+            let header = iobuf.get_header();
+
+            // skip if already sealed
+            if is_sealed(header) {
+                // already sealed, wait until current_buf has been bumped
+                // by sealer
+                let mut buf_mu = self.buf_mu.lock().unwrap();
+                while current_buf == self.current_buf.load(SeqCst) {
+                    buf_mu = self.buf_updated.wait(buf_mu).unwrap();
+                }
+                continue;
+            }
+
+            // try to claim space
+            let buf_offset = offset(header);
+            let prospective_size = buf_offset as usize + buf_len;
+            let would_overflow = prospective_size > iobuf.get_capacity();
+            if would_overflow {
+                // This buffer is too full to accept our write!
+                // Try to seal the buffer, and maybe write it if
+                // there are zero writers.
+                self.maybe_seal_and_write_iobuf(idx, header, true)?;
+                spin_loop();
+                continue;
+            }
+
+            // attempt to claim by incrementing an unsealed header
+            let bumped_offset = bump_offset(header, buf_len as Header);
+
+            // check for maxed out IO buffer writers
+            if n_writers(bumped_offset) == MAX_WRITERS {
+                spin_loop();
+                continue;
+            }
+
+            let claimed = incr_writers(bumped_offset);
+            assert!(!is_sealed(claimed));
+
+            if iobuf.cas_header(header, claimed).is_err() {
+                // CAS failed, start over
+                spin_loop();
+                continue;
+            }
+
+            // if we're giving out a reservation,
+            // the writer count should be positive
+            assert_ne!(n_writers(claimed), 0);
+
+            let lid = iobuf.get_lid();
+            assert_ne!(
+                lid, UNINIT_LID,
+                "reserved in uninitialised buffer idx {}\n{:?}",
+                idx, self
+            );
+
+            // SAFETY: the range is claimed by the header CAS above, no
+            // other reservation can overlap it and the buffer is not read
+            // until this reservation exits
+            let destination = unsafe { iobuf.slice_mut(buf_offset as usize, buf_len) };
+
+            let reservation_lid = lid + buf_offset;
+            let reservation_lsn = iobuf.get_lsn() + buf_offset as Lsn;
+
+            trace!(
+                "reserved {} bytes at lsn {} lid {}",
+                buf_len,
+                reservation_lsn,
+                reservation_lid,
+            );
+
+            self.bump_max_reserved_lsn(reservation_lsn);
+
+            let (header, data) = Self::encapsulate(kind, key, payload, reservation_lsn);
+
+            return Ok(Reservation {
+                iobufs: self,
+                idx,
+                data,
+                destination,
+                header,
+                flushed: false,
+                lid: reservation_lid,
+            });
+        }
+    }
+
+    /// Called by Reservation on termination (completion or abort).
+    /// Handles departure from shared state, and possibly writing
+    /// the buffer to log file if necessary.
+    fn exit_reservation(&self, idx: usize) -> Result<()> {
+        let iobuf = &self.bufs[idx];
+        let mut header = iobuf.get_header();
+
+        // Decrement writer count, retrying until successful.
+        loop {
+            let new_hv = decr_writers(header);
+            match iobuf.cas_header(header, new_hv) {
+                Ok(new) => {
+                    header = new;
+                    break;
+                }
+                Err(new) => {
+                    // we failed to decr, retry
+                    header = new;
+                }
+            }
+        }
+
+        // Succeeded in decrementing writers, if we decremented writers
+        // to 0 and it's sealed then we should write it to log.
+        if n_writers(header) == 0 && is_sealed(header) {
+            trace!("exiting idx {} from res", idx);
+            self.write_to_log(idx)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Blocks until the specified log sequence number has been made stable
+    /// in log file.
+    pub(super) fn make_stable(&self, lsn: Lsn) -> Result<()> {
+        // NB before we write the 0th byte of the file, stable is -1
+        while self.stable() < lsn {
+            let idx = self.idx();
+            let header = self.bufs[idx].get_header();
+            if offset(header) == 0 || is_sealed(header) {
+                // nothing to write, don't bother sealing
+                // current IO buffer.
+            } else {
+                self.maybe_seal_and_write_iobuf(idx, header, false)?;
+                continue;
+            }
+
+            // block until another thread updates the stable lsn
+            let waiter = self.intervals.lock().unwrap();
+
+            if self.stable() < lsn {
+                #[cfg(feature = "failpoints")]
+                {
+                    if self._failpoint_crashing.load(SeqCst) {
+                        return Err(Error::FailPoint);
+                    }
+                }
+                trace!("waiting on cond var for make_stable({})", lsn);
+
+                let _waiter = self.interval_updated.wait(waiter).unwrap();
+            } else {
+                trace!("make_stable({}) returning", lsn);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Force the current buffer to write all pending reservations.
+    #[inline]
+    pub(super) fn flush(&self) -> Result<()> {
+        self.make_stable(self.max_reserved_lsn())
+    }
+
+    // ensure self.max_reserved_lsn is set to this Lsn
+    // or greater, for use in correct calls to flush.
+    fn bump_max_reserved_lsn(&self, lsn: Lsn) {
+        let mut current = self.max_reserved_lsn.load(SeqCst);
+        while current < lsn {
+            match self
+                .max_reserved_lsn
+                .compare_exchange(current, lsn, SeqCst, SeqCst)
+            {
+                Ok(_) => return,
+                Err(last) => current = last,
+            }
+        }
+    }
+
+    // Attempt to seal the current IO buffer, possibly
+    // writing it to log if there are no other writers
+    // operating on it.
+    fn maybe_seal_and_write_iobuf(
+        &self,
+        idx: usize,
+        header: Header,
+        from_reserve: bool,
+    ) -> Result<()> {
+        let iobuf = &self.bufs[idx];
+
+        if is_sealed(header) {
+            // this buffer is already sealed. nothing to do here.
+            return Ok(());
+        }
+
+        // NB need to do this before CAS because it can get
+        // written and reset by another thread afterward
+        let lid = iobuf.get_lid();
+        let lsn = iobuf.get_lsn();
+        let capacity = iobuf.get_capacity();
+        let io_buf_size = self.io_buf_size;
+
+        if offset(header) as usize > capacity {
+            // a race happened, nothing we can do
+            return Ok(());
+        }
+
+        let sealed = mk_sealed(header);
+        let res_len = offset(sealed) as usize;
+
+        let maxed = res_len == capacity;
+
+        let worked = iobuf.linearized(|| {
+            if iobuf.cas_header(header, sealed).is_err() {
+                // cas failed, don't try to continue
+                return false;
+            }
+
+            trace!("{} sealed", idx);
+
+            if from_reserve || maxed {
+                // NB we linearize this together with sealing
+                // the header here to guarantee that in write_to_log,
+                // which may be executing as soon as the seal is set
+                // by another thread, the thread that calls
+                // iobuf.get_maxed() is linearized with this one!
+                trace!("setting maxed to true for idx {}", idx);
+                iobuf.set_maxed(true);
+            }
+            true
+        });
+        if !worked {
+            return Ok(());
+        }
+
+        assert_ne!(
+            lid, UNINIT_LID,
+            "sealing something that should never have \
+             been claimed (idx {})\n{:?}",
+            idx, self
+        );
+
+        // open new slot
+        let mut next_lsn = lsn;
+
+        let next_offset = if from_reserve || maxed {
+            // roll lsn to the next segment
+            let lsn_idx = lsn / io_buf_size as Lsn;
+            next_lsn = (lsn_idx + 1) * io_buf_size as Lsn;
+
+            debug!(
+                "rolling to new segment after clearing {}-{}",
+                lid,
+                lid + res_len as LogId,
+            );
+
+            let ret = {
+                let mut sa = self.segment_accountant.lock().unwrap();
+                sa.next(next_lsn)
+            };
+            #[cfg(feature = "failpoints")]
+            {
+                if let Err(Error::FailPoint) = ret {
+                    self._failpoint_crashing.store(true, SeqCst);
+                    // wake up any waiting threads so they don't stall forever
+                    self.interval_updated.notify_all();
+                }
+            }
+            ret?
+        } else {
+            trace!(
+                "advancing offset within the current segment from {} to {}",
+                lid,
+                lid + res_len as LogId
+            );
+            next_lsn += res_len as Lsn;
+
+            lid + res_len as LogId
+        };
+
+        let next_idx = (idx + 1) % self.bufs.len();
+        let next_iobuf = &self.bufs[next_idx];
+
+        // NB we spin on this CAS because the next iobuf may not actually
+        // be written to log yet! (we've lapped the writer in the iobuf
+        // ring buffer)
+        let mut spins = 0;
+        while next_iobuf.cas_lid(UNINIT_LID, next_offset).is_err() {
+            spins += 1;
+            if spins > 1_000_000 {
+                debug!("have spun >1,000,000x in seal of buf {}", idx);
+                spins = 0;
+            }
+            #[cfg(feature = "failpoints")]
+            {
+                if self._failpoint_crashing.load(SeqCst) {
+                    return Err(Error::FailPoint);
+                }
+            }
+            spin_loop();
+        }
+        trace!("{} log set to {}", next_idx, next_offset);
+
+        // NB as soon as the "sealed" bit is 0, this allows new threads
+        // to start writing into this buffer, so do that after it's all
+        // set up. expect this thread to block until the buffer completes
+        // its entire lifecycle as soon as we do that.
+        if from_reserve || maxed {
+            next_iobuf.set_capacity(io_buf_size - SEG_TRAILER_LEN);
+            next_iobuf.store_segment_header(sealed, next_lsn);
+        } else {
+            let new_cap = capacity - res_len;
+            assert_ne!(new_cap, 0);
+            next_iobuf.set_capacity(new_cap);
+            next_iobuf.set_lsn(next_lsn);
+            let last_salt = salt(sealed);
+            let new_salt = bump_salt(last_salt);
+            next_iobuf.set_header(new_salt);
+        }
+
+        trace!("{} zeroed header", next_idx);
+
+        // we acquire this mutex to guarantee that any threads that
+        // are going to wait on the condition variable will observe
+        // the change.
+        {
+            let _buf_mu = self.buf_mu.lock().unwrap();
+
+            // communicate to other threads that we have advanced an IO
+            // buffer.
+            self.current_buf.fetch_add(1, SeqCst);
+        }
+
+        // let any threads that are blocked on buf_mu know about the
+        // updated counter.
+        self.buf_updated.notify_all();
+
+        // if writers is 0, it's our responsibility to write the buffer.
+        if n_writers(sealed) == 0 {
+            trace!("writing to log from maybe_seal");
+            self.write_to_log(idx)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Write an IO buffer's data to log file and set up the next IO buffer
+    // for writing.
+    //
+    // Unused space at the end of a maxed buffer is not padded, the segment
+    // trailer tells recovery where the segment data ends.
+    fn write_to_log(&self, idx: usize) -> Result<()> {
+        let iobuf = &self.bufs[idx];
+        let header = iobuf.get_header();
+        let lid = iobuf.get_lid();
+        let base_lsn = iobuf.get_lsn();
+        let io_buf_size = self.io_buf_size;
+
+        assert_eq!(
+            (lid % io_buf_size as LogId) as Lsn,
+            base_lsn % io_buf_size as Lsn
+        );
+
+        assert_ne!(
+            lid, UNINIT_LID,
+            "created reservation for uninitialized slot",
+        );
+
+        assert!(is_sealed(header));
+
+        let res_len = offset(header) as usize;
+        let maxed = iobuf.linearized(|| iobuf.get_maxed());
+
+        // SAFETY: the buffer is sealed with no writers left, nothing writes
+        // to it until its lid is reset below
+        let data = unsafe { iobuf.slice(0, res_len) };
+        io_fail!(self, "log::buffer_write");
+        self.file.pwrite_all(data, lid)?;
+        self.file.sync_data()?;
+        io_fail!(self, "log::buffer_write_post");
+
+        // write a trailer if we're maxed
+        if maxed {
+            let segment_lsn = base_lsn / io_buf_size as Lsn * io_buf_size as Lsn;
+            let segment_lid = lid / io_buf_size as LogId * io_buf_size as LogId;
+            let used = (lid - segment_lid) as usize + res_len;
+
+            let trailer_lid = segment_lid + (io_buf_size - SEG_TRAILER_LEN) as LogId;
+            let trailer = SegmentTrailer::new(segment_lsn as u64 / io_buf_size as u64, used);
+
+            io_fail!(self, "log::trailer_write");
+            self.file.pwrite_all(&trailer.to_bytes(), trailer_lid)?;
+            self.file.sync_data()?;
+            io_fail!(self, "log::trailer_write_post");
+
+            iobuf.set_maxed(false);
+
+            debug!(
+                "wrote trailer at lid {} for lsn {}",
+                trailer_lid, segment_lsn
+            );
+        }
+
+        if res_len > 0 || maxed {
+            let complete_len = if maxed {
+                let lsn_idx = base_lsn as usize / io_buf_size;
+                let next_seg_beginning = (lsn_idx + 1) * io_buf_size;
+                next_seg_beginning - base_lsn as usize
+            } else {
+                res_len
+            };
+
+            trace!(
+                "wrote lsns {}-{} to log at offsets {}-{} in buffer {}",
+                base_lsn,
+                base_lsn + res_len as Lsn - 1,
+                lid,
+                lid + res_len as LogId - 1,
+                idx
+            );
+            self.mark_interval(base_lsn, complete_len);
+        }
+
+        // signal that this IO buffer is now uninitialized
+        iobuf.set_lid(UNINIT_LID);
+        trace!("{} log <- MAX", idx);
+
+        // we acquire this mutex to guarantee that any threads that
+        // are going to wait on the condition variable will observe
+        // the change.
+        {
+            let _buf_mu = self.buf_mu.lock().unwrap();
+
+            // communicate to other threads that we have written an IO
+            // buffer.
+            self.written_bufs.fetch_add(1, SeqCst);
+        }
+
+        // let any threads that are blocked on buf_mu know about the
+        // updated counter.
+        self.buf_updated.notify_all();
+
+        Ok(())
+    }
+
+    // It's possible that IO buffers are written out of order!
+    // So we need to use this to keep track of them, and only
+    // increment self.stable. If we didn't do this, then we would
+    // accidentally decrement self.stable sometimes, or bump stable
+    // above an offset that corresponds to a buffer that hasn't actually
+    // been written yet! It's OK to use a mutex here because it is pretty
+    // fast, compared to the other operations on shared state.
+    fn mark_interval(&self, whence: Lsn, len: usize) {
+        trace!("mark_interval({}, {})", whence, len);
+        assert_ne!(
+            len, 0,
+            "mark_interval called with a zero-length range, starting from {}",
+            whence
+        );
+        let mut intervals = self.intervals.lock().unwrap();
+        let lsn_before = self.stable_lsn.load(SeqCst);
+
+        let interval = (whence, whence + len as Lsn - 1);
+
+        intervals.push(interval);
+
+        debug_assert!(
+            intervals.len() < 1000,
+            "intervals is getting crazy... {:?}",
+            *intervals
+        );
+
+        // reverse sort
+        intervals.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut updated = false;
+        let mut lsn_after = lsn_before;
+
+        while let Some(&(low, high)) = intervals.last() {
+            let cur_stable = self.stable_lsn.load(SeqCst);
+            assert!(
+                low > cur_stable,
+                "somehow, we marked offset {} stable while \
+                 interval {}-{} had not yet been applied!",
+                cur_stable,
+                low,
+                high
+            );
+            if cur_stable + 1 == low {
+                let old = self.stable_lsn.swap(high, SeqCst);
+                assert_eq!(
+                    old, cur_stable,
+                    "concurrent stable offset modification detected"
+                );
+                trace!("new highest interval: {} - {}", low, high);
+                intervals.pop();
+                updated = true;
+                lsn_after = high;
+            } else {
+                break;
+            }
+        }
+
+        if updated {
+            self.interval_updated.notify_all();
+        }
+
+        drop(intervals);
+
+        // segments whose whole lsn range is stable are sealed
+        let io_buf_size = self.io_buf_size as Lsn;
+        let logical_segment_before = (lsn_before + 1) / io_buf_size;
+        let logical_segment_after = (lsn_after + 1) / io_buf_size;
+        if logical_segment_before != logical_segment_after {
+            let mut sa = self.segment_accountant.lock().unwrap();
+            for logical_segment in logical_segment_before..logical_segment_after {
+                let segment_lsn = logical_segment * io_buf_size;
+                // transition this segment into sealed mode
+                trace!("deactivating segment with lsn {}", segment_lsn);
+                if let Err(err) = sa.deactivate_segment(segment_lsn) {
+                    error!("segment accountant failed to deactivate segment: {}", err);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for IoBufs {
+    fn drop(&mut self) {
+        // don't do any more IO if we're simulating a crash
+        #[cfg(feature = "failpoints")]
+        {
+            if self._failpoint_crashing.load(SeqCst) {
+                return;
+            }
+        }
+
+        if let Err(err) = self.flush() {
+            error!("failed to flush from IoBufs::drop: {}", err);
+        }
+
+        debug!("IoBufs dropped");
+    }
+}
+
+impl Debug for IoBufs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let current_buf = self.current_buf.load(SeqCst);
+        let written_bufs = self.written_bufs.load(SeqCst);
+
+        f.write_fmt(format_args!(
+            "IoBufs {{ sealed: {}, written: {}, bufs: {:?} }}",
+            current_buf, written_bufs, self.bufs
+        ))
+    }
+}
+
+impl Debug for IoBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = self.get_header();
+        f.write_fmt(format_args!(
+            "\n\tIoBuf {{ lid: {}, n_writers: {}, offset: \
+             {}, sealed: {} }}",
+            self.get_lid(),
+            n_writers(header),
+            offset(header),
+            is_sealed(header)
+        ))
+    }
+}
+
+impl IoBuf {
+    fn new(buf_size: usize) -> IoBuf {
+        IoBuf {
+            buf: (0..buf_size).map(|_| UnsafeCell::new(0)).collect(),
+            header: AtomicU64::new(0),
+            lid: AtomicU64::new(UNINIT_LID),
+            lsn: AtomicI64::new(0),
+            capacity: AtomicUsize::new(0),
+            maxed: AtomicBool::new(false),
+            linearizer: Mutex::new(()),
+        }
+    }
+
+    // Returns bytes in the range, the caller must make sure no one writes
+    // to the range while the slice is alive.
+    unsafe fn slice(&self, start: usize, len: usize) -> &[u8] {
+        let cells = &self.buf[start..start + len];
+        slice::from_raw_parts(cells.as_ptr() as *const u8, len)
+    }
+
+    // Returns bytes in the range for writing, the caller must have exclusive
+    // access to the range while the slice is alive.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice_mut(&self, start: usize, len: usize) -> &mut [u8] {
+        let cells = &self.buf[start..start + len];
+        slice::from_raw_parts_mut(cells.as_ptr() as *mut u8, len)
+    }
+
+    // use this for operations on an IoBuf that must be
+    // linearized together, and can't fit in the header!
+    fn linearized<F, B>(&self, f: F) -> B
+    where
+        F: FnOnce() -> B,
+    {
+        let _l = self.linearizer.lock().unwrap();
+        f()
+    }
+
+    // This is called upon the initialization of a fresh segment.
+    // We write a new segment header to the beginning of the buffer
+    // for assistance during recovery. The caller is responsible
+    // for ensuring that the IoBuf's capacity has been set properly.
+    fn store_segment_header(&self, last: Header, lsn: Lsn) {
+        debug!("storing lsn {} in beginning of buffer", lsn);
+        assert!(self.get_capacity() >= SEG_HEADER_LEN + SEG_TRAILER_LEN);
+
+        self.set_lsn(lsn);
+
+        let seq = lsn as u64 / self.buf.len() as u64;
+        let header = SegmentHeader::new(seq, lsn);
+
+        // SAFETY: the buffer header is still sealed, so no reservation can
+        // be made in it before the new header is set below
+        let dst = unsafe { self.slice_mut(0, SEG_HEADER_LEN) };
+        dst.copy_from_slice(&header.to_bytes());
+
+        // ensure writes to the buffer land after our header.
+        let last_salt = salt(last);
+        let new_salt = bump_salt(last_salt);
+        let bumped = bump_offset(new_salt, SEG_HEADER_LEN as Header);
+        self.set_header(bumped);
+    }
+
+    #[inline]
+    fn set_capacity(&self, cap: usize) {
+        self.capacity.store(cap, SeqCst);
+    }
+
+    #[inline]
+    fn get_capacity(&self) -> usize {
+        self.capacity.load(SeqCst)
+    }
+
+    #[inline]
+    fn set_lsn(&self, lsn: Lsn) {
+        self.lsn.store(lsn, SeqCst);
+    }
+
+    #[inline]
+    fn get_lsn(&self) -> Lsn {
+        self.lsn.load(SeqCst)
+    }
+
+    #[inline]
+    fn set_maxed(&self, maxed: bool) {
+        self.maxed.store(maxed, SeqCst);
+    }
+
+    #[inline]
+    fn get_maxed(&self) -> bool {
+        self.maxed.load(SeqCst)
+    }
+
+    #[inline]
+    fn set_lid(&self, offset: LogId) {
+        self.lid.store(offset, SeqCst);
+    }
+
+    #[inline]
+    fn get_lid(&self) -> LogId {
+        self.lid.load(SeqCst)
+    }
+
+    #[inline]
+    fn get_header(&self) -> Header {
+        self.header.load(SeqCst)
+    }
+
+    #[inline]
+    fn set_header(&self, new: Header) {
+        self.header.store(new, SeqCst);
+    }
+
+    #[inline]
+    fn cas_header(&self, old: Header, new: Header) -> std::result::Result<Header, Header> {
+        self.header
+            .compare_exchange(old, new, SeqCst, SeqCst)
+            .map(|_| new)
+    }
+
+    #[inline]
+    fn cas_lid(&self, old: LogId, new: LogId) -> std::result::Result<LogId, LogId> {
+        self.lid
+            .compare_exchange(old, new, SeqCst, SeqCst)
+            .map(|_| new)
+    }
+}
+
+#[inline]
+fn is_sealed(v: Header) -> bool {
+    v & 1 << 31 == 1 << 31
+}
+
+#[inline]
+fn mk_sealed(v: Header) -> Header {
+    v | 1 << 31
+}
+
+#[inline]
+fn n_writers(v: Header) -> Header {
+    v << 33 >> 57
+}
+
+#[inline]
+fn incr_writers(v: Header) -> Header {
+    assert_ne!(n_writers(v), MAX_WRITERS);
+    v + (1 << 24)
+}
+
+#[inline]
+fn decr_writers(v: Header) -> Header {
+    assert_ne!(n_writers(v), 0);
+    v - (1 << 24)
+}
+
+#[inline]
+fn offset(v: Header) -> Header {
+    v << 40 >> 40
+}
+
+#[inline]
+fn bump_offset(v: Header, by: Header) -> Header {
+    assert_eq!(by >> 24, 0);
+    v + by
+}
+
+#[inline]
+fn bump_salt(v: Header) -> Header {
+    (v + (1 << 32)) & 0xFFFF_FFFF_0000_0000
+}
+
+#[inline]
+fn salt(v: Header) -> Header {
+    v >> 32 << 32
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::thread;
+
+    use self::tempdir::TempDir;
+    use super::super::reader::{LogRead, LogReader};
+    use super::super::segment::SEG_SIZE;
+    use super::*;
+    use crate::util::init_env;
+
+    const THREADS: u64 = 8;
+    const MSGS: u64 = 200;
+    const MSG_LEN: usize = 4000;
+
+    #[test]
+    fn concurrent_reserve() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let path = tmpdir.path().join("log");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        let file = Arc::new(file);
+        let sa = Arc::new(Mutex::new(SegmentAccountant::default()));
+        let iobufs = Arc::new(IoBufs::start(file.clone(), SEG_SIZE, 2, sa.clone(), 0, 0).unwrap());
+
+        // append messages from multiple threads, some are aborted
+        let children: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let iobufs = iobufs.clone();
+                thread::spawn(move || {
+                    for i in 0..MSGS {
+                        let key = MessageKey::from_suffix(t << 32 | i);
+                        let payload = vec![(t * 31 + i) as u8; MSG_LEN];
+                        let res = iobufs
+                            .reserve(MessageKind::SuperBlk, key, &payload)
+                            .unwrap();
+                        if i % 10 != 9 {
+                            res.complete().unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for child in children {
+            child.join().unwrap();
+        }
+        iobufs.flush().unwrap();
+        assert!(iobufs.stable() > iobufs.max_reserved_lsn());
+
+        // read all segments back in order
+        let seg_cnt = sa.lock().unwrap().segs().len();
+        assert!(seg_cnt > 1);
+        let mut hdrs: Vec<_> = (0..seg_cnt)
+            .map(|idx| {
+                let lid = SegmentAccountant::seg_offset(idx);
+                (file.read_segment_header(lid).unwrap(), lid)
+            })
+            .collect();
+        hdrs.sort_by_key(|&(hdr, _)| hdr.seq);
+
+        let mut found = HashMap::new();
+        for (n, &(hdr, lid)) in hdrs.iter().enumerate() {
+            // all segments except the tip are sealed with trailer
+            let limit = if n < hdrs.len() - 1 {
+                let trailer = file.read_segment_trailer(lid).unwrap();
+                assert_eq!(trailer.seq, hdr.seq);
+                trailer.used
+            } else {
+                SEG_SIZE - SEG_TRAILER_LEN
+            };
+
+            let mut pos = SEG_HEADER_LEN;
+            while pos + MSG_HEADER_LEN <= limit {
+                let max_len = limit - pos - MSG_HEADER_LEN;
+                let (msg, payload) = match file.read_message(lid + pos as LogId, max_len).unwrap() {
+                    LogRead::Inline(msg, payload) => (msg, payload),
+                    LogRead::Corrupted => break,
+                };
+                assert_eq!(msg.lsn, hdr.base_lsn + pos as Lsn);
+                if msg.kind == MessageKind::SuperBlk {
+                    let suffix = msg.key.to_suffix();
+                    assert!(found.insert(suffix, payload).is_none());
+                }
+                pos += MSG_HEADER_LEN + msg.len;
+            }
+            if n < hdrs.len() - 1 {
+                assert_eq!(pos, limit);
+            }
+        }
+
+        // every completed message is there exactly once
+        assert_eq!(found.len() as u64, THREADS * MSGS / 10 * 9);
+        for t in 0..THREADS {
+            for i in (0..MSGS).filter(|i| i % 10 != 9) {
+                let payload = &found[&(t << 32 | i)];
+                assert_eq!(payload, &vec![(t * 31 + i) as u8; MSG_LEN]);
+            }
+        }
+    }
+}
@@ -0,0 +1,1040 @@
+use std::cmp::min;
+use std::fmt::{self, Debug};
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::blob::BlobStore;
+use super::iobuf::IoBufs;
+use super::reader::{LogRead, LogReader};
+use super::segment::{
+    BlobRef, MessageHeader, MessageKey, MessageKind, SegState, SegmentAccountant, SegmentHeader,
+    SegmentTrailer, MAX_MSG_LEN, MSG_HEADER_LEN, SEG_HEADER_LEN, SEG_SIZE, SEG_TRAILER_LEN,
+};
+use crate::diskptr::DiskPtr;
+use crate::error::{Error, Result};
+use crate::parallel_io::Pio;
+use crate::trans::eid::Eid;
+use crate::util::collections::HashMap;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+use crate::volume::storage::Storable;
+use crate::{BlobPointer, LogId, Lsn};
+
+// location and length of a value, the value is at the offset from where
+// the pointer points to
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    // lsn of the message holding the value, the value can only be read
+    // from log file when the lsn is stable
+    lsn: Lsn,
+    ptr: DiskPtr,
+    offset: usize,
+    len: usize,
+}
+
+impl Slot {
+    #[inline]
+    fn inline(lsn: Lsn, lid: LogId, offset: usize, len: usize) -> Self {
+        Slot {
+            lsn,
+            ptr: DiskPtr::Inline(lid),
+            offset,
+            len,
+        }
+    }
+
+    #[inline]
+    fn blob(lsn: Lsn, lid: LogId, blob_ptr: BlobPointer, offset: usize, len: usize) -> Self {
+        Slot {
+            lsn,
+            ptr: DiskPtr::Blob(lid, blob_ptr),
+            offset,
+            len,
+        }
+    }
+
+    #[inline]
+    fn seg_idx(&self) -> usize {
+        SegmentAccountant::seg_idx(self.ptr.lid())
+    }
+
+    // bytes used in log, blob value only takes its log record
+    #[inline]
+    fn log_len(&self) -> usize {
+        match self.ptr {
+            DiskPtr::Inline(_) => self.len,
+            DiskPtr::Blob(..) => 0,
+        }
+    }
+
+    // check if this slot is the value at offset in the message data
+    #[inline]
+    fn is_at(&self, data: LogId, offset: usize) -> bool {
+        self.ptr.lid() == data && self.offset == offset
+    }
+}
+
+// insert or remove a value slot in index, and update segment live bytes
+// and blob references
+fn update_index<K: Hash + Eq>(
+    index: &mut HashMap<K, Slot>,
+    segs: &mut [SegState],
+    blobs: &mut BlobStore,
+    key: K,
+    slot: Option<Slot>,
+) {
+    let old = match slot {
+        Some(slot) => {
+            segs[slot.seg_idx()].live += slot.log_len();
+            if let DiskPtr::Blob(_, blob_ptr) = slot.ptr {
+                blobs.inc_ref(blob_ptr);
+            }
+            index.insert(key, slot)
+        }
+        None => index.remove(&key),
+    };
+    if let Some(old) = old {
+        segs[old.seg_idx()].live -= old.log_len();
+        if let DiskPtr::Blob(_, blob_ptr) = old.ptr {
+            blobs.dec_ref(blob_ptr);
+        }
+    }
+}
+
+/// Log Storage
+///
+/// This storage keeps the whole volume in a single append-only log file.
+/// The log file is divided into segments, every update is appended to the
+/// tip segment as a message through the lock-free IO buffers. An in-memory
+/// index is rebuilt by scanning all segments when the storage is opened.
+///
+/// Address and block messages stay in IO buffers until they are flushed,
+/// super block and WAL messages are flushed immediately.
+///
+/// Segments are cleaned from the oldest one when the proportion of live
+/// data drops below a threshold, live messages in it are appended again
+/// to the tip and then the segment is reused.
+///
+/// Large block writes are stored off-log in blob files, only a reference
+/// to the blob is appended to the log.
+pub struct LogStorage {
+    base: PathBuf,
+    file: Option<Arc<File>>,
+
+    // log writer, it is started after the log is recovered
+    iobufs: Option<IoBufs>,
+
+    // segment states, shared with log writer
+    sa: Arc<Mutex<SegmentAccountant>>,
+
+    // value indexes
+    super_blks: HashMap<u64, Slot>,
+    wals: HashMap<Eid, Slot>,
+    addrs: HashMap<Eid, Slot>,
+    blks: HashMap<usize, Slot>,
+
+    blobs: BlobStore,
+
+    // volume block layout
+    layout: Layout,
+}
+
+impl LogStorage {
+    // log file name
+    const LOG_FILE_NAME: &'static str = "log";
+
+    // number of IO buffers
+    const IO_BUFS: usize = 2;
+
+    // segments will be cleaned if live data is less than this
+    // percentage of used space
+    const CLEAN_THRESHOLD: usize = 50;
+
+    pub fn new(base: &Path) -> Self {
+        LogStorage {
+            base: base.to_path_buf(),
+            file: None,
+            iobufs: None,
+            sa: Arc::new(Mutex::new(SegmentAccountant::default())),
+            super_blks: HashMap::new(),
+            wals: HashMap::new(),
+            addrs: HashMap::new(),
+            blks: HashMap::new(),
+            blobs: BlobStore::new(base),
+            layout: Layout::default(),
+        }
+    }
+
+    // block writes not smaller than this are stored in blob
+    #[inline]
+    fn blob_threshold(&self) -> usize {
+        self.layout.frame_size()
+    }
+
+    #[inline]
+    fn log_path(&self) -> PathBuf {
+        self.base.join(Self::LOG_FILE_NAME)
+    }
+
+    #[inline]
+    fn file(&self) -> Result<&Arc<File>> {
+        self.file.as_ref().ok_or(Error::NotFound)
+    }
+
+    #[inline]
+    fn iobufs(&self) -> Result<&IoBufs> {
+        self.iobufs.as_ref().ok_or(Error::NotFound)
+    }
+
+    // stop log writer and clear all states, pending writes are flushed
+    // when log writer is dropped
+    fn reset(&mut self) {
+        self.iobufs = None;
+        *self.sa.lock().unwrap() = SegmentAccountant::default();
+        self.super_blks.clear();
+        self.wals.clear();
+        self.addrs.clear();
+        self.blks.clear();
+        self.blobs.reset();
+    }
+
+    // start log writer at the tip
+    fn start(&mut self, next_lsn: Lsn, next_lid: LogId) -> Result<()> {
+        let iobufs = IoBufs::start(
+            self.file()?.clone(),
+            SEG_SIZE,
+            Self::IO_BUFS,
+            self.sa.clone(),
+            next_lsn,
+            next_lid,
+        )?;
+        self.iobufs = Some(iobufs);
+        Ok(())
+    }
+
+    // write trailer to a segment which is not full, this is only used for
+    // segments left unsealed by crash, log writer seals full segments
+    fn seal_seg(&mut self, seg_idx: usize) -> Result<()> {
+        let mut sa = self.sa.lock().unwrap();
+        let seg = &mut sa.segs_mut()[seg_idx];
+        let trailer = SegmentTrailer::new(seg.seq, seg.used);
+        let offset = SegmentAccountant::seg_offset(seg_idx) + (SEG_SIZE - SEG_TRAILER_LEN) as LogId;
+        self.file()?.pwrite_all(&trailer.to_bytes(), offset)?;
+        seg.sealed = true;
+        Ok(())
+    }
+
+    // append a message to log and apply it to index
+    fn append(&mut self, kind: MessageKind, key: MessageKey, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_MSG_LEN {
+            return Err(Error::InvalidArgument);
+        }
+
+        let (hdr, lid) = {
+            let res = self.iobufs()?.reserve(kind, key, payload)?;
+            let hdr = *res.header();
+            let lid = res.lid();
+            res.complete()?;
+            (hdr, lid)
+        };
+
+        {
+            let mut sa = self.sa.lock().unwrap();
+            sa.mark_used(lid, MSG_HEADER_LEN + payload.len());
+        }
+        self.apply(&hdr, lid, payload);
+
+        Ok(())
+    }
+
+    // apply a message at the log offset to index
+    fn apply(&mut self, hdr: &MessageHeader, lid: LogId, payload: &[u8]) {
+        let data = lid + MSG_HEADER_LEN as LogId;
+        let slot = Slot::inline(hdr.lsn, data, 0, hdr.len);
+        let blk_size = self.layout.blk_size;
+        let mut sa = self.sa.lock().unwrap();
+        let segs = sa.segs_mut();
+        let blobs = &mut self.blobs;
+
+        match hdr.kind {
+            MessageKind::SuperBlk => {
+                let suffix = hdr.key.to_suffix();
+                update_index(&mut self.super_blks, segs, blobs, suffix, Some(slot))
+            }
+            MessageKind::Wal => {
+                update_index(&mut self.wals, segs, blobs, hdr.key.to_eid(), Some(slot))
+            }
+            MessageKind::DelWal => {
+                update_index(&mut self.wals, segs, blobs, hdr.key.to_eid(), None)
+            }
+            MessageKind::Addr => {
+                update_index(&mut self.addrs, segs, blobs, hdr.key.to_eid(), Some(slot))
+            }
+            MessageKind::DelAddr => {
+                update_index(&mut self.addrs, segs, blobs, hdr.key.to_eid(), None)
+            }
+            MessageKind::Blocks => {
+                for (i, blk_idx) in hdr.key.to_span().into_iter().enumerate() {
+                    let slot = Slot::inline(hdr.lsn, data, i * blk_size, blk_size);
+                    update_index(&mut self.blks, segs, blobs, blk_idx, Some(slot));
+                }
+            }
+            MessageKind::BlobBlocks => {
+                let blob_ref = BlobRef::from_bytes(payload);
+                for (i, blk_idx) in hdr.key.to_span().into_iter().enumerate() {
+                    let offset = blob_ref.offset + i * blk_size;
+                    let slot = Slot::blob(hdr.lsn, data, blob_ref.ptr, offset, blk_size);
+                    update_index(&mut self.blks, segs, blobs, blk_idx, Some(slot));
+                }
+            }
+            MessageKind::DelBlocks => {
+                for blk_idx in hdr.key.to_span() {
+                    update_index(&mut self.blks, segs, blobs, blk_idx, None);
+                }
+            }
+
+            // aborted reservation
+            MessageKind::Pad => {}
+        }
+    }
+
+    // read value from log or blob
+    fn read_at(&self, slot: &Slot, dst: &mut [u8]) -> Result<()> {
+        match slot.ptr {
+            DiskPtr::Inline(lid) => {
+                // value could still be in IO buffer
+                let iobufs = self.iobufs()?;
+                if slot.lsn > iobufs.stable() {
+                    iobufs.make_stable(slot.lsn)?;
+                }
+                self.file()?.pread_exact(dst, lid + slot.offset as LogId)?
+            }
+            DiskPtr::Blob(_, blob_ptr) => self.blobs.read(blob_ptr, dst, slot.offset)?,
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn read_slot(&self, slot: &Slot) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; slot.len];
+        self.read_at(slot, &mut buf)?;
+        Ok(buf)
+    }
+
+    // append blocks, split them into multiple messages if necessary
+    fn append_blocks(&mut self, span: Span, mut blks: &[u8]) -> Result<()> {
+        let blk_size = self.layout.blk_size;
+        let max_msg_blks = MAX_MSG_LEN / blk_size;
+        let mut begin = span.begin;
+        while begin < span.end() {
+            // fill up the tip segment, log writer will roll to a new
+            // segment if there is no room for a single block
+            let mut room = min(self.iobufs()?.room() / blk_size, max_msg_blks);
+            if room == 0 {
+                room = max_msg_blks;
+            }
+            let cnt = min(span.end() - begin, room);
+            let key = MessageKey::from_span(Span::new(begin, cnt));
+            self.append(MessageKind::Blocks, key, &blks[..cnt * blk_size])?;
+            blks = &blks[cnt * blk_size..];
+            begin += cnt;
+        }
+        Ok(())
+    }
+
+    // write blocks to a new blob and append its reference to log
+    fn append_blob(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        // the next message lsn is greater than this, so the blob pointer is
+        // unique and not greater than lsn of the message referring to it
+        let blob_ptr = self.iobufs()?.max_reserved_lsn() + 1;
+        self.blobs.write(blob_ptr, blks)?;
+        let blob_ref = BlobRef::new(blob_ptr, 0);
+        self.append(
+            MessageKind::BlobBlocks,
+            MessageKey::from_span(span),
+            &blob_ref.to_bytes(),
+        )
+    }
+
+    // find runs of blocks in a message which are still live
+    fn live_runs<F>(&self, span: Span, is_live: F) -> Vec<Span>
+    where
+        F: Fn(usize, &Slot) -> bool,
+    {
+        let mut runs: Vec<Span> = Vec::new();
+        for (i, blk_idx) in span.into_iter().enumerate() {
+            if !self
+                .blks
+                .get(&blk_idx)
+                .map_or(false, |slot| is_live(i, slot))
+            {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run) if run.end() == blk_idx => run.cnt += 1,
+                _ => runs.push(Span::new(blk_idx, 1)),
+            }
+        }
+        runs
+    }
+
+    // read messages in a segment up to limit, return the end position of
+    // the last valid message
+    fn scan_seg<F>(
+        file: &File,
+        seg_idx: usize,
+        hdr: &SegmentHeader,
+        limit: usize,
+        mut f: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&MessageHeader, usize, &[u8]) -> Result<()>,
+    {
+        let seg_offset = SegmentAccountant::seg_offset(seg_idx);
+        let mut pos = SEG_HEADER_LEN;
+
+        while pos + MSG_HEADER_LEN <= limit {
+            let max_len = limit - pos - MSG_HEADER_LEN;
+            let (msg, payload) = match file.read_message(seg_offset + pos as LogId, max_len)? {
+                LogRead::Inline(msg, payload) => (msg, payload),
+                LogRead::Corrupted => break,
+            };
+
+            // stale message left by previous use of this segment
+            if msg.lsn != hdr.base_lsn + pos as Lsn {
+                break;
+            }
+
+            f(&msg, pos, &payload)?;
+            pos += MSG_HEADER_LEN + msg.len;
+        }
+
+        Ok(pos)
+    }
+
+    // scan all segments to rebuild index and start log writer
+    fn recover(&mut self) -> Result<()> {
+        self.reset();
+
+        let file = self.file()?.clone();
+        let file_len = file.metadata()?.len() as usize;
+        let seg_cnt = (file_len + SEG_SIZE - 1) / SEG_SIZE;
+        *self.sa.lock().unwrap() = SegmentAccountant::new(vec![SegState::new_free(); seg_cnt]);
+
+        // read all valid segment headers and sort them in write order
+        let mut hdrs = Vec::new();
+        for seg_idx in 0..seg_cnt {
+            match file.read_segment_header(SegmentAccountant::seg_offset(seg_idx)) {
+                Ok(hdr) => hdrs.push((hdr, seg_idx)),
+                Err(Error::Corrupted) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        hdrs.sort_by_key(|&(hdr, _)| hdr.seq);
+
+        for &(hdr, seg_idx) in hdrs.iter() {
+            let seg_offset = SegmentAccountant::seg_offset(seg_idx);
+            let seg_len = min(SEG_SIZE, file_len - seg_offset as usize);
+
+            // sealed segment has a trailer tells its used length
+            let trailer = match file.read_segment_trailer(seg_offset) {
+                Ok(trailer) if trailer.seq == hdr.seq => Some(trailer),
+                Ok(_) | Err(Error::Corrupted) => None,
+                Err(err) => return Err(err),
+            };
+            let limit = trailer
+                .map(|t| t.used)
+                .unwrap_or(SEG_SIZE - SEG_TRAILER_LEN)
+                .min(seg_len);
+
+            self.sa.lock().unwrap().segs_mut()[seg_idx] = SegState {
+                seq: hdr.seq,
+                used: SEG_HEADER_LEN,
+                live: 0,
+                sealed: trailer.is_some(),
+                free: false,
+            };
+
+            let end = Self::scan_seg(&file, seg_idx, &hdr, limit, |msg, pos, payload| {
+                self.apply(msg, seg_offset + pos as LogId, payload);
+                Ok(())
+            })?;
+            if trailer.is_some() && end != limit {
+                warn!("log segment#{} is corrupted", seg_idx);
+                return Err(Error::Corrupted);
+            }
+            self.sa.lock().unwrap().segs_mut()[seg_idx].used = end;
+        }
+
+        // continue writing on the latest segment if it is not sealed and
+        // still has room, any other unsealed segments are sealed now
+        let mut tip = None;
+        if let Some(&(hdr, seg_idx)) = hdrs.last() {
+            let seg = self.sa.lock().unwrap().segs()[seg_idx];
+            if !seg.sealed && seg.used + MSG_HEADER_LEN < SEG_SIZE - SEG_TRAILER_LEN {
+                tip = Some(seg_idx);
+            }
+            for &(_, seg_idx) in hdrs.iter() {
+                if Some(seg_idx) != tip && !self.sa.lock().unwrap().segs()[seg_idx].sealed {
+                    self.seal_seg(seg_idx)?;
+                }
+            }
+            match tip {
+                Some(seg_idx) => {
+                    let used = seg.used;
+                    let next_lsn = hdr.base_lsn + used as Lsn;
+                    let next_lid = SegmentAccountant::seg_offset(seg_idx) + used as LogId;
+                    self.start(next_lsn, next_lid)?;
+                }
+                None => self.start(hdr.base_lsn + SEG_SIZE as Lsn, 0)?,
+            }
+        } else {
+            self.start(0, 0)?;
+        }
+
+        // remove blobs whose log records are lost
+        let orphans = self.blobs.remove_orphans()?;
+
+        debug!(
+            "log recovered: {} segments, stable lsn {}, {} orphan blobs removed",
+            hdrs.len(),
+            self.iobufs()?.stable(),
+            orphans
+        );
+
+        Ok(())
+    }
+
+    // move live messages in a segment to tip and free it
+    fn clean_seg(&mut self, seg_idx: usize) -> Result<()> {
+        let file = self.file()?.clone();
+        let seg_offset = SegmentAccountant::seg_offset(seg_idx);
+        let used = self.sa.lock().unwrap().segs()[seg_idx].used;
+        let hdr = file.read_segment_header(seg_offset)?;
+        let blk_size = self.layout.blk_size;
+
+        Self::scan_seg(&file, seg_idx, &hdr, used, |msg, pos, payload| {
+            let data = seg_offset + (pos + MSG_HEADER_LEN) as LogId;
+            let is_live = |slot: Option<&Slot>| slot.map_or(false, |s| s.is_at(data, 0));
+
+            match msg.kind {
+                MessageKind::SuperBlk => {
+                    if is_live(self.super_blks.get(&msg.key.to_suffix())) {
+                        self.append(msg.kind, msg.key, payload)?;
+                    }
+                }
+                MessageKind::Wal => {
+                    if is_live(self.wals.get(&msg.key.to_eid())) {
+                        self.append(msg.kind, msg.key, payload)?;
+                    }
+                }
+                MessageKind::Addr => {
+                    if is_live(self.addrs.get(&msg.key.to_eid())) {
+                        self.append(msg.kind, msg.key, payload)?;
+                    }
+                }
+                MessageKind::Blocks => {
+                    // rewrite runs of live blocks
+                    let span = msg.key.to_span();
+                    let runs = self.live_runs(span, |i, slot| slot.is_at(data, i * blk_size));
+                    for run in runs {
+                        let off = (run.begin - span.begin) * blk_size;
+                        let len = run.bytes_len(blk_size);
+                        self.append_blocks(run, &payload[off..off + len])?;
+                    }
+                }
+                MessageKind::BlobBlocks => {
+                    // blob is not moved, only its live references are
+                    // rewritten
+                    let span = msg.key.to_span();
+                    let blob_ref = BlobRef::from_bytes(payload);
+                    let runs = self.live_runs(span, |i, slot| {
+                        slot.is_at(data, blob_ref.offset + i * blk_size)
+                    });
+                    for run in runs {
+                        let off = blob_ref.offset + (run.begin - span.begin) * blk_size;
+                        let run_ref = BlobRef::new(blob_ref.ptr, off);
+                        let key = MessageKey::from_span(run);
+                        self.append(MessageKind::BlobBlocks, key, &run_ref.to_bytes())?;
+                    }
+                }
+
+                // this is the oldest segment, so tombstones can be dropped
+                // as all older messages have been cleaned already
+                MessageKind::DelWal | MessageKind::DelAddr | MessageKind::DelBlocks => {}
+
+                MessageKind::Pad => {}
+            }
+            Ok(())
+        })?;
+
+        // make sure relocated messages are persistent before the segment
+        // is freed
+        self.iobufs()?.flush()?;
+        file.pwrite_all(&[0u8; SEG_HEADER_LEN], seg_offset)?;
+        file.sync_data()?;
+
+        let mut sa = self.sa.lock().unwrap();
+        debug_assert_eq!(sa.segs()[seg_idx].live, 0);
+        sa.free_seg(seg_idx);
+
+        Ok(())
+    }
+
+    // clean the oldest segments until live data proportion is above the
+    // threshold, return number of cleaned segments
+    fn clean(&mut self) -> Result<usize> {
+        let mut cleaned = 0;
+        let seg_cnt = self.sa.lock().unwrap().segs().len();
+
+        for _ in 0..seg_cnt {
+            let oldest = {
+                let sa = self.sa.lock().unwrap();
+                let (used, live) = sa
+                    .segs()
+                    .iter()
+                    .filter(|s| !s.free)
+                    .fold((0, 0), |(used, live), s| {
+                        (used + s.used - SEG_HEADER_LEN, live + s.live)
+                    });
+                if live * 100 >= used * Self::CLEAN_THRESHOLD {
+                    break;
+                }
+
+                sa.segs()
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, s)| !s.free && s.sealed)
+                    .min_by_key(|&(_, s)| s.seq)
+                    .map(|(idx, _)| idx)
+            };
+            match oldest {
+                Some(seg_idx) => self.clean_seg(seg_idx)?,
+                None => break,
+            }
+            cleaned += 1;
+        }
+
+        // shrink log file if there are free segments at the end
+        if cleaned > 0 {
+            let seg_cnt = self.sa.lock().unwrap().truncate();
+            let len = SegmentAccountant::seg_offset(seg_cnt);
+            let file = self.file()?;
+            if file.metadata()?.len() > len {
+                file.set_len(len)?;
+            }
+            debug!("log cleaned {} segments", cleaned);
+        }
+
+        Ok(cleaned)
+    }
+}
+
+impl Storable for LogStorage {
+    #[inline]
+    fn exists(&self) -> Result<bool> {
+        Ok(self.log_path().exists())
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        if self.file.is_some() || !self.log_path().exists() {
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.log_path())?;
+        self.file = Some(Arc::new(file));
+        self.recover()
+    }
+
+    fn init(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        fs::create_dir_all(&self.base)?;
+        self.blobs.init()?;
+        self.reset();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.log_path())?;
+        file.sync_all()?;
+        self.file = Some(Arc::new(file));
+        self.start(0, 0)
+    }
+
+    #[inline]
+    fn open(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        self.connect()
+    }
+
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        let changed = layout != self.layout;
+        self.layout = layout;
+
+        // block index built when connecting depends on block size, so it
+        // must be rebuilt
+        if changed && self.file.is_some() {
+            self.recover()?;
+        }
+        Ok(())
+    }
+
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        let slot = *self.super_blks.get(&suffix).ok_or(Error::NotFound)?;
+        self.read_slot(&slot)
+    }
+
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        let key = MessageKey::from_suffix(suffix);
+        self.append(MessageKind::SuperBlk, key, super_blk)?;
+        self.iobufs()?.flush()
+    }
+
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        let slot = *self.wals.get(id).ok_or(Error::NotFound)?;
+        self.read_slot(&slot)
+    }
+
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        self.append(MessageKind::Wal, MessageKey::from_eid(id), wal)?;
+        self.iobufs()?.flush()
+    }
+
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        if self.wals.contains_key(id) {
+            self.append(MessageKind::DelWal, MessageKey::from_eid(id), &[])?;
+            self.iobufs()?.flush()?;
+        }
+        Ok(())
+    }
+
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        let slot = *self.addrs.get(id).ok_or(Error::NotFound)?;
+        self.read_slot(&slot)
+    }
+
+    #[inline]
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        self.append(MessageKind::Addr, MessageKey::from_eid(id), addr)
+    }
+
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        if self.addrs.contains_key(id) {
+            self.append(MessageKind::DelAddr, MessageKey::from_eid(id), &[])?;
+        }
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        let blk_size = self.layout.blk_size;
+        assert_eq!(dst.len(), span.bytes_len(blk_size));
+
+        // read continuous blocks in one go
+        let mut read = 0;
+        let mut blk_idx = span.begin;
+        while blk_idx < span.end() {
+            let slot = *self.blks.get(&blk_idx).ok_or(Error::NotFound)?;
+            let mut cnt = 1;
+            while blk_idx + cnt < span.end() {
+                match self.blks.get(&(blk_idx + cnt)) {
+                    Some(next) if next.is_at(slot.ptr.lid(), slot.offset + cnt * blk_size) => {
+                        cnt += 1
+                    }
+                    _ => break,
+                }
+            }
+            let len = cnt * blk_size;
+            self.read_at(&slot, &mut dst[read..read + len])?;
+            read += len;
+            blk_idx += cnt;
+        }
+
+        Ok(())
+    }
+
+    fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        assert_eq!(blks.len(), span.bytes_len(self.layout.blk_size));
+        if blks.len() >= self.blob_threshold() {
+            self.append_blob(span, blks)
+        } else {
+            self.append_blocks(span, blks)
+        }
+    }
+
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        if span
+            .into_iter()
+            .any(|blk_idx| self.blks.contains_key(&blk_idx))
+        {
+            self.append(MessageKind::DelBlocks, MessageKey::from_span(span), &[])?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.iobufs()?.flush()?;
+        self.clean()?;
+
+        // blobs can be removed now as the log records released them are
+        // persistent
+        self.blobs.collect()?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        true
+    }
+}
+
+impl Debug for LogStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LogStorage")
+            .field("base", &self.base)
+            .field("segs", &self.sa.lock().unwrap().segs().len())
+            .field("iobufs", &self.iobufs)
+            .field("addrs", &self.addrs.len())
+            .field("blks", &self.blks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::util::crypto::{Cipher, Cost, RandomSeed};
+    use crate::util::init_env;
+    use crate::BLK_SIZE;
+
+    fn open_storage(base: &Path) -> LogStorage {
+        let mut ls = LogStorage::new(base);
+        ls.connect().unwrap();
+        ls.open(Crypto::default(), Key::new_empty()).unwrap();
+        ls
+    }
+
+    fn blocks(cnt: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; cnt * BLK_SIZE];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn file_len(base: &Path) -> u64 {
+        fs::metadata(base.join(LogStorage::LOG_FILE_NAME))
+            .unwrap()
+            .len()
+    }
+
+    // put blocks in small chunks so they are stored inline
+    fn put_inline(ls: &mut LogStorage, span: Span, blks: &[u8]) {
+        let chunk = ls.blob_threshold() / BLK_SIZE - 1;
+        let mut begin = span.begin;
+        for buf in blks.chunks(chunk * BLK_SIZE) {
+            let cnt = buf.len() / BLK_SIZE;
+            ls.put_blocks(Span::new(begin, cnt), buf).unwrap();
+            begin += cnt;
+        }
+    }
+
+    fn read_blocks(ls: &mut LogStorage, span: Span) -> Result<Vec<u8>> {
+        let mut dst = vec![0u8; span.bytes_len(BLK_SIZE)];
+        ls.get_blocks(&mut dst, span)?;
+        Ok(dst)
+    }
+
+    #[test]
+    fn log_recover() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let base = tmpdir.path().join("repo");
+        let (id, id2) = (Eid::new(), Eid::new());
+        let blks = blocks(3, 42);
+
+        {
+            let mut ls = LogStorage::new(&base);
+            assert!(!ls.exists().unwrap());
+            let crypto = Crypto::new(Cost::default(), Cipher::default()).unwrap();
+            ls.init(crypto, Key::new_empty()).unwrap();
+            assert!(ls.exists().unwrap());
+
+            ls.put_super_block(&[1, 2, 3], 0).unwrap();
+            ls.put_super_block(&[4, 5, 6], 0).unwrap();
+            ls.put_wal(&id, &[1]).unwrap();
+            ls.put_wal(&id2, &[2]).unwrap();
+            ls.del_wal(&id2).unwrap();
+            ls.put_address(&id, &[3, 4]).unwrap();
+            ls.put_blocks(Span::new(0, 3), &blks).unwrap();
+            ls.del_blocks(Span::new(1, 1)).unwrap();
+            ls.flush().unwrap();
+        }
+
+        // reopen and rebuild index
+        {
+            let mut ls = open_storage(&base);
+            assert_eq!(ls.get_super_block(0).unwrap(), vec![4, 5, 6]);
+            assert_eq!(ls.get_super_block(1).unwrap_err(), Error::NotFound);
+            assert_eq!(ls.get_wal(&id).unwrap(), vec![1]);
+            assert_eq!(ls.get_wal(&id2).unwrap_err(), Error::NotFound);
+            assert_eq!(ls.get_address(&id).unwrap(), vec![3, 4]);
+            let mut dst = vec![0u8; BLK_SIZE];
+            ls.get_blocks(&mut dst, Span::new(2, 1)).unwrap();
+            assert_eq!(&dst[..], &blks[2 * BLK_SIZE..]);
+            assert_eq!(
+                ls.get_blocks(&mut dst, Span::new(1, 1)).unwrap_err(),
+                Error::NotFound
+            );
+            ls.put_address(&id2, &[5]).unwrap();
+        }
+
+        // torn write at the end of log should be ignored
+        {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(base.join(LogStorage::LOG_FILE_NAME))
+                .unwrap();
+            file.write_all(&[6u8; 30]).unwrap();
+        }
+        let mut ls = open_storage(&base);
+        assert_eq!(ls.get_address(&id2).unwrap(), vec![5]);
+        ls.put_address(&id2, &[6]).unwrap();
+        ls.flush().unwrap();
+        let mut ls = open_storage(&base);
+        assert_eq!(ls.get_address(&id2).unwrap(), vec![6]);
+    }
+
+    #[test]
+    fn log_clean() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let base = tmpdir.path().to_path_buf();
+        let id = Eid::new();
+        let span = Span::new(0, 256);
+        let blks = blocks(span.cnt, 42);
+        let blks2 = blocks(span.cnt, 43);
+
+        let mut ls = LogStorage::new(&base);
+        ls.init(Crypto::default(), Key::new_empty()).unwrap();
+        ls.put_super_block(&[1, 2, 3], 0).unwrap();
+        put_inline(&mut ls, span, &blks);
+        put_inline(&mut ls, Span::new(span.end(), span.cnt), &blks2);
+
+        // overwrite the same blocks many times
+        for i in 0..20 {
+            ls.put_address(&id, &[i]).unwrap();
+            put_inline(&mut ls, span, &blks2);
+        }
+        put_inline(&mut ls, span, &blks);
+        ls.del_blocks(Span::new(span.end(), 1)).unwrap();
+        let len = file_len(&base);
+        assert!(len > 4 * SEG_SIZE as u64);
+
+        ls.flush().unwrap();
+        let in_use = |ls: &LogStorage| {
+            let sa = ls.sa.lock().unwrap();
+            sa.segs().iter().filter(|s| !s.free).count()
+        };
+        assert!(in_use(&ls) * SEG_SIZE < len as usize / 2);
+
+        // cleaned segments should be reused without growing log file
+        for _ in 0..4 {
+            put_inline(&mut ls, span, &blks2);
+        }
+        put_inline(&mut ls, span, &blks);
+        ls.flush().unwrap();
+        assert!(file_len(&base) <= len);
+
+        // all live data should still be there after reopen
+        let mut ls = open_storage(&base);
+        assert_eq!(ls.get_super_block(0).unwrap(), vec![1, 2, 3]);
+        assert_eq!(ls.get_address(&id).unwrap(), vec![19]);
+        let mut dst = vec![0u8; span.bytes_len(BLK_SIZE)];
+        ls.get_blocks(&mut dst, span).unwrap();
+        assert_eq!(&dst[..], &blks[..]);
+        let mut dst = vec![0u8; BLK_SIZE];
+        assert_eq!(
+            ls.get_blocks(&mut dst, Span::new(span.end(), 1))
+                .unwrap_err(),
+            Error::NotFound
+        );
+        let mut dst = vec![0u8; (span.cnt - 1) * BLK_SIZE];
+        ls.get_blocks(&mut dst, Span::new(span.end() + 1, span.cnt - 1))
+            .unwrap();
+        assert_eq!(&dst[..], &blks2[BLK_SIZE..]);
+    }
+
+    #[test]
+    fn log_blob() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let base = tmpdir.path().to_path_buf();
+        let big = blocks(32, 42);
+        let big2 = blocks(16, 43);
+        let small = blocks(16, 44);
+
+        let mut ls = LogStorage::new(&base);
+        ls.init(Crypto::default(), Key::new_empty()).unwrap();
+
+        // large write goes to blob, small one stays inline
+        ls.put_blocks(Span::new(0, 32), &big).unwrap();
+        ls.put_blocks(Span::new(32, 4), &small[..4 * BLK_SIZE])
+            .unwrap();
+        assert_eq!(ls.blobs.blob_cnt(), 1);
+        let dst = read_blocks(&mut ls, Span::new(30, 6)).unwrap();
+        assert_eq!(&dst[..2 * BLK_SIZE], &big[30 * BLK_SIZE..]);
+        assert_eq!(&dst[2 * BLK_SIZE..], &small[..4 * BLK_SIZE]);
+
+        // blob is kept while any of its blocks is still live
+        put_inline(&mut ls, Span::new(0, 16), &small);
+        ls.del_blocks(Span::new(16, 8)).unwrap();
+        ls.put_blocks(Span::new(100, 16), &big2).unwrap();
+        ls.flush().unwrap();
+        assert_eq!(ls.blobs.blob_cnt(), 2);
+
+        // blob is removed after its last reference is released and flushed
+        ls.del_blocks(Span::new(24, 8)).unwrap();
+        assert_eq!(ls.blobs.blob_cnt(), 2);
+        ls.flush().unwrap();
+        assert_eq!(ls.blobs.blob_cnt(), 1);
+
+        // blob references should be moved when cleaning log
+        let span = Span::new(1000, 256);
+        let blks = blocks(span.cnt, 45);
+        for _ in 0..20 {
+            put_inline(&mut ls, span, &blks);
+        }
+        ls.flush().unwrap();
+        assert_eq!(ls.blobs.blob_cnt(), 1);
+
+        // orphan blob should be removed on open
+        fs::write(
+            base.join("blobs").join(format!("{:016x}", 1u64 << 40)),
+            [1, 2, 3],
+        )
+        .unwrap();
+        assert_eq!(ls.blobs.blob_cnt(), 2);
+
+        let mut ls = open_storage(&base);
+        assert_eq!(ls.blobs.blob_cnt(), 1);
+        let dst = read_blocks(&mut ls, Span::new(0, 16)).unwrap();
+        assert_eq!(&dst[..], &small[..]);
+        assert_eq!(
+            read_blocks(&mut ls, Span::new(16, 16)).unwrap_err(),
+            Error::NotFound
+        );
+        let dst = read_blocks(&mut ls, Span::new(100, 16)).unwrap();
+        assert_eq!(&dst[..], &big2[..]);
+        let dst = read_blocks(&mut ls, span).unwrap();
+        assert_eq!(&dst[..], &blks[..]);
+    }
+}
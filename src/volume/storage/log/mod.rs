@@ -0,0 +1,8 @@
+mod blob;
+mod iobuf;
+#[allow(clippy::module_inception)]
+mod log;
+mod reader;
+mod segment;
+
+pub use self::log::LogStorage;
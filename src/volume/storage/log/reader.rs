@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::ErrorKind;
+
+use super::segment::{
+    MessageHeader, SegmentHeader, SegmentTrailer, MSG_HEADER_LEN, SEG_HEADER_LEN, SEG_SIZE,
+    SEG_TRAILER_LEN,
+};
+use crate::error::{Error, Result};
+use crate::parallel_io::Pio;
+use crate::LogId;
+
+/// Result of reading a message from log
+#[derive(Debug)]
+pub(crate) enum LogRead {
+    /// A valid message and its payload
+    Inline(MessageHeader, Vec<u8>),
+
+    /// Invalid message, such as a torn write, a stale message or garbage
+    Corrupted,
+}
+
+/// Log reader
+///
+/// Invalid segment header or trailer is returned as `Corrupted` error, it
+/// is normal for a free segment or a segment which is not sealed yet.
+pub(crate) trait LogReader {
+    fn read_segment_header(&self, id: LogId) -> Result<SegmentHeader>;
+
+    fn read_segment_trailer(&self, id: LogId) -> Result<SegmentTrailer>;
+
+    fn read_message_header(&self, id: LogId) -> Result<MessageHeader>;
+
+    // read a message whose payload length is not more than max_len
+    fn read_message(&self, lid: LogId, max_len: usize) -> Result<LogRead>;
+}
+
+// read exactly at offset, treat reading beyond file end as corruption
+fn read_exact(file: &File, buf: &mut [u8], offset: LogId) -> Result<()> {
+    match file.pread_exact(buf, offset) {
+        Ok(_) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => Err(Error::Corrupted),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl LogReader for File {
+    fn read_segment_header(&self, id: LogId) -> Result<SegmentHeader> {
+        let mut buf = [0u8; SEG_HEADER_LEN];
+        read_exact(self, &mut buf, id)?;
+        SegmentHeader::from_bytes(&buf).ok_or(Error::Corrupted)
+    }
+
+    fn read_segment_trailer(&self, id: LogId) -> Result<SegmentTrailer> {
+        let mut buf = [0u8; SEG_TRAILER_LEN];
+        read_exact(self, &mut buf, id + (SEG_SIZE - SEG_TRAILER_LEN) as LogId)?;
+        SegmentTrailer::from_bytes(&buf).ok_or(Error::Corrupted)
+    }
+
+    fn read_message_header(&self, id: LogId) -> Result<MessageHeader> {
+        let mut buf = [0u8; MSG_HEADER_LEN];
+        read_exact(self, &mut buf, id)?;
+        MessageHeader::from_bytes(&buf).ok_or(Error::Corrupted)
+    }
+
+    fn read_message(&self, lid: LogId, max_len: usize) -> Result<LogRead> {
+        let hdr = match self.read_message_header(lid) {
+            Ok(hdr) => hdr,
+            Err(Error::Corrupted) => return Ok(LogRead::Corrupted),
+            Err(err) => return Err(err),
+        };
+        if hdr.len > max_len {
+            return Ok(LogRead::Corrupted);
+        }
+
+        let mut payload = vec![0u8; hdr.len];
+        match read_exact(self, &mut payload, lid + MSG_HEADER_LEN as LogId) {
+            Ok(_) => {}
+            Err(Error::Corrupted) => return Ok(LogRead::Corrupted),
+            Err(err) => return Err(err),
+        }
+        if !hdr.verify(&payload) {
+            return Ok(LogRead::Corrupted);
+        }
+
+        Ok(LogRead::Inline(hdr, payload))
+    }
+}
@@ -0,0 +1,443 @@
+//! Log segment and message format.
+//!
+//! The log file is divided into fixed size segments. Each segment starts
+//! with a header and, once it is sealed, ends with a trailer. Messages are
+//! appended in between, each message has a header followed by its payload.
+//!
+//! ```text
+//! +----------+-----------+-----------+-----+---------+
+//! | seg hdr  | msg hdr | | msg hdr | | ... | trailer |
+//! +----------+-----------+-----------+-----+---------+
+//! ```
+
+use crate::error::{Error, Result};
+use crate::trans::Eid;
+use crate::util::crypto::Crypto;
+use crate::util::little_endian as le;
+use crate::volume::address::Span;
+use crate::{BlobPointer, LogId, Lsn};
+
+// segment size, must be able to hold at least one frame
+pub const SEG_SIZE: usize = 4 * 1024 * 1024;
+
+// segment header: magic(4) + seq(8) + base lsn(8) + checksum(4)
+pub const SEG_HEADER_LEN: usize = 24;
+
+// segment trailer: magic(4) + seq(8) + used length(8) + checksum(4)
+pub const SEG_TRAILER_LEN: usize = 24;
+
+// message header: kind(1) + lsn(8) + key(32) + length(4) + checksum(4)
+pub const MSG_HEADER_LEN: usize = 49;
+
+// max message payload length
+pub const MAX_MSG_LEN: usize = SEG_SIZE - SEG_HEADER_LEN - SEG_TRAILER_LEN - MSG_HEADER_LEN;
+
+// blob reference: blob pointer(8) + offset in blob(8)
+pub const BLOB_REF_LEN: usize = 16;
+
+const SEG_HEADER_MAGIC: u32 = 0x4632_4c48;
+const SEG_TRAILER_MAGIC: u32 = 0x4632_4c54;
+
+const KEY_LEN: usize = 32;
+
+// calculate checksum of a list of buffers
+fn checksum(bufs: &[&[u8]]) -> u32 {
+    let mut state = Crypto::hash_init();
+    for buf in bufs {
+        Crypto::hash_update(&mut state, buf);
+    }
+    let hash = Crypto::hash_final(&mut state);
+    le::read(&hash[..4])
+}
+
+/// Segment header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub seq: u64,
+
+    // lsn of the first byte in this segment, a message's lsn is this plus
+    // its position in segment, messages not matching that are stale ones
+    // left by previous use of this segment
+    pub base_lsn: Lsn,
+}
+
+impl SegmentHeader {
+    pub fn new(seq: u64, base_lsn: Lsn) -> Self {
+        SegmentHeader { seq, base_lsn }
+    }
+
+    pub fn to_bytes(self) -> [u8; SEG_HEADER_LEN] {
+        let mut buf = [0u8; SEG_HEADER_LEN];
+        le::write(&mut buf[0..4], SEG_HEADER_MAGIC);
+        le::write(&mut buf[4..12], self.seq);
+        le::write(&mut buf[12..20], self.base_lsn as u64);
+        let crc = checksum(&[&buf[..20]]);
+        le::write(&mut buf[20..24], crc);
+        buf
+    }
+
+    // return None if the header is invalid, for example, it is a free
+    // segment or a torn write
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < SEG_HEADER_LEN
+            || le::read::<u32>(&buf[0..4]) != SEG_HEADER_MAGIC
+            || le::read::<u32>(&buf[20..24]) != checksum(&[&buf[..20]])
+        {
+            return None;
+        }
+        Some(SegmentHeader {
+            seq: le::read(&buf[4..12]),
+            base_lsn: le::read::<u64>(&buf[12..20]) as Lsn,
+        })
+    }
+}
+
+/// Segment trailer, written when a segment is sealed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentTrailer {
+    pub seq: u64,
+
+    // used length in segment, including segment header
+    pub used: usize,
+}
+
+impl SegmentTrailer {
+    pub fn new(seq: u64, used: usize) -> Self {
+        SegmentTrailer { seq, used }
+    }
+
+    pub fn to_bytes(self) -> [u8; SEG_TRAILER_LEN] {
+        let mut buf = [0u8; SEG_TRAILER_LEN];
+        le::write(&mut buf[0..4], SEG_TRAILER_MAGIC);
+        le::write(&mut buf[4..12], self.seq);
+        le::write(&mut buf[12..20], self.used as u64);
+        let crc = checksum(&[&buf[..20]]);
+        le::write(&mut buf[20..24], crc);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < SEG_TRAILER_LEN
+            || le::read::<u32>(&buf[0..4]) != SEG_TRAILER_MAGIC
+            || le::read::<u32>(&buf[20..24]) != checksum(&[&buf[..20]])
+        {
+            return None;
+        }
+        Some(SegmentTrailer {
+            seq: le::read(&buf[4..12]),
+            used: le::read::<u64>(&buf[12..20]) as usize,
+        })
+    }
+}
+
+/// Message kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    SuperBlk = 1,
+    Wal = 2,
+    DelWal = 3,
+    Addr = 4,
+    DelAddr = 5,
+    Blocks = 6,
+    DelBlocks = 7,
+    BlobBlocks = 8,
+    Pad = 9,
+}
+
+impl MessageKind {
+    fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            1 => Some(MessageKind::SuperBlk),
+            2 => Some(MessageKind::Wal),
+            3 => Some(MessageKind::DelWal),
+            4 => Some(MessageKind::Addr),
+            5 => Some(MessageKind::DelAddr),
+            6 => Some(MessageKind::Blocks),
+            7 => Some(MessageKind::DelBlocks),
+            8 => Some(MessageKind::BlobBlocks),
+            9 => Some(MessageKind::Pad),
+            _ => None,
+        }
+    }
+}
+
+/// Message key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageKey([u8; KEY_LEN]);
+
+impl MessageKey {
+    pub fn from_suffix(suffix: u64) -> Self {
+        let mut key = [0u8; KEY_LEN];
+        le::write(&mut key[..8], suffix);
+        MessageKey(key)
+    }
+
+    pub fn from_eid(id: &Eid) -> Self {
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(id.as_ref());
+        MessageKey(key)
+    }
+
+    pub fn from_span(span: Span) -> Self {
+        let mut key = [0u8; KEY_LEN];
+        le::write(&mut key[..8], span.begin as u64);
+        le::write(&mut key[8..16], span.cnt as u64);
+        MessageKey(key)
+    }
+
+    #[inline]
+    pub fn to_suffix(self) -> u64 {
+        le::read(&self.0[..8])
+    }
+
+    #[inline]
+    pub fn to_eid(self) -> Eid {
+        Eid::from_slice(&self.0)
+    }
+
+    #[inline]
+    pub fn to_span(self) -> Span {
+        Span::new(
+            le::read::<u64>(&self.0[..8]) as usize,
+            le::read::<u64>(&self.0[8..16]) as usize,
+        )
+    }
+}
+
+/// Blob reference, payload of blob blocks message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobRef {
+    pub ptr: BlobPointer,
+
+    // offset of the first block in blob
+    pub offset: usize,
+}
+
+impl BlobRef {
+    pub fn new(ptr: BlobPointer, offset: usize) -> Self {
+        BlobRef { ptr, offset }
+    }
+
+    pub fn to_bytes(self) -> [u8; BLOB_REF_LEN] {
+        let mut buf = [0u8; BLOB_REF_LEN];
+        le::write(&mut buf[0..8], self.ptr as u64);
+        le::write(&mut buf[8..16], self.offset as u64);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        BlobRef {
+            ptr: le::read::<u64>(&buf[0..8]) as BlobPointer,
+            offset: le::read::<u64>(&buf[8..16]) as usize,
+        }
+    }
+}
+
+/// Message header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub kind: MessageKind,
+    pub lsn: Lsn,
+    pub key: MessageKey,
+    pub len: usize,
+    crc: u32,
+}
+
+impl MessageHeader {
+    pub fn new(kind: MessageKind, lsn: Lsn, key: MessageKey, payload: &[u8]) -> Self {
+        let mut hdr = MessageHeader {
+            kind,
+            lsn,
+            key,
+            len: payload.len(),
+            crc: 0,
+        };
+        let buf = hdr.to_bytes();
+        hdr.crc = checksum(&[&buf[..MSG_HEADER_LEN - 4], payload]);
+        hdr
+    }
+
+    pub fn to_bytes(self) -> [u8; MSG_HEADER_LEN] {
+        let mut buf = [0u8; MSG_HEADER_LEN];
+        buf[0] = self.kind as u8;
+        le::write(&mut buf[1..9], self.lsn as u64);
+        buf[9..41].copy_from_slice(&self.key.0);
+        le::write(&mut buf[41..45], self.len as u32);
+        le::write(&mut buf[45..49], self.crc);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < MSG_HEADER_LEN {
+            return None;
+        }
+        let kind = MessageKind::from_u8(buf[0])?;
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&buf[9..41]);
+        Some(MessageHeader {
+            kind,
+            lsn: le::read::<u64>(&buf[1..9]) as Lsn,
+            key: MessageKey(key),
+            len: le::read::<u32>(&buf[41..45]) as usize,
+            crc: le::read(&buf[45..49]),
+        })
+    }
+
+    // verify message payload against header checksum
+    pub fn verify(&self, payload: &[u8]) -> bool {
+        let buf = self.to_bytes();
+        payload.len() == self.len && self.crc == checksum(&[&buf[..MSG_HEADER_LEN - 4], payload])
+    }
+}
+
+/// Segment state
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SegState {
+    pub seq: u64,
+    pub used: usize, // used bytes, including segment header
+    pub live: usize, // live payload bytes
+    pub sealed: bool,
+    pub free: bool,
+}
+
+impl SegState {
+    pub fn new_free() -> Self {
+        SegState {
+            free: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Segment accountant
+///
+/// It keeps state of all segments in log file. Segment in log file is
+/// identified by its index, which is its offset divided by segment size,
+/// and logical segment in lsn space is identified by its sequence number,
+/// which is its lsn divided by segment size.
+#[derive(Debug, Default)]
+pub struct SegmentAccountant {
+    segs: Vec<SegState>,
+}
+
+impl SegmentAccountant {
+    pub fn new(segs: Vec<SegState>) -> Self {
+        SegmentAccountant { segs }
+    }
+
+    #[inline]
+    pub fn seg_offset(seg_idx: usize) -> LogId {
+        (seg_idx * SEG_SIZE) as LogId
+    }
+
+    #[inline]
+    pub fn seg_idx(lid: LogId) -> usize {
+        lid as usize / SEG_SIZE
+    }
+
+    #[inline]
+    pub fn segs(&self) -> &[SegState] {
+        &self.segs
+    }
+
+    #[inline]
+    pub fn segs_mut(&mut self) -> &mut [SegState] {
+        &mut self.segs
+    }
+
+    // allocate a free segment for logical segment starting at lsn and
+    // return its offset in log file
+    pub fn next(&mut self, lsn: Lsn) -> Result<LogId> {
+        assert_eq!(lsn % SEG_SIZE as Lsn, 0);
+        let seg_idx = match self.segs.iter().position(|s| s.free) {
+            Some(idx) => idx,
+            None => {
+                self.segs.push(SegState::new_free());
+                self.segs.len() - 1
+            }
+        };
+        self.segs[seg_idx] = SegState {
+            seq: lsn as u64 / SEG_SIZE as u64,
+            used: SEG_HEADER_LEN,
+            live: 0,
+            sealed: false,
+            free: false,
+        };
+        Ok(Self::seg_offset(seg_idx))
+    }
+
+    // mark logical segment starting at lsn as sealed, it has been fully
+    // written and no more messages will be appended to it
+    pub fn deactivate_segment(&mut self, lsn: Lsn) -> Result<()> {
+        let seq = lsn as u64 / SEG_SIZE as u64;
+        let seg = self
+            .segs
+            .iter_mut()
+            .find(|s| !s.free && s.seq == seq)
+            .ok_or(Error::NotFound)?;
+        seg.sealed = true;
+        Ok(())
+    }
+
+    // update used length of the segment a message is appended to
+    pub fn mark_used(&mut self, lid: LogId, len: usize) {
+        let seg = &mut self.segs[Self::seg_idx(lid)];
+        let end = lid as usize % SEG_SIZE + len;
+        if end > seg.used {
+            seg.used = end;
+        }
+    }
+
+    #[inline]
+    pub fn free_seg(&mut self, seg_idx: usize) {
+        self.segs[seg_idx] = SegState::new_free();
+    }
+
+    // remove free segments at the end of log file, return number of
+    // segments left
+    pub fn truncate(&mut self) -> usize {
+        while self.segs.last().map(|s| s.free).unwrap_or(false) {
+            self.segs.pop();
+        }
+        self.segs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::init_env;
+
+    #[test]
+    fn segment_format() {
+        init_env();
+
+        let hdr = SegmentHeader::new(42, 100);
+        let mut buf = hdr.to_bytes();
+        assert_eq!(SegmentHeader::from_bytes(&buf), Some(hdr));
+        buf[5] ^= 1;
+        assert!(SegmentHeader::from_bytes(&buf).is_none());
+        assert!(SegmentHeader::from_bytes(&[0u8; SEG_HEADER_LEN]).is_none());
+
+        let trailer = SegmentTrailer::new(42, 1234);
+        let buf = trailer.to_bytes();
+        assert_eq!(SegmentTrailer::from_bytes(&buf), Some(trailer));
+
+        let id = Eid::new();
+        let payload = vec![42u8; 100];
+        let msg = MessageHeader::new(MessageKind::Wal, 7, MessageKey::from_eid(&id), &payload);
+        let buf = msg.to_bytes();
+        let msg2 = MessageHeader::from_bytes(&buf).unwrap();
+        assert_eq!(msg, msg2);
+        assert_eq!(msg2.key.to_eid(), id);
+        assert!(msg2.verify(&payload));
+        assert!(!msg2.verify(&payload[1..]));
+
+        let blob_ref = BlobRef::new(42, 4096);
+        assert_eq!(BlobRef::from_bytes(&blob_ref.to_bytes()), blob_ref);
+
+        let span = Span::new(3, 5);
+        assert_eq!(MessageKey::from_span(span).to_span(), span);
+        assert_eq!(MessageKey::from_suffix(1).to_suffix(), 1);
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rmp_serde::{Deserializer, Serializer};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::Storable;
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::little_endian;
+
+// max number of deltas saved after the manifest base, when it is reached
+// the deltas are merged into a new base
+const MAX_DELTAS: u64 = 64;
+
+// manifest delta is saved as an address whose id is the reserved id with
+// the delta sequence in its last 8 bytes
+fn delta_id(seq: u64) -> Eid {
+    let mut buf = [0xff; Eid::EID_SIZE];
+    little_endian::write(&mut buf[Eid::EID_SIZE - 8..], seq);
+    Eid::from_slice(&buf)
+}
+
+// delete an address, it is not an error if it doesn't exist
+pub(super) fn del_address_if_exists(member: &mut Box<dyn Storable>, id: &Eid) -> Result<()> {
+    match member.del_address(id) {
+        Err(ref err) if *err == Error::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// manifest delta
+//
+// It records WALs and addresses put (true) or deleted (false) since the
+// previous save, and the block information at the time of save.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+struct Delta<T> {
+    seq: u64,
+    wals: BTreeMap<Eid, bool>,
+    addrs: BTreeMap<Eid, bool>,
+    blks: T,
+}
+
+// manifest of storage built on member storages.
+//
+// It records keys of all WALs and addresses written to the storage, so
+// that a member can be rebuilt by copying them from other members, along
+// with storage specific block information. The sequence is increased on
+// every save, a member whose manifest sequence is behind others has missed
+// some writes.
+//
+// The manifest is saved incrementally. Its base is saved as an address
+// with the reserved id, and each save after that only writes a delta with
+// the changes since the previous save. Deltas in the range `[gc_from, seq)`
+// of the base are merged into it and can be removed.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(super) struct Manifest<T> {
+    seq: u64,
+    gc_from: u64,
+    wals: BTreeSet<Eid>,
+    addrs: BTreeSet<Eid>,
+    blks: T,
+
+    // sequence of the manifest base
+    #[serde(skip_serializing, skip_deserializing, default)]
+    base_seq: u64,
+
+    // changes since last save
+    #[serde(skip_serializing, skip_deserializing, default)]
+    delta: Delta<T>,
+
+    #[serde(skip_serializing, skip_deserializing, default)]
+    changed: bool,
+}
+
+impl<T> Manifest<T>
+where
+    T: Default + Clone + Serialize + DeserializeOwned,
+{
+    // create an empty manifest, it is saved as a base on first save
+    pub fn new() -> Self {
+        Manifest {
+            changed: true,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    #[inline]
+    pub fn wals(&self) -> &BTreeSet<Eid> {
+        &self.wals
+    }
+
+    #[inline]
+    pub fn addrs(&self) -> &BTreeSet<Eid> {
+        &self.addrs
+    }
+
+    #[inline]
+    pub fn blks(&self) -> &T {
+        &self.blks
+    }
+
+    #[inline]
+    pub fn blks_mut(&mut self) -> &mut T {
+        self.changed = true;
+        &mut self.blks
+    }
+
+    // return whether the manifest has changed since last save
+    #[inline]
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn put_wal(&mut self, id: &Eid) {
+        if self.wals.insert(id.clone()) {
+            self.delta.wals.insert(id.clone(), true);
+            self.changed = true;
+        }
+    }
+
+    pub fn del_wal(&mut self, id: &Eid) {
+        if self.wals.remove(id) {
+            self.delta.wals.insert(id.clone(), false);
+            self.changed = true;
+        }
+    }
+
+    pub fn put_address(&mut self, id: &Eid) {
+        if self.addrs.insert(id.clone()) {
+            self.delta.addrs.insert(id.clone(), true);
+            self.changed = true;
+        }
+    }
+
+    pub fn del_address(&mut self, id: &Eid) {
+        if self.addrs.remove(id) {
+            self.delta.addrs.insert(id.clone(), false);
+            self.changed = true;
+        }
+    }
+
+    // serialize the whole manifest as a base
+    pub fn seri(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf))?;
+        Ok(buf)
+    }
+
+    // return ids of all deltas which might be left in a member this
+    // manifest is loaded from
+    pub fn delta_ids(&self) -> impl Iterator<Item = Eid> {
+        (self.gc_from..=self.seq).map(delta_id)
+    }
+
+    // apply a delta to the manifest
+    fn apply(&mut self, delta: Delta<T>) {
+        for (id, put) in delta.wals {
+            if put {
+                self.wals.insert(id);
+            } else {
+                self.wals.remove(&id);
+            }
+        }
+        for (id, put) in delta.addrs {
+            if put {
+                self.addrs.insert(id);
+            } else {
+                self.addrs.remove(&id);
+            }
+        }
+        self.blks = delta.blks;
+        self.seq = delta.seq;
+    }
+
+    // load manifest base and all its deltas from a member, return None if
+    // the base is not found
+    pub fn load(member: &mut Box<dyn Storable>) -> Result<Option<Self>> {
+        let buf = match member.get_address(&Eid::RESERVED) {
+            Ok(buf) => buf,
+            Err(ref err) if *err == Error::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut de = Deserializer::new(&buf[..]);
+        let mut manifest: Manifest<T> = Deserialize::deserialize(&mut de)?;
+        manifest.base_seq = manifest.seq;
+
+        loop {
+            let buf = match member.get_address(&delta_id(manifest.seq + 1)) {
+                Ok(buf) => buf,
+                Err(ref err) if *err == Error::NotFound => break,
+                Err(err) => return Err(err),
+            };
+            let mut de = Deserializer::new(&buf[..]);
+            let delta: Delta<T> = Deserialize::deserialize(&mut de)?;
+            manifest.apply(delta);
+        }
+
+        Ok(Some(manifest))
+    }
+
+    // whether next save should write a new base rather than a delta
+    #[inline]
+    fn is_base_due(&self) -> bool {
+        self.seq == 0 || self.seq + 1 - self.base_seq > MAX_DELTAS
+    }
+
+    // return the address writes to save changes since last save, each of
+    // them is an address id and its content, or None if the address should
+    // be deleted. `saved` must be called after they are all written to
+    // members
+    pub fn writes(&self) -> Result<Vec<(Eid, Option<Vec<u8>>)>> {
+        let seq = self.seq + 1;
+        let mut writes = Vec::new();
+
+        if self.is_base_due() {
+            // merge all deltas into a new base and then remove them
+            let mut base = self.clone();
+            base.seq = seq;
+            writes.push((Eid::RESERVED, Some(base.seri()?)));
+            for gc_seq in self.gc_from..seq {
+                writes.push((delta_id(gc_seq), None));
+            }
+        } else {
+            let mut delta = self.delta.clone();
+            delta.seq = seq;
+            delta.blks = self.blks.clone();
+            let mut buf = Vec::new();
+            delta.serialize(&mut Serializer::new(&mut buf))?;
+            writes.push((delta_id(seq), Some(buf)));
+        }
+
+        Ok(writes)
+    }
+
+    // mark the changes returned by `writes` as saved
+    pub fn saved(&mut self) {
+        let seq = self.seq + 1;
+        if self.is_base_due() {
+            self.base_seq = seq;
+            self.gc_from = seq + 1;
+        }
+        self.seq = seq;
+        self.delta = Delta::default();
+        self.changed = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::crypto::{Crypto, Key};
+    use crate::util::init_env;
+    use crate::volume::storage::MemStorage;
+
+    fn save(manifest: &mut Manifest<usize>, member: &mut Box<dyn Storable>) {
+        for (id, buf) in manifest.writes().unwrap() {
+            match buf {
+                Some(buf) => member.put_address(&id, &buf).unwrap(),
+                None => del_address_if_exists(member, &id).unwrap(),
+            }
+        }
+        manifest.saved();
+    }
+
+    #[test]
+    fn manifest_incremental() {
+        init_env();
+        let mut member: Box<dyn Storable> = Box::new(MemStorage::new());
+        member.connect().unwrap();
+        member.init(Crypto::default(), Key::new_empty()).unwrap();
+        let mut manifest = Manifest::new();
+        save(&mut manifest, &mut member);
+
+        // save only writes changes since last save
+        let ids: Vec<Eid> = (0..100).map(|_| Eid::new()).collect();
+        for id in ids.iter() {
+            manifest.put_address(id);
+        }
+        save(&mut manifest, &mut member);
+        manifest.put_wal(&ids[1]);
+        manifest.del_address(&ids[0]);
+        *manifest.blks_mut() = 42;
+        save(&mut manifest, &mut member);
+        let delta_len = member.get_address(&delta_id(2)).unwrap().len();
+        let delta_len2 = member.get_address(&delta_id(3)).unwrap().len();
+        assert!(delta_len2 * 10 < delta_len);
+        assert!(!manifest.is_changed());
+
+        let loaded = Manifest::<usize>::load(&mut member).unwrap().unwrap();
+        assert_eq!(loaded.seq(), 3);
+        assert_eq!(loaded.wals(), manifest.wals());
+        assert_eq!(loaded.addrs(), manifest.addrs());
+        assert_eq!(*loaded.blks(), 42);
+
+        // deltas are merged into base when there are too many of them
+        for _ in 0..MAX_DELTAS {
+            manifest.put_address(&Eid::new());
+            save(&mut manifest, &mut member);
+        }
+        assert_eq!(
+            member.get_address(&delta_id(3)).unwrap_err(),
+            Error::NotFound
+        );
+        let loaded = Manifest::<usize>::load(&mut member).unwrap().unwrap();
+        assert_eq!(loaded.seq(), manifest.seq());
+        assert_eq!(loaded.addrs(), manifest.addrs());
+        assert_eq!(*loaded.blks(), 42);
+    }
+}
@@ -1,72 +1,315 @@
 use std::fmt::{self, Debug};
+use std::io::{Read, Write};
+use std::sync::{Arc, RwLock};
 
 use crate::error::{Error, Result};
 use crate::trans::eid::Eid;
 use crate::util::{
     collections::HashMap,
     crypto::{Crypto, Key},
-    IntoRef,
+    little_endian, IntoRef,
 };
-use crate::volume::{address::Span, storage::Storable};
+use crate::volume::storage::registry::Registry;
+use crate::volume::{address::Span, layout::Layout, storage::Storable};
 use crate::BLK_SIZE;
 
-/// Mem Storage
-#[derive(Clone)]
-pub struct MemStorage {
+// memory store content
+#[derive(Default)]
+struct MemStore {
     super_blk_map: HashMap<u64, Vec<u8>>,
     wal_map: HashMap<Eid, Vec<u8>>,
     blk_map: HashMap<usize, Vec<u8>>,
     addr_map: HashMap<Eid, Vec<u8>>,
 }
 
+// image magic and version
+const IMAGE_MAGIC: &[u8] = b"F2UFSIMG";
+const IMAGE_VERSION: u32 = 2;
+
+fn write_u64(w: &mut dyn Write, n: u64) -> Result<()> {
+    let mut buf = [0u8; 8];
+    little_endian::write(&mut buf, n);
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+fn read_u64(r: &mut dyn Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(little_endian::read(&buf))
+}
+
+fn write_bytes(w: &mut dyn Write, buf: &[u8]) -> Result<()> {
+    write_u64(w, buf.len() as u64)?;
+    w.write_all(buf)?;
+    Ok(())
+}
+
+fn read_bytes(r: &mut dyn Read) -> Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(Error::Corrupted);
+    }
+    Ok(buf)
+}
+
+fn read_eid(r: &mut dyn Read) -> Result<Eid> {
+    let mut buf = [0u8; Eid::EID_SIZE];
+    r.read_exact(&mut buf)?;
+    Ok(Eid::from_slice(&buf))
+}
+
+impl MemStore {
+    // image layout:
+    // magic, version, then super blocks, wals, addresses and blocks, each
+    // section starts with its entry count
+    fn export(&self, w: &mut dyn Write) -> Result<()> {
+        let mut ver = [0u8; 4];
+        little_endian::write(&mut ver, IMAGE_VERSION);
+        w.write_all(IMAGE_MAGIC)?;
+        w.write_all(&ver)?;
+
+        write_u64(w, self.super_blk_map.len() as u64)?;
+        for (suffix, super_blk) in self.super_blk_map.iter() {
+            write_u64(w, *suffix)?;
+            write_bytes(w, super_blk)?;
+        }
+
+        write_u64(w, self.wal_map.len() as u64)?;
+        for (id, wal) in self.wal_map.iter() {
+            w.write_all(id.as_ref())?;
+            write_bytes(w, wal)?;
+        }
+
+        write_u64(w, self.addr_map.len() as u64)?;
+        for (id, addr) in self.addr_map.iter() {
+            w.write_all(id.as_ref())?;
+            write_bytes(w, addr)?;
+        }
+
+        write_u64(w, self.blk_map.len() as u64)?;
+        for (blk_idx, blk) in self.blk_map.iter() {
+            write_u64(w, *blk_idx as u64)?;
+            write_bytes(w, blk)?;
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    fn import(r: &mut dyn Read) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        let mut ver = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic[..] != IMAGE_MAGIC {
+            return Err(Error::InvalidArgument);
+        }
+        r.read_exact(&mut ver)?;
+        let ver = little_endian::read::<u32>(&ver);
+        if ver != IMAGE_VERSION {
+            return Err(Error::WrongVersion);
+        }
+
+        let mut store = MemStore::default();
+
+        for _ in 0..read_u64(r)? {
+            let suffix = read_u64(r)?;
+            store.super_blk_map.insert(suffix, read_bytes(r)?);
+        }
+
+        for _ in 0..read_u64(r)? {
+            let id = read_eid(r)?;
+            store.wal_map.insert(id, read_bytes(r)?);
+        }
+
+        for _ in 0..read_u64(r)? {
+            let id = read_eid(r)?;
+            store.addr_map.insert(id, read_bytes(r)?);
+        }
+
+        for _ in 0..read_u64(r)? {
+            let blk_idx = read_u64(r)? as usize;
+            store.blk_map.insert(blk_idx, read_bytes(r)?);
+        }
+
+        Ok(store)
+    }
+}
+
+lazy_static! {
+    // process-global named stores, a store lives until it is destroyed or
+    // process exits
+    static ref STORES: Registry<Arc<RwLock<MemStore>>> = Registry::new();
+}
+
+/// Mem Storage
+///
+/// An anonymous memory storage, created by [`new`] or from URI `mem://`,
+/// lives only as long as the storage itself.
+///
+/// A named memory storage, created by [`with_name`] or from URI
+/// `mem://name`, refers to a process-global store. The store is created when
+/// the storage is initialised and persists after the storage is dropped, so
+/// it can be opened again by name until it is removed by [`destroy`].
+///
+/// Cloned storages share the same store.
+///
+/// [`new`]: #method.new
+/// [`with_name`]: #method.with_name
+/// [`destroy`]: #method.destroy
+#[derive(Clone)]
+pub struct MemStorage {
+    name: Option<String>,
+    store: Arc<RwLock<MemStore>>,
+    blk_size: usize,
+}
+
+impl Default for MemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemStorage {
     pub fn new() -> Self {
         MemStorage {
-            super_blk_map: HashMap::new(),
-            wal_map: HashMap::new(),
-            blk_map: HashMap::new(),
-            addr_map: HashMap::new(),
+            name: None,
+            store: Arc::new(RwLock::new(MemStore::default())),
+            blk_size: BLK_SIZE,
         }
     }
+
+    /// Creates a memory storage referring to a process-global named store.
+    pub fn with_name(name: &str) -> Self {
+        let store = STORES.get(name).unwrap_or_default();
+        MemStorage {
+            name: Some(name.to_string()),
+            store,
+            blk_size: BLK_SIZE,
+        }
+    }
+
+    /// Removes a process-global named store.
+    ///
+    /// Returns `true` if the named store existed. Storages already attached
+    /// to the store can still use it, but it cannot be opened by name
+    /// anymore.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::error::Result;
+    /// use f2ufs::repo::{Repo, RepoOpener};
+    /// use f2ufs::util::init_env;
+    /// use f2ufs::volume::storage::MemStorage;
+    /// # fn foo() -> Result<()> {
+    /// init_env();
+    ///
+    /// {
+    ///     let mut repo = RepoOpener::new()
+    ///         .create(true)
+    ///         .open("mem://foo", "pwd")?;
+    /// }
+    /// assert!(Repo::exists("mem://foo")?);
+    ///
+    /// assert!(MemStorage::destroy("foo"));
+    /// assert!(!Repo::exists("mem://foo")?);
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    pub fn destroy(name: &str) -> bool {
+        STORES.remove(name).is_some()
+    }
+
+    /// Creates an anonymous memory storage from an image.
+    ///
+    /// The image is the whole storage content written by
+    /// [`Storable::export_image`]. As content is kept encrypted in storage,
+    /// the image is encrypted as well.
+    ///
+    /// [`Storable::export_image`]: trait.Storable.html#method.export_image
+    pub fn import_image(r: &mut dyn Read) -> Result<Self> {
+        let store = MemStore::import(r)?;
+        Ok(MemStorage {
+            name: None,
+            store: Arc::new(RwLock::new(store)),
+            blk_size: BLK_SIZE,
+        })
+    }
+
+    // attach to the registered named store
+    fn attach(&mut self) -> Result<()> {
+        if let Some(ref name) = self.name {
+            self.store = STORES.get(name).ok_or(Error::NotFound)?;
+        }
+        Ok(())
+    }
 }
 
 impl Storable for MemStorage {
     #[inline]
     fn exists(&self) -> Result<bool> {
-        Ok(false)
+        match self.name {
+            Some(ref name) => Ok(STORES.contains(name)),
+            None => Ok(false),
+        }
     }
 
     #[inline]
     fn connect(&mut self) -> Result<()> {
-        Ok(())
+        match self.attach() {
+            Ok(_) => Ok(()),
+            Err(ref err) if *err == Error::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
     }
 
-    #[inline]
     fn init(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        self.store = Arc::new(RwLock::new(MemStore::default()));
+        if let Some(ref name) = self.name {
+            if !STORES.insert(name, self.store.clone()) {
+                return Err(Error::AlreadyExists);
+            }
+        }
         Ok(())
     }
 
     #[inline]
     fn open(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        self.attach()
+    }
+
+    #[inline]
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.blk_size = layout.blk_size;
         Ok(())
     }
 
     fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
-        self.super_blk_map
+        let store = self.store.read().unwrap();
+        store
+            .super_blk_map
             .get(&suffix)
-            .map(|b| b.clone())
+            .cloned()
             .ok_or(Error::NotFound)
     }
 
     #[inline]
     fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
-        self.super_blk_map.insert(suffix, super_blk.to_vec());
+        let mut store = self.store.write().unwrap();
+        store.super_blk_map.insert(suffix, super_blk.to_vec());
         Ok(())
     }
 
     #[inline]
     fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
-        self.wal_map
+        let store = self.store.read().unwrap();
+        store
+            .wal_map
             .get(id)
             .map(|wal| wal.to_owned())
             .ok_or(Error::NotFound)
@@ -74,43 +317,47 @@ impl Storable for MemStorage {
 
     #[inline]
     fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
-        self.wal_map.insert(id.clone(), wal.to_vec());
+        let mut store = self.store.write().unwrap();
+        store.wal_map.insert(id.clone(), wal.to_vec());
         Ok(())
     }
 
     #[inline]
     fn del_wal(&mut self, id: &Eid) -> Result<()> {
-        self.wal_map.remove(id);
+        let mut store = self.store.write().unwrap();
+        store.wal_map.remove(id);
         Ok(())
     }
 
     fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
-        self.addr_map
-            .get(id)
-            .map(|addr| addr.clone())
-            .ok_or(Error::NotFound)
+        let store = self.store.read().unwrap();
+        store.addr_map.get(id).cloned().ok_or(Error::NotFound)
     }
 
     #[inline]
     fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
-        self.addr_map.insert(id.clone(), addr.to_vec());
+        let mut store = self.store.write().unwrap();
+        store.addr_map.insert(id.clone(), addr.to_vec());
         Ok(())
     }
 
     #[inline]
     fn del_address(&mut self, id: &Eid) -> Result<()> {
-        self.addr_map.remove(id);
+        let mut store = self.store.write().unwrap();
+        store.addr_map.remove(id);
         Ok(())
     }
 
     fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
-        assert_eq!(dst.len(), span.bytes_len());
+        let blk_size = self.blk_size;
+        assert_eq!(dst.len(), span.bytes_len(blk_size));
+        let store = self.store.read().unwrap();
         let mut read = 0;
         for blk_idx in span {
-            match self.blk_map.get(&blk_idx) {
+            match store.blk_map.get(&blk_idx) {
                 Some(blk) => {
-                    dst[read..read + BLK_SIZE].copy_from_slice(blk);
-                    read += BLK_SIZE;
+                    dst[read..read + blk_size].copy_from_slice(blk);
+                    read += blk_size;
                 }
                 None => return Err(Error::NotFound),
             }
@@ -119,17 +366,20 @@ impl Storable for MemStorage {
     }
 
     fn put_blocks(&mut self, span: Span, mut blks: &[u8]) -> Result<()> {
-        assert_eq!(blks.len(), span.bytes_len());
+        let blk_size = self.blk_size;
+        assert_eq!(blks.len(), span.bytes_len(blk_size));
+        let mut store = self.store.write().unwrap();
         for blk_idx in span {
-            self.blk_map.insert(blk_idx, blks[..BLK_SIZE].to_vec());
-            blks = &blks[BLK_SIZE..];
+            store.blk_map.insert(blk_idx, blks[..blk_size].to_vec());
+            blks = &blks[blk_size..];
         }
         Ok(())
     }
 
     fn del_blocks(&mut self, span: Span) -> Result<()> {
+        let mut store = self.store.write().unwrap();
         for blk_idx in span {
-            self.blk_map.remove(&blk_idx);
+            store.blk_map.remove(&blk_idx);
         }
         Ok(())
     }
@@ -138,14 +388,26 @@ impl Storable for MemStorage {
     fn flush(&mut self) -> Result<()> {
         Ok(())
     }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        true
+    }
+
+    fn export_image(&mut self, w: &mut dyn Write) -> Result<()> {
+        let store = self.store.read().unwrap();
+        store.export(w)
+    }
 }
 
 impl Debug for MemStorage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let store = self.store.read().unwrap();
         f.debug_struct("MemStorage")
-            .field("super_blk_map", &self.super_blk_map.len())
-            .field("blk_map", &self.blk_map.len())
-            .field("addr_map", &self.addr_map.len())
+            .field("name", &self.name)
+            .field("super_blk_map", &store.super_blk_map.len())
+            .field("blk_map", &store.blk_map.len())
+            .field("addr_map", &store.addr_map.len())
             .finish()
     }
 }
@@ -190,4 +452,96 @@ mod tests {
             speed_str(&write_time, DATA_LEN)
         );
     }
+
+    #[test]
+    fn named_store() {
+        init_env();
+
+        let name = "mem_named_store";
+        let crypto = Crypto::default();
+        let key = Key::new_empty();
+        let span = Span::new(0, 1);
+        let blk = vec![42u8; BLK_SIZE];
+        let mut dst = vec![0u8; BLK_SIZE];
+
+        // anonymous storage never exists
+        let ms = MemStorage::new();
+        assert!(!ms.exists().unwrap());
+
+        // named storage doesn't exist before init
+        let mut ms = MemStorage::with_name(name);
+        assert!(!ms.exists().unwrap());
+        ms.connect().unwrap();
+        assert_eq!(
+            ms.open(crypto.clone(), key.clone()).unwrap_err(),
+            Error::NotFound
+        );
+        ms.init(crypto.clone(), key.clone()).unwrap();
+        assert!(ms.exists().unwrap());
+        ms.put_blocks(span, &blk).unwrap();
+        drop(ms);
+
+        // init again should fail
+        let mut ms = MemStorage::with_name(name);
+        assert_eq!(
+            ms.init(crypto.clone(), key.clone()).unwrap_err(),
+            Error::AlreadyExists
+        );
+
+        // content persists after storage is dropped
+        let mut ms = MemStorage::with_name(name);
+        assert!(ms.exists().unwrap());
+        ms.connect().unwrap();
+        ms.open(crypto.clone(), key.clone()).unwrap();
+        ms.get_blocks(&mut dst, span).unwrap();
+        assert_eq!(dst, blk);
+
+        // destroy named store
+        assert!(MemStorage::destroy(name));
+        assert!(!MemStorage::destroy(name));
+        assert!(!ms.exists().unwrap());
+        let mut ms = MemStorage::with_name(name);
+        assert_eq!(ms.open(crypto, key).unwrap_err(), Error::NotFound);
+    }
+
+    #[test]
+    fn image() {
+        init_env();
+
+        let span = Span::new(3, 2);
+        let blks = vec![42u8; 2 * BLK_SIZE];
+        let id = Eid::new();
+        let mut dst = vec![0u8; 2 * BLK_SIZE];
+
+        let mut ms = MemStorage::new();
+        ms.put_super_block(&[1, 2, 3], 0).unwrap();
+        ms.put_wal(&id, &[4, 5]).unwrap();
+        ms.put_address(&id, &[6]).unwrap();
+        ms.put_blocks(span, &blks).unwrap();
+
+        let mut img = Vec::new();
+        ms.export_image(&mut img).unwrap();
+
+        let mut ms = MemStorage::import_image(&mut &img[..]).unwrap();
+        assert!(!ms.exists().unwrap());
+        assert_eq!(ms.get_super_block(0).unwrap(), vec![1, 2, 3]);
+        assert_eq!(ms.get_wal(&id).unwrap(), vec![4, 5]);
+        assert_eq!(ms.get_address(&id).unwrap(), vec![6]);
+        ms.get_blocks(&mut dst, span).unwrap();
+        assert_eq!(dst, blks);
+
+        // truncated and invalid images
+        assert!(MemStorage::import_image(&mut &img[..img.len() - 1]).is_err());
+        assert_eq!(
+            MemStorage::import_image(&mut &img[1..]).unwrap_err(),
+            Error::InvalidArgument
+        );
+        for ver in [1, 3].iter() {
+            img[8] = *ver;
+            assert_eq!(
+                MemStorage::import_image(&mut &img[..]).unwrap_err(),
+                Error::WrongVersion
+            );
+        }
+    }
 }
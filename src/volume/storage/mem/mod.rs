@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod mem;
 
 pub use self::mem::MemStorage;
@@ -0,0 +1,805 @@
+use std::cmp::min;
+use std::fmt::{self, Debug};
+use std::io::Write;
+
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+use crate::volume::storage::manifest::{del_address_if_exists, Manifest};
+use crate::volume::storage::{CompactUnit, Storable};
+
+// super block suffixes
+const SUPER_BLK_SUFFIXES: [u64; 2] = [0, 1];
+
+/// Mirror Storage
+///
+/// This storage keeps identical copies of data in several member storages,
+/// like RAID-1. Writes go to all members, reads are served by the first
+/// member which can read the data successfully.
+///
+/// A member failed to write is marked as out of sync and is no longer used
+/// until it is rebuilt by [`resync`]. When the mirror is opened, members
+/// which are replaced or have missed writes are rebuilt automatically.
+///
+/// [`resync`]: struct.MirrorStorage.html#method.resync
+pub struct MirrorStorage {
+    members: Vec<Box<dyn Storable>>,
+
+    // members out of sync
+    failed: Vec<bool>,
+
+    // mirror manifest, its block information is the block high watermark
+    manifest: Manifest<usize>,
+
+    // copy selected for reading
+    read_copy: Option<usize>,
+
+    // crypto context, used to initialise replaced members
+    ctx: Option<(Crypto, Key)>,
+
+    // volume block layout
+    layout: Layout,
+}
+
+impl MirrorStorage {
+    pub fn new(members: Vec<Box<dyn Storable>>) -> Self {
+        assert!(!members.is_empty());
+        MirrorStorage {
+            failed: vec![false; members.len()],
+            members,
+            manifest: Manifest::default(),
+            read_copy: None,
+            ctx: None,
+            layout: Layout::default(),
+        }
+    }
+
+    /// Returns whether a member is out of sync.
+    #[inline]
+    pub fn is_failed(&self, idx: usize) -> bool {
+        self.failed[idx]
+    }
+
+    // read from selected copy, or try members in sync one by one until
+    // read is successful
+    fn read<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Box<dyn Storable>) -> Result<T>,
+    {
+        if let Some(copy) = self.read_copy {
+            if self.failed[copy] {
+                return Err(Error::NoMember);
+            }
+            return f(&mut self.members[copy]);
+        }
+
+        let mut first_err = None;
+        for (idx, member) in self.members.iter_mut().enumerate() {
+            if self.failed[idx] {
+                continue;
+            }
+            match f(member) {
+                Ok(ret) => {
+                    if let Some(ref err) = first_err {
+                        warn!("mirror read fallback to member#{}: {}", idx, err);
+                    }
+                    return Ok(ret);
+                }
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        Err(first_err.unwrap_or(Error::NoMember))
+    }
+
+    // write to all members in sync, the write is successful if it is
+    // successful on at least one member and the other members are marked
+    // as out of sync
+    fn write<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Box<dyn Storable>) -> Result<()>,
+    {
+        let mut first_err = None;
+        let mut failed = Vec::new();
+        for (idx, member) in self.members.iter_mut().enumerate() {
+            if self.failed[idx] {
+                continue;
+            }
+            if let Err(err) = f(member) {
+                warn!("mirror member#{} write failed: {}", idx, err);
+                failed.push(idx);
+                first_err.get_or_insert(err);
+            }
+        }
+
+        if failed.len() == self.failed.iter().filter(|f| !**f).count() {
+            return Err(first_err.unwrap_or(Error::NoMember));
+        }
+        for idx in failed {
+            self.failed[idx] = true;
+        }
+        Ok(())
+    }
+
+    // write to all members, fail if any of them fails
+    fn write_all<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Box<dyn Storable>) -> Result<()>,
+    {
+        for member in self.members.iter_mut() {
+            f(member)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild a member from the other members.
+    ///
+    /// All super blocks, WALs, addresses and blocks are copied from the other
+    /// members to this member, which is then marked as in sync. It is used
+    /// to rebuild a member which has been replaced or has missed writes.
+    ///
+    /// The mirror must be initialised or opened before resync.
+    pub fn resync(&mut self, idx: usize) -> Result<()> {
+        let (crypto, key) = self.ctx.clone().ok_or(Error::Closed)?;
+        let was_failed = self.failed[idx];
+
+        // don't read from the member being rebuilt
+        self.failed[idx] = true;
+        if self.failed.iter().all(|f| *f) {
+            self.failed[idx] = was_failed;
+            return Err(Error::NoMember);
+        }
+
+        {
+            let member = &mut self.members[idx];
+            if member.exists()? {
+                member.open(crypto, key)?;
+            } else {
+                member.init(crypto, key)?;
+            }
+        }
+        let old = Manifest::load(&mut self.members[idx])?;
+
+        self.copy_to(idx, old)?;
+        self.failed[idx] = false;
+        debug!("mirror member#{} resynced", idx);
+
+        Ok(())
+    }
+
+    // copy all data to a member, old is the member's previous manifest
+    fn copy_to(&mut self, idx: usize, old: Option<Manifest<usize>>) -> Result<()> {
+        // super blocks
+        for suffix in SUPER_BLK_SUFFIXES.iter() {
+            match self.read(|m| m.get_super_block(*suffix)) {
+                Ok(buf) => self.members[idx].put_super_block(&buf, *suffix)?,
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // wals and addresses, they might be deleted after manifest is
+        // saved, so those not found are skipped
+        let manifest = self.manifest.clone();
+        for id in manifest.wals().iter() {
+            match self.read(|m| m.get_wal(id)) {
+                Ok(buf) => self.members[idx].put_wal(id, &buf)?,
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        for id in manifest.addrs().iter() {
+            match self.read(|m| m.get_address(id)) {
+                Ok(buf) => self.members[idx].put_address(id, &buf)?,
+                Err(ref err) if *err == Error::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // remove stale wals, addresses and manifest deltas left in the member
+        if let Some(old) = old {
+            let member = &mut self.members[idx];
+            for id in old.wals().difference(manifest.wals()) {
+                member.del_wal(id)?;
+            }
+            for id in old.addrs().difference(manifest.addrs()) {
+                member.del_address(id)?;
+            }
+            for id in old.delta_ids() {
+                del_address_if_exists(member, &id)?;
+            }
+        }
+
+        // blocks, copied frame by frame, if a frame cannot be read as a
+        // whole copy its blocks one by one and skip those not present
+        let Layout {
+            blk_size,
+            blks_per_frame,
+            ..
+        } = self.layout;
+        let mut buf = vec![0u8; self.layout.frame_size()];
+        let mut begin = 0;
+        let blk_wmark = *manifest.blks();
+        while begin < blk_wmark {
+            let span = Span::new(begin, min(blks_per_frame, blk_wmark - begin));
+            let frame = &mut buf[..span.bytes_len(blk_size)];
+            if self.read(|m| m.get_blocks(frame, span)).is_ok() {
+                self.members[idx].put_blocks(span, frame)?;
+            } else {
+                for blk_idx in span {
+                    let blk_span = Span::new(blk_idx, 1);
+                    let blk = &mut frame[..blk_size];
+                    if self.read(|m| m.get_blocks(blk, blk_span)).is_ok() {
+                        self.members[idx].put_blocks(blk_span, blk)?;
+                    }
+                }
+            }
+            begin = span.end();
+        }
+
+        // save manifest as a new base and flush the member
+        let member = &mut self.members[idx];
+        member.put_address(&Eid::RESERVED, &manifest.seri()?)?;
+        member.flush()
+    }
+
+    // save manifest changes since last flush to all members in sync
+    fn save_manifest(&mut self) -> Result<()> {
+        for (id, buf) in self.manifest.writes()? {
+            match buf {
+                Some(buf) => self.write(|m| m.put_address(&id, &buf))?,
+                None => self.write(|m| del_address_if_exists(m, &id))?,
+            }
+        }
+        self.manifest.saved();
+        Ok(())
+    }
+}
+
+impl Storable for MirrorStorage {
+    fn exists(&self) -> Result<bool> {
+        for member in self.members.iter() {
+            if member.exists()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    #[inline]
+    fn connect(&mut self) -> Result<()> {
+        self.write_all(|m| m.connect())
+    }
+
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.write_all(|m| m.set_layout(layout))?;
+        self.layout = layout;
+        Ok(())
+    }
+
+    fn init(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.write_all(|m| m.init(crypto.clone(), key.clone()))?;
+        self.ctx = Some((crypto, key));
+        self.failed = vec![false; self.members.len()];
+        self.manifest = Manifest::new();
+        Ok(())
+    }
+
+    fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+        self.ctx = Some((crypto.clone(), key.clone()));
+
+        // open existing members and load their manifests, replaced members
+        // don't exist and have no manifest
+        let mut manifests = Vec::new();
+        for member in self.members.iter_mut() {
+            if member.exists()? {
+                member.open(crypto.clone(), key.clone())?;
+                manifests.push(Manifest::load(member)?);
+            } else {
+                manifests.push(None);
+            }
+        }
+
+        // use the latest manifest, if no member has manifest the mirror
+        // has never been flushed and there is nothing to resync
+        let latest = manifests
+            .iter()
+            .filter_map(|m| m.as_ref())
+            .max_by_key(|m| m.seq())
+            .cloned();
+        let latest = match latest {
+            Some(latest) => latest,
+            None => {
+                self.failed = vec![false; self.members.len()];
+                self.manifest = Manifest::default();
+                return Ok(());
+            }
+        };
+
+        // members behind the latest manifest are out of sync
+        self.failed = manifests
+            .iter()
+            .map(|m| m.as_ref().map_or(true, |m| m.seq() < latest.seq()))
+            .collect();
+        self.manifest = latest;
+
+        for idx in 0..self.members.len() {
+            if self.failed[idx] {
+                warn!("mirror member#{} is out of sync, resync it", idx);
+                self.resync(idx)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        self.read(|m| m.get_super_block(suffix))
+    }
+
+    #[inline]
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        self.write(|m| m.put_super_block(super_blk, suffix))
+    }
+
+    #[inline]
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.read(|m| m.get_wal(id))
+    }
+
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        self.write(|m| m.put_wal(id, wal))?;
+        self.manifest.put_wal(id);
+        Ok(())
+    }
+
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        self.write(|m| m.del_wal(id))?;
+        self.manifest.del_wal(id);
+        Ok(())
+    }
+
+    #[inline]
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.read(|m| m.get_address(id))
+    }
+
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        self.write(|m| m.put_address(id, addr))?;
+        self.manifest.put_address(id);
+        Ok(())
+    }
+
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        self.write(|m| m.del_address(id))?;
+        self.manifest.del_address(id);
+        Ok(())
+    }
+
+    #[inline]
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        self.read(|m| m.get_blocks(dst, span))
+    }
+
+    fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        self.write(|m| m.put_blocks(span, blks))?;
+        if span.end() > *self.manifest.blks() {
+            *self.manifest.blks_mut() = span.end();
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        self.write(|m| m.del_blocks(span))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.manifest.is_changed() {
+            self.save_manifest()?;
+        }
+        self.write(|m| m.flush())
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        self.members.iter().all(|m| m.can_reuse_blocks())
+    }
+
+    #[inline]
+    fn copies(&self) -> usize {
+        self.members.len()
+    }
+
+    #[inline]
+    fn set_read_copy(&mut self, copy: Option<usize>) {
+        self.read_copy = copy;
+    }
+
+    // members have the same content, so the image is exported from the
+    // first member in sync
+    fn export_image(&mut self, w: &mut dyn Write) -> Result<()> {
+        let idx = self
+            .failed
+            .iter()
+            .position(|f| !*f)
+            .ok_or(Error::NoMember)?;
+        self.members[idx].export_image(w)
+    }
+
+    // candidates of all members in sync, members might have different dead
+    // blocks if one was resynced, so the largest dead size is used
+    fn compact_candidates(
+        &mut self,
+        blk_wmark: usize,
+        dead_ratio: f32,
+    ) -> Result<Vec<CompactUnit>> {
+        let mut units: Vec<CompactUnit> = Vec::new();
+        for (idx, member) in self.members.iter_mut().enumerate() {
+            if self.failed[idx] {
+                continue;
+            }
+            for unit in member.compact_candidates(blk_wmark, dead_ratio)? {
+                match units.iter_mut().find(|u| u.idx == unit.idx) {
+                    Some(u) if u.dead < unit.dead => *u = unit,
+                    Some(_) => {}
+                    None => units.push(unit),
+                }
+            }
+        }
+        units.sort_by_key(|u| u.idx);
+        Ok(units)
+    }
+
+    // compact the unit in all members in sync, return total bytes written
+    // and reclaimed in all of them
+    fn compact_unit(&mut self, idx: usize) -> Result<(usize, usize)> {
+        let mut written = 0;
+        let mut reclaimed = 0;
+        self.write(|m| {
+            let (w, r) = m.compact_unit(idx)?;
+            written += w;
+            reclaimed += r;
+            Ok(())
+        })?;
+        Ok((written, reclaimed))
+    }
+
+    // deleted blocks of the first member in sync
+    fn deleted_blocks(&mut self, blk_wmark: usize) -> Result<Option<Vec<Span>>> {
+        let idx = self
+            .failed
+            .iter()
+            .position(|f| !*f)
+            .ok_or(Error::NoMember)?;
+        self.members[idx].deleted_blocks(blk_wmark)
+    }
+}
+
+impl Debug for MirrorStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MirrorStorage")
+            .field("members", &self.members)
+            .field("failed", &self.failed)
+            .field("read_copy", &self.read_copy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::repo::RepoOpener;
+    use crate::util::init_env;
+    use crate::volume::storage::{register_storage, MemStorage};
+    use crate::BLK_SIZE;
+
+    // flaky storage modes
+    const NORMAL: usize = 0;
+    const READ_ERR: usize = 1;
+    const READ_GARBAGE: usize = 2;
+    const WRITE_ERR: usize = 3;
+
+    // memory storage which can fail reads or writes, or return garbage
+    #[derive(Debug)]
+    struct FlakyStorage {
+        inner: MemStorage,
+        mode: Arc<AtomicUsize>,
+    }
+
+    impl FlakyStorage {
+        fn new(mode: &Arc<AtomicUsize>) -> Self {
+            FlakyStorage {
+                inner: MemStorage::new(),
+                mode: mode.clone(),
+            }
+        }
+
+        fn read<T: AsMut<[u8]>>(&self, result: Result<T>) -> Result<T> {
+            match self.mode.load(Ordering::SeqCst) {
+                READ_ERR => Err(Error::Corrupted),
+                READ_GARBAGE => result.map(|mut buf| {
+                    for b in buf.as_mut().iter_mut() {
+                        *b ^= 0x5a;
+                    }
+                    buf
+                }),
+                _ => result,
+            }
+        }
+
+        fn write(&self) -> Result<()> {
+            if self.mode.load(Ordering::SeqCst) == WRITE_ERR {
+                return Err(Error::Corrupted);
+            }
+            Ok(())
+        }
+    }
+
+    impl Storable for FlakyStorage {
+        fn exists(&self) -> Result<bool> {
+            self.inner.exists()
+        }
+
+        fn connect(&mut self) -> Result<()> {
+            self.inner.connect()
+        }
+
+        fn init(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+            self.inner.init(crypto, key)
+        }
+
+        fn open(&mut self, crypto: Crypto, key: Key) -> Result<()> {
+            self.inner.open(crypto, key)
+        }
+
+        fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+            let result = self.inner.get_super_block(suffix);
+            self.read(result)
+        }
+
+        fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+            self.write()?;
+            self.inner.put_super_block(super_blk, suffix)
+        }
+
+        fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+            let result = self.inner.get_wal(id);
+            self.read(result)
+        }
+
+        fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+            self.write()?;
+            self.inner.put_wal(id, wal)
+        }
+
+        fn del_wal(&mut self, id: &Eid) -> Result<()> {
+            self.write()?;
+            self.inner.del_wal(id)
+        }
+
+        fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+            let result = self.inner.get_address(id);
+            self.read(result)
+        }
+
+        fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+            self.write()?;
+            self.inner.put_address(id, addr)
+        }
+
+        fn del_address(&mut self, id: &Eid) -> Result<()> {
+            self.write()?;
+            self.inner.del_address(id)
+        }
+
+        fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+            let result = self.inner.get_blocks(dst, span).map(|_| dst);
+            self.read(result).map(|_| ())
+        }
+
+        fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+            self.write()?;
+            self.inner.put_blocks(span, blks)
+        }
+
+        fn del_blocks(&mut self, span: Span) -> Result<()> {
+            self.write()?;
+            self.inner.del_blocks(span)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.write()?;
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn mirror_fallback() {
+        init_env();
+        let mode = Arc::new(AtomicUsize::new(NORMAL));
+        let mut ms = MirrorStorage::new(vec![
+            Box::new(FlakyStorage::new(&mode)),
+            Box::new(MemStorage::new()),
+        ]);
+        ms.connect().unwrap();
+        ms.init(Crypto::default(), Key::new_empty()).unwrap();
+
+        let id = Eid::new();
+        let blks = vec![42u8; 3 * BLK_SIZE];
+        ms.put_super_block(&[1, 2, 3], 0).unwrap();
+        ms.put_address(&id, &[4, 5]).unwrap();
+        ms.put_blocks(Span::new(0, 3), &blks).unwrap();
+        ms.flush().unwrap();
+
+        // read error falls back to the other member
+        mode.store(READ_ERR, Ordering::SeqCst);
+        assert_eq!(ms.get_address(&id).unwrap(), vec![4, 5]);
+        let mut dst = vec![0u8; 3 * BLK_SIZE];
+        ms.get_blocks(&mut dst, Span::new(0, 3)).unwrap();
+        assert_eq!(dst, blks);
+
+        // reading a specified copy doesn't fall back
+        ms.set_read_copy(Some(0));
+        assert_eq!(ms.get_super_block(0).unwrap_err(), Error::Corrupted);
+        ms.set_read_copy(Some(1));
+        assert_eq!(ms.get_super_block(0).unwrap(), vec![1, 2, 3]);
+        ms.set_read_copy(None);
+
+        // write failure marks the member out of sync
+        mode.store(WRITE_ERR, Ordering::SeqCst);
+        ms.put_wal(&id, &[6]).unwrap();
+        ms.put_blocks(Span::new(2, 2), &blks[..2 * BLK_SIZE]).unwrap();
+        ms.flush().unwrap();
+        assert!(ms.is_failed(0));
+        assert!(!ms.is_failed(1));
+        mode.store(NORMAL, Ordering::SeqCst);
+        ms.set_read_copy(Some(0));
+        assert_eq!(ms.get_wal(&id).unwrap_err(), Error::NoMember);
+        ms.set_read_copy(None);
+
+        // resync the member and then read from it
+        ms.resync(0).unwrap();
+        assert!(!ms.is_failed(0));
+        ms.set_read_copy(Some(0));
+        assert_eq!(ms.get_wal(&id).unwrap(), vec![6]);
+        assert_eq!(ms.get_address(&id).unwrap(), vec![4, 5]);
+        let mut dst = vec![0u8; 4 * BLK_SIZE];
+        ms.get_blocks(&mut dst, Span::new(0, 4)).unwrap();
+        assert_eq!(&dst[..3 * BLK_SIZE], &blks[..]);
+        assert_eq!(&dst[3 * BLK_SIZE..], &blks[..BLK_SIZE]);
+    }
+
+    #[test]
+    fn mirror_repo() {
+        init_env();
+
+        // corrupted data in a member is read from the other member
+        let mode = Arc::new(AtomicUsize::new(NORMAL));
+        {
+            let mode = mode.clone();
+            register_storage("mirror-test", move |_| {
+                let depot: Box<dyn Storable> = Box::new(MirrorStorage::new(vec![
+                    Box::new(FlakyStorage::new(&mode)),
+                    Box::new(MemStorage::new()),
+                ]));
+                Ok(depot)
+            })
+            .unwrap();
+        }
+        let buf: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let mut repo = RepoOpener::new()
+            .create(true)
+            .open("mirror-test://", "pwd")
+            .unwrap();
+        let mut f = repo.create_file("/file").unwrap();
+        f.write_all(&buf).unwrap();
+        f.finish().unwrap();
+        mode.store(READ_GARBAGE, Ordering::SeqCst);
+        let mut f = repo.open_file("/file").unwrap();
+        let mut dst = Vec::new();
+        f.read_to_end(&mut dst).unwrap();
+        assert_eq!(dst, buf);
+        drop(f);
+        drop(repo);
+
+        // replaced member is resynced when repo is opened
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let dir_a = tmpdir.path().join("a");
+        let dir_b = tmpdir.path().join("b");
+        let uri = format!("mirror://file://{}|file://{}", dir_a.display(), dir_b.display());
+        {
+            let mut repo = RepoOpener::new().create(true).open(&uri, "pwd").unwrap();
+            let mut f = repo.create_file("/file").unwrap();
+            f.write_all(&buf).unwrap();
+            f.finish().unwrap();
+        }
+        for dir in [&dir_b, &dir_a].iter() {
+            fs::remove_dir_all(dir).unwrap();
+            let mut repo = RepoOpener::new().open(&uri, "pwd").unwrap();
+            assert!(dir.exists());
+            let mut f = repo.open_file("/file").unwrap();
+            let mut dst = Vec::new();
+            f.read_to_end(&mut dst).unwrap();
+            assert_eq!(dst, buf);
+        }
+    }
+
+    #[test]
+    fn mirror_compact_export() {
+        use crate::volume::storage::FileStorage;
+
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let mut ms = MirrorStorage::new(vec![
+            Box::new(FileStorage::new(&tmpdir.path().join("a"))),
+            Box::new(FileStorage::new(&tmpdir.path().join("b"))),
+        ]);
+        let layout = Layout {
+            blk_size: 1024,
+            blks_per_frame: 4,
+            blks_per_sector: 16,
+        };
+        ms.set_layout(layout).unwrap();
+        ms.connect().unwrap();
+        ms.init(Crypto::default(), Key::new_empty()).unwrap();
+
+        // finish a sector in both members and delete some of its blocks
+        let blks: Vec<u8> = (0..16 * 1024).map(|i| (i / 1024) as u8).collect();
+        ms.put_blocks(Span::new(0, 16), &blks).unwrap();
+        ms.del_blocks(Span::new(2, 6)).unwrap();
+        ms.flush().unwrap();
+
+        // candidates and compaction are forwarded to members
+        let units = ms.compact_candidates(16, 0.3).unwrap();
+        assert_eq!(
+            units,
+            vec![CompactUnit {
+                idx: 0,
+                size: layout.sector_size(),
+                dead: 6 * 1024,
+            }]
+        );
+        assert_eq!(ms.compact_unit(0).unwrap(), (2 * 10 * 1024, 2 * 6 * 1024));
+        assert!(ms.compact_candidates(16, 0.0).unwrap().is_empty());
+        for copy in 0..2 {
+            ms.set_read_copy(Some(copy));
+            let mut dst = vec![0u8; 8 * 1024];
+            ms.get_blocks(&mut dst, Span::new(8, 8)).unwrap();
+            assert_eq!(&dst[..], &blks[8 * 1024..]);
+        }
+        ms.set_read_copy(None);
+
+        // file members cannot be exported
+        assert_eq!(
+            ms.export_image(&mut Vec::new()).unwrap_err(),
+            Error::NotSupported
+        );
+
+        // image is exported from a member in sync
+        let mut ms = MirrorStorage::new(vec![
+            Box::new(MemStorage::new()),
+            Box::new(MemStorage::new()),
+        ]);
+        ms.connect().unwrap();
+        ms.init(Crypto::default(), Key::new_empty()).unwrap();
+        ms.put_super_block(&[1, 2, 3], 0).unwrap();
+        ms.flush().unwrap();
+        let mut img = Vec::new();
+        ms.export_image(&mut img).unwrap();
+        let mut depot = MemStorage::import_image(&mut &img[..]).unwrap();
+        assert_eq!(depot.get_super_block(0).unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+mod mirror;
+
+pub use self::mirror::MirrorStorage;
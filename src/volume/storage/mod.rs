@@ -1,17 +1,54 @@
+mod cache;
+mod erasure;
+mod faulty;
 mod file;
+mod log;
+mod manifest;
 mod mem;
+mod mirror;
+mod object;
+mod registry;
+#[cfg(feature = "storage-sqlite")]
+mod sqlite;
+#[allow(clippy::module_inception)]
 pub mod storage;
 
+pub use self::cache::{CacheOptions, CacheStorage};
+pub use self::erasure::ErasureStorage;
+pub use self::faulty::{Fault, FaultInjector, FaultyStorage};
 pub use self::file::FileStorage;
+pub use self::log::LogStorage;
 pub use self::mem::MemStorage;
+pub use self::mirror::MirrorStorage;
+pub use self::object::{MemObjectClient, ObjectClient, ObjectStorage};
+pub use self::registry::{register_storage, unregister_storage, StorageFactory};
+#[cfg(feature = "storage-sqlite")]
+pub use self::sqlite::SqliteStorage;
 pub use self::storage::{Reader, Storage, StorageRef, Writer};
 
 use std::fmt::Debug;
+use std::io::Write;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::trans::Eid;
 use crate::util::crypto::{Crypto, Key};
 use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+
+/// Storage unit which can be rewritten to reclaim space of deleted blocks,
+/// such as a sector of file storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactUnit {
+    /// unit index in storage
+    pub idx: usize,
+
+    /// unit size in bytes, including deleted blocks
+    pub size: usize,
+
+    /// bytes of deleted blocks in unit
+    pub dead: usize,
+}
+
 /// Storable trait
 pub trait Storable: Debug + Send + Sync {
     // check if storage exists
@@ -26,6 +63,18 @@ pub trait Storable: Debug + Send + Sync {
     // open a storage
     fn open(&mut self, crypto: Crypto, key: Key) -> Result<()>;
 
+    // set block layout of the volume, it is called before storage is
+    // initialised or opened, storage only supporting the default layout
+    // should return NotSupported error for any other layout
+    #[inline]
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        if layout == Layout::default() {
+            Ok(())
+        } else {
+            Err(Error::NotSupported)
+        }
+    }
+
     // super block read/write, must not buffered
     // write no need to be atomic, but must gurantee any successful
     // write is persistent
@@ -54,4 +103,56 @@ pub trait Storable: Debug + Send + Sync {
     // flush possibly buffered address and block to storage
     // storage must gurantee write is persistent
     fn flush(&mut self) -> Result<()>;
+
+    // number of data copies kept by storage, storage keeping more than one
+    // copy should support reading from a specified copy
+    #[inline]
+    fn copies(&self) -> usize {
+        1
+    }
+
+    // set the copy subsequent reads are served from, None means storage
+    // can choose any copy
+    #[inline]
+    fn set_read_copy(&mut self, _copy: Option<usize>) {}
+
+    // whether blocks can be written again after they are deleted, if so
+    // deleted blocks can be reused by block allocator
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        false
+    }
+
+    // write whole storage content to an image, storage not supporting
+    // image should return NotSupported error
+    #[inline]
+    fn export_image(&mut self, _w: &mut dyn Write) -> Result<()> {
+        Err(Error::NotSupported)
+    }
+
+    // get storage units whose proportion of deleted blocks is not less than
+    // the dead ratio, block watermark is the upper bound of allocated
+    // blocks, storage which doesn't need compaction returns nothing
+    #[inline]
+    fn compact_candidates(
+        &mut self,
+        _blk_wmark: usize,
+        _dead_ratio: f32,
+    ) -> Result<Vec<CompactUnit>> {
+        Ok(Vec::new())
+    }
+
+    // rewrite a storage unit without its deleted blocks, return number of
+    // bytes written and reclaimed
+    #[inline]
+    fn compact_unit(&mut self, _idx: usize) -> Result<(usize, usize)> {
+        Ok((0, 0))
+    }
+
+    // get blocks marked as deleted up to block watermark, in ascending
+    // order, storage which doesn't keep deletion marks returns None
+    #[inline]
+    fn deleted_blocks(&mut self, _blk_wmark: usize) -> Result<Option<Vec<Span>>> {
+        Ok(None)
+    }
 }
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+
+use crate::error::{Error, Result};
+
+/// Object store client.
+///
+/// This is a minimal interface to an object store such as S3, objects are
+/// addressed by string keys and always read or written as a whole.
+pub trait ObjectClient: Debug + Send + Sync {
+    /// Read an object, returns [`NotFound`] error if it doesn't exist.
+    ///
+    /// [`NotFound`]: ../../error/enum.Error.html#variant.NotFound
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+
+    /// Write an object, an existing object with the same key is replaced.
+    /// The object must be persistent when this function returns.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Delete an object, deleting non-existing object is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// List keys of all objects starting with the prefix.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// In-process object store client.
+///
+/// Objects are kept in memory, cloned clients share the same objects. It is
+/// mainly used for testing.
+#[derive(Debug, Clone, Default)]
+pub struct MemObjectClient {
+    objs: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemObjectClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns number of objects in store.
+    pub fn len(&self) -> usize {
+        self.objs.read().unwrap().len()
+    }
+
+    /// Returns whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.objs.read().unwrap().is_empty()
+    }
+}
+
+impl ObjectClient for MemObjectClient {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let objs = self.objs.read().unwrap();
+        objs.get(key).cloned().ok_or(Error::NotFound)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let mut objs = self.objs.write().unwrap();
+        objs.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let mut objs = self.objs.write().unwrap();
+        objs.remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let objs = self.objs.read().unwrap();
+        Ok(objs
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
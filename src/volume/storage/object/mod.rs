@@ -0,0 +1,6 @@
+mod client;
+#[allow(clippy::module_inception)]
+mod object;
+
+pub use self::client::{MemObjectClient, ObjectClient};
+pub use self::object::ObjectStorage;
@@ -0,0 +1,650 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use super::client::ObjectClient;
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::collections::HashMap;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+use crate::volume::storage::registry::Registry;
+use crate::volume::storage::Storable;
+use crate::BLK_SIZE;
+
+// object key prefixes
+const SUPER_BLK_PREFIX: &str = "super/";
+const WAL_PREFIX: &str = "wal/";
+const ADDR_PREFIX: &str = "addr/";
+const BLK_PREFIX: &str = "blk/";
+const TOMB_PREFIX: &str = "tomb/";
+
+// block or tombstone object key, sequence goes first so that listed keys
+// are in write order
+#[inline]
+fn span_key(prefix: &str, seq: u64, span: Span) -> String {
+    format!(
+        "{}{:016x}-{:016x}-{:08x}",
+        prefix, seq, span.begin, span.cnt
+    )
+}
+
+#[inline]
+fn blk_key(seq: u64, span: Span) -> String {
+    span_key(BLK_PREFIX, seq, span)
+}
+
+#[inline]
+fn tomb_key(seq: u64, span: Span) -> String {
+    span_key(TOMB_PREFIX, seq, span)
+}
+
+fn parse_span_key(prefix: &str, key: &str) -> Option<(u64, Span)> {
+    if !key.starts_with(prefix) {
+        return None;
+    }
+    let mut parts = key[prefix.len()..].split('-');
+    let seq = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let begin = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let cnt = usize::from_str_radix(parts.next()?, 16).ok()?;
+    if parts.next().is_some() || cnt == 0 {
+        return None;
+    }
+    Some((seq, Span::new(begin, cnt)))
+}
+
+// block object
+#[derive(Debug, Clone, Copy)]
+struct BlkObj {
+    span: Span,
+    live: usize, // number of live blocks
+}
+
+// tombstone of deleted blocks, it is kept until all objects which had the
+// blocks are deleted
+#[derive(Debug, Clone)]
+struct Tomb {
+    span: Span,
+    objs: Vec<u64>,
+}
+
+lazy_static! {
+    // process-global object clients by name, used by URI `object://name`
+    static ref CLIENTS: Registry<Arc<dyn ObjectClient>> = Registry::new();
+}
+
+/// Object Storage
+///
+/// This storage keeps super blocks, WALs and addresses as individual
+/// objects in an object store through an [`ObjectClient`]. Blocks written
+/// in one `put_blocks` call are stored in multi-block objects.
+///
+/// Blocks still live in each object are tracked, an object is deleted once
+/// all of its blocks are deleted or overwritten. Live blocks are never
+/// rewritten. Deleting blocks from an object which still has live blocks
+/// writes a tombstone object, which is removed along with that object.
+///
+/// The block index is rebuilt from object keys when opening the storage,
+/// later objects and tombstones override blocks in earlier objects.
+///
+/// Storage created from URI `object://name` uses the client registered by
+/// [`register_client`] with the same name.
+///
+/// [`ObjectClient`]: trait.ObjectClient.html
+/// [`register_client`]: #method.register_client
+pub struct ObjectStorage {
+    client: Arc<dyn ObjectClient>,
+
+    // block index to sequence of its object
+    blks: HashMap<usize, u64>,
+
+    // block objects
+    objs: HashMap<u64, BlkObj>,
+
+    // tombstones
+    tombs: HashMap<u64, Tomb>,
+
+    // sequence for next block object
+    next_seq: u64,
+
+    blk_size: usize,
+}
+
+impl ObjectStorage {
+    // max number of blocks in one object
+    const OBJECT_BLKS: usize = 64;
+
+    pub fn new(client: Box<dyn ObjectClient>) -> Self {
+        Self::with_client(Arc::from(client))
+    }
+
+    fn with_client(client: Arc<dyn ObjectClient>) -> Self {
+        ObjectStorage {
+            client,
+            blks: HashMap::new(),
+            objs: HashMap::new(),
+            tombs: HashMap::new(),
+            next_seq: 0,
+            blk_size: BLK_SIZE,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.blks.clear();
+        self.objs.clear();
+        self.tombs.clear();
+        self.next_seq = 0;
+    }
+
+    // map blocks to an object, return sequences of the objects whose blocks
+    // are overwritten
+    fn map_obj(&mut self, seq: u64, span: Span) -> Vec<u64> {
+        let mut affected = Vec::new();
+        for blk_idx in span {
+            if let Some(old) = self.blks.insert(blk_idx, seq) {
+                self.objs.get_mut(&old).unwrap().live -= 1;
+                affected.push(old);
+            }
+        }
+        self.objs.insert(
+            seq,
+            BlkObj {
+                span,
+                live: span.cnt,
+            },
+        );
+        affected
+    }
+
+    // unmap blocks, return sequences of the objects had the blocks
+    fn unmap_blks(&mut self, span: Span) -> Vec<u64> {
+        let mut affected = Vec::new();
+        for blk_idx in span {
+            if let Some(seq) = self.blks.remove(&blk_idx) {
+                self.objs.get_mut(&seq).unwrap().live -= 1;
+                affected.push(seq);
+            }
+        }
+        affected.sort();
+        affected.dedup();
+        affected
+    }
+
+    // delete objects which have no live blocks, and then tombstones which
+    // are no longer needed
+    fn collect(&mut self, affected: Vec<u64>) -> Result<()> {
+        let mut deleted = Vec::new();
+        for seq in affected {
+            match self.objs.get(&seq) {
+                Some(obj) if obj.live == 0 => {
+                    self.client.delete(&blk_key(seq, obj.span))?;
+                    self.objs.remove(&seq);
+                    deleted.push(seq);
+                }
+                _ => {}
+            }
+        }
+
+        if !deleted.is_empty() {
+            for tomb in self.tombs.values_mut() {
+                tomb.objs.retain(|seq| !deleted.contains(seq));
+            }
+        }
+        let unused: Vec<u64> = self
+            .tombs
+            .iter()
+            .filter(|(_, tomb)| tomb.objs.is_empty())
+            .map(|(&seq, _)| seq)
+            .collect();
+        for seq in unused {
+            let tomb = self.tombs.remove(&seq).unwrap();
+            self.client.delete(&tomb_key(seq, tomb.span))?;
+        }
+
+        Ok(())
+    }
+
+    // rebuild block index from object list
+    fn recover(&mut self) -> Result<()> {
+        self.reset();
+
+        let blk_keys = self.client.list(BLK_PREFIX)?;
+        let tomb_keys = self.client.list(TOMB_PREFIX)?;
+        let mut keys: Vec<(u64, Span, bool)> = blk_keys
+            .iter()
+            .filter_map(|key| parse_span_key(BLK_PREFIX, key))
+            .map(|(seq, span)| (seq, span, false))
+            .chain(
+                tomb_keys
+                    .iter()
+                    .filter_map(|key| parse_span_key(TOMB_PREFIX, key))
+                    .map(|(seq, span)| (seq, span, true)),
+            )
+            .collect();
+        keys.sort_by_key(|&(seq, _, _)| seq);
+
+        // objects fully overridden or deleted by later ones are left by
+        // interrupted overwrite or delete, they are removed now
+        let mut affected = Vec::new();
+        for &(seq, span, is_tomb) in keys.iter() {
+            if is_tomb {
+                let objs = self.unmap_blks(span);
+                affected.extend(objs.iter());
+                self.tombs.insert(seq, Tomb { span, objs });
+            } else {
+                affected.extend(self.map_obj(seq, span));
+            }
+            self.next_seq = seq + 1;
+        }
+        affected.sort();
+        affected.dedup();
+        self.collect(affected)?;
+
+        debug!(
+            "object storage recovered: {} objects, {} blocks",
+            self.objs.len(),
+            self.blks.len()
+        );
+
+        Ok(())
+    }
+
+    /// Creates an object storage using the client registered by
+    /// [`register_client`].
+    ///
+    /// Returns [`NotFound`] error if no client is registered with the name.
+    ///
+    /// [`register_client`]: #method.register_client
+    /// [`NotFound`]: ../../error/enum.Error.html#variant.NotFound
+    pub fn with_name(name: &str) -> Result<Self> {
+        CLIENTS
+            .get(name)
+            .map(Self::with_client)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Registers an object client with a name, so that repositories can be
+    /// created or opened on it using URI `object://name`.
+    ///
+    /// Returns [`AlreadyExists`] error if the name has already been
+    /// registered.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use f2ufs::error::Result;
+    /// use f2ufs::repo::RepoOpener;
+    /// use f2ufs::util::init_env;
+    /// use f2ufs::volume::storage::{MemObjectClient, ObjectStorage};
+    /// # fn foo() -> Result<()> {
+    /// init_env();
+    ///
+    /// ObjectStorage::register_client("bucket", Box::new(MemObjectClient::new()))?;
+    ///
+    /// let mut repo = RepoOpener::new()
+    ///     .create(true)
+    ///     .open("object://bucket", "pwd")?;
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    ///
+    /// [`AlreadyExists`]: ../../error/enum.Error.html#variant.AlreadyExists
+    pub fn register_client(name: &str, client: Box<dyn ObjectClient>) -> Result<()> {
+        if !CLIENTS.insert(name, Arc::from(client)) {
+            return Err(Error::AlreadyExists);
+        }
+        Ok(())
+    }
+
+    /// Unregisters an object client.
+    ///
+    /// Returns `true` if the name was registered. Repositories already
+    /// opened using this client are not affected.
+    pub fn unregister_client(name: &str) -> bool {
+        CLIENTS.remove(name).is_some()
+    }
+}
+
+impl Storable for ObjectStorage {
+    #[inline]
+    fn exists(&self) -> Result<bool> {
+        Ok(!self.client.list(SUPER_BLK_PREFIX)?.is_empty())
+    }
+
+    #[inline]
+    fn connect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn init(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        self.reset();
+        Ok(())
+    }
+
+    #[inline]
+    fn open(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        self.recover()
+    }
+
+    #[inline]
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.blk_size = layout.blk_size;
+        Ok(())
+    }
+
+    #[inline]
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        self.client.get(&format!("{}{}", SUPER_BLK_PREFIX, suffix))
+    }
+
+    #[inline]
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        self.client
+            .put(&format!("{}{}", SUPER_BLK_PREFIX, suffix), super_blk)
+    }
+
+    #[inline]
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.client.get(&format!("{}{}", WAL_PREFIX, id))
+    }
+
+    #[inline]
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        self.client.put(&format!("{}{}", WAL_PREFIX, id), wal)
+    }
+
+    #[inline]
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        self.client.delete(&format!("{}{}", WAL_PREFIX, id))
+    }
+
+    #[inline]
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.client.get(&format!("{}{}", ADDR_PREFIX, id))
+    }
+
+    #[inline]
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        self.client.put(&format!("{}{}", ADDR_PREFIX, id), addr)
+    }
+
+    #[inline]
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        self.client.delete(&format!("{}{}", ADDR_PREFIX, id))
+    }
+
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        let blk_size = self.blk_size;
+        assert_eq!(dst.len(), span.bytes_len(blk_size));
+
+        // read blocks in the same object in one go
+        let mut blk_idx = span.begin;
+        while blk_idx < span.end() {
+            let seq = *self.blks.get(&blk_idx).ok_or(Error::NotFound)?;
+            let obj = self.objs[&seq];
+            let mut cnt = 1;
+            while blk_idx + cnt < span.end() && self.blks.get(&(blk_idx + cnt)) == Some(&seq) {
+                cnt += 1;
+            }
+
+            let data = self.client.get(&blk_key(seq, obj.span))?;
+            if data.len() != obj.span.bytes_len(blk_size) {
+                return Err(Error::Corrupted);
+            }
+            let src = (blk_idx - obj.span.begin) * blk_size;
+            let dst_offset = (blk_idx - span.begin) * blk_size;
+            let len = cnt * blk_size;
+            dst[dst_offset..dst_offset + len].copy_from_slice(&data[src..src + len]);
+            blk_idx += cnt;
+        }
+
+        Ok(())
+    }
+
+    fn put_blocks(&mut self, span: Span, blks: &[u8]) -> Result<()> {
+        let blk_size = self.blk_size;
+        assert_eq!(blks.len(), span.bytes_len(blk_size));
+
+        let mut begin = span.begin;
+        for chunk in blks.chunks(Self::OBJECT_BLKS * blk_size) {
+            let span = Span::new(begin, chunk.len() / blk_size);
+            let seq = self.next_seq;
+            self.client.put(&blk_key(seq, span), chunk)?;
+            self.next_seq += 1;
+            let affected = self.map_obj(seq, span);
+            self.collect(affected)?;
+            begin += span.cnt;
+        }
+
+        Ok(())
+    }
+
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        let affected = self.unmap_blks(span);
+
+        // deleted blocks in objects still having live blocks must not come
+        // back when reopening, so a tombstone is written for them
+        let objs: Vec<u64> = affected
+            .iter()
+            .filter(|seq| self.objs[seq].live > 0)
+            .cloned()
+            .collect();
+        if !objs.is_empty() {
+            let seq = self.next_seq;
+            self.client.put(&tomb_key(seq, span), &[])?;
+            self.next_seq += 1;
+            self.tombs.insert(seq, Tomb { span, objs });
+        }
+
+        self.collect(affected)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        true
+    }
+}
+
+impl Debug for ObjectStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ObjectStorage")
+            .field("client", &self.client)
+            .field("objs", &self.objs.len())
+            .field("tombs", &self.tombs.len())
+            .field("blks", &self.blks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::client::MemObjectClient;
+    use super::*;
+    use crate::repo::RepoOpener;
+    use crate::util::crypto::RandomSeed;
+    use crate::util::init_env;
+
+    fn blocks(cnt: usize, seed: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; cnt * BLK_SIZE];
+        Crypto::random_buf_deterministic(&mut buf, &RandomSeed::from(&[seed; 32]));
+        buf
+    }
+
+    fn blk_objs(client: &MemObjectClient) -> Vec<String> {
+        client.list(BLK_PREFIX).unwrap()
+    }
+
+    fn tomb_objs(client: &MemObjectClient) -> Vec<String> {
+        client.list(TOMB_PREFIX).unwrap()
+    }
+
+    fn read_blocks(os: &mut ObjectStorage, span: Span) -> Result<Vec<u8>> {
+        let mut dst = vec![0u8; span.bytes_len(BLK_SIZE)];
+        os.get_blocks(&mut dst, span)?;
+        Ok(dst)
+    }
+
+    fn open_storage(client: &MemObjectClient) -> ObjectStorage {
+        let mut os = ObjectStorage::new(Box::new(client.clone()));
+        os.connect().unwrap();
+        os.open(Crypto::default(), Key::new_empty()).unwrap();
+        os
+    }
+
+    #[test]
+    fn object_storage() {
+        init_env();
+        let client = MemObjectClient::new();
+        let blks = blocks(8, 42);
+        let blks2 = blocks(4, 43);
+        let id = Eid::new();
+
+        let mut os = ObjectStorage::new(Box::new(client.clone()));
+        assert!(!os.exists().unwrap());
+        os.init(Crypto::default(), Key::new_empty()).unwrap();
+        os.put_super_block(&[1, 2, 3], 0).unwrap();
+        os.put_address(&id, &[4]).unwrap();
+        assert!(os.exists().unwrap());
+
+        os.put_blocks(Span::new(0, 8), &blks).unwrap();
+        assert_eq!(
+            read_blocks(&mut os, Span::new(2, 4)).unwrap(),
+            &blks[2 * BLK_SIZE..6 * BLK_SIZE]
+        );
+        os.del_blocks(Span::new(2, 2)).unwrap();
+        os.put_blocks(Span::new(4, 4), &blks2).unwrap();
+
+        // block index is rebuilt on open
+        drop(os);
+        let mut os = open_storage(&client);
+        assert_eq!(os.get_super_block(0).unwrap(), vec![1, 2, 3]);
+        assert_eq!(os.get_address(&id).unwrap(), vec![4]);
+        assert_eq!(
+            read_blocks(&mut os, Span::new(0, 2)).unwrap(),
+            &blks[..2 * BLK_SIZE]
+        );
+        assert_eq!(read_blocks(&mut os, Span::new(4, 4)).unwrap(), blks2);
+        assert_eq!(
+            read_blocks(&mut os, Span::new(2, 1)).unwrap_err(),
+            Error::NotFound
+        );
+        let dst = read_blocks(&mut os, Span::new(1, 4)).unwrap_err();
+        assert_eq!(dst, Error::NotFound);
+
+        os.del_blocks(Span::new(0, 8)).unwrap();
+        assert!(blk_objs(&client).is_empty());
+        assert!(tomb_objs(&client).is_empty());
+    }
+
+    #[test]
+    fn object_batch() {
+        init_env();
+        let client = MemObjectClient::new();
+        let blks = blocks(ObjectStorage::OBJECT_BLKS + 8, 42);
+        let mut os = ObjectStorage::new(Box::new(client.clone()));
+        os.init(Crypto::default(), Key::new_empty()).unwrap();
+
+        // blocks in one put are stored in one object
+        os.put_blocks(Span::new(0, 8), &blks[..8 * BLK_SIZE])
+            .unwrap();
+        assert_eq!(blk_objs(&client).len(), 1);
+        os.put_blocks(Span::new(8, 1), &blks[..BLK_SIZE]).unwrap();
+        assert_eq!(blk_objs(&client).len(), 2);
+
+        // large put is split into objects of max size
+        let span = Span::new(100, ObjectStorage::OBJECT_BLKS + 8);
+        os.put_blocks(span, &blks).unwrap();
+        assert_eq!(blk_objs(&client).len(), 4);
+        assert_eq!(read_blocks(&mut os, span).unwrap(), blks);
+    }
+
+    #[test]
+    fn object_live_blocks() {
+        init_env();
+        let client = MemObjectClient::new();
+        let blks = blocks(8, 42);
+        let blks2 = blocks(4, 43);
+        let mut os = ObjectStorage::new(Box::new(client.clone()));
+        os.init(Crypto::default(), Key::new_empty()).unwrap();
+        os.put_blocks(Span::new(0, 8), &blks).unwrap();
+
+        // object is kept while any of its blocks is live, deleted blocks
+        // in it are recorded in tombstone
+        os.del_blocks(Span::new(0, 2)).unwrap();
+        assert_eq!(tomb_objs(&client).len(), 1);
+        os.put_blocks(Span::new(2, 4), &blks2).unwrap();
+        assert_eq!(blk_objs(&client).len(), 2);
+        assert_eq!(
+            read_blocks(&mut os, Span::new(6, 2)).unwrap(),
+            &blks[6 * BLK_SIZE..]
+        );
+
+        // object is deleted once all its blocks are gone
+        os.del_blocks(Span::new(6, 1)).unwrap();
+        assert_eq!(blk_objs(&client).len(), 2);
+        assert_eq!(tomb_objs(&client).len(), 2);
+        os.put_blocks(Span::new(7, 1), &blks2[..BLK_SIZE]).unwrap();
+        assert_eq!(blk_objs(&client).len(), 2);
+        assert!(tomb_objs(&client).is_empty());
+        let mut os = open_storage(&client);
+        assert_eq!(
+            read_blocks(&mut os, Span::new(0, 2)).unwrap_err(),
+            Error::NotFound
+        );
+        assert_eq!(read_blocks(&mut os, Span::new(2, 4)).unwrap(), blks2);
+        os.del_blocks(Span::new(2, 4)).unwrap();
+        assert_eq!(blk_objs(&client).len(), 1);
+        assert!(tomb_objs(&client).is_empty());
+
+        // object fully overridden by later ones is removed on open
+        let old = blk_key(0, Span::new(7, 1));
+        client.put(&old, &blks[..BLK_SIZE]).unwrap();
+        assert_eq!(blk_objs(&client).len(), 2);
+        let mut os = open_storage(&client);
+        assert_eq!(blk_objs(&client).len(), 1);
+        assert_eq!(
+            read_blocks(&mut os, Span::new(7, 1)).unwrap(),
+            &blks2[..BLK_SIZE]
+        );
+    }
+
+    #[test]
+    fn object_uri() {
+        init_env();
+        let client = MemObjectClient::new();
+        ObjectStorage::register_client("object_uri", Box::new(client.clone())).unwrap();
+        assert_eq!(
+            ObjectStorage::register_client("object_uri", Box::new(MemObjectClient::new()))
+                .unwrap_err(),
+            Error::AlreadyExists
+        );
+
+        {
+            let mut repo = RepoOpener::new()
+                .create(true)
+                .open("object://object_uri", "pwd")
+                .unwrap();
+            repo.create_dir("/dir").unwrap();
+        }
+        assert!(!client.is_empty());
+
+        let repo = RepoOpener::new()
+            .open("object://object_uri", "pwd")
+            .unwrap();
+        assert!(repo.path_exists("/dir").unwrap());
+
+        // client must be registered
+        assert!(ObjectStorage::unregister_client("object_uri"));
+        assert!(RepoOpener::new()
+            .open("object://object_uri", "pwd")
+            .is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::{Arc, RwLock};
+
+use super::storage;
+use super::Storable;
+use crate::error::{Error, Result};
+use crate::util::collections::HashMap;
+
+/// Storage factory.
+///
+/// A factory creates a storage from the location, which is the string after
+/// `<scheme>://` in the repository URI.
+pub type StorageFactory = dyn Fn(&str) -> Result<Box<dyn Storable>> + Send + Sync;
+
+// process-global registry of named values, values are cloned out so the lock
+// is never held by callers
+pub(super) struct Registry<T> {
+    map: RwLock<HashMap<String, T>>,
+}
+
+impl<T: Clone> Registry<T> {
+    pub fn new() -> Self {
+        Registry {
+            map: RwLock::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<T> {
+        self.map.read().unwrap().get(name).cloned()
+    }
+
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.map.read().unwrap().contains_key(name)
+    }
+
+    // insert value if the name is not registered yet, return false if the
+    // name is already registered
+    pub fn insert(&self, name: &str, val: T) -> bool {
+        let mut map = self.map.write().unwrap();
+        if map.contains_key(name) {
+            return false;
+        }
+        map.insert(name.to_string(), val);
+        true
+    }
+
+    // remove value if the predicate is true, return the removed value
+    pub fn remove_if<F>(&self, name: &str, pred: F) -> Option<T>
+    where
+        F: FnOnce(&T) -> bool,
+    {
+        let mut map = self.map.write().unwrap();
+        match map.get(name) {
+            Some(val) if pred(val) => map.remove(name),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn remove(&self, name: &str) -> Option<T> {
+        self.remove_if(name, |_| true)
+    }
+}
+
+// registered storage factory
+#[derive(Clone)]
+struct Factory {
+    create: Arc<StorageFactory>,
+    builtin: bool,
+}
+
+lazy_static! {
+    // storage factories by scheme, built-in storages are registered when it
+    // is first used
+    static ref FACTORIES: Registry<Factory> = {
+        let factories = Registry::new();
+        storage::register_builtins(&mut |scheme, create| {
+            let factory = Factory {
+                create,
+                builtin: true,
+            };
+            assert!(factories.insert(scheme, factory));
+        });
+        factories
+    };
+}
+
+// scheme must start with a letter, followed by letters, digits, `+`, `-`
+// or `.`
+fn is_valid_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+/// Register a storage factory for an URI scheme.
+///
+/// After registration, repositories can be created or opened using URI
+/// `<scheme>://<location>`, the factory will be called with `<location>` to
+/// create the storage, which is any type implementing [`Storable`].
+///
+/// Built-in schemes, such as `file` and `mem`, and schemes starting with
+/// `faulty+` cannot be registered.
+///
+/// # Errors
+///
+/// Returns [`InvalidArgument`] if the scheme is not valid.
+///
+/// Returns [`AlreadyExists`] if the scheme is built-in or has already been
+/// registered.
+///
+/// # Examples
+///
+/// ```
+/// # #![allow(unused_mut, unused_variables, dead_code)]
+/// # use f2ufs::error::Result;
+/// use f2ufs::repo::RepoOpener;
+/// use f2ufs::util::init_env;
+/// use f2ufs::volume::storage::{register_storage, MemStorage, Storable};
+/// # fn foo() -> Result<()> {
+/// init_env();
+///
+/// register_storage("mystore", |_location| {
+///     let depot: Box<Storable> = Box::new(MemStorage::new());
+///     Ok(depot)
+/// })?;
+///
+/// let mut repo = RepoOpener::new()
+///     .create(true)
+///     .open("mystore://foo", "pwd")?;
+/// # Ok(())
+/// # }
+/// # foo().unwrap();
+/// ```
+///
+/// [`Storable`]: trait.Storable.html
+/// [`InvalidArgument`]: ../../error/enum.Error.html#variant.InvalidArgument
+/// [`AlreadyExists`]: ../../error/enum.Error.html#variant.AlreadyExists
+pub fn register_storage<F>(scheme: &str, factory: F) -> Result<()>
+where
+    F: Fn(&str) -> Result<Box<dyn Storable>> + Send + Sync + 'static,
+{
+    if !is_valid_scheme(scheme) {
+        return Err(Error::InvalidArgument);
+    }
+    if scheme.starts_with("faulty+") {
+        return Err(Error::AlreadyExists);
+    }
+
+    let factory = Factory {
+        create: Arc::new(factory),
+        builtin: false,
+    };
+    if !FACTORIES.insert(scheme, factory) {
+        return Err(Error::AlreadyExists);
+    }
+
+    Ok(())
+}
+
+/// Unregister a storage factory.
+///
+/// Returns `true` if the scheme was registered. Built-in schemes cannot be
+/// unregistered. Repositories already opened using this scheme are not
+/// affected.
+pub fn unregister_storage(scheme: &str) -> bool {
+    FACTORIES
+        .remove_if(scheme, |factory| !factory.builtin)
+        .is_some()
+}
+
+// create storage using registered factory, return None if the scheme in
+// uri is not registered
+pub(super) fn create_storage(uri: &str) -> Result<Option<Box<dyn Storable>>> {
+    let pos = match uri.find("://") {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+
+    // factory is cloned out, so the lock is not held while calling it
+    match FACTORIES.get(&uri[..pos]) {
+        Some(factory) => (factory.create)(&uri[pos + 3..]).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::repo::RepoOpener;
+    use crate::util::init_env;
+    use crate::volume::storage::{MemStorage, Storage};
+
+    fn mem_factory(_loc: &str) -> Result<Box<dyn Storable>> {
+        Ok(Box::new(MemStorage::new()))
+    }
+
+    #[test]
+    fn storage_registry() {
+        init_env();
+
+        // invalid and built-in schemes
+        assert_eq!(
+            register_storage("", mem_factory).unwrap_err(),
+            Error::InvalidArgument
+        );
+        assert_eq!(
+            register_storage("1abc", mem_factory).unwrap_err(),
+            Error::InvalidArgument
+        );
+        assert_eq!(
+            register_storage("a://b", mem_factory).unwrap_err(),
+            Error::InvalidArgument
+        );
+        assert_eq!(
+            register_storage("file", mem_factory).unwrap_err(),
+            Error::AlreadyExists
+        );
+        assert!(!unregister_storage("file"));
+        assert!(Storage::new("mem://").is_ok());
+
+        // register a scheme and create repo on it
+        let locs = Arc::new(RwLock::new(Vec::new()));
+        let cnt = Arc::new(AtomicUsize::new(0));
+        {
+            let locs = locs.clone();
+            let cnt = cnt.clone();
+            register_storage("test-reg+kv", move |loc| {
+                locs.write().unwrap().push(loc.to_string());
+                cnt.fetch_add(1, Ordering::SeqCst);
+                mem_factory(loc)
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            register_storage("test-reg+kv", mem_factory).unwrap_err(),
+            Error::AlreadyExists
+        );
+
+        let mut repo = RepoOpener::new()
+            .create(true)
+            .open("test-reg+kv://foo/bar", "pwd")
+            .unwrap();
+        repo.create_dir("/dir").unwrap();
+        assert!(repo.path_exists("/dir").unwrap());
+        assert!(cnt.load(Ordering::SeqCst) > 0);
+        assert!(locs.read().unwrap().iter().all(|loc| loc == "foo/bar"));
+
+        // factory error is passed through
+        register_storage("test-reg-err", |_| Err(Error::NotFound)).unwrap();
+        assert_eq!(
+            Storage::new("test-reg-err://foo").unwrap_err(),
+            Error::NotFound
+        );
+
+        // unregistered scheme is invalid
+        assert!(unregister_storage("test-reg+kv"));
+        assert!(!unregister_storage("test-reg+kv"));
+        assert_eq!(
+            Storage::new("test-reg+kv://foo").unwrap_err(),
+            Error::InvalidUri
+        );
+        assert_eq!(Storage::new("foo").unwrap_err(), Error::InvalidUri);
+    }
+}
@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+mod sqlite;
+
+pub use self::sqlite::SqliteStorage;
@@ -0,0 +1,409 @@
+use std::fmt::{self, Debug};
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, ToSql, NO_PARAMS};
+
+use crate::error::{Error, Result};
+use crate::trans::eid::Eid;
+use crate::util::crypto::{Crypto, Key};
+use crate::volume::address::Span;
+use crate::volume::layout::Layout;
+use crate::volume::storage::Storable;
+use crate::BLK_SIZE;
+
+// in-memory database path
+const IN_MEMORY: &str = ":memory:";
+
+// table schema
+const SCHEMA: &str = "
+    CREATE TABLE super_blocks (
+        suffix  INTEGER PRIMARY KEY,
+        data    BLOB NOT NULL
+    );
+    CREATE TABLE wals (
+        id      BLOB PRIMARY KEY,
+        data    BLOB NOT NULL
+    );
+    CREATE TABLE addresses (
+        id      BLOB PRIMARY KEY,
+        data    BLOB NOT NULL
+    );
+    CREATE TABLE blocks (
+        idx     INTEGER PRIMARY KEY,
+        data    BLOB NOT NULL
+    );
+";
+
+/// SQLite Storage
+///
+/// Super blocks, WALs, addresses and blocks are stored in separate tables
+/// of a SQLite database. Address and block updates are buffered in a
+/// database transaction, which is committed when the storage is flushed.
+/// Super block and WAL updates are not buffered, the buffered transaction
+/// is committed first and then they are committed by SQLite immediately.
+pub struct SqliteStorage {
+    path: String,
+
+    // connection is only used through `&mut self`, the mutex is only to
+    // make the storage `Sync`
+    conn: Option<Mutex<Connection>>,
+
+    // if there is an uncommitted transaction
+    in_trans: bool,
+
+    blk_size: usize,
+}
+
+impl SqliteStorage {
+    pub fn new(path: &str) -> Self {
+        SqliteStorage {
+            path: path.to_string(),
+            conn: None,
+            in_trans: false,
+            blk_size: BLK_SIZE,
+        }
+    }
+
+    #[inline]
+    fn conn(&mut self) -> Result<&mut Connection> {
+        self.conn
+            .as_mut()
+            .map(|conn| conn.get_mut().unwrap())
+            .ok_or(Error::NotFound)
+    }
+
+    // start a transaction if it is not started yet
+    fn begin_trans(&mut self) -> Result<()> {
+        if !self.in_trans {
+            self.conn()?.execute_batch("BEGIN")?;
+            self.in_trans = true;
+        }
+        Ok(())
+    }
+
+    // commit the transaction if it is started
+    fn commit_trans(&mut self) -> Result<()> {
+        if self.in_trans {
+            self.conn()?.execute_batch("COMMIT")?;
+            self.in_trans = false;
+        }
+        Ok(())
+    }
+
+    // check if the database has super block table
+    fn has_schema(conn: &Connection) -> Result<bool> {
+        let cnt: i64 = conn.query_row(
+            "SELECT count(*) FROM sqlite_master
+             WHERE type = 'table' AND name = 'super_blocks'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        Ok(cnt > 0)
+    }
+
+    fn get_value(&mut self, sql: &str, key: &dyn ToSql) -> Result<Vec<u8>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(sql)?;
+        stmt.query_row(&[key], |row| row.get(0))
+            .optional()?
+            .ok_or(Error::NotFound)
+    }
+
+    fn execute(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<()> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(sql)?;
+        stmt.execute(params)?;
+        Ok(())
+    }
+
+    // execute in auto-commit mode, so the update is persistent when this
+    // returns
+    fn execute_unbuffered(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<()> {
+        self.commit_trans()?;
+        self.execute(sql, params)
+    }
+}
+
+impl Storable for SqliteStorage {
+    fn exists(&self) -> Result<bool> {
+        match self.conn {
+            Some(ref conn) => Self::has_schema(&conn.lock().unwrap()),
+            None => {
+                if self.path == IN_MEMORY || !Path::new(&self.path).exists() {
+                    return Ok(false);
+                }
+
+                // a file which is not a database or has no schema is not a
+                // repository
+                let conn =
+                    Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                match Self::has_schema(&conn) {
+                    Ok(has_schema) => Ok(has_schema),
+                    Err(Error::Sqlite(_)) => Ok(false),
+                    Err(err) => Err(err),
+                }
+            }
+        }
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        if self.conn.is_none() {
+            let conn = Connection::open(&self.path)?;
+            self.conn = Some(Mutex::new(conn));
+        }
+        Ok(())
+    }
+
+    fn init(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        self.conn()?.execute_batch(SCHEMA)?;
+        Ok(())
+    }
+
+    #[inline]
+    fn open(&mut self, _crypto: Crypto, _key: Key) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        self.blk_size = layout.blk_size;
+        Ok(())
+    }
+
+    #[inline]
+    fn get_super_block(&mut self, suffix: u64) -> Result<Vec<u8>> {
+        self.get_value(
+            "SELECT data FROM super_blocks WHERE suffix = ?",
+            &(suffix as i64),
+        )
+    }
+
+    fn put_super_block(&mut self, super_blk: &[u8], suffix: u64) -> Result<()> {
+        self.execute_unbuffered(
+            "INSERT OR REPLACE INTO super_blocks (suffix, data) VALUES (?, ?)",
+            params![suffix as i64, super_blk],
+        )
+    }
+
+    #[inline]
+    fn get_wal(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.get_value("SELECT data FROM wals WHERE id = ?", &id.as_ref())
+    }
+
+    fn put_wal(&mut self, id: &Eid, wal: &[u8]) -> Result<()> {
+        self.execute_unbuffered(
+            "INSERT OR REPLACE INTO wals (id, data) VALUES (?, ?)",
+            params![id.as_ref(), wal],
+        )
+    }
+
+    #[inline]
+    fn del_wal(&mut self, id: &Eid) -> Result<()> {
+        self.execute_unbuffered("DELETE FROM wals WHERE id = ?", params![id.as_ref()])
+    }
+
+    #[inline]
+    fn get_address(&mut self, id: &Eid) -> Result<Vec<u8>> {
+        self.get_value("SELECT data FROM addresses WHERE id = ?", &id.as_ref())
+    }
+
+    fn put_address(&mut self, id: &Eid, addr: &[u8]) -> Result<()> {
+        self.begin_trans()?;
+        self.execute(
+            "INSERT OR REPLACE INTO addresses (id, data) VALUES (?, ?)",
+            params![id.as_ref(), addr],
+        )
+    }
+
+    fn del_address(&mut self, id: &Eid) -> Result<()> {
+        self.begin_trans()?;
+        self.execute("DELETE FROM addresses WHERE id = ?", params![id.as_ref()])
+    }
+
+    fn get_blocks(&mut self, dst: &mut [u8], span: Span) -> Result<()> {
+        let blk_size = self.blk_size;
+        assert_eq!(dst.len(), span.bytes_len(blk_size));
+
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT idx, data FROM blocks WHERE idx >= ? AND idx < ?")?;
+        let mut rows = stmt.query(params![span.begin as i64, span.end() as i64])?;
+
+        let mut cnt = 0;
+        while let Some(row) = rows.next()? {
+            let idx: i64 = row.get(0)?;
+            let blk: Vec<u8> = row.get(1)?;
+            if blk.len() != blk_size {
+                return Err(Error::Corrupted);
+            }
+            let offset = (idx as usize - span.begin) * blk_size;
+            dst[offset..offset + blk_size].copy_from_slice(&blk);
+            cnt += 1;
+        }
+
+        if cnt != span.cnt {
+            return Err(Error::NotFound);
+        }
+
+        Ok(())
+    }
+
+    fn put_blocks(&mut self, span: Span, mut blks: &[u8]) -> Result<()> {
+        let blk_size = self.blk_size;
+        assert_eq!(blks.len(), span.bytes_len(blk_size));
+        self.begin_trans()?;
+        for idx in span {
+            self.execute(
+                "INSERT OR REPLACE INTO blocks (idx, data) VALUES (?, ?)",
+                params![idx as i64, &blks[..blk_size]],
+            )?;
+            blks = &blks[blk_size..];
+        }
+        Ok(())
+    }
+
+    fn del_blocks(&mut self, span: Span) -> Result<()> {
+        self.begin_trans()?;
+        self.execute(
+            "DELETE FROM blocks WHERE idx >= ? AND idx < ?",
+            params![span.begin as i64, span.end() as i64],
+        )
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        self.commit_trans()
+    }
+
+    #[inline]
+    fn can_reuse_blocks(&self) -> bool {
+        true
+    }
+}
+
+impl Debug for SqliteStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SqliteStorage")
+            .field("path", &self.path)
+            .field("in_trans", &self.in_trans)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use crate::util::init_env;
+
+    #[test]
+    fn sqlite_flush() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let path = tmpdir.path().join("repo.db");
+        let path = path.to_str().unwrap();
+        let id = Eid::new();
+        let blks = vec![42u8; 2 * BLK_SIZE];
+
+        {
+            let mut ss = SqliteStorage::new(path);
+            assert!(!ss.exists().unwrap());
+            ss.connect().unwrap();
+            ss.init(Crypto::default(), Key::new_empty()).unwrap();
+            assert!(ss.exists().unwrap());
+
+            ss.put_super_block(&[1, 2, 3], 0).unwrap();
+            ss.put_address(&id, &[4, 5]).unwrap();
+            ss.put_blocks(Span::new(0, 2), &blks).unwrap();
+            ss.flush().unwrap();
+
+            // wal write is committed immediately
+            ss.put_wal(&id, &[6]).unwrap();
+
+            // updates not flushed should be lost
+            ss.del_blocks(Span::new(1, 1)).unwrap();
+            ss.del_address(&id).unwrap();
+        }
+
+        let mut ss = SqliteStorage::new(path);
+        assert!(ss.exists().unwrap());
+        ss.connect().unwrap();
+        ss.open(Crypto::default(), Key::new_empty()).unwrap();
+        assert_eq!(ss.get_super_block(0).unwrap(), vec![1, 2, 3]);
+        assert_eq!(ss.get_wal(&id).unwrap(), vec![6]);
+        assert_eq!(ss.get_address(&id).unwrap(), vec![4, 5]);
+        let mut dst = vec![0u8; BLK_SIZE];
+        ss.get_blocks(&mut dst, Span::new(0, 1)).unwrap();
+        assert_eq!(&dst[..], &blks[..BLK_SIZE]);
+        ss.get_blocks(&mut dst, Span::new(1, 1)).unwrap();
+        assert_eq!(&dst[..], &blks[BLK_SIZE..]);
+    }
+
+    #[test]
+    fn sqlite_unbuffered_wal() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let path = tmpdir.path().join("repo.db");
+        let path = path.to_str().unwrap();
+        let (id, id2) = (Eid::new(), Eid::new());
+
+        {
+            let mut ss = SqliteStorage::new(path);
+            ss.connect().unwrap();
+            ss.init(Crypto::default(), Key::new_empty()).unwrap();
+            ss.put_wal(&id2, &[1]).unwrap();
+
+            // super block and wal writes must be persistent even if there
+            // are buffered updates
+            ss.put_address(&id, &[2, 3]).unwrap();
+            ss.put_super_block(&[4, 5, 6], 0).unwrap();
+            ss.put_wal(&id, &[7]).unwrap();
+            ss.del_blocks(Span::new(0, 1)).unwrap();
+            ss.del_wal(&id2).unwrap();
+            ss.put_address(&id2, &[8]).unwrap();
+        }
+
+        // storage is dropped without flush
+        let mut ss = SqliteStorage::new(path);
+        ss.connect().unwrap();
+        ss.open(Crypto::default(), Key::new_empty()).unwrap();
+        assert_eq!(ss.get_super_block(0).unwrap(), vec![4, 5, 6]);
+        assert_eq!(ss.get_wal(&id).unwrap(), vec![7]);
+        assert_eq!(ss.get_wal(&id2).unwrap_err(), Error::NotFound);
+        assert_eq!(ss.get_address(&id2).unwrap_err(), Error::NotFound);
+    }
+
+    #[test]
+    fn sqlite_exists() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+
+        // file which is not a database
+        let path = tmpdir.path().join("foo.db");
+        std::fs::write(&path, b"not a database").unwrap();
+        let ss = SqliteStorage::new(path.to_str().unwrap());
+        assert!(!ss.exists().unwrap());
+
+        // database without super block table
+        let path = tmpdir.path().join("bar.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("CREATE TABLE foo (id INTEGER)").unwrap();
+        }
+        let ss = SqliteStorage::new(path.to_str().unwrap());
+        assert!(!ss.exists().unwrap());
+
+        // initialised storage
+        let path = tmpdir.path().join("baz.db");
+        {
+            let mut ss = SqliteStorage::new(path.to_str().unwrap());
+            ss.connect().unwrap();
+            ss.init(Crypto::default(), Key::new_empty()).unwrap();
+        }
+        let ss = SqliteStorage::new(path.to_str().unwrap());
+        assert!(ss.exists().unwrap());
+    }
+}
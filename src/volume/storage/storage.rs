@@ -1,5 +1,4 @@
 use std::cmp::min;
-use std::error::Error as StdError;
 use std::fmt::{self, Debug};
 use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
 use std::path::Path;
@@ -8,86 +7,226 @@ use std::sync::{Arc, RwLock};
 use rmp_serde::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
 
+use super::cache::{CacheOptions, CacheStorage};
+use super::erasure::ErasureStorage;
+use super::faulty::{FaultInjector, FaultyStorage};
 use super::file::FileStorage;
+use super::log::LogStorage;
 use super::mem::MemStorage;
+use super::mirror::MirrorStorage;
+use super::object::ObjectStorage;
+use super::registry::{self, StorageFactory};
+#[cfg(feature = "storage-sqlite")]
+use super::sqlite::SqliteStorage;
 use crate::error::{Error, Result};
-use crate::trans::{eid::Eid, Finish};
+use crate::fs::CacheSizes;
+use crate::trans::{eid::Eid, EntityType, Finish};
 use crate::util::{
     align_ceil_chunk,
     crypto::{Cipher, Cost, Crypto, Key},
-    lru::{CountMeter, Lru, Meter, PinChecker},
+    lru::{PinChecker, ShardedLru, SizeMeter},
     IntoRef,
 };
 use crate::volume::{
-    address::Addr,
+    address::{Addr, Span},
     allocator::{Allocator, AllocatorRef},
-    storage::Storable,
+    layout::Layout,
+    storage::{CompactUnit, Storable},
 };
-use crate::{BLKS_PER_FRAME, BLK_SIZE, FRAME_SIZE};
 
-// frame cache meter, measured by frame byte size
-#[derive(Debug, Default)]
-struct FrameCacheMeter;
+// decrypted frame cache, key is the begin block index of frame
+type FrameCache = ShardedLru<usize, Vec<u8>, SizeMeter<Vec<u8>>, PinChecker<Vec<u8>>>;
 
-impl Meter<Vec<u8>> for FrameCacheMeter {
-    #[inline]
-    fn measure(&self, item: &Vec<u8>) -> isize {
-        item.len() as isize
+// create depot from uri
+fn create_depot(uri: &str) -> Result<Box<dyn Storable>> {
+    if let Some(inner_uri) = uri.strip_prefix("faulty+") {
+        // faults are injected by the global injector of this uri
+        let inner = create_depot(inner_uri)?;
+        let depot = FaultyStorage::new(inner, FaultInjector::get(uri));
+        return Ok(Box::new(depot));
     }
+
+    registry::create_storage(uri)?.ok_or(Error::InvalidUri)
+}
+
+// register built-in storage factories
+pub(super) fn register_builtins(register: &mut dyn FnMut(&'static str, Arc<StorageFactory>)) {
+    register(
+        "mem",
+        Arc::new(|name| {
+            let depot = if name.is_empty() {
+                MemStorage::new()
+            } else {
+                MemStorage::with_name(name)
+            };
+            Ok(Box::new(depot))
+        }),
+    );
+
+    register(
+        "file",
+        Arc::new(|path| Ok(Box::new(FileStorage::new(Path::new(path))))),
+    );
+
+    register(
+        "log",
+        Arc::new(|path| Ok(Box::new(LogStorage::new(Path::new(path))))),
+    );
+
+    register(
+        "object",
+        Arc::new(|name| Ok(Box::new(ObjectStorage::with_name(name)?))),
+    );
+
+    #[cfg(feature = "storage-sqlite")]
+    register(
+        "sqlite",
+        Arc::new(|path| Ok(Box::new(SqliteStorage::new(path)))),
+    );
+
+    // members are separated by '|', at least 2 members are needed
+    register(
+        "mirror",
+        Arc::new(|loc| {
+            let members = loc
+                .split('|')
+                .map(create_depot)
+                .collect::<Result<Vec<_>>>()?;
+            if members.len() < 2 {
+                return Err(Error::InvalidUri);
+            }
+            Ok(Box::new(MirrorStorage::new(members)))
+        }),
+    );
+
+    // shard numbers go before members, for example,
+    // erasure://2+1/file:///a|file:///b|file:///c
+    register(
+        "erasure",
+        Arc::new(|loc| {
+            let pos = loc.find('/').ok_or(Error::InvalidUri)?;
+            let shards = loc[..pos]
+                .split('+')
+                .map(|s| s.parse().map_err(|_| Error::InvalidUri))
+                .collect::<Result<Vec<usize>>>()?;
+            if shards.len() != 2 {
+                return Err(Error::InvalidUri);
+            }
+            let members = loc[pos + 1..]
+                .split('|')
+                .map(create_depot)
+                .collect::<Result<Vec<_>>>()?;
+            let depot = ErasureStorage::new(shards[0], shards[1], members)
+                .map_err(|_| Error::InvalidUri)?;
+            Ok(Box::new(depot))
+        }),
+    );
+
+    // cache location goes before backend, for example,
+    // cache:///tmp/cache?size=256&write=back|file:///a
+    register(
+        "cache",
+        Arc::new(|loc| {
+            let pos = loc.find('|').ok_or(Error::InvalidUri)?;
+            let (dir, opts) = CacheOptions::parse(&loc[..pos])?;
+            let backend = create_depot(&loc[pos + 1..])?;
+            Ok(Box::new(CacheStorage::new(Path::new(dir), backend, opts)))
+        }),
+    );
 }
 
 /// Storage
+///
+/// Storage can optionally have a separate data depot, blocks of direct
+/// entities, which are segment data, are kept in data depot and everything
+/// else is kept in the main depot.
+///
+/// Both depots share one block address space, blocks are allocated by the
+/// same allocator and each block index is used in only one of the depots.
+/// So neither depot has all blocks below the block watermark, and a data
+/// depot cannot be shared by several volumes.
 pub struct Storage {
-    depot: Box<Storable>,
+    depot: Box<dyn Storable>,
+
+    // optional depot for direct entity blocks, its block indices are
+    // allocated together with the main depot's
+    data_depot: Option<Box<dyn Storable>>,
 
     // block allocator
     allocator: AllocatorRef,
 
+    // whether deleted blocks can be reused, all depots must support it
+    reuse_blks: bool,
+
+    // volume block layout
+    layout: Layout,
+
     // crypto context
     crypto: Crypto,
     key: Key,
 
-    // decrypted frame cache, key is the begin block index
-    frame_cache: Lru<usize, Vec<u8>, FrameCacheMeter, PinChecker<Vec<u8>>>,
+    // decrypted frame cache, shared with readers so cached frames can be
+    // read without locking storage
+    frame_cache: Arc<FrameCache>,
 
     // entity address cache
-    addr_cache: Lru<Eid, Addr, CountMeter<Addr>, PinChecker<Addr>>,
+    addr_cache: ShardedLru<Eid, Addr, SizeMeter<Addr>, PinChecker<Addr>>,
 }
 
 impl Storage {
-    // frame cache size, in bytes
-    const FRAME_CACHE_SIZE: usize = 4 * 1024 * 1024;
-
     // frame cache threshold size, in bytes
     // if the entity size is larger than this, its frames won't be
     // put in frame cache
     const FRAME_CACHE_THRESHOLD: usize = 512 * 1024;
 
-    // address cache size
-    const ADDRESS_CACHE_SIZE: usize = 64;
+    // additional data for data depot id AEAD encryption
+    const DATA_ID_AD: [u8; 4] = [101, 103, 107, 109];
 
     pub fn new(uri: &str) -> Result<Self> {
-        let depot: Box<Storable> = if uri.starts_with("mem://") {
-            let depot = MemStorage::new();
-            Box::new(depot)
-        } else if uri.starts_with("file://") {
-            let path = Path::new(&uri[7..]);
-            let depot = FileStorage::new(path);
-            Box::new(depot)
-        } else {
-            return Err(Error::InvalidUri);
-        };
+        let depot = create_depot(uri)?;
+        Ok(Self::with_depot(depot))
+    }
 
-        let frame_cache = Lru::new(Self::FRAME_CACHE_SIZE);
+    // create storage on an existing depot
+    pub fn with_depot(depot: Box<dyn Storable>) -> Self {
+        let caches = CacheSizes::default();
+        let reuse_blks = depot.can_reuse_blocks();
 
-        Ok(Storage {
+        Storage {
             depot,
+            data_depot: None,
             allocator: Allocator::new().into_ref(),
+            reuse_blks,
+            layout: Layout::default(),
             crypto: Crypto::default(),
             key: Key::new_empty(),
-            frame_cache,
-            addr_cache: Lru::new(Self::ADDRESS_CACHE_SIZE),
-        })
+            frame_cache: Arc::new(FrameCache::new(caches.frame)),
+            addr_cache: ShardedLru::new(caches.address),
+        }
+    }
+
+    // set frame and address cache sizes, in bytes
+    pub fn set_cache_sizes(&mut self, caches: &CacheSizes) {
+        self.frame_cache.set_capacity(caches.frame);
+        self.addr_cache.set_capacity(caches.address);
+    }
+
+    // create storage with a separate depot for direct entity blocks
+    pub fn with_data(uri: &str, data_uri: &str) -> Result<Self> {
+        let mut storage = Self::new(uri)?;
+        let data_depot = create_depot(data_uri)?;
+        storage.reuse_blks = storage.reuse_blks && data_depot.can_reuse_blocks();
+        storage.data_depot = Some(data_depot);
+        Ok(storage)
+    }
+
+    // get depot where blocks of the entity type are kept
+    #[inline]
+    fn blk_depot(&mut self, ent_type: EntityType) -> &mut Box<dyn Storable> {
+        match (ent_type, self.data_depot.as_mut()) {
+            (EntityType::Direct, Some(data_depot)) => data_depot,
+            _ => &mut self.depot,
+        }
     }
 
     #[inline]
@@ -95,14 +234,33 @@ impl Storage {
         &self.key
     }
 
+    #[inline]
+    pub fn get_layout(&self) -> Layout {
+        self.layout
+    }
+
+    // set block layout, must be called before init or open
+    pub fn set_layout(&mut self, layout: Layout) -> Result<()> {
+        layout.validate()?;
+        self.depot.set_layout(layout)?;
+        if let Some(ref mut data_depot) = self.data_depot {
+            data_depot.set_layout(layout)?;
+        }
+        self.layout = layout;
+        Ok(())
+    }
+
     #[inline]
     pub fn exists(&self) -> Result<bool> {
         self.depot.exists()
     }
 
-    #[inline]
     pub fn connect(&mut self) -> Result<()> {
-        self.depot.connect()
+        self.depot.connect()?;
+        if let Some(ref mut data_depot) = self.data_depot {
+            data_depot.connect()?;
+        }
+        Ok(())
     }
 
     pub fn init(&mut self, cost: Cost, cipher: Cipher) -> Result<()> {
@@ -110,16 +268,74 @@ impl Storage {
         self.crypto = Crypto::new(cost, cipher)?;
         self.key = Crypto::gen_master_key();
 
-        // initialise depot
-        self.depot.init(self.crypto.clone(), self.key.derive(0))
+        // initialise depots
+        self.depot.init(self.crypto.clone(), self.key.derive(0))?;
+        if let Some(ref mut data_depot) = self.data_depot {
+            data_depot.init(self.crypto.clone(), self.key.derive(0))?;
+        }
+        Ok(())
     }
 
     pub fn open(&mut self, cost: Cost, cipher: Cipher, key: Key) -> Result<()> {
         self.crypto = Crypto::new(cost, cipher)?;
         self.key = key;
 
-        // open depot
-        self.depot.open(self.crypto.clone(), self.key.derive(0))
+        // open depots
+        self.depot.open(self.crypto.clone(), self.key.derive(0))?;
+        if let Some(ref mut data_depot) = self.data_depot {
+            data_depot.open(self.crypto.clone(), self.key.derive(0))?;
+        }
+        Ok(())
+    }
+
+    // create a new id for the data depot and save it encrypted in the data
+    // depot's super block, return an empty id if there is no separate data
+    // depot
+    pub fn init_data_id(&mut self) -> Result<Eid> {
+        match self.data_depot {
+            Some(ref mut data_depot) => {
+                let data_id = Eid::new();
+                let buf =
+                    self.crypto
+                        .encrypt_with_ad(data_id.as_ref(), &self.key, &Self::DATA_ID_AD)?;
+                data_depot.put_super_block(&buf, 0)?;
+                Ok(data_id)
+            }
+            None => Ok(Eid::new_empty()),
+        }
+    }
+
+    // check the data depot is the one whose id is recorded in the volume
+    // super block, a volume created with a data depot cannot be opened
+    // without it and vice versa
+    pub fn check_data_id(&mut self, data_id: &Eid) -> Result<()> {
+        match self.data_depot {
+            None if data_id.is_empty() => Ok(()),
+            None => {
+                error!("data storage is required to open the volume");
+                Err(Error::InvalidArgument)
+            }
+            Some(_) if data_id.is_empty() => {
+                error!("volume is created without data storage");
+                Err(Error::InvalidArgument)
+            }
+            Some(ref mut data_depot) => {
+                // data depot of other volume cannot be decrypted
+                let buf = match data_depot.get_super_block(0) {
+                    Ok(buf) => self
+                        .crypto
+                        .decrypt_with_ad(&buf, &self.key, &Self::DATA_ID_AD)
+                        .unwrap_or_default(),
+                    Err(ref err) if *err == Error::NotFound => Vec::new(),
+                    Err(err) => return Err(err),
+                };
+                if buf[..] != data_id.as_ref()[..] {
+                    error!("data storage doesn't belong to the volume");
+                    return Err(Error::InvalidArgument);
+                }
+                Ok(())
+            }
+        }
     }
 
     #[inline]
@@ -138,15 +354,17 @@ impl Storage {
     }
 
     // read entity address from depot and save to address cache
-    fn get_address(&mut self, id: &Eid) -> Result<Addr> {
+    pub fn get_address(&mut self, id: &Eid) -> Result<Addr> {
         // get from address cache first
-        if let Some(addr) = self.addr_cache.get_refresh(id) {
-            return Ok(addr.clone());
+        if let Some(addr) = self.addr_cache.get(id) {
+            return Ok(addr);
         }
 
         // if not in the cache, load if from depot
-        let buf = self.depot.get_address(id)?;
-        let buf = self.crypto.decrypt(&buf, &self.key)?;
+        let buf = self.read_copies(|storage| {
+            let buf = storage.depot.get_address(id)?;
+            storage.crypto.decrypt(&buf, &storage.key)
+        })?;
         let mut de = Deserializer::new(&buf[..]);
         let addr: Addr = Deserialize::deserialize(&mut de)?;
 
@@ -171,23 +389,27 @@ impl Storage {
     }
 
     // remove all blocks in a address
-    fn remove_address_blocks(&mut self, addr: &Addr) -> Result<()> {
+    fn remove_address_blocks(&mut self, addr: &Addr, ent_type: EntityType) -> Result<()> {
         let mut inaddr_idx = 0;
         for loc_span in addr.iter() {
             let blk_cnt = loc_span.span.cnt;
 
-            // delete blocks
-            self.depot.del_blocks(loc_span.span)?;
+            // delete blocks and free them in allocator
+            self.blk_depot(ent_type).del_blocks(loc_span.span)?;
+            if self.reuse_blks {
+                let mut allocator = self.allocator.write().unwrap();
+                allocator.free(loc_span.span);
+            }
 
             let mut blk_idx = loc_span.span.begin;
             let end_idx = inaddr_idx + blk_cnt;
 
             while inaddr_idx < end_idx {
-                let offset = inaddr_idx % BLKS_PER_FRAME;
+                let offset = inaddr_idx % self.layout.blks_per_frame;
                 if offset == 0 {
                     self.frame_cache.remove(&blk_idx);
                 }
-                let step = min(end_idx - inaddr_idx, BLKS_PER_FRAME - offset);
+                let step = min(end_idx - inaddr_idx, self.layout.blks_per_frame - offset);
                 inaddr_idx += step;
                 blk_idx += step;
             }
@@ -200,7 +422,12 @@ impl Storage {
         self.depot.del_wal(id)
     }
 
+    #[inline]
     pub fn del(&mut self, id: &Eid) -> Result<()> {
+        self.del_with_type(id, EntityType::Cow)
+    }
+
+    pub fn del_with_type(&mut self, id: &Eid, ent_type: EntityType) -> Result<()> {
         // get address first
         let addr = match self.get_address(id) {
             Ok(addr) => addr,
@@ -209,7 +436,7 @@ impl Storage {
         };
 
         // remove blocks in the address
-        self.remove_address_blocks(&addr)?;
+        self.remove_address_blocks(&addr, ent_type)?;
 
         // remove address
         self.depot.del_address(id)?;
@@ -219,9 +446,110 @@ impl Storage {
     }
 
     // flush underlying storage
-    #[inline]
     pub fn flush(&mut self) -> Result<()> {
-        self.depot.flush()
+        if let Some(ref mut data_depot) = self.data_depot {
+            data_depot.flush()?;
+        }
+        self.depot.flush()?;
+
+        // deletion is persistent now, freed blocks can be reused
+        if self.reuse_blks {
+            let mut allocator = self.allocator.write().unwrap();
+            allocator.release();
+        }
+        Ok(())
+    }
+
+    // get compaction candidates in all depots, each candidate is tagged with
+    // the entity type whose blocks are kept in its depot
+    pub fn compact_candidates(
+        &mut self,
+        dead_ratio: f32,
+    ) -> Result<Vec<(EntityType, CompactUnit)>> {
+        let blk_wmark = {
+            let allocator = self.allocator.read().unwrap();
+            allocator.block_wmark()
+        };
+
+        let mut units: Vec<(EntityType, CompactUnit)> = self
+            .depot
+            .compact_candidates(blk_wmark, dead_ratio)?
+            .into_iter()
+            .map(|unit| (EntityType::Cow, unit))
+            .collect();
+        if let Some(ref mut data_depot) = self.data_depot {
+            let data_units = data_depot.compact_candidates(blk_wmark, dead_ratio)?;
+            units.extend(
+                data_units
+                    .into_iter()
+                    .map(|unit| (EntityType::Direct, unit)),
+            );
+        }
+
+        Ok(units)
+    }
+
+    // get deleted blocks in the depot where blocks of the entity type are
+    // kept, None if the depot doesn't keep deletion marks
+    pub fn deleted_blocks(&mut self, ent_type: EntityType) -> Result<Option<Vec<Span>>> {
+        let blk_wmark = {
+            let allocator = self.allocator.read().unwrap();
+            allocator.block_wmark()
+        };
+        self.blk_depot(ent_type).deleted_blocks(blk_wmark)
+    }
+
+    // compact a unit in the depot where blocks of the entity type are kept,
+    // return number of bytes written and reclaimed
+    #[inline]
+    pub fn compact_unit(&mut self, ent_type: EntityType, idx: usize) -> Result<(usize, usize)> {
+        self.blk_depot(ent_type).compact_unit(idx)
+    }
+
+    // flush and write whole depot content to an image, storage with a
+    // separate data depot cannot be exported as a single image
+    pub fn export_image(&mut self, w: &mut dyn Write) -> Result<()> {
+        if self.data_depot.is_some() {
+            return Err(Error::NotSupported);
+        }
+        self.depot.flush()?;
+        self.depot.export_image(w)
+    }
+
+    // read and decrypt data from depot, if the data cannot be decrypted and
+    // depot keeps more than one copy, try the other copies one by one
+    #[inline]
+    pub(crate) fn read_copies<T, F>(&mut self, read: F) -> Result<T>
+    where
+        F: FnMut(&mut Storage) -> Result<T>,
+    {
+        self.read_blk_copies(EntityType::Cow, read)
+    }
+
+    // same as read_copies, but read from the depot where blocks of the
+    // entity type are kept
+    fn read_blk_copies<T, F>(&mut self, ent_type: EntityType, mut read: F) -> Result<T>
+    where
+        F: FnMut(&mut Storage) -> Result<T>,
+    {
+        let mut result = read(self);
+        let copies = self.blk_depot(ent_type).copies();
+        match result {
+            Err(ref err) if *err == Error::Decrypt && copies > 1 => {}
+            _ => return result,
+        }
+
+        for copy in 0..copies {
+            self.blk_depot(ent_type).set_read_copy(Some(copy));
+            result = read(self);
+            if result.is_ok() {
+                warn!("data cannot be decrypted, read from copy#{}", copy);
+                break;
+            }
+        }
+        self.blk_depot(ent_type).set_read_copy(None);
+
+        result
     }
 }
 
@@ -229,6 +557,7 @@ impl Debug for Storage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Storage")
             .field("depot", &self.depot)
+            .field("data_depot", &self.data_depot)
             .field("allocator", &self.allocator)
             .finish()
     }
@@ -264,17 +593,20 @@ impl Read for WalReader {
         if self.wal.is_empty() {
             let mut storage = self.storage.write().unwrap();
 
-            // read wal bytes from underlying storage layer
-            let wal = storage.depot.get_wal(&self.id).map_err(|err| {
-                if err == Error::NotFound {
-                    IoError::new(ErrorKind::NotFound, "Wal not found")
-                } else {
-                    IoError::new(ErrorKind::Other, err.description())
-                }
-            })?;
-
-            // decrypt wal
-            self.wal = map_io_err!(storage.crypto.decrypt(&wal, &storage.key,))?;
+            // read wal bytes from underlying storage layer and decrypt it
+            let id = &self.id;
+            self.wal = storage
+                .read_copies(|storage| {
+                    let wal = storage.depot.get_wal(id)?;
+                    storage.crypto.decrypt(&wal, &storage.key)
+                })
+                .map_err(|err| {
+                    if err == Error::NotFound {
+                        IoError::new(ErrorKind::NotFound, "Wal not found")
+                    } else {
+                        IoError::new(ErrorKind::Other, err.to_string())
+                    }
+                })?;
         }
 
         let copy_len = min(self.wal.len() - self.read, buf.len());
@@ -288,7 +620,10 @@ impl Read for WalReader {
 /// Storage Reader
 #[derive(Debug)]
 pub struct Reader {
+    id: Eid,
+    ent_type: EntityType,
     storage: StorageRef,
+    frame_cache: Arc<FrameCache>,
 
     // addresses split into frames
     addrs: Vec<Addr>,
@@ -314,22 +649,45 @@ pub struct Reader {
 }
 
 impl Reader {
+    #[inline]
     pub fn new(id: &Eid, storage: &StorageRef) -> Result<Self> {
-        let (addr, dec_frame_size) = {
-            let mut storage = storage.write().unwrap();
-            let addr = storage.get_address(id)?;
-            (addr, storage.crypto.decrypted_len(FRAME_SIZE))
+        Self::with_type(id, EntityType::Cow, storage)
+    }
+
+    pub fn with_type(id: &Eid, ent_type: EntityType, storage: &StorageRef) -> Result<Self> {
+        // try address cache with read lock first, so opening readers of
+        // cached entities doesn't block each other
+        let cached = {
+            let storage = storage.read().unwrap();
+            storage.addr_cache.get(id)
+        };
+        let addr = match cached {
+            Some(addr) => addr,
+            None => {
+                let mut storage = storage.write().unwrap();
+                storage.get_address(id).map_err(|err| err.with_eid(id))?
+            }
+        };
+
+        let (layout, dec_frame_size, frame_cache) = {
+            let storage = storage.read().unwrap();
+            let layout = storage.layout;
+            let dec_frame_size = storage.crypto.decrypted_len(layout.frame_size());
+            (layout, dec_frame_size, storage.frame_cache.clone())
         };
 
         // split address to frames and set the first frame key
-        let addrs = addr.divide_to_frames();
+        let addrs = addr.divide_to_frames(&layout);
         let frm_key = addrs[0].list[0].span.begin;
 
         let mut rdr = Reader {
+            id: id.clone(),
+            ent_type,
             storage: storage.clone(),
+            frame_cache,
             addrs,
             ent_len: addr.len,
-            frame: vec![0u8; FRAME_SIZE],
+            frame: vec![0u8; layout.frame_size()],
             frm_idx: 0,
             frm_key,
             dec_frame: vec![0u8; dec_frame_size],
@@ -342,6 +700,23 @@ impl Reader {
         Ok(rdr)
     }
 
+    // read current frame from depot and decrypt it
+    fn read_frame(&mut self, storage: &mut Storage) -> Result<usize> {
+        let mut read = 0;
+        for loc_span in self.addrs[self.frm_idx].iter() {
+            let read_len = loc_span.span.bytes_len(storage.layout.blk_size);
+            storage
+                .blk_depot(self.ent_type)
+                .get_blocks(&mut self.frame[read..read + read_len], loc_span.span)?;
+            read += read_len;
+        }
+        storage.crypto.decrypt_to(
+            &mut self.dec_frame,
+            &self.frame[..self.addrs[self.frm_idx].len],
+            &storage.key,
+        )
+    }
+
     // copy data out from decrypte frame to destination
     // return copied bytes length and flag if frame is exhausted
     fn copy_frame_out(&self, dst: &mut [u8], dec_frame: &[u8]) -> (usize, bool) {
@@ -359,50 +734,50 @@ impl Read for Reader {
             return Ok(0);
         }
 
-        let mut storage = self.storage.write().unwrap();
+        let cacheable = self.ent_len < Storage::FRAME_CACHE_THRESHOLD;
 
-        // if decrypted frame has been exhausted and the
-        // frame is not in the frame cache, read it from underlying depot
-        // and save to cache if it is necessary
-        if self.dec_frame_len == 0 && !storage.frame_cache.contains_key(&self.frm_key) {
-            // read a frame from depot
-            let mut read = 0;
-            for loc_span in self.addrs[self.frm_idx].iter() {
-                let read_len = loc_span.span.bytes_len();
-                storage
-                    .depot
-                    .get_blocks(&mut self.frame[read..read + read_len], loc_span.span)
-                    .map_err(|err| {
-                        if err == Error::NotFound {
-                            IoError::new(ErrorKind::NotFound, "Blocks not found")
-                        } else {
-                            IoError::new(ErrorKind::Other, err.description())
-                        }
-                    })?;
-                read += read_len;
-            }
+        // if the frame hasn't been read from depot, copy it out from frame
+        // cache first, storage is not locked for this
+        let cached = if self.dec_frame_len == 0 && cacheable {
+            let mut frame_cache = self.frame_cache.shard(&self.frm_key);
+            frame_cache
+                .get_refresh(&self.frm_key)
+                .map(|dec_frame| self.copy_frame_out(buf, dec_frame))
+        } else {
+            None
+        };
 
-            // decrypt frame
-            self.dec_frame_len = map_io_err!(storage.crypto.decrypt_to(
-                &mut self.dec_frame,
-                &self.frame[..self.addrs[self.frm_idx].len],
-                &storage.key,
-            ))?;
-
-            // and then add the decrypted frame to cache if it is not too big
-            if self.ent_len < Storage::FRAME_CACHE_THRESHOLD {
-                storage
-                    .frame_cache
-                    .insert(self.frm_key, self.dec_frame[..self.dec_frame_len].to_vec());
-            }
-        }
+        let (copy_len, frm_is_exhausted) = match cached {
+            Some(ret) => ret,
+            None => {
+                // if decrypted frame has been exhausted, read it from
+                // underlying depot and save to cache if it is necessary
+                if self.dec_frame_len == 0 {
+                    let storage_ref = self.storage.clone();
+                    let mut storage = storage_ref.write().unwrap();
+
+                    // read a frame from depot and decrypt it
+                    self.dec_frame_len = storage
+                        .read_blk_copies(self.ent_type, |storage| self.read_frame(storage))
+                        .map_err(|err| {
+                            if err == Error::NotFound {
+                                IoError::new(ErrorKind::NotFound, "Blocks not found")
+                            } else {
+                                IoError::new(ErrorKind::Other, err.with_eid(&self.id).to_string())
+                            }
+                        })?;
+
+                    // and then add the decrypted frame to cache if it is
+                    // not too big
+                    if cacheable {
+                        self.frame_cache
+                            .insert(self.frm_key, self.dec_frame[..self.dec_frame_len].to_vec());
+                    }
+                }
 
-        // copy decryped frame out to destination
-        let (copy_len, frm_is_exhausted) = if self.ent_len < Storage::FRAME_CACHE_THRESHOLD {
-            let dec_frame = storage.frame_cache.get_refresh(&self.frm_key).unwrap();
-            self.copy_frame_out(buf, dec_frame)
-        } else {
-            self.copy_frame_out(buf, &self.dec_frame[..self.dec_frame_len])
+                // copy decryped frame out to destination
+                self.copy_frame_out(buf, &self.dec_frame[..self.dec_frame_len])
+            }
         };
         self.read += copy_len;
 
@@ -462,29 +837,37 @@ impl Finish for WalWriter {
 /// Storage Writer
 pub struct Writer {
     id: Eid,
+    ent_type: EntityType,
     addr: Addr,
     storage: StorageRef,
 
     // encrypted frame
     frame: Vec<u8>,
 
-    // stage data buffer, length is decrypted_len(FRAME_SIZE)
+    // stage data buffer, length is decrypted_len(frame size)
     stg: Vec<u8>,
     stg_len: usize,
 }
 
 impl Writer {
+    #[inline]
     pub fn new(id: &Eid, storage: &StorageRef) -> Self {
-        let stg_size;
+        Self::with_type(id, EntityType::Cow, storage)
+    }
+
+    pub fn with_type(id: &Eid, ent_type: EntityType, storage: &StorageRef) -> Self {
+        let (frm_size, stg_size);
         {
             let storage = storage.read().unwrap();
-            stg_size = storage.crypto.decrypted_len(FRAME_SIZE);
+            frm_size = storage.layout.frame_size();
+            stg_size = storage.crypto.decrypted_len(frm_size);
         }
         let mut wtr = Writer {
             id: id.clone(),
+            ent_type,
             addr: Addr::default(),
             storage: storage.clone(),
-            frame: vec![0u8; FRAME_SIZE],
+            frame: vec![0u8; frm_size],
             stg: vec![0u8; stg_size],
             stg_len: 0,
         };
@@ -507,8 +890,9 @@ impl Writer {
                 .crypto
                 .encrypt_to(&mut self.frame, &self.stg[..self.stg_len], &storage.key)?;
 
-        let blk_cnt = align_ceil_chunk(enc_len, BLK_SIZE);
-        let aligned_len = blk_cnt * BLK_SIZE;
+        let blk_size = storage.layout.blk_size;
+        let blk_cnt = align_ceil_chunk(enc_len, blk_size);
+        let aligned_len = blk_cnt * blk_size;
 
         // add padding bytes
         Crypto::random_buf(&mut self.frame[enc_len..aligned_len]);
@@ -521,7 +905,9 @@ impl Writer {
         };
 
         // write frame to depot
-        storage.depot.put_blocks(span, &self.frame[..aligned_len])?;
+        storage
+            .blk_depot(self.ent_type)
+            .put_blocks(span, &self.frame[..aligned_len])?;
 
         // append to address and reset stage buffer
         self.addr.append(span, enc_len);
@@ -559,7 +945,7 @@ impl Finish for Writer {
         let mut storage = self.storage.write().unwrap();
         match storage.get_address(&self.id) {
             Ok(old_addr) => {
-                storage.remove_address_blocks(&old_addr)?;
+                storage.remove_address_blocks(&old_addr, self.ent_type)?;
             }
             Err(ref err) if *err == Error::NotFound => {}
             Err(err) => return Err(err),
@@ -583,7 +969,8 @@ mod tests {
     use crate::util::crypto::{Cipher, Cost, Crypto, RandomSeed, RANDOM_SEED_SIZE};
     use crate::util::init_env;
     use crate::util::speed_str;
-    use crate::volume::address::Span;
+    use crate::volume::storage::MemObjectClient;
+    use crate::{BLKS_PER_FRAME, BLK_SIZE, FRAME_SIZE};
 
     struct SizeVar {
         blk_size: usize,
@@ -598,13 +985,15 @@ mod tests {
         fn new(storage: &StorageRef) -> Self {
             let storage = storage.read().unwrap();
             let crypto = &storage.crypto;
+            let blk_size = storage.layout.blk_size;
+            let frm_size = storage.layout.frame_size();
             SizeVar {
-                blk_size: BLK_SIZE,
-                frm_size: FRAME_SIZE,
-                enc_blk_size: crypto.encrypted_len(BLK_SIZE),
-                enc_frm_size: crypto.encrypted_len(FRAME_SIZE),
-                dec_blk_size: crypto.decrypted_len(BLK_SIZE),
-                dec_frm_size: crypto.decrypted_len(FRAME_SIZE),
+                blk_size,
+                frm_size,
+                enc_blk_size: crypto.encrypted_len(blk_size),
+                enc_frm_size: crypto.encrypted_len(frm_size),
+                dec_blk_size: crypto.decrypted_len(blk_size),
+                dec_frm_size: crypto.decrypted_len(frm_size),
             }
         }
     }
@@ -764,7 +1153,7 @@ mod tests {
     #[test]
     fn mem_depot() {
         init_env();
-        let storage = Storage::new("mem://foo").unwrap();
+        let storage = Storage::new("mem://foo_depot").unwrap();
         assert!(!storage.exists().unwrap());
         test_depot(storage.into_ref());
     }
@@ -778,32 +1167,193 @@ mod tests {
         test_depot(storage.into_ref());
     }
 
-    #[cfg(feature = "storage-sqlite")]
     #[test]
-    fn sqlite_depot() {
+    fn mirror_depot() {
         init_env();
-        let mut storage = Storage::new("sqlite://:memory:").unwrap();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("mirror://mem://|log://{}", tmpdir.path().display());
+        let mut storage = Storage::new(&uri).unwrap();
+        storage.connect().unwrap();
+        storage.init(Cost::default(), Cipher::default()).unwrap();
+        test_depot(storage.into_ref());
+
+        // mirror needs at least 2 members
+        assert_eq!(
+            Storage::new("mirror://mem://").unwrap_err(),
+            Error::InvalidUri
+        );
+        assert_eq!(
+            Storage::new("mirror://mem://|foo").unwrap_err(),
+            Error::InvalidUri
+        );
+    }
+
+    #[test]
+    fn erasure_depot() {
+        init_env();
+        let storage = Storage::new("erasure://2+1/mem://|mem://|mem://").unwrap();
+        test_depot(storage.into_ref());
+
+        // number of members must match shard numbers
+        for uri in [
+            "erasure://2+1/mem://|mem://",
+            "erasure://2/mem://|mem://",
+            "erasure://0+2/mem://|mem://",
+            "erasure://2+1",
+        ]
+        .iter()
+        {
+            assert_eq!(Storage::new(uri).unwrap_err(), Error::InvalidUri);
+        }
+    }
+
+    #[test]
+    fn cache_depot() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        for opts in ["", "?size=1", "?size=1&write=back"].iter() {
+            let uri = format!("cache://{}{}|mem://", tmpdir.path().display(), opts);
+            let mut storage = Storage::new(&uri).unwrap();
+            storage.connect().unwrap();
+            storage.init(Cost::default(), Cipher::default()).unwrap();
+            test_depot(storage.into_ref());
+        }
+
+        for uri in [
+            "cache:///tmp/foo",
+            "cache://|mem://",
+            "cache:///tmp/foo?size=0|mem://",
+            "cache:///tmp/foo?write=bar|mem://",
+        ]
+        .iter()
+        {
+            assert_eq!(Storage::new(uri).unwrap_err(), Error::InvalidUri);
+        }
+    }
+
+    #[test]
+    fn layout_depot() {
+        init_env();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let layout = Layout {
+            blk_size: 4096,
+            blks_per_frame: 4,
+            blks_per_sector: 64,
+        };
+        let base = tmpdir.path().display();
+        for uri in [
+            "mem://".to_string(),
+            format!("file://{}/file", base),
+            format!("log://{}/log", base),
+            format!("mirror://mem://|file://{}/mirror", base),
+            "erasure://2+1/mem://|mem://|mem://".to_string(),
+            format!("cache://{}/cache?size=1|mem://", base),
+        ]
+        .iter()
+        {
+            let mut storage = Storage::new(uri).unwrap();
+            storage.set_layout(layout).unwrap();
+            storage.connect().unwrap();
+            storage.init(Cost::default(), Cipher::default()).unwrap();
+            test_depot(storage.into_ref());
+        }
+
+        // invalid layout
+        let mut storage = Storage::new("mem://").unwrap();
+        let layout = Layout {
+            blk_size: 3000,
+            ..Default::default()
+        };
+        assert_eq!(
+            storage.set_layout(layout).unwrap_err(),
+            Error::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn data_depot() {
+        init_env();
+        let mut storage = Storage::with_data("mem://", "mem://").unwrap();
+        storage.connect().unwrap();
+        storage.init(Cost::default(), Cipher::default()).unwrap();
+        let storage = storage.into_ref();
+        let mut buf = vec![0u8; 3 * BLK_SIZE];
+        Crypto::random_buf(&mut buf);
+        let id = Eid::new();
+        let id2 = Eid::new();
+
+        // write cow and direct entities
+        for (id, ent_type) in [(&id, EntityType::Cow), (&id2, EntityType::Direct)].iter() {
+            let mut wtr = Writer::with_type(id, *ent_type, &storage);
+            wtr.write_all(&buf).unwrap();
+            wtr.finish().unwrap();
+            let mut rdr = Reader::with_type(id, *ent_type, &storage).unwrap();
+            let mut dst = Vec::new();
+            rdr.read_to_end(&mut dst).unwrap();
+            assert_eq!(dst, buf);
+        }
+
+        // blocks are routed by entity type
+        let mut storage = storage.write().unwrap();
+        let addr = storage.get_address(&id).unwrap();
+        let addr2 = storage.get_address(&id2).unwrap();
+        let blk = Span::new(addr.iter().next().unwrap().span.begin, 1);
+        let blk2 = Span::new(addr2.iter().next().unwrap().span.begin, 1);
+        let mut dst = vec![0u8; BLK_SIZE];
+        storage.depot.get_blocks(&mut dst, blk).unwrap();
+        assert!(storage.depot.get_blocks(&mut dst, blk2).is_err());
+        let data_depot = storage.data_depot.as_mut().unwrap();
+        data_depot.get_blocks(&mut dst, blk2).unwrap();
+        assert!(data_depot.get_blocks(&mut dst, blk).is_err());
+
+        // delete direct entity
+        storage.del_with_type(&id2, EntityType::Direct).unwrap();
+        assert_eq!(storage.get_address(&id2).unwrap_err(), Error::NotFound);
+        let data_depot = storage.data_depot.as_mut().unwrap();
+        assert!(data_depot.get_blocks(&mut dst, blk2).is_err());
+
+        // data depot id is encrypted and authenticated
+        let data_id = storage.init_data_id().unwrap();
+        storage.check_data_id(&data_id).unwrap();
+        let data_depot = storage.data_depot.as_mut().unwrap();
+        let mut buf = data_depot.get_super_block(0).unwrap();
+        assert!(!buf
+            .windows(data_id.as_ref().len())
+            .any(|w| w == data_id.as_ref()));
+        buf[0] ^= 1;
+        data_depot.put_super_block(&buf, 0).unwrap();
+        assert_eq!(
+            storage.check_data_id(&data_id).unwrap_err(),
+            Error::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn object_depot() {
+        init_env();
+        ObjectStorage::register_client("object_depot", Box::new(MemObjectClient::new())).unwrap();
+        let mut storage = Storage::new("object://object_depot").unwrap();
         storage.connect().unwrap();
         storage.init(Cost::default(), Cipher::default()).unwrap();
         test_depot(storage.into_ref());
     }
 
-    #[cfg(feature = "storage-redis")]
+    #[cfg(feature = "storage-sqlite")]
     #[test]
-    fn redis_depot() {
+    fn sqlite_depot() {
         init_env();
-        let mut storage = Storage::new("redis://127.0.0.1").unwrap();
+        let mut storage = Storage::new("sqlite://:memory:").unwrap();
         storage.connect().unwrap();
         storage.init(Cost::default(), Cipher::default()).unwrap();
         test_depot(storage.into_ref());
     }
 
-    #[cfg(feature = "storage-f2ufs")]
     #[test]
     fn f2ufs_depot() {
         init_env();
-        let mut storage =
-            Storage::new("f2ufs://accessKey456@repo456?cache_type=mem&cache_size=1").unwrap();
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("log://{}", tmpdir.path().display());
+        let mut storage = Storage::new(&uri).unwrap();
         storage.connect().unwrap();
         storage.init(Cost::default(), Cipher::default()).unwrap();
         test_depot(storage.into_ref());
@@ -842,10 +1392,11 @@ mod tests {
     #[test]
     fn mem_perf() {
         init_env();
-        let mut storage = Storage::new("mem://foo").unwrap();
+        let mut storage = Storage::new("mem://foo_perf").unwrap();
         storage.init(Cost::default(), Cipher::default()).unwrap();
         let storage = storage.into_ref();
         perf_test(&storage, "Memory storage");
+        assert!(MemStorage::destroy("foo_perf"));
     }
 
     #[test]
@@ -859,6 +1410,131 @@ mod tests {
         perf_test(&storage, "File storage");
     }
 
+    // concurrent readers of different entities, read throughput should
+    // scale with threads as entities are in different cache shards
+    #[test]
+    #[ignore]
+    fn concurrent_read_perf() {
+        use std::thread;
+
+        const DATA_LEN: usize = 256 * 1024;
+        const ROUNDS: usize = 256;
+        const MAX_THREADS: usize = 8;
+
+        init_env();
+        let mut storage = Storage::new("mem://").unwrap();
+        storage.init(Cost::default(), Cipher::default()).unwrap();
+        let storage = storage.into_ref();
+
+        let mut buf = vec![0u8; DATA_LEN];
+        let seed = RandomSeed::from(&[0u8; RANDOM_SEED_SIZE]);
+        Crypto::random_buf_deterministic(&mut buf, &seed);
+        let ids: Vec<Eid> = (0..MAX_THREADS)
+            .map(|_| {
+                let id = Eid::new();
+                let mut wtr = Writer::new(&id, &storage);
+                wtr.write_all(&buf).unwrap();
+                wtr.finish().unwrap();
+                id
+            })
+            .collect();
+
+        let mut thread_cnt = 1;
+        while thread_cnt <= MAX_THREADS {
+            let now = Instant::now();
+            let children: Vec<_> = ids[..thread_cnt]
+                .iter()
+                .map(|id| {
+                    let id = id.clone();
+                    let storage = storage.clone();
+                    thread::spawn(move || {
+                        let mut dst = Vec::with_capacity(DATA_LEN);
+                        for _ in 0..ROUNDS {
+                            dst.clear();
+                            let mut rdr = Reader::new(&id, &storage).unwrap();
+                            let read = rdr.read_to_end(&mut dst).unwrap();
+                            assert_eq!(read, DATA_LEN);
+                        }
+                    })
+                })
+                .collect();
+            for child in children {
+                child.join().unwrap();
+            }
+            let time = now.elapsed();
+
+            println!(
+                "Concurrent read perf ({} threads): {}",
+                thread_cnt,
+                speed_str(&time, DATA_LEN * ROUNDS * thread_cnt)
+            );
+            thread_cnt *= 2;
+        }
+    }
+
+    // concurrent readers of different small entities, opening readers
+    // should scale with threads as addresses are in different cache shards
+    #[test]
+    #[ignore]
+    fn concurrent_open_perf() {
+        use std::thread;
+
+        const ENT_CNT: usize = 32;
+        const ROUNDS: usize = 64 * 1024;
+        const MAX_THREADS: usize = 8;
+
+        init_env();
+        let mut storage = Storage::new("mem://").unwrap();
+        storage.init(Cost::default(), Cipher::default()).unwrap();
+        let storage = storage.into_ref();
+
+        let ids: Vec<Vec<Eid>> = (0..MAX_THREADS)
+            .map(|_| {
+                (0..ENT_CNT)
+                    .map(|_| {
+                        let id = Eid::new();
+                        let mut wtr = Writer::new(&id, &storage);
+                        wtr.write_all(&[42u8; 64]).unwrap();
+                        wtr.finish().unwrap();
+                        id
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut thread_cnt = 1;
+        while thread_cnt <= MAX_THREADS {
+            let now = Instant::now();
+            let children: Vec<_> = ids[..thread_cnt]
+                .iter()
+                .map(|ids| {
+                    let ids = ids.clone();
+                    let storage = storage.clone();
+                    thread::spawn(move || {
+                        let mut dst = Vec::new();
+                        for i in 0..ROUNDS {
+                            dst.clear();
+                            let mut rdr = Reader::new(&ids[i % ENT_CNT], &storage).unwrap();
+                            rdr.read_to_end(&mut dst).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for child in children {
+                child.join().unwrap();
+            }
+            let time = now.elapsed();
+            let secs = time.as_secs() as f32 + time.subsec_nanos() as f32 / 1_000_000_000.0;
+
+            println!(
+                "Concurrent open perf ({} threads): {} ops/s",
+                thread_cnt,
+                (ROUNDS * thread_cnt) as f32 / secs
+            );
+            thread_cnt *= 2;
+        }
+    }
+
     #[test]
     #[ignore]
     fn crypto_perf_test() {
@@ -9,6 +9,7 @@ use crate::util::{
     time::Time,
     version::Version,
 };
+use crate::volume::layout::Layout;
 use crate::volume::storage::Storage;
 use crate::BLK_SIZE;
 
@@ -59,6 +60,16 @@ pub(super) struct Body {
     pub ctime: Time,
     pub mtime: Time,
     pub payload: Vec<u8>,
+
+    // volume created before block layout is configurable uses the
+    // default layout
+    #[serde(default)]
+    pub layout: Layout,
+
+    // id of the separate data storage, empty if the volume has no data
+    // storage
+    #[serde(default)]
+    pub data_id: Eid,
 }
 
 impl Body {
@@ -139,10 +150,21 @@ impl SuperBlk {
         let pwd_hash = crypto.hash_pwd(pwd, &head.salt)?;
         let vkey = &pwd_hash.value;
 
-        // read encryped body
-        let mut comp_buf = crypto
-            .decrypt_with_ad(&buf[Head::BYTES_LEN..], vkey, &Self::MAGIC)?
-            .into_buf();
+        // read encryped body, if it cannot be decrypted try other copies
+        // in storage which have the same head, so the volume key can be
+        // reused
+        let head_buf = &buf[..Head::BYTES_LEN];
+        let dec_buf = match crypto.decrypt_with_ad(&buf[Head::BYTES_LEN..], vkey, &Self::MAGIC) {
+            Err(ref err) if *err == Error::Decrypt => storage.read_copies(|storage| {
+                let copy = storage.get_super_block(suffix)?;
+                if !copy.starts_with(head_buf) {
+                    return Err(Error::Decrypt);
+                }
+                crypto.decrypt_with_ad(&copy[Head::BYTES_LEN..], vkey, &Self::MAGIC)
+            }),
+            result => result,
+        }?;
+        let mut comp_buf = dec_buf.into_buf();
         let body_buf_len = comp_buf.get_u64_le() as usize;
         let body = Body::deseri(&comp_buf.bytes()[..body_buf_len])?;
 
@@ -162,7 +184,7 @@ impl SuperBlk {
                     } else if left.body.seq < right.body.seq {
                         Ok(right)
                     } else {
-                        return Err(Error::InvalidSuperBlk);
+                        Err(Error::InvalidSuperBlk)
                     }
                 }
                 Err(ref err) if *err == Error::NotFound => Ok(left),
@@ -170,14 +192,14 @@ impl SuperBlk {
                     warn!("super block right arm is corrupted");
                     Ok(left)
                 }
-                Err(err) => return Err(err),
+                Err(err) => Err(err),
             },
             Err(ref err) if *err == Error::NotFound => right_arm,
             Err(ref err) if *err == Error::Decrypt || *err == Error::InvalidSuperBlk => {
                 warn!("super block left arm is corrupted");
                 right_arm
             }
-            Err(err) => return Err(err),
+            Err(err) => Err(err),
         }
     }
 }
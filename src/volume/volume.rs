@@ -6,15 +6,19 @@ use lz4::{Decoder as Lz4Decoder, Encoder as Lz4Encoder, EncoderBuilder as Lz4Enc
 
 use super::super_block::SuperBlk;
 use crate::error::{Error, Result};
-use crate::fs::Config;
+use crate::fs::{CacheSizes, Config};
 use crate::trans::eid::Eid;
-use crate::trans::Finish;
+use crate::trans::{EntityType, Finish};
 use crate::util::crypto::{Cipher, Cost, Salt};
 use crate::util::time::Time;
 use crate::util::version::Version;
 use crate::util::IntoRef;
+use crate::volume::address::Addr;
 use crate::volume::allocator::AllocatorRef;
+use crate::volume::armor::{Arm, ArmMap};
+use crate::volume::layout::Layout;
 use crate::volume::storage::storage::{self, Storage, StorageRef};
+use crate::volume::storage::Storable;
 
 /// Volume info
 #[derive(Debug, Clone, Default)]
@@ -25,6 +29,7 @@ pub struct Info {
     pub compress: bool,
     pub cost: Cost,
     pub cipher: Cipher,
+    pub layout: Layout,
     pub ctime: Time,
 }
 
@@ -33,25 +38,70 @@ pub struct Info {
 pub struct Volume {
     info: Info,
     storage: StorageRef,
+    arm_map: ArmMap,
 }
 
 impl Volume {
     /// Create volume instance
     pub fn new(uri: &str) -> Result<Self> {
-        let mut info = Info::default();
-        info.uri = uri.to_string();
-        let storage = Storage::new(uri)?.into_ref();
+        let info = Info {
+            uri: uri.to_string(),
+            ..Default::default()
+        };
+        let storage = Storage::new(uri)
+            .map_err(|err| err.with_uri(uri))?
+            .into_ref();
+
+        Ok(Volume {
+            info,
+            storage,
+            arm_map: ArmMap::new(),
+        })
+    }
+
+    /// Create volume instance with a separate data storage
+    ///
+    /// Blocks of direct entities are kept in data storage at `data_uri`,
+    /// everything else is kept in storage at `uri`.
+    pub fn with_data(uri: &str, data_uri: &str) -> Result<Self> {
+        let info = Info {
+            uri: uri.to_string(),
+            ..Default::default()
+        };
+        let storage = Storage::with_data(uri, data_uri)
+            .map_err(|err| err.with_uri(data_uri))?
+            .into_ref();
 
-        Ok(Volume { info, storage })
+        Ok(Volume {
+            info,
+            storage,
+            arm_map: ArmMap::new(),
+        })
+    }
+
+    /// Create volume instance on an existing storage depot
+    pub fn with_depot(uri: &str, depot: Box<dyn Storable>) -> Self {
+        let info = Info {
+            uri: uri.to_string(),
+            ..Default::default()
+        };
+        let storage = Storage::with_depot(depot).into_ref();
+        Volume {
+            info,
+            storage,
+            arm_map: ArmMap::new(),
+        }
     }
 
     /// Initialise volume
     pub fn init(&mut self, pwd: &str, cfg: &Config, payload: &[u8]) -> Result<()> {
         let mut storage = self.storage.write().unwrap();
+        storage.set_layout(cfg.layout)?;
         storage.connect()?;
 
         // initialise storage
         storage.init(cfg.cost, cfg.cipher)?;
+        let data_id = storage.init_data_id()?;
 
         // initialise info
         self.info.id = Eid::new();
@@ -59,6 +109,7 @@ impl Volume {
         self.info.compress = cfg.compress;
         self.info.cost = cfg.cost;
         self.info.cipher = cfg.cipher;
+        self.info.layout = cfg.layout;
         self.info.ctime = Time::now();
 
         // initialise super block
@@ -73,6 +124,8 @@ impl Volume {
         super_blk.body.compress = cfg.compress;
         super_blk.body.ctime = self.info.ctime;
         super_blk.body.payload = payload.to_vec();
+        super_blk.body.layout = cfg.layout;
+        super_blk.body.data_id = data_id;
 
         // save super block twice to save its both arms
         super_blk
@@ -86,6 +139,12 @@ impl Volume {
 
     /// Open volume, return super block payload and meta payload
     pub fn open(&mut self, pwd: &str) -> Result<Vec<u8>> {
+        let uri = self.info.uri.clone();
+        self.open_storage(pwd).map_err(|err| err.with_uri(&uri))
+    }
+
+    // open storage and load super block payload
+    fn open_storage(&mut self, pwd: &str) -> Result<Vec<u8>> {
         let mut storage = self.storage.write().unwrap();
         storage.connect()?;
 
@@ -97,12 +156,14 @@ impl Volume {
             return Err(Error::WrongVersion);
         }
 
-        // open storage
+        // open storage using the block layout chosen at creation
+        storage.set_layout(super_blk.body.layout)?;
         storage.open(
             super_blk.head.cost,
             super_blk.head.cipher,
             super_blk.body.key.clone(),
         )?;
+        storage.check_data_id(&super_blk.body.data_id)?;
 
         // set up info
         self.info.id = super_blk.body.volume_id.clone();
@@ -110,8 +171,13 @@ impl Volume {
         self.info.compress = super_blk.body.compress;
         self.info.cost = super_blk.head.cost;
         self.info.cipher = super_blk.head.cipher;
+        self.info.layout = super_blk.body.layout;
         self.info.ctime = super_blk.body.ctime;
 
+        // load active arm map
+        drop(storage);
+        self.arm_map.load(&self.info.id, &self.storage)?;
+
         debug!("volume opened");
 
         Ok(super_blk.body.payload.clone())
@@ -152,6 +218,13 @@ impl Volume {
         ret
     }
 
+    // set storage cache sizes
+    #[inline]
+    pub fn set_cache_sizes(&mut self, caches: &CacheSizes) {
+        let mut storage = self.storage.write().unwrap();
+        storage.set_cache_sizes(caches);
+    }
+
     // get allocator from storage
     #[inline]
     pub fn get_allocator(&self) -> AllocatorRef {
@@ -159,6 +232,25 @@ impl Volume {
         storage.get_allocator()
     }
 
+    // get entity address
+    #[inline]
+    pub fn get_address(&self, id: &Eid) -> Result<Addr> {
+        let mut storage = self.storage.write().unwrap();
+        storage.get_address(id)
+    }
+
+    // get recorded active arm of an entity
+    #[inline]
+    pub fn active_arm(&self, id: &Eid) -> Option<Arm> {
+        self.arm_map.get(id)
+    }
+
+    // record active arm of an entity, None to forget it
+    #[inline]
+    pub fn set_active_arm(&self, id: &Eid, arm: Option<Arm>) {
+        self.arm_map.set(id, arm);
+    }
+
     #[inline]
     pub fn del_wal(&mut self, id: &Eid) -> Result<()> {
         let mut storage = self.storage.write().unwrap();
@@ -172,11 +264,36 @@ impl Volume {
         storage.del(id)
     }
 
+    // delete an entity of specified type
     #[inline]
+    pub fn del_with_type(&mut self, id: &Eid, ent_type: EntityType) -> Result<()> {
+        let mut storage = self.storage.write().unwrap();
+        storage.del_with_type(id, ent_type)
+    }
+
     pub fn flush(&mut self) -> Result<()> {
+        // save arm map first so recorded arms never lag behind entities
+        self.arm_map.save(&self.info.id, &self.storage)?;
         let mut storage = self.storage.write().unwrap();
         storage.flush()
     }
+
+    /// Write whole storage content to an image
+    ///
+    /// Volume is flushed first, so the arm map and all entities written so
+    /// far are in the image.
+    pub fn export_image(&mut self, w: &mut dyn Write) -> Result<()> {
+        self.flush()?;
+        let mut storage = self.storage.write().unwrap();
+        storage.export_image(w)
+    }
+
+    /// Get underlying storage, so long storage IO such as compaction can
+    /// run without holding the volume lock
+    #[inline]
+    pub(crate) fn storage(&self) -> StorageRef {
+        self.storage.clone()
+    }
 }
 
 impl Default for Volume {
@@ -185,6 +302,7 @@ impl Default for Volume {
         Volume {
             info: Info::default(),
             storage,
+            arm_map: ArmMap::new(),
         }
     }
 }
@@ -218,13 +336,19 @@ impl Read for WalReader {
 
 /// Volume Reader
 pub struct Reader {
-    inner: Box<Read>,
+    inner: Box<dyn Read>,
 }
 
 impl Reader {
+    #[inline]
     pub fn new(id: &Eid, vol: &VolumeRef) -> Result<Self> {
+        Self::with_type(id, EntityType::Cow, vol)
+    }
+
+    pub fn with_type(id: &Eid, ent_type: EntityType, vol: &VolumeRef) -> Result<Self> {
         let vol = vol.read().unwrap();
-        let rdr = storage::Reader::new(id, &vol.storage)?;
+        let rdr = storage::Reader::with_type(id, ent_type, &vol.storage)
+            .map_err(|err| err.with_uri(&vol.info.uri))?;
         if vol.info.compress {
             Ok(Reader {
                 inner: Box::new(Lz4Decoder::new(rdr).unwrap()),
@@ -297,9 +421,14 @@ pub struct Writer {
 }
 
 impl Writer {
+    #[inline]
     pub fn new(id: &Eid, vol: &VolumeRef) -> Result<Self> {
+        Self::with_type(id, EntityType::Cow, vol)
+    }
+
+    pub fn with_type(id: &Eid, ent_type: EntityType, vol: &VolumeRef) -> Result<Self> {
         let vol = vol.read().unwrap();
-        let wtr = storage::Writer::new(id, &vol.storage);
+        let wtr = storage::Writer::with_type(id, ent_type, &vol.storage);
         let inner = if vol.info.compress {
             let comp = Lz4EncoderBuilder::new()
                 .level(0)
@@ -334,7 +463,7 @@ impl Finish for Writer {
         match self.inner {
             InnerWriter::Compress(inner) => {
                 let (wtr, result) = inner.finish();
-                result.map_err(|err| Error::from(err))?;
+                result.map_err(Error::from)?;
                 wtr.finish()
             }
             InnerWriter::NoCompress(inner) => inner.finish(),
@@ -361,12 +490,17 @@ mod tests {
     use crate::util::crypto::{Crypto, RandomSeed, RANDOM_SEED_SIZE};
     use crate::util::init_env;
     use crate::util::speed_str;
+    use crate::volume::storage::MemStorage;
 
     fn setup_mem_vol() -> VolumeRef {
         init_env();
-        let uri = "mem://test".to_string();
+        let name = format!("foo_{}", Eid::new());
+        let uri = format!("mem://{}", name);
         let mut vol = Volume::new(&uri).unwrap();
         vol.init("pwd", &Config::default(), &Vec::new()).unwrap();
+
+        // volume keeps using the store after it is removed from registry
+        MemStorage::destroy(&name);
         vol.into_ref()
     }
 
@@ -385,16 +519,16 @@ mod tests {
     }
 
     fn write_to_entity(id: &Eid, buf: &[u8], vol: &VolumeRef) {
-        let mut wtr = Writer::new(&id, &vol).unwrap();
-        wtr.write_all(&buf).unwrap();
+        let mut wtr = Writer::new(id, vol).unwrap();
+        wtr.write_all(buf).unwrap();
         wtr.finish().unwrap();
     }
 
     fn verify_entity(id: &Eid, buf: &[u8], vol: &VolumeRef) {
         let mut dst = Vec::new();
-        let mut rdr = Reader::new(&id, &vol).unwrap();
+        let mut rdr = Reader::new(id, vol).unwrap();
         rdr.read_to_end(&mut dst).unwrap();
-        assert_eq!(&dst[..], &buf[..]);
+        assert_eq!(&dst[..], buf);
     }
 
     fn read_write_test(vol: &VolumeRef) {
@@ -403,19 +537,19 @@ mod tests {
         let buf2 = [4, 5, 6];
 
         // #1, write and read
-        write_to_entity(&id, &buf, &vol);
-        verify_entity(&id, &buf, &vol);
+        write_to_entity(&id, &buf, vol);
+        verify_entity(&id, &buf, vol);
 
         // #2, write and read on same entity again
-        write_to_entity(&id, &buf2, &vol);
-        verify_entity(&id, &buf2, &vol);
+        write_to_entity(&id, &buf2, vol);
+        verify_entity(&id, &buf2, vol);
 
         // #3, delete entity
         {
             let mut vol = vol.write().unwrap();
             vol.del(&id).unwrap();
         }
-        assert_eq!(Reader::new(&id, &vol).unwrap_err(), Error::NotFound);
+        assert_eq!(Reader::new(&id, vol).unwrap_err(), Error::NotFound);
     }
 
     fn reopen_test(pwd: &str, payload: &[u8], vol: VolumeRef) {
@@ -441,8 +575,8 @@ mod tests {
         // re-open volume
         drop(vol);
         let mut vol = Volume::new(&uri).unwrap();
-        let buf = vol.open(&pwd).unwrap();
-        assert_eq!(&buf[..], &payload[..]);
+        let buf = vol.open(pwd).unwrap();
+        assert_eq!(&buf[..], payload);
         {
             let storage = vol.storage.write().unwrap();
             let allocator_ref = storage.get_allocator();
@@ -461,26 +595,49 @@ mod tests {
         read_write_test(&vol);
     }
 
+    #[test]
+    fn export_image() {
+        let vol = setup_mem_vol();
+        let id = Eid::new();
+        let arm_id = Eid::new();
+        let buf = [1, 2, 3];
+
+        // changes not flushed yet are exported as well
+        write_to_entity(&id, &buf, &vol);
+        let mut img = Vec::new();
+        {
+            let mut vol = vol.write().unwrap();
+            vol.set_active_arm(&arm_id, Some(Arm::Right));
+            vol.export_image(&mut img).unwrap();
+        }
+
+        let depot = MemStorage::import_image(&mut &img[..]).unwrap();
+        let mut vol = Volume::with_depot("mem://", Box::new(depot));
+        vol.open("pwd").unwrap();
+        assert_eq!(vol.active_arm(&arm_id), Some(Arm::Right));
+        verify_entity(&id, &buf, &vol.into_ref());
+    }
+
     #[test]
     fn file_volume() {
         let pwd = "pwd";
         let payload = [1, 2, 3];
-        let (vol, _tmpdir) = setup_file_vol(&pwd, &payload);
-        reopen_test(&pwd, &payload, vol);
+        let (vol, _tmpdir) = setup_file_vol(pwd, &payload);
+        reopen_test(pwd, &payload, vol);
     }
 
-    #[cfg(feature = "storage-f2ufs")]
     #[test]
     fn f2ufs_volume() {
         init_env();
         let pwd = "pwd";
         let payload = [1, 2, 3];
-        let uri = "f2ufs://accessKey456@repo456?cache_type=mem&cache_size=1";
+        let tmpdir = TempDir::new("f2ufs_test").expect("Create temp dir failed");
+        let uri = format!("log://{}", tmpdir.path().display());
         let mut vol = Volume::new(&uri).unwrap();
-        vol.init(&pwd, &Config::default(), &payload).unwrap();
+        vol.init(pwd, &Config::default(), &payload).unwrap();
         let vol = vol.into_ref();
 
-        reopen_test(&pwd, &payload, vol);
+        reopen_test(pwd, &payload, vol);
     }
 
     fn perf_test(vol: VolumeRef, prefix: &str) {